@@ -1,11 +1,13 @@
-use std::{fs::{metadata, remove_file, rename, File, OpenOptions}, io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write}, process::{exit, id}, sync::{Arc, RwLock}};
+use std::{fs::{metadata, remove_file, rename, File, OpenOptions}, io::{BufRead, BufReader, BufWriter, Error, ErrorKind, Read, Write}, process::{exit, id}, sync::{Arc, RwLock}, time::Instant};
+#[cfg(feature = "testing-hooks")]
+use std::{mem::take, thread::sleep, time::Duration};
 use libc::{close, fork, pid_t, strerror};
-use crate::{client::RedisClient, cmd::RedisCommand, server::{server_read, server_write, AppendFsync}, util::{error, log, timestamp, LogLevel}};
-use super::{cmd::lookup_command, obj::{try_object_encoding, try_object_sharing, RedisObject, StringStorageType}};
+use crate::{client::{ClientFlags, RedisClient}, cmd::RedisCommand, log_debug, latency::latency_add_sample_if_needed, server::{server_read, server_write, AppendFsync}, util::{error, log, timestamp, LogLevel}};
+use super::{cmd::lookup_original_command, obj::{try_object_encoding, try_object_sharing, RedisObject, StringStorageType}};
 
-/// Replay the append log file. On error REDIS_OK is returned. On non fatal
-/// error (the append only file is zero-length) REDIS_ERR is returned. On
-/// fatal error an error message is logged and the program exists.
+/// Replay the append log file. A missing file or a damaged record is an
+/// error, see aof_load_error() for how the latter is handled. An empty
+/// file loads nothing.
 pub fn load_append_only_file(filename: &str) -> Result<(), String> {
     match metadata(&filename) {
         Ok(meta) => {
@@ -44,97 +46,133 @@ pub fn load_append_only_file(filename: &str) -> Result<(), String> {
         },
     }
 
-    let read_err = |err: &str| {
-        log(LogLevel::Warning, &format!("Unrecoverable error reading the append only file: {err}"));
-        exit(1);
-    };
-
-    let fmt_err = || {
-        log(LogLevel::Warning, "Bad file format reading the append only file");
-        exit(1);
-    };
-
-    let mut iter = BufReader::new(_reader.unwrap()).lines();
+    let mut reader = BufReader::new(_reader.unwrap());
     let mut fake_client = Box::new(RedisClient::create_fake_client());
+    fake_client.flags.enable(ClientFlags::aof_client());
+    let mut offset = 0u64;
+    let mut multi_offset = 0u64;
     loop {
-        if let Some(line) = iter.next() {
-            match line {
-                Ok(line) => {
-                    if !line.starts_with("*") {
-                        fmt_err();
-                    }
-                    let mut argc = 0;
-                    let mut argv: Vec<Arc<RwLock<RedisObject>>> = Vec::new();
-                    if let Ok(i) = (line[1..]).parse() {
-                        argc = i;
-                    } else { fmt_err(); }
-                    for _ in 0..argc {
-                        let mut len = 0u64;
-                        if let Some(line_a) = iter.next() {
-                            match line_a {
-                                Ok(line_a) => {
-                                    if !line_a.starts_with("$") {
-                                        fmt_err();
-                                    }
-                                    if let Ok(l) = (line_a[1..]).parse() {
-                                        len = l;
-                                    } else { fmt_err(); }
-                                },
-                                Err(e) => { read_err(&e.to_string()); },
-                            }
-                        } else { fmt_err(); }
-                        if let Some(line_a) = iter.next() {
-                            match line_a {
-                                Ok(line_a) => {
-                                    if line_a.len() != len as usize { fmt_err(); }
-                                    argv.push(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(line_a) })));
-                                },
-                                Err(e) => { read_err(&e.to_string()); },
-                            }
-                        } else { fmt_err(); }
-                    }
+        // Where the record starts, and the DB it is replayed against: the
+        // fake client may be moved by a SELECT, an error names the DB the
+        // failing command would have touched.
+        let record_offset = offset;
+        let db_id = fake_client.db_id();
+        let mut argv: Vec<String> = Vec::new();
+        match read_aof_command(&mut reader, &mut offset, &mut argv) {
+            Ok(true) => {},
+            Ok(false) => { break; },
+            Err(e) => {
+                let name = argv.first().map(|n| n.as_str()).unwrap_or("(unreadable)");
+                return aof_load_error(&mut fake_client, name, db_id, record_offset, &e);
+            },
+        }
+        let argc = argv.len();
+        let argv: Vec<Arc<RwLock<RedisObject>>> = argv.into_iter()
+            .map(|a| Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(a) }.tracked())))
+            .collect();
+
+        // Command lookup
+        let name = argv[0].read().unwrap().as_key().to_string();
+        let Some(cmd) = lookup_original_command(&name) else {
+            return aof_load_error(&mut fake_client, &name, db_id, record_offset, "unknown command");
+        };
 
-                    // Command lookup
-                    let arg_r = argv[0].read().unwrap();
-                    let name = arg_r.string().unwrap().string().unwrap();
-                    match lookup_command(name) {
-                        None => {
-                            log(LogLevel::Warning, &format!("Unknown command '{}' reading the append only file", name));
-                            exit(1);
-                        },
-                        Some(cmd) => {
-                            // Try object sharing and encoding
-                            if server_read().share_objects {
-                                for j in 1..argc {
-                                    try_object_sharing(argv[j].clone());
-                                }
-                            }
-                            if cmd.is_bulk() {
-                                try_object_encoding(argv[argc - 1].clone());
-                            }
-
-                            // Run the command in the context of a fake client
-                            fake_client.set_argv(argv.clone());
-                            cmd.proc()(&mut fake_client);
-                        },
-                    }
+        // Try object sharing and encoding
+        if server_read().share_objects {
+            for arg in &argv[1..argc] {
+                try_object_sharing(arg.clone());
+            }
+        }
+        if cmd.is_bulk() {
+            try_object_encoding(argv[argc - 1].clone());
+        }
 
-                    
-                    // Discard the reply objects list from the fake client
+        if cmd.name() == "multi" {
+            multi_offset = record_offset;
+        }
 
-                    // Clean up, ready for the next command
-                },
-                Err(e) => {
-                    read_err(&e.to_string());
-                },
-            }
+        // Run the command in the context of a fake client.
+        // Commands that can't be replayed (BGSAVE, SHUTDOWN, ...)
+        // are skipped. Inside MULTI they are queued as for a real client,
+        // so a transaction is applied only once its EXEC is read.
+        if let Err(e) = cmd.check_context(fake_client.context()) {
+            log(LogLevel::Warning, &format!("Skipping command reading the append only file: {}", e));
+        } else if fake_client.is_multi() && !matches!(cmd.name(), "exec" | "discard" | "multi") {
+            fake_client.set_argv(argv);
+            fake_client.queue_multi_command(cmd);
         } else {
-            break;
+            fake_client.set_argv(argv);
+            cmd.proc()(&mut fake_client);
         }
+
+        // Discard the reply objects list from the fake client
+        fake_client.clear_replies();
+    }
+    if fake_client.is_multi() {
+        let db_id = fake_client.db_id();
+        return aof_load_error(&mut fake_client, "MULTI", db_id, multi_offset, "the file ends inside the transaction");
     }
     Ok(())
 }
 
+/// Read one multi bulk command starting at `offset` into `argv`, moving
+/// `offset` past it. Returns false at the end of the file. On error `argv`
+/// holds the arguments read so far.
+fn read_aof_command(reader: &mut impl BufRead, offset: &mut u64, argv: &mut Vec<String>) -> Result<bool, String> {
+    // A "*<argc>" or "$<len>" line, None at the end of the file
+    fn read_line(reader: &mut impl BufRead, offset: &mut u64, prefix: u8) -> Result<Option<usize>, String> {
+        let mut line = Vec::new();
+        let n = reader.read_until(b'\n', &mut line).map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Ok(None);
+        }
+        *offset += n as u64;
+        if !line.ends_with(b"\r\n") || line[0] != prefix {
+            return Err("bad file format".to_string());
+        }
+        std::str::from_utf8(&line[1..n - 2]).ok().and_then(|l| l.parse().ok())
+            .map(Some).ok_or_else(|| "bad file format".to_string())
+    }
+
+    let Some(argc) = read_line(reader, offset, b'*')? else { return Ok(false); };
+    if argc == 0 {
+        return Err("bad file format".to_string());
+    }
+    for _ in 0..argc {
+        let len = read_line(reader, offset, b'$')?.ok_or_else(|| "unexpected end of file".to_string())?;
+        let mut payload = vec![0u8; len + 2];
+        reader.read_exact(&mut payload).map_err(|e| match e.kind() {
+            ErrorKind::UnexpectedEof => "unexpected end of file".to_string(),
+            _ => e.to_string(),
+        })?;
+        *offset += payload.len() as u64;
+        if !payload.ends_with(b"\r\n") {
+            return Err("bad file format".to_string());
+        }
+        payload.truncate(len);
+        argv.push(String::from_utf8(payload).map_err(|_| "bad file format".to_string())?);
+    }
+    Ok(true)
+}
+
+/// Report a record that can't be replayed. With aof-load-truncated the
+/// commands before it are kept and the rest of the file is ignored, a
+/// transaction left open is discarded: its commands were only queued.
+/// Otherwise the server can't start.
+fn aof_load_error(fake_client: &mut RedisClient, name: &str, db_id: i32, offset: u64, reason: &str) -> Result<(), String> {
+    let err = format!("error replaying command {} against db {} at offset {}: {}", name, db_id, offset, reason);
+    log(LogLevel::Warning, &err);
+    if !server_read().aof_load_truncated {
+        log(LogLevel::Warning, "Unrecoverable error reading the append only file, exiting now. Set aof-load-truncated to yes to start with the commands before the bad record.");
+        exit(1);
+    }
+    if fake_client.is_multi() {
+        fake_client.end_multi();
+    }
+    log(LogLevel::Warning, &format!("AOF loaded anyway up to offset {} because aof-load-truncated is enabled", offset));
+    Err(err)
+}
+
 /// This is how rewriting of the append only file in background works:
 /// 
 /// 1) The user calls BGREWRITEAOF
@@ -151,11 +189,17 @@ pub fn rewrite_append_only_file_background() -> bool {
         return false;
     }
 
+    #[cfg(feature = "testing-hooks")]
+    let sleep_after_fork = take(&mut server_write().debug_faults.sleep_after_fork);
+
     unsafe {
+        let start = Instant::now();
         let child_pid = fork();
         if child_pid == 0 {
             // child
             close(server_read().fd);
+            #[cfg(feature = "testing-hooks")]
+            sleep(Duration::from_secs(sleep_after_fork));
             let tmp_file = format!("temp-rewriteaof-bg-{}.aof", id());
             if rewrite_append_only_file(&tmp_file) {
                 exit(0);
@@ -164,6 +208,8 @@ pub fn rewrite_append_only_file_background() -> bool {
             }
         } else {
             // parent
+            latency_add_sample_if_needed("fork", start.elapsed().as_millis() as u64);
+            log(LogLevel::Debug, &format!("fork for the append only file rewrite took {} us", start.elapsed().as_micros()));
 
             if child_pid == -1 {
                 log(LogLevel::Warning, &format!("Can't rewrite append only file in background: fork: {}", *strerror(error())));
@@ -304,17 +350,12 @@ fn rewrite_append_only_file(filename: &str) -> bool {
                             Err(e) => { return w_err(&e.to_string()); },
                         }
                     }
-                } else {
-                    assert!(false, "impossible code");
-                }
-
-                // Save the expire time
-                match db.read().unwrap().expires.get(entry.0) {
-                    Some(when) => {
-                        if *when < timestamp().as_secs() {
-                            continue;
-                        }
-                        match buf_writer.write("*3\r\n$8\r\nEXPIREAT\r\n".as_bytes()) {
+                } else if entry.1.read().unwrap().is_hash() {
+                    // Emit the HSETs needed to rebuild the hash
+                    let hash_r = entry.1.read().unwrap();
+                    let hash = hash_r.hash().unwrap();
+                    for (field, value) in hash.iter() {
+                        match buf_writer.write("*4\r\n$4\r\nHSET\r\n".as_bytes()) {
                             Ok(_) => {},
                             Err(e) => { return w_err(&e.to_string()); },
                         }
@@ -322,12 +363,36 @@ fn rewrite_append_only_file(filename: &str) -> bool {
                             Ok(_) => {},
                             Err(e) => { return w_err(&e.to_string()); },
                         }
-                        match write_bulk_u64(&mut buf_writer, *when) {
+                        match write_bulk_raw_string(&mut buf_writer, field) {
+                            Ok(_) => {},
+                            Err(e) => { return w_err(&e.to_string()); },
+                        }
+                        match write_bulk_string_object(&mut buf_writer, Arc::new(RwLock::new(value.get_decoded()))) {
                             Ok(_) => {},
                             Err(e) => { return w_err(&e.to_string()); },
                         }
-                    },
-                    None => {},
+                    }
+                } else {
+                    panic!("impossible code");
+                }
+
+                // Save the expire time
+                if let Some(when) = db.read().unwrap().expires.get(entry.0) {
+                    if *when <= timestamp().as_millis() as u64 {
+                        continue;
+                    }
+                    match buf_writer.write("*3\r\n$9\r\nPEXPIREAT\r\n".as_bytes()) {
+                        Ok(_) => {},
+                        Err(e) => { return w_err(&e.to_string()); },
+                    }
+                    match write_bulk_raw_string(&mut buf_writer, entry.0) {
+                        Ok(_) => {},
+                        Err(e) => { return w_err(&e.to_string()); },
+                    }
+                    match write_bulk_u64(&mut buf_writer, *when) {
+                        Ok(_) => {},
+                        Err(e) => { return w_err(&e.to_string()); },
+                    }
                 }
             }
         }
@@ -389,76 +454,182 @@ fn write_bulk_raw_string(buf_w: &mut BufWriter<File>, str: &str) -> Result<(), E
     Ok(())
 }
 
-pub fn feed_append_only_file(cmd: Arc<RedisCommand>, db_id: i32, argv: &Vec<Arc<RwLock<RedisObject>>>) {
-    let mut buf = String::new();
-    // The DB this command was targetting is not the same as the last command
-    // we appendend. To issue a SELECT command is needed.
+fn string_arg(s: &str) -> Arc<RwLock<RedisObject>> {
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(s.to_string()) }.tracked()))
+}
+
+/// Append `argv` to `buf` in the multi bulk protocol.
+fn cat_append_only_generic_command(buf: &mut String, argv: &[Arc<RwLock<RedisObject>>]) {
+    buf.push_str(&format!("*{}\r\n", argv.len()));
+    for arg in argv {
+        let decoded_arg = arg.read().unwrap().get_decoded();
+        let arg_str = decoded_arg.string().unwrap().string().unwrap();
+        buf.push_str(&format!("${}\r\n{}\r\n", arg_str.len(), arg_str));
+    }
+}
+
+/// Append a PEXPIREAT of `key` at now plus `ttl` times `unit` milliseconds,
+/// keeping the NX/XX/GT/LT conditions in `flags`.
+fn cat_append_only_expire_at_command(buf: &mut String, key: &Arc<RwLock<RedisObject>>, ttl: &Arc<RwLock<RedisObject>>,
+                                     unit: u64, flags: &[Arc<RwLock<RedisObject>>]) {
+    let ttl: u64 = match ttl.read().unwrap().get_decoded().string().unwrap().string().unwrap().parse() {
+        Ok(t) => t,
+        Err(e) => {
+            log(LogLevel::Warning, &format!("failed to parse expired time: {}", e));
+            0
+        },
+    };
+    let when = (timestamp().as_millis() as u64).saturating_add(ttl.saturating_mul(unit));
+    let mut argv = vec![string_arg("PEXPIREAT"), key.clone(), string_arg(&when.to_string())];
+    argv.extend_from_slice(flags);
+    cat_append_only_generic_command(buf, &argv);
+}
+
+/// Switch the AOF to the DB, if the last command appended targeted
+/// another one.
+fn cat_append_only_select(buf: &mut String, db_id: i32) {
     if db_id != server_read().append_sel_db {
         let sel_db = db_id.to_string();
         buf.push_str(&format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", sel_db.len(), sel_db));
         server_write().append_sel_db = db_id;
     }
+}
 
-    // "Fix" the argv vector if the command is EXPIRE. We want to translate
-    // EXPIREs into EXPIREATs calls
-    let mut mapped_argv = argv.clone();
-    if Arc::ptr_eq(&cmd.proc(), &lookup_command("expire").unwrap().proc()) {
-        let mut when = 0u64;
-        mapped_argv[0] = Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("EXPIREAT".to_string()) }));
-        match mapped_argv[1].read().unwrap().get_decoded().string().unwrap().string().unwrap().parse() {
-            Ok(t) => { when = t; },
-            Err(e) => {
-                log(LogLevel::Warning, &format!("failed to parse expired time: {}", e));
-            },
-        }
-        when += timestamp().as_secs();
-        mapped_argv[2] = Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(when.to_string()) }));
+pub fn feed_append_only_file(cmd: Arc<RedisCommand>, db_id: i32, argv: &[Arc<RwLock<RedisObject>>]) {
+    let mut buf = String::new();
+    // The DB this command was targetting is not the same as the last command
+    // we appendend. To issue a SELECT command is needed.
+    cat_append_only_select(&mut buf, db_id);
+
+    // A command called by its rename-command name is logged by its own
+    // name, so that the file loads whatever the renames are.
+    let mut mapped_argv = argv.to_vec();
+    if !mapped_argv[0].read().unwrap().as_key().eq_ignore_ascii_case(cmd.name()) {
+        mapped_argv[0] = string_arg(cmd.name());
     }
 
-    // Append the actual command
-    buf.push_str(&format!("*{}\r\n", argv.len()));
-    for arg in mapped_argv {
-        let decoded_arg = arg.read().unwrap().get_decoded();
-        let arg_str = decoded_arg.string().unwrap().string().unwrap();
-        buf.push_str(&format!("${}\r\n{}\r\n", arg_str.len(), arg_str));
+    // "Fix" the commands with a relative TTL: replaying them later must not
+    // restart the countdown, so they are logged with an absolute PEXPIREAT.
+    // GETEX is propagated as a PEXPIREAT already.
+    let is = |name: &str| Arc::ptr_eq(&cmd.proc(), &lookup_original_command(name).unwrap().proc());
+    if is("expire") || is("pexpire") {
+        let unit = if is("expire") { 1000 } else { 1 };
+        cat_append_only_expire_at_command(&mut buf, &mapped_argv[1], &mapped_argv[2], unit, &mapped_argv[3..]);
+    } else if is("setex") || is("psetex") {
+        let unit = if is("setex") { 1000 } else { 1 };
+        cat_append_only_generic_command(&mut buf, &[string_arg("SET"), mapped_argv[1].clone(), mapped_argv[3].clone()]);
+        cat_append_only_expire_at_command(&mut buf, &mapped_argv[1], &mapped_argv[2], unit, &[]);
+    } else {
+        cat_append_only_generic_command(&mut buf, &mapped_argv);
     }
+    append_to_aof(&buf);
+}
 
+/// Log the keys deleted by the active expire cycle as explicit DELs, so
+/// that the file doesn't depend on when it's replayed.
+pub fn feed_append_only_expired_keys(keys: &[(i32, String)]) {
+    let mut buf = String::new();
+    for (db_id, key) in keys {
+        cat_append_only_select(&mut buf, *db_id);
+        cat_append_only_generic_command(&mut buf, &[string_arg("DEL"), string_arg(key)]);
+    }
+    append_to_aof(&buf);
+}
+
+fn append_to_aof(buf: &str) {
     // We want to perform a single write. This should be guaranteed atomic
     // at least if the filesystem we are writing is a real physical one.
     // While this will save us against the server being killed I don't think
     // there is much to do about the whole server stopping for power problems
     // or alike
-    
-    match server_write().append_file.as_ref().unwrap().write_all(buf.as_bytes()) {
-        Ok(_) => {},
-        Err(e) => {
-            // Ooops, we are in troubles. The best thing to do for now is
-            // to simply exit instead to give the illusion that everything is
-            // working as expected.
-            log(LogLevel::Warning, &format!("Exiting on error writing to the append-only file: {}", e));
-            exit(1);
-        },
-    }
+    server_write().aof_buf.extend_from_slice(buf.as_bytes());
 
     // If a background append only file rewriting is in progress we want to
     // accumulate the differences between the child DB and the current one
     // in a buffer, so that when the child process will do its work we
     // can append the differences to the new append only file.
     if server_read().bg_rewrite_child_pid != -1 {
-        server_write().bg_rewrite_buf.push_str(&buf);
+        server_write().bg_rewrite_buf.push_str(buf);
     }
 
+    flush_append_only_file();
+}
+
+/// The handle the server appends to. It's a trait only so that tests can
+/// replace the real file with something that fails on demand.
+pub trait AofWriter: Write + Send + Sync {
+    fn sync(&mut self) -> Result<(), Error>;
+}
+impl AofWriter for File {
+    fn sync(&mut self) -> Result<(), Error> {
+        self.sync_all()
+    }
+}
+
+/// Write the pending AOF buffer on disk, fsyncing according to the
+/// configured policy.
+/// 
+/// On error (think ENOSPC) the part of the buffer that didn't make it is kept
+/// for the next attempt and the error is remembered in aof_last_write_status,
+/// so that write commands are refused instead of acknowledging data we can't
+/// persist. The first successful flush clears the condition.
+pub fn flush_append_only_file() {
+    let mut server = server_write();
+    let was_ok = server.aof_last_write_status.is_ok();
+    if server.aof_buf.is_empty() && was_ok {
+        return;
+    }
+    let buf = std::mem::take(&mut server.aof_buf);
     let now = timestamp().as_secs();
-    if server_read().append_fsync == AppendFsync::Always ||
-        (server_read().append_fsync == AppendFsync::EverySec && now - server_read().last_fsync > 1) {
-        match server_read().append_file.as_ref().unwrap().sync_all() {
-            Ok(_) => {},
-            Err(e) => {
-                log(LogLevel::Warning, &format!("failed to sync file to disk: {}", e));
-                return;
-            },
-        }
-        server_write().last_fsync = now;
+    let need_sync = !was_ok || server.append_fsync == AppendFsync::Always ||
+        (server.append_fsync == AppendFsync::EverySec && now - server.last_fsync > 1);
+
+    #[cfg(feature = "testing-hooks")]
+    let injected = take(&mut server.debug_faults.fail_next_aof_write);
+    #[cfg(not(feature = "testing-hooks"))]
+    let injected = false;
+
+    let mut written = 0;
+    let result = match server.append_file.as_mut() {
+        None => Ok(()),
+        Some(_) if injected => Err(Error::other("injected failure (DEBUG FAIL-NEXT-AOF-WRITE)")),
+        Some(f) => {
+            let mut result = Ok(());
+            while written < buf.len() {
+                match f.write(&buf[written..]) {
+                    Ok(0) => { result = Err(Error::from(ErrorKind::WriteZero)); break; },
+                    Ok(n) => { written += n; },
+                    Err(e) if e.kind() == ErrorKind::Interrupted => {},
+                    Err(e) => { result = Err(e); break; },
+                }
+            }
+            match result {
+                Ok(_) if need_sync => f.sync(),
+                r => r,
+            }
+        },
+    };
+    server.aof_buf = buf[written..].to_vec();
+
+    match result {
+        Ok(_) => {
+            if need_sync {
+                server.last_fsync = now;
+            }
+            server.aof_last_write_status = Ok(());
+            drop(server);
+            log_debug!("{} bytes written to the append only file", written);
+            if !was_ok {
+                log(LogLevel::Warning, "AOF write error looks solved, Redis can write again.");
+            }
+        },
+        Err(e) => {
+            server.aof_last_write_status = Err(e.to_string());
+            drop(server);
+            if was_ok {
+                log(LogLevel::Warning, &format!("Error writing to the append-only file: {}", e));
+            }
+        },
     }
 }
 
@@ -473,7 +644,345 @@ pub fn aof_remove_temp_file(child_pid: pid_t) {
 
 #[cfg(test)]
 mod tests {
-    use std::io::{BufRead, BufReader, Cursor};
+    use std::{fs::{read, remove_file, write}, io::{self, BufRead, BufReader, Cursor, Write}, ops::Deref, process::id, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex}};
+    use crate::{client::tests::{run, test_client}, server::{server_read, server_write, tests::setup, AppendFsync}};
+    use super::{flush_append_only_file, load_append_only_file, rewrite_append_only_file, AofWriter};
+
+    /// An append only file living in memory that fails with ENOSPC on demand.
+    #[derive(Clone, Default)]
+    struct MockAof {
+        data: Arc<Mutex<Vec<u8>>>,
+        full: Arc<AtomicBool>,
+    }
+    impl Write for MockAof {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.full.load(Ordering::SeqCst) {
+                return Err(io::Error::from_raw_os_error(libc::ENOSPC));
+            }
+            self.data.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl AofWriter for MockAof {
+        fn sync(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+    impl MockAof {
+        /// Turn the AOF on, writing to a fresh MockAof, until the guard is
+        /// dropped. The server is restored even if the test fails.
+        fn install() -> MockAofGuard {
+            let aof = MockAof::default();
+            let mut server = server_write();
+            server.append_only = true;
+            server.append_fsync = AppendFsync::Always;
+            server.append_file = Some(Box::new(aof.clone()));
+            server.append_sel_db = 0;
+            server.aof_buf.clear();
+            server.aof_last_write_status = Ok(());
+            MockAofGuard(aof)
+        }
+    }
+
+    struct MockAofGuard(MockAof);
+    impl Deref for MockAofGuard {
+        type Target = MockAof;
+        fn deref(&self) -> &MockAof {
+            &self.0
+        }
+    }
+    impl Drop for MockAofGuard {
+        fn drop(&mut self) {
+            let mut server = server_write();
+            server.append_only = false;
+            server.append_file = None;
+            server.append_sel_db = -1;
+            server.aof_buf.clear();
+            server.aof_last_write_status = Ok(());
+        }
+    }
+
+    #[test]
+    fn aof_write_error_rejects_writes_until_flushed() {
+        let _guard = setup();
+        let aof = MockAof::install();
+        let mut c = test_client();
+
+        aof.full.store(true, Ordering::SeqCst);
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+OK\r\n");
+        assert!(server_read().aof_last_write_status.is_err());
+        assert!(!server_read().aof_buf.is_empty());
+
+        // Writes are refused, reads still work.
+        let reply = run(&mut c, &["SET", "k", "w"]);
+        assert!(reply.starts_with("-MISCONF Errors writing to the AOF file: "), "{}", reply);
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\nv\r\n");
+
+        // Still full: the retry keeps the error and the pending data.
+        flush_append_only_file();
+        assert!(server_read().aof_last_write_status.is_err());
+
+        aof.full.store(false, Ordering::SeqCst);
+        flush_append_only_file();
+        assert!(server_read().aof_last_write_status.is_ok());
+        assert!(server_read().aof_buf.is_empty());
+        assert!(String::from_utf8_lossy(&aof.data.lock().unwrap()).ends_with("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n"));
+        assert_eq!(run(&mut c, &["SET", "k", "w"]), "+OK\r\n");
+    }
+
+    #[test]
+    fn negative_expire_is_propagated_as_del() {
+        let _guard = setup();
+        let aof = MockAof::install();
+        let mut c = test_client();
+        run(&mut c, &["SET", "k", "v"]);
+        let dirty = server_read().dirty;
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "-1"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "k"]), ":0\r\n");
+        assert_eq!(server_read().dirty, dirty + 1);
+        // A missing key: nothing to propagate.
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "-1"]), ":0\r\n");
+
+        let data = String::from_utf8_lossy(&aof.data.lock().unwrap()).to_string();
+        assert!(data.ends_with("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*2\r\n$3\r\nDEL\r\n$1\r\nk\r\n"), "{:?}", data);
+    }
+
+    #[test]
+    fn actively_expired_keys_are_propagated_as_del() {
+        let _guard = setup();
+        let aof = MockAof::install();
+        let mut c = test_client();
+        run(&mut c, &["SET", "live", "v"]);
+        run(&mut c, &["EXPIRE", "live", "1000"]);
+        for (db, key) in [("0", "a"), ("1", "b"), ("1", "c")] {
+            run(&mut c, &["SELECT", db]);
+            run(&mut c, &["SET", key, "v"]);
+            run(&mut c, &["PEXPIRE", key, "1"]);
+        }
+        std::thread::sleep(std::time::Duration::from_millis(5));
+        let expired = server_read().stat_expiredkeys;
+        let dirty = server_read().dirty;
+        aof.data.lock().unwrap().clear();
+
+        crate::handler::server_cron(0, None);
+        assert_eq!(server_read().stat_expiredkeys, expired + 3);
+        assert_eq!(server_read().dirty, dirty + 3);
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":0\r\n");
+        run(&mut c, &["SELECT", "0"]);
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":1\r\n");
+
+        // Each DEL in the DB of its key, whatever the sampling order
+        let data = String::from_utf8_lossy(&aof.data.lock().unwrap()).to_string();
+        let mut db = "1";
+        let mut deleted = Vec::new();
+        for cmd in data.split("*2\r\n").skip(1) {
+            let args: Vec<&str> = cmd.split("\r\n").skip(1).step_by(2).collect();
+            match args[..] {
+                ["SELECT", id] => { db = id; },
+                ["DEL", key] => { deleted.push((db, key)); },
+                _ => panic!("{:?}", data),
+            }
+        }
+        deleted.sort();
+        assert_eq!(deleted, [("0", "a"), ("1", "b"), ("1", "c")]);
+    }
+
+    #[test]
+    fn transactions_are_propagated_as_a_whole() {
+        let _guard = setup();
+        let aof = MockAof::install();
+        let mut c = test_client();
+        run(&mut c, &["MULTI"]);
+        run(&mut c, &["SET", "k", "v"]);
+        run(&mut c, &["GET", "k"]);
+        run(&mut c, &["EXEC"]);
+
+        let data = String::from_utf8_lossy(&aof.data.lock().unwrap()).to_string();
+        assert_eq!(data, "*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*1\r\n$4\r\nEXEC\r\n");
+
+        // A SELECT inside the transaction moves the following commands
+        aof.data.lock().unwrap().clear();
+        run(&mut c, &["MULTI"]);
+        run(&mut c, &["SELECT", "2"]);
+        run(&mut c, &["SET", "k", "v"]);
+        run(&mut c, &["EXEC"]);
+        let data = String::from_utf8_lossy(&aof.data.lock().unwrap()).to_string();
+        assert_eq!(data, "*1\r\n$5\r\nMULTI\r\n*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n*3\r\n$3\r\nSET\r\n$1\r\nk\r\n$1\r\nv\r\n*1\r\n$4\r\nEXEC\r\n");
+    }
+
+    #[test]
+    fn rewrite_rebuilds_hashes() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["HSET", "h", "a", "1"]);
+        run(&mut c, &["HSET", "h", "b", "two"]);
+        let file = format!("test-rewrite-hash-{}.aof", id());
+        assert!(rewrite_append_only_file(&file));
+        assert!(String::from_utf8_lossy(&read(&file).unwrap()).contains("*4\r\n$4\r\nHSET\r\n$1\r\nh\r\n"));
+
+        run(&mut c, &["FLUSHALL"]);
+        assert!(load_append_only_file(&file).is_ok());
+        remove_file(&file).unwrap();
+        run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "1"]);
+        assert_eq!(run(&mut c, &["HGETALL", "h"]), "*4\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$3\r\ntwo\r\n");
+        run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "0"]);
+    }
+
+    #[test]
+    fn aof_replay_follows_select_into_other_dbs() {
+        let _guard = setup();
+        let aof = MockAof::install();
+        let mut c = test_client();
+        run(&mut c, &["SET", "in0", "a"]);
+        run(&mut c, &["SELECT", "3"]);
+        run(&mut c, &["SET", "in3", "b"]);
+        run(&mut c, &["RPUSH", "list3", "x"]);
+        let data = aof.data.lock().unwrap().clone();
+        // The replay must not be appended again
+        drop(aof);
+        assert!(String::from_utf8_lossy(&data).contains("*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n"));
+
+        run(&mut c, &["FLUSHALL"]);
+        let file = format!("test-select-{}.aof", id());
+        write(&file, &data).unwrap();
+        assert!(load_append_only_file(&file).is_ok());
+        remove_file(&file).unwrap();
+
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":2\r\n");
+        assert_eq!(run(&mut c, &["GET", "in3"]), "$1\r\nb\r\n");
+        let key = run(&mut c, &["RANDOMKEY"]);
+        assert!(key == "+in3\r\n" || key == "+list3\r\n", "{}", key);
+        run(&mut c, &["SELECT", "0"]);
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["RANDOMKEY"]), "+in0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "in3"]), ":0\r\n");
+    }
+
+    #[test]
+    fn aof_replay_skips_admin_commands() {
+        let _guard = setup();
+        let mut c = test_client();
+        let file = format!("test-context-{}.aof", id());
+        let aof = ["*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n",
+            "*1\r\n$6\r\nBGSAVE\r\n",
+            "*3\r\n$5\r\nDEBUG\r\n$5\r\nSLEEP\r\n$2\r\n10\r\n",
+            "*1\r\n$8\r\nSHUTDOWN\r\n",
+            "*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n"].concat();
+        write(&file, aof).unwrap();
+        assert!(load_append_only_file(&file).is_ok());
+        remove_file(&file).unwrap();
+
+        assert_eq!(server_read().bg_save_child_pid, -1);
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":2\r\n");
+        assert_eq!(run(&mut c, &["GET", "b"]), "$1\r\n2\r\n");
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn relative_ttls_are_logged_as_deadlines() {
+        use std::time::Duration;
+        use crate::util::set_mock_time;
+        let _guard = setup();
+        let aof = MockAof::install();
+        let t0 = Duration::from_secs(1_000_000);
+        set_mock_time(Some(t0));
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["SETEX", "s", "100", "v"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["PSETEX", "p", "100500", "v"]), "+OK\r\n");
+        run(&mut c, &["SET", "e", "v"]);
+        assert_eq!(run(&mut c, &["EXPIRE", "e", "100", "NX"]), ":1\r\n");
+        run(&mut c, &["SET", "g", "v"]);
+        assert_eq!(run(&mut c, &["GETEX", "g", "PX", "100000"]), "$1\r\nv\r\n");
+        assert_eq!(run(&mut c, &["SETEX", "s", "0", "v"]), "-ERR invalid expire time in 'setex' command\r\n");
+        let data = aof.data.lock().unwrap().clone();
+        drop(aof);
+        assert_eq!(String::from_utf8_lossy(&data), [
+            "*3\r\n$3\r\nSET\r\n$1\r\ns\r\n$1\r\nv\r\n",
+            "*3\r\n$9\r\nPEXPIREAT\r\n$1\r\ns\r\n$10\r\n1000100000\r\n",
+            "*3\r\n$3\r\nSET\r\n$1\r\np\r\n$1\r\nv\r\n",
+            "*3\r\n$9\r\nPEXPIREAT\r\n$1\r\np\r\n$10\r\n1000100500\r\n",
+            "*3\r\n$3\r\nSET\r\n$1\r\ne\r\n$1\r\nv\r\n",
+            "*4\r\n$9\r\nPEXPIREAT\r\n$1\r\ne\r\n$10\r\n1000100000\r\n$2\r\nNX\r\n",
+            "*3\r\n$3\r\nSET\r\n$1\r\ng\r\n$1\r\nv\r\n",
+            "*3\r\n$9\r\nPEXPIREAT\r\n$1\r\ng\r\n$10\r\n1000100000\r\n",
+        ].concat());
+
+        // Replayed 30 seconds later the keys have 70 seconds left, not 100.
+        run(&mut c, &["FLUSHALL"]);
+        set_mock_time(Some(t0 + Duration::from_secs(30)));
+        let file = format!("test-ttl-{}.aof", id());
+        write(&file, &data).unwrap();
+        assert!(load_append_only_file(&file).is_ok());
+        remove_file(&file).unwrap();
+        assert_eq!(run(&mut c, &["TTL", "s"]), ":70\r\n");
+        assert_eq!(run(&mut c, &["TTL", "p"]), ":71\r\n");
+        assert_eq!(run(&mut c, &["TTL", "e"]), ":70\r\n");
+        assert_eq!(run(&mut c, &["TTL", "g"]), ":70\r\n");
+        assert_eq!(run(&mut c, &["GET", "s"]), "$1\r\nv\r\n");
+        set_mock_time(None);
+    }
+
+    #[test]
+    fn aof_load_errors_name_the_db_and_the_offset() {
+        let _guard = setup();
+        server_write().aof_load_truncated = true;
+        let mut c = test_client();
+        let file = format!("test-damaged-{}.aof", id());
+        let good = ["*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n",
+            "*2\r\n$6\r\nSELECT\r\n$1\r\n2\r\n",
+            "*3\r\n$3\r\nSET\r\n$1\r\nb\r\n$1\r\n2\r\n"].concat();
+        let cases = [
+            ("*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$9\r\n3\r\n", "command SET against db 2 at offset 77: unexpected end of file"),
+            ("*3\r\n$3\r\nSET\r\nx$1\r\nc\r\n", "command SET against db 2 at offset 77: bad file format"),
+            ("*1\r\n$4\r\nNOPE\r\n*3\r\n$3\r\nSET\r\n$1\r\nd\r\n$1\r\n4\r\n", "command NOPE against db 2 at offset 77: unknown command"),
+            ("garbage\r\n", "command (unreadable) against db 2 at offset 77: bad file format"),
+            ("*1\r\n$5\r\nMULTI\r\n*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n",
+                "command MULTI against db 2 at offset 77: the file ends inside the transaction"),
+        ];
+        for (bad, err) in cases {
+            run(&mut c, &["FLUSHALL"]);
+            write(&file, [&good, bad].concat()).unwrap();
+            assert_eq!(load_append_only_file(&file), Err(format!("error replaying {}", err)));
+
+            // Everything before the bad record is there, nothing after it.
+            assert_eq!(run(&mut c, &["GET", "a"]), "$1\r\n1\r\n", "{}", err);
+            run(&mut c, &["SELECT", "2"]);
+            assert_eq!(run(&mut c, &["DBSIZE"]), ":1\r\n", "{}", err);
+            assert_eq!(run(&mut c, &["GET", "b"]), "$1\r\n2\r\n", "{}", err);
+            run(&mut c, &["SELECT", "0"]);
+        }
+
+        // A whole transaction is replayed, in the DB it selects.
+        run(&mut c, &["FLUSHALL"]);
+        let multi = ["*1\r\n$5\r\nMULTI\r\n", "*2\r\n$6\r\nSELECT\r\n$1\r\n3\r\n",
+            "*3\r\n$3\r\nSET\r\n$1\r\nc\r\n$1\r\n3\r\n", "*1\r\n$4\r\nEXEC\r\n"].concat();
+        write(&file, [&good, &multi[..]].concat()).unwrap();
+        assert_eq!(load_append_only_file(&file), Ok(()));
+        run(&mut c, &["SELECT", "3"]);
+        assert_eq!(run(&mut c, &["GET", "c"]), "$1\r\n3\r\n");
+        remove_file(&file).unwrap();
+        server_write().aof_load_truncated = false;
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn injected_aof_write_failure_is_recovered_by_cron() {
+        let _guard = setup();
+        let aof = MockAof::install();
+        let mut c = test_client();
+
+        assert_eq!(run(&mut c, &["DEBUG", "FAIL-NEXT-AOF-WRITE"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+OK\r\n");
+        assert!(run(&mut c, &["INCR", "n"]).starts_with("-MISCONF "));
+
+        crate::handler::server_cron(0, None);
+        assert!(server_read().aof_last_write_status.is_ok());
+        assert_eq!(run(&mut c, &["INCR", "n"]), ":1\r\n");
+        assert!(String::from_utf8_lossy(&aof.data.lock().unwrap()).contains("$1\r\nk\r\n$1\r\nv\r\n"));
+    }
 
 
     #[test]
@@ -1,9 +1,8 @@
-use std::{collections::{HashSet, LinkedList}, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+use std::{collections::{HashSet, LinkedList}, fs::File, mem::take, ops::Deref, sync::{atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
 use libc::close;
 use once_cell::sync::Lazy;
-use rand::Rng;
-use crate::{cmd::lookup_command, eventloop::{create_file_event, delete_file_event, Mask}, handler::{read_query_from_client, send_reply_to_client}, net::{nonblock, tcp_no_delay}, server::{server_read, server_write, RedisDB, ReplState, ONE_GB}, util::{log, timestamp, LogLevel}, zmalloc::MemCounter};
-use super::{cmd::{call, MultiCmd, MAX_SIZE_INLINE_CMD}, obj::{RedisObject, StringStorageType, CRLF}};
+use crate::{cmd::lookup_command, errors, resp::Value, eventloop::{create_file_event, delete_file_event, Mask}, handler::{read_query_from_client, send_reply_to_client}, lazyfree::free_object, net::{nonblock, peer_to_string, tcp_no_delay}, pubsub::{pubsub_unsubscribe_all_channels, pubsub_unsubscribe_all_patterns}, server::{server_read, server_write, ClientBufferLimit, RedisDB, ReplState, DEFAULT_CLIENT_OBUF_LIMITS, ONE_GB}, util::{log, timestamp, LogLevel}, zmalloc::MemCounter};
+use super::{cmd::{call, CmdContext, MultiCmd, RedisCommand, MAX_SIZE_INLINE_CMD}, obj::{HashStorageType, ListStorageType, ObjSet, RedisObject, SetStorageType, StringStorageType, ZSetStorageType, CRLF, NULL_MULTI_BULK, PONG_BYTES, QUEUED, WRONG_TYPE_ERR}};
 
 
 /// 
@@ -21,21 +20,102 @@ pub fn clients_read() -> RwLockReadGuard<'static, LinkedList<Arc<RwLock<RedisCli
 pub fn clients_write() -> RwLockWriteGuard<'static, LinkedList<Arc<RwLock<RedisClient>>>> {
     CLIENTS.write().unwrap()
 }
-/// Deleted client fd set.
-pub static DELETED_CLIENTS: Lazy<RwLock<HashSet<i32>>> = Lazy::new(|| {
+/// Memory used by the server as seen by maxmemory. Every allocation goes
+/// through the counting allocator, so this already includes the client query
+/// and reply buffers: a slow client filling its output buffer pushes the
+/// server towards the limit like a big value would.
+pub fn used_memory() -> usize {
+    MemCounter::used_memory()
+}
+
+/// A bulk payload is counted twice against maxmemory before it's read: once
+/// in the query buffer, once in the argument object.
+static BULK_OOM_HEADROOM: usize = 2;
+
+/// The part of used_memory() taken by the buffers of connected clients.
+/// A client currently locked by the caller is skipped.
+pub fn clients_buffers_size() -> usize {
+    clients_read().iter()
+        .filter_map(|c| c.try_read().ok().map(|c| c.buffers_size()))
+        .sum()
+}
+/// Close the connection of every client. A client locked by the caller
+/// (i.e. the one running SHUTDOWN) is skipped and must be closed by it.
+pub fn close_all_clients() {
+    for c in clients_read().iter() {
+        if let Ok(mut c) = c.try_write() {
+            c.close_connection();
+        }
+    }
+}
+/// Ids of the clients unblocked since the last before_sleep(), which runs
+/// the commands they received while blocked.
+pub static UNBLOCKED_CLIENTS: Lazy<RwLock<Vec<u64>>> = Lazy::new(|| {
+    RwLock::new(Vec::new())
+});
+pub fn unblocked_clients_write() -> RwLockWriteGuard<'static, Vec<u64>> {
+    UNBLOCKED_CLIENTS.write().unwrap()
+}
+/// Ids of the clients to free in before_sleep(), see free_client_async().
+/// Ids are never reused, unlike fds, so a stale entry can't hit a new client.
+pub static DELETED_CLIENTS: Lazy<RwLock<HashSet<u64>>> = Lazy::new(|| {
     RwLock::new(HashSet::new())
 });
-pub fn deleled_clients_read() -> RwLockReadGuard<'static, HashSet<i32>> {
+pub fn deleled_clients_read() -> RwLockReadGuard<'static, HashSet<u64>> {
     DELETED_CLIENTS.read().unwrap()
 }
-pub fn deleted_clients_write() -> RwLockWriteGuard<'static, HashSet<i32>> {
+pub fn deleted_clients_write() -> RwLockWriteGuard<'static, HashSet<u64>> {
     DELETED_CLIENTS.write().unwrap()
 }
+/// Whether buf starts with a complete RESET, inline or multi bulk.
+fn starts_with_reset(buf: &str) -> bool {
+    let line = |s: &str| s.find('\n').map(|nl| s[..nl].strip_suffix('\r').unwrap_or(&s[..nl]).to_string());
+    match line(buf) {
+        Some(l) if l.eq_ignore_ascii_case("reset") => true,
+        Some(l) if l == "*1" => {
+            let rest = &buf[buf.find('\n').unwrap() + 1..];
+            rest.find('\n').is_some_and(|nl| rest[..nl].trim_end_matches('\r') == "$5") &&
+                line(&rest[rest.find('\n').unwrap() + 1..]).is_some_and(|a| a.eq_ignore_ascii_case("reset"))
+        },
+        _ => false,
+    }
+}
+/// Next client id, ids start from 1.
+static NEXT_CLIENT_ID: AtomicU64 = AtomicU64::new(1);
+fn next_client_id() -> u64 {
+    NEXT_CLIENT_ID.fetch_add(1, Ordering::Relaxed)
+}
+/// Connection counters for INFO. They are atomics so the accept path and
+/// the client destructor can update them without the server lock.
+static CONNECTED_CLIENTS: AtomicU64 = AtomicU64::new(0);
+static CONNECTED_CLIENTS_PEAK: AtomicU64 = AtomicU64::new(0);
+static REJECTED_CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub fn connected_clients() -> u64 {
+    CONNECTED_CLIENTS.load(Ordering::Relaxed)
+}
+pub fn connected_clients_peak() -> u64 {
+    CONNECTED_CLIENTS_PEAK.load(Ordering::Relaxed)
+}
+pub fn rejected_connections() -> u64 {
+    REJECTED_CONNECTIONS.load(Ordering::Relaxed)
+}
+/// Count a connection refused because of maxclients.
+pub fn count_rejected_connection() -> u64 {
+    REJECTED_CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1
+}
+/// The output buffer limits of every client class, kept out of the server
+/// so that add_reply() never takes the server lock. Updated when
+/// 'client-output-buffer-limit' is set.
+static CLIENT_OBUF_LIMITS: RwLock<[ClientBufferLimit; 3]> = RwLock::new(DEFAULT_CLIENT_OBUF_LIMITS);
+pub fn set_client_obuf_limits(limits: [ClientBufferLimit; 3]) {
+    *CLIENT_OBUF_LIMITS.write().unwrap() = limits;
+}
 
 
 /// With multiplexing we need to take per-clinet state.
 /// Clients are taken in a liked list.
 pub struct RedisClient {
+    id: u64,                        // unique, never reused
     pub fd: i32,
     pub db: Option<Arc<RwLock<RedisDB>>>,
     pub query_buf: String,
@@ -43,15 +123,35 @@ pub struct RedisClient {
     mbargv: Vec<Arc<RwLock<RedisObject>>>,
     bulk_len: i32,                  // bulk read len. -1 if not in bulk read mode
     multi_bulk: i32,                // multi bulk command format active
+    discarding: bool,               // the command was refused (OOM), skip the rest of it
+    discard_len: usize,             // bytes of a refused bulk payload still to skip
     pub sent_len: usize,
     pub reply: RwLock<LinkedList<Arc<RedisObject>>>,
+    reply_bytes: AtomicUsize,       // bytes of the objects in the reply list, sent ones included
+    buf: Vec<u8>,                   // output buffer for small replies, sent before the reply list
+    obuf_soft_limit_reached_time: AtomicU64,    // when the soft output buffer limit was first hit, 0 if below it
+    close_asap: AtomicBool,         // over the output buffer limits: drop the replies, freed in before_sleep()
     pub flags: ClientFlags,
     pub last_interaction: u64,          // time of the last interaction, used for timeout (in seconds)
+    ctime: u64,                         // creation time (in seconds)
+    pub last_cmd: &'static str,         // name of the last command run, for CLIENT LIST
     pub authenticated: bool,            // when requirepass is non-NULL
-    repl_state: ReplState,          // replication state if this is a slave
+    pub repl_state: ReplState,      // replication state if this is a slave
+    pub repl_db: Option<File>,      // the dump sent to the slave, see send_bulk_to_slave()
+    pub repl_db_off: u64,           // bytes of the dump sent, its preamble included
+    pub repl_db_size: u64,          // size of the dump
+    pub slave_sel_db: i32,          // DB of the last command sent to the slave, -1 for none
+    pub slave_addr: String,         // "ip:port" the slave connected from, taken at SYNC
     mstate: MultiState,             // MULTI/EXEC state
-    blocking_keys: RwLock<Vec<Arc<RedisObject>>>,   // The key we are waiting to terminate a blocking
-                                            // operation such as BLPOP. Otherwise NULL.
+    blocking_keys: Vec<String>,     // The keys we are waiting to terminate a blocking
+                                    // operation such as BLPOP. Otherwise empty.
+    blocking_timeout: u64,          // unix time in ms the blocking operation times out at, 0 for never
+    pub pubsub_channels: HashSet<String>,   // channels a client is interested in (SUBSCRIBE)
+    pub pubsub_patterns: Vec<String>,       // patterns a client is interested in (PSUBSCRIBE)
+    pub pubsub_shard_channels: HashSet<String>, // sharded channels a client is interested in (SSUBSCRIBE)
+    connected: bool,                        // counted in connected_clients, fake clients aren't
+    propagate_argv: Option<Vec<Arc<RwLock<RedisObject>>>>,  // replaces argv in the AOF, see propagate_as()
+    event_commands: u64,                    // commands run since process_input_buf() was called
 }
 
 impl RedisClient {
@@ -65,23 +165,47 @@ impl RedisClient {
             Err(e) => { return Err(e); },
         }
         let mut c = RedisClient {
+            id: next_client_id(),
             fd,
             db: None,
             query_buf: String::new(),
             argv: Vec::new(),
             bulk_len: -1,
             multi_bulk: 0,
+            discarding: false,
+            discard_len: 0,
             mbargv: Vec::new(),
             sent_len: 0,
             flags: ClientFlags(RwLock::new(0)),
             last_interaction: timestamp().as_secs(),
+            ctime: timestamp().as_secs(),
+            last_cmd: "NULL",
             authenticated: false,
             repl_state: ReplState::None,
+            repl_db: None,
+            repl_db_off: 0,
+            repl_db_size: 0,
+            slave_sel_db: -1,
+            slave_addr: String::new(),
             reply: RwLock::new(LinkedList::new()),
-            blocking_keys: RwLock::new(Vec::new()),
+            reply_bytes: AtomicUsize::new(0),
+            buf: Vec::new(),
+            obuf_soft_limit_reached_time: AtomicU64::new(0),
+            close_asap: AtomicBool::new(false),
+            blocking_keys: Vec::new(),
+            blocking_timeout: 0,
             mstate: MultiState { commands: Vec::new() },
+            pubsub_channels: HashSet::new(),
+            pubsub_patterns: Vec::new(),
+            pubsub_shard_channels: HashSet::new(),
+            connected: true,
+            propagate_argv: None,
+            event_commands: 0,
         };
         c.select_db(0);
+        log(LogLevel::Debug, &format!("Client id={} fd={} created", c.id, fd));
+        let connected = CONNECTED_CLIENTS.fetch_add(1, Ordering::Relaxed) + 1;
+        CONNECTED_CLIENTS_PEAK.fetch_max(connected, Ordering::Relaxed);
         let c = Arc::new(RwLock::new(c));
         create_file_event(fd, Mask::Readable, Arc::new(read_query_from_client))?;
         clients_write().push_back(c.clone());
@@ -92,6 +216,7 @@ impl RedisClient {
     /// order to load the append only file we need to create a fake client.
     pub fn create_fake_client() -> RedisClient {
         let mut c = RedisClient { 
+            id: next_client_id(),
             db: None, 
             fd: -1, 
             query_buf: String::new(),
@@ -100,15 +225,35 @@ impl RedisClient {
             // We set the fake client as a slave waiting for the synchronization
             // so that Redis will not try to send replies to this client.
             repl_state: ReplState::WaitBgSaveStart,
+            repl_db: None,
+            repl_db_off: 0,
+            repl_db_size: 0,
+            slave_sel_db: -1,
+            slave_addr: String::new(),
             reply: RwLock::new(LinkedList::new()),
+            reply_bytes: AtomicUsize::new(0),
+            buf: Vec::new(),
+            obuf_soft_limit_reached_time: AtomicU64::new(0),
+            close_asap: AtomicBool::new(false),
             mbargv: Vec::new(),
             bulk_len: 0,
             multi_bulk: 0,
+            discarding: false,
+            discard_len: 0,
             sent_len: 0,
             last_interaction: 0,
+            ctime: timestamp().as_secs(),
+            last_cmd: "NULL",
             authenticated: false,
             mstate: MultiState { commands: Vec::new() },
-            blocking_keys: RwLock::new(Vec::new()),
+            blocking_keys: Vec::new(),
+            blocking_timeout: 0,
+            pubsub_channels: HashSet::new(),
+            pubsub_patterns: Vec::new(),
+            pubsub_shard_channels: HashSet::new(),
+            connected: false,
+            propagate_argv: None,
+            event_commands: 0,
         };
 
         c.select_db(0);
@@ -116,85 +261,124 @@ impl RedisClient {
     }
 
     pub fn process_input_buf(&mut self) {
-        // Before to process the input buffer, make sure the client is not
-        // waitig for a blocking operation such as BLPOP. Note that the first
-        // iteration the client is never blocked, otherwise the processInputBuffer
-        // would not be called at all, but after the execution of the first commands
-        // in the input buffer the client may be blocked, and the "goto again"
-        // will try to reiterate. The following line will make it return asap.
-        if self.flags.is_blocked() || self.flags.is_io_wait() {
-            return;
+        // Work on the buffer out of the client and drop the consumed bytes
+        // once, so that a big pipeline doesn't move the rest of the buffer
+        // for every command.
+        let mut buf = std::mem::take(&mut self.query_buf);
+        self.event_commands = 0;
+        let consumed = self.process_commands(&buf);
+        buf.drain(..consumed);
+        buf.push_str(&self.query_buf);
+        self.query_buf = buf;
+        // How many commands the clients send at once, see INFO
+        if self.event_commands > 0 {
+            server_write().stat_pipeline.record(self.event_commands);
         }
-        // log(LogLevel::Verbose, &format!("process_input_buf entered: {}", self.bulk_len));
-        if self.bulk_len == -1 {
-            if self.query_buf.contains("\n") {
-                // Read the first line of the query
-                let query_buf_c = self.query_buf.clone();
-                let mut iter = query_buf_c.lines();
-                let query = iter.next().expect("first query doesn't exist");
-                let remaining: Vec<&str> = iter.collect();
-                self.query_buf = remaining.join("\r\n");
-                if query_buf_c.ends_with("\n") && !self.query_buf.is_empty() {
-                    self.query_buf.push_str("\r\n");
-                }
+    }
 
-                // Now we can split the query in arguments
-                let argv: Vec<Arc<RwLock<RedisObject>>> = query.split(" ")
-                    .filter(|a| !a.is_empty())
-                    .map(|a| Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(a.to_string()) })))
-                    .collect();
-                self.argv = argv;
-                if !self.argv.is_empty() {
-                    // log(LogLevel::Verbose, "process_input_buf ing");
-                    // Execute the command. If the client is still valid
-                    // after processCommand() return and there is something
-                    // on the query buffer try to process the next command.
-                    if self.process_command() && !self.query_buf.is_empty() {
-                        self.process_input_buf();
-                    }
-                } else {
-                    // Nothing to process, argc == 0. Just process the query
-                    // buffer if it's not empty or return to the caller
-                    if !self.query_buf.is_empty() {
-                        self.process_input_buf();
-                    }
+    /// Process the commands in buf one after the other, until it's consumed
+    /// or only holds a partial command. Returns the number of bytes consumed.
+    fn process_commands(&mut self, buf: &str) -> usize {
+        let mut pos = 0;
+        while pos < buf.len() {
+            // Before to process the input buffer, make sure the client is not
+            // waitig for a blocking operation such as BLPOP. Note that the first
+            // iteration the client is never blocked, otherwise the processInputBuffer
+            // would not be called at all, but after the execution of the first commands
+            // in the input buffer the client may be blocked, and the loop will
+            // try to reiterate. The following line will make it return asap.
+            // The only way out of a block from the connection itself is RESET:
+            // the pending BLPOP times out as it would, then RESET runs.
+            if self.flags.is_blocked() && self.bulk_len == -1 && self.multi_bulk == 0 &&
+                starts_with_reset(&buf[pos..]) {
+                self.add_reply(NULL_MULTI_BULK.clone());
+                self.unblock_client_waiting_data();
+            }
+            if self.flags.is_blocked() || self.flags.is_io_wait() {
+                break;
+            }
+            // The payload of a refused command is skipped as it arrives,
+            // it never piles up in the query buffer.
+            if self.discard_len > 0 {
+                let skipped = self.discard_len.min(buf.len() - pos);
+                pos += skipped;
+                self.discard_len -= skipped;
+                while !buf.is_char_boundary(pos) {
+                    pos += 1;
                 }
-                return;
-            } else if self.query_buf.len() >= MAX_SIZE_INLINE_CMD {
-                log(LogLevel::Verbose, "Client protocol error");
-                // TODO: free client?
-                return;
+                if self.discard_len == 0 && self.multi_bulk == 0 {
+                    self.reset();
+                }
+                continue;
             }
-        } else {
-            // Bulk read handling. Note that if we are at this point
-            // the client already sent a command terminated with a newline,
-            // we are reading the bulk data that is actually the last
-            // argument of the command.
-            if self.bulk_len as usize <= self.query_buf.len() {
-                let query_buf_c = self.query_buf.clone();
-                let mut iter = query_buf_c.lines();
-                let arg = iter.next().expect("last arg doesn't exist");
+            // log(LogLevel::Verbose, &format!("process_input_buf entered: {}", self.bulk_len));
+            let rest = &buf[pos..];
+            if self.bulk_len == -1 {
+                // Redis ignores empty inline commands: skip the blank lines
+                // here rather than looping once for every one of them.
+                let blank = rest.len() - rest.trim_start_matches(['\r', '\n']).len();
+                if blank > 0 {
+                    pos += blank;
+                    continue;
+                }
+                match rest.find('\n') {
+                    Some(nl) => {
+                        // Read the first line of the query
+                        let query = rest[..nl].strip_suffix('\r').unwrap_or(&rest[..nl]);
+                        pos += nl + 1;
+
+                        // Now we can split the query in arguments
+                        let argv: Vec<Arc<RwLock<RedisObject>>> = query.split(" ")
+                            .filter(|a| !a.is_empty())
+                            .map(|a| Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(a.to_string()) }.tracked())))
+                            .collect();
+                        self.argv = argv;
+                        // Nothing to process if argc == 0, otherwise execute the
+                        // command. If the client is still valid after processCommand()
+                        // return and there is something on the query buffer try to
+                        // process the next command.
+                        if !self.argv.is_empty() && !self.process_command() {
+                            break;
+                        }
+                    },
+                    None => {
+                        if rest.len() >= MAX_SIZE_INLINE_CMD {
+                            log(LogLevel::Verbose, "Client protocol error");
+                            // TODO: free client?
+                        }
+                        break;
+                    },
+                }
+            } else {
+                // Bulk read handling. Note that if we are at this point
+                // the client already sent a command terminated with a newline,
+                // we are reading the bulk data that is actually the last
+                // argument of the command.
+                if self.bulk_len as usize > rest.len() {
+                    break;
+                }
+                let (arg, len) = match rest.find('\n') {
+                    Some(nl) => (rest[..nl].strip_suffix('\r').unwrap_or(&rest[..nl]), nl + 1),
+                    None => (rest, rest.len()),
+                };
                 if arg.len() != self.bulk_len as usize {
                     log(LogLevel::Warning, &format!("arg '{}' isn't consistent with bulk len '{}'", arg, self.bulk_len));
                     // TODO: free client?
-                    return;
-                }
-                let remaining: Vec<&str> = iter.collect();
-                self.query_buf = remaining.join("\r\n");
-                if query_buf_c.ends_with("\n") && !self.query_buf.is_empty() {
-                    self.query_buf.push_str("\r\n");
+                    break;
                 }
+                pos += len;
 
-                self.argv.push(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(arg.to_string()) })));
+                self.argv.push(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(arg.to_string()) }.tracked())));
 
                 // Process the command. If the client is still valid after
                 // the processing and there is more data in the buffer
                 // try to parse it.
-                if self.process_command() && !self.query_buf.is_empty() {
-                    self.process_input_buf();
+                if !self.process_command() {
+                    break;
                 }
             }
         }
+        pos
     }
 
     /// If this function gets called we already read a whole
@@ -257,13 +441,19 @@ impl RedisClient {
                     }
                     self.argv.clear();
                     if self.bulk_len < 0 || self.bulk_len > ONE_GB {
-                        self.add_reply_str("-ERR invalid bulk write count\r\n");
+                        self.add_reply_error(&errors::err("invalid bulk write count"));
                         self.reset();
                         return true;
                     }
+                    // The command name is the first argument, the payloads follow.
+                    let name = self.mbargv.first().map(|a| a.read().unwrap().as_key().to_string());
+                    if self.discarding || name.is_some_and(|n| self.bulk_would_oom(&n, self.bulk_len as usize)) {
+                        self.multi_bulk -= 1;
+                        self.discard_bulk(self.bulk_len as usize);
+                    }
                     return true;
                 } else {
-                    self.add_reply_str("-ERR multi bulk protocol error\r\n");
+                    self.add_reply_error(&errors::err("multi bulk protocol error"));
                     self.reset();
                     return true;
                 }
@@ -294,12 +484,23 @@ impl RedisClient {
         {
             let name_arg = self.argv[0].read().unwrap();
             name = name_arg.string().unwrap().string().unwrap().to_string();
-            // The QUIT command is handled as a special case. Normal command
-            // procs are unable to close the client connection safely
-            if name.eq_ignore_ascii_case("quit") {
-                deleted_clients_write().insert(self.fd);
-                return false;
-            }
+        }
+        // The QUIT command is handled as a special case. Normal command
+        // procs are unable to close the client connection safely
+        if name.eq_ignore_ascii_case("quit") {
+            self.free_client_async();
+            return false;
+        }
+
+        // PING is what load balancers use as health check: answer it here,
+        // without the command lookup and call(), unless the client is in a
+        // state where PING doesn't simply reply PONG.
+        if self.argv.len() == 1 && name.eq_ignore_ascii_case("ping") && self.can_fast_ping() {
+            self.add_reply_bytes(PONG_BYTES);
+            server_write().stat_numcommands += 1;
+            self.event_commands += 1;
+            self.reset();
+            return true;
         }
 
         // Now lookup the command and check ASAP about trivial error conditions
@@ -307,21 +508,12 @@ impl RedisClient {
         let cmd = lookup_command(&name);
         match cmd {
             None => {
-                self.add_reply_str(&format!("-ERR unknown command '{}'\r\n", name));
-                self.reset();
+                self.reject_command(&errors::err(&format!("unknown command '{}'", name)));
                 return true;
             },
             Some(cmd) => {
-                if (cmd.arity() > 0 && cmd.arity() != self.argv.len() as i32) ||
-                    (self.argv.len() as i32) < (-cmd.arity()) {    // TODO: < 0???
-                    self.add_reply_str(&format!("-ERR wrong number of arguments for '{}' command\r\n", cmd.name()));
-                    self.reset();
-                    return true;
-                } else if server_read().max_memory > 0 && 
-                    cmd.flags().is_deny_oom() &&
-                    MemCounter::used_memory() as u128 > server_read().max_memory {
-                    self.add_reply_str("-ERR command not allowed when used memory > 'maxmemory'\r\n");
-                    self.reset();
+                if let Err(e) = cmd.check_arity(self.argv.len()) {
+                    self.reject_command(&errors::wrong_arity(cmd.name(), e));
                     return true;
                 } else if cmd.flags().is_bulk() && self.bulk_len == -1 {
                     // This is a bulk command, we have to read the last argument yet.
@@ -336,29 +528,35 @@ impl RedisClient {
                     }
 
                     if self.bulk_len < 0 || self.bulk_len > ONE_GB {
-                        self.add_reply_str("-ERR invalid bulk write count\r\n");
+                        self.add_reply_error(&errors::err("invalid bulk write count"));
                         self.reset();
                         return true;
                     }
-                    // It is possible that the bulk read is already in the
-                    // buffer. Check this condition and handle it accordingly.
-                    // This is just a fast path, alternative to call processInputBuffer().
-                    // It's a good idea since the code is small and this condition
-                    // happens most of the times.
-                    if self.query_buf.len() as i32 >= self.bulk_len {
-                        let query_buf_c = self.query_buf.clone();
-                        let mut iter = query_buf_c.lines();
-                        let arg = iter.next().expect("bulk arg doesn't exist");
-                        let remaining: Vec<&str> = iter.collect();
-                        self.query_buf = remaining.join("\r\n");
-                        if query_buf_c.ends_with("\n") && !self.query_buf.is_empty() {
-                            self.query_buf.push_str("\r\n");
-                        }
+                    if self.bulk_would_oom(cmd.name(), self.bulk_len as usize) {
+                        self.discard_bulk(self.bulk_len as usize);
+                    }
+                    // Return and let process_commands() read the last
+                    // argument, it may be in the buffer already.
+                    return true;
+                }
 
-                        self.argv.push(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(arg.to_string()) })));
-                    } else {
-                        // Otherwise return... there is to read the last argument
-                        // from the socket.
+                // Checked once the bulk argument is read, or skipped, so
+                // that the payload isn't mistaken for the next command.
+                if server_read().max_memory > 0 &&
+                    cmd.flags().is_deny_oom() &&
+                    used_memory() as u128 > server_read().max_memory {
+                    self.reject_command(&errors::oom());
+                    return true;
+                }
+
+                // Don't accept write commands if the last write to the
+                // append only file failed: we'd acknowledge data we can't
+                // persist. Checked here for the same reason as maxmemory.
+                if cmd.flags().is_write() {
+                    let aof_status = server_read().aof_last_write_status.clone();
+                    if let Err(e) = aof_status {
+                        self.add_reply_error(&errors::misconf("AOF file", &e));
+                        self.reset();
                         return true;
                     }
                 }
@@ -370,14 +568,33 @@ impl RedisClient {
                 // TODO
 
                 // Check if the user is authenticated
-                // TODO
+                if !self.authenticated && !server_read().require_pass.is_empty() &&
+                    !matches!(cmd.name(), "auth" | "reset") {
+                    self.reject_command(&errors::no_auth());
+                    return true;
+                }
 
-                let exec = lookup_command("exec").unwrap();
-                let discard = lookup_command("discard").unwrap();
-                // Exec the command
-                if self.flags.is_multi() && !Arc::ptr_eq(&cmd.proc(), &exec.proc()) &&
-                    !Arc::ptr_eq(&cmd.proc(), &discard.proc()) {
-                        // TODO
+                // A read only replica only takes writes from its master
+                if cmd.flags().is_write() && !self.flags.is_master() && {
+                    let server = server_read();
+                    server.is_slave() && server.repl_slave_ro
+                } {
+                    self.reject_command(&errors::read_only());
+                    return true;
+                }
+
+                // Some commands make no sense in the state the client or the
+                // server is in, e.g. SAVE queued in a MULTI block.
+                if let Err(e) = cmd.check_context(self.context()) {
+                    self.reject_command(&e);
+                    return true;
+                }
+
+                // Exec the command, or queue it inside MULTI
+                self.event_commands += 1;
+                if self.flags.is_multi() && !matches!(cmd.name(), "exec" | "discard" | "multi" | "reset") {
+                    self.queue_multi_command(cmd);
+                    self.add_reply(QUEUED.clone());
                 } else {
                     // TODO: vm
                     call(self, cmd);
@@ -391,15 +608,46 @@ impl RedisClient {
     }
 
     pub fn add_reply(&self, obj: Arc<RwLock<RedisObject>>) {
+        self.add_reply_shared(Arc::new(obj.read().unwrap().get_decoded()));
+    }
+    /// Queue an already decoded, immutable reply without copying it.
+    pub fn add_reply_shared(&self, obj: Arc<RedisObject>) {
         let mut reply_w = self.reply.write().unwrap();
-        if reply_w.is_empty() &&
-            (self.repl_state == ReplState::None ||
-             self.repl_state == ReplState::Online) &&
-            create_file_event(self.fd, Mask::Writable, 
-                Arc::new(send_reply_to_client)).is_err() {
+        if !self.prepare_to_write(reply_w.is_empty()) {
             return;
         }
-        reply_w.push_back(Arc::new(obj.read().unwrap().get_decoded()));
+        self.reply_bytes.fetch_add(obj.string().map_or(0, |s| s.as_bytes().len()), Ordering::Relaxed);
+        reply_w.push_back(obj);
+        drop(reply_w);
+        self.check_output_buffer_limits();
+    }
+    /// Append a reply to the output buffer as raw bytes, without building
+    /// a reply object. The buffer is sent before the reply list, so once
+    /// something is queued in the list the bytes go there instead to keep
+    /// the replies in order. The reply list holds strings: the bytes must
+    /// be valid UTF-8.
+    pub fn add_reply_bytes(&mut self, bytes: &[u8]) {
+        if self.reply.read().unwrap().is_empty() {
+            if self.prepare_to_write(true) {
+                self.buf.extend_from_slice(bytes);
+                self.check_output_buffer_limits();
+            }
+        } else {
+            debug_assert!(std::str::from_utf8(bytes).is_ok(), "non UTF-8 reply");
+            self.add_reply_str(&String::from_utf8_lossy(bytes));
+        }
+    }
+    /// Install the write handler when the first reply is queued. Returns
+    /// false if the client can't be written to, then the reply is dropped.
+    fn prepare_to_write(&self, reply_empty: bool) -> bool {
+        if self.close_asap.load(Ordering::Relaxed) {
+            return false;
+        }
+        !(reply_empty && self.buf.is_empty() &&
+            (self.repl_state == ReplState::None ||
+             self.repl_state == ReplState::Online) &&
+            create_file_event(self.fd, Mask::Writable,
+                Arc::new(send_reply_to_client)).is_err())
     }
     pub fn add_reply_bulk_str(&self, s: &str) {
         self.add_reply_str(&format!("${}\r\n", s.len()));
@@ -414,25 +662,31 @@ impl RedisClient {
     fn add_reply_bulk_len(&self, obj: Arc<RwLock<RedisObject>>) {
         let mut len = 0usize;
         match obj.read().unwrap().string() {
-            Some(str_storage) => {
-                match str_storage {
-                    StringStorageType::String(s) => { len = s.len(); },
-                    StringStorageType::Integer(n) => {
-                        // Compute how many bytes will take this integer as a radix 10 string
-                        len = n.to_string().len();
-                    },
-                }
-            },
+            Some(str_storage) => { len = str_storage.as_bytes().len(); },
             None => { assert!(false, "impossible code"); },
         }
         self.add_reply_str(&format!("${len}\r\n"));
     }
+    /// Reply with an error. The '-' prefix and the trailing CRLF are added
+    /// when missing, so a malformed message can't desync the protocol.
+    pub fn add_reply_error(&self, msg: &str) {
+        self.add_reply_str(&errors::error_line(msg));
+    }
+    /// Reply with the WRONGTYPE error, counting it in INFO.
+    pub fn add_reply_wrong_type(&self) {
+        server_write().stat_wrongtype_errors += 1;
+        self.add_reply(WRONG_TYPE_ERR.clone());
+    }
     pub fn add_reply_str(&self, s: &str) {
-        self.add_reply(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(s.to_string()) })));
+        self.add_reply(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(s.to_string()) }.tracked())));
     }
     pub fn add_reply_u64(&self, n: u64) {
         self.add_reply_str(&format!(":{}\r\n", n.to_string()));
     }
+    /// Reply with a value built as a whole, typically a nested one.
+    pub fn add_reply_value(&mut self, value: &Value) {
+        self.add_reply_bytes(&value.to_bytes());
+    }
     pub fn add_reply_f64(&self, f: f64) {
         let s = f.to_string();
         self.add_reply_str(&format!("${}\r\n{}\r\n", s.len(), s));
@@ -456,13 +710,209 @@ impl RedisClient {
             Some(v) => { Some(v.clone()) },
         }
     }
+
+    /// The argument `idx` of the command being run, borrowed from argv so
+    /// that even a big value is not copied. It holds the argument read
+    /// lock: don't keep it while the argument may be encoded or stored.
+    pub fn arg(&self, idx: usize) -> ArgRef<'_> {
+        ArgRef(self.argv[idx].read().unwrap())
+    }
+
+    // Typed access to the keyspace. Most commands work on a value of one
+    // type: they look the key up, reply WRONGTYPE if it holds something
+    // else, reply something specific if it's missing, and delete the key
+    // if they leave it empty (there are no empty aggregates in the keyspace).
+    // The with_*() helpers do all of that and run the command body on the
+    // value storage, returning its result, or None if they already replied:
+    //
+    //     if let Some(len) = c.with_list(&c.arg(1), C_ZERO.clone(), |l| l.len()) {
+    //         c.add_reply_u64(len as u64);
+    //     }
+    //
+    // The _mut variants look the key up for writing, the _or_create ones
+    // create an empty value when the key is missing.
+
+    pub fn with_list<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&ListStorageType) -> R) -> Option<R> {
+        self.with_value(key, missing, RedisObject::list, f)
+    }
+    pub fn with_list_mut<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&mut ListStorageType) -> R) -> Option<R> {
+        self.with_value_mut(key, Err(missing), RedisObject::list_mut, |l| l.len() == 0, f)
+    }
+    pub fn with_list_or_create<R>(&self, key: &str, f: impl FnOnce(&mut ListStorageType) -> R) -> Option<R> {
+        let create = || RedisObject::List { l: ListStorageType::LinkedList(LinkedList::new()) };
+        self.with_value_mut(key, Ok(&create), RedisObject::list_mut, |l| l.len() == 0, f)
+    }
+    pub fn with_set<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&SetStorageType) -> R) -> Option<R> {
+        self.with_value(key, missing, RedisObject::set, f)
+    }
+    pub fn with_set_mut<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&mut SetStorageType) -> R) -> Option<R> {
+        self.with_value_mut(key, Err(missing), RedisObject::set_mut, |s| s.len() == 0, f)
+    }
+    pub fn with_set_or_create<R>(&self, key: &str, f: impl FnOnce(&mut SetStorageType) -> R) -> Option<R> {
+        let create = || RedisObject::Set { s: SetStorageType::HashSet(ObjSet::new()) };
+        self.with_value_mut(key, Ok(&create), RedisObject::set_mut, |s| s.len() == 0, f)
+    }
+    pub fn with_zset_mut<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&mut ZSetStorageType) -> R) -> Option<R> {
+        self.with_value_mut(key, Err(missing), RedisObject::zset_mut, |zs| zs.len() == 0, f)
+    }
+    pub fn with_hash<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&HashStorageType) -> R) -> Option<R> {
+        self.with_value(key, missing, RedisObject::hash, f)
+    }
+    pub fn with_hash_mut<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&mut HashStorageType) -> R) -> Option<R> {
+        self.with_value_mut(key, Err(missing), RedisObject::hash_mut, HashStorageType::is_empty, f)
+    }
+    pub fn with_hash_or_create<R>(&self, key: &str, f: impl FnOnce(&mut HashStorageType) -> R) -> Option<R> {
+        let create = || RedisObject::Hash { h: HashStorageType::new() };
+        self.with_value_mut(key, Ok(&create), RedisObject::hash_mut, HashStorageType::is_empty, f)
+    }
+    pub fn with_string<R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>, f: impl FnOnce(&StringStorageType) -> R) -> Option<R> {
+        self.with_value(key, missing, RedisObject::string, f)
+    }
+    /// Mutate the string at `key` as bytes, creating an empty one if the
+    /// key is missing. An integer encoded value is decoded first and stays
+    /// raw afterwards. The key is signaled as modified and dirty bumped.
+    /// Values are stored as UTF-8 strings: a mutation leaving invalid UTF-8
+    /// is refused with an error and the value is left untouched.
+    pub fn with_string_mut<R>(&self, key: &str, f: impl FnOnce(&mut Vec<u8>) -> R) -> Option<R> {
+        let create = || RedisObject::String { ptr: StringStorageType::String(String::new()) };
+        let ret = self.with_value_mut(key, Ok(&create), RedisObject::string_mut, |_| false, |v| {
+            let mut bytes = v.as_bytes().into_owned();
+            let ret = f(&mut bytes);
+            String::from_utf8(bytes).map(|s| {
+                *v = StringStorageType::String(s);
+                ret
+            })
+        })?;
+        match ret {
+            Ok(ret) => {
+                server_write().dirty += 1;
+                self.signal_modified_key(key);
+                Some(ret)
+            },
+            Err(_) => {
+                self.add_reply_error(&errors::err("string values must be valid UTF-8"));
+                None
+            },
+        }
+    }
+    /// The list at `key`, Some(None) if the key is missing. A key of another
+    /// type gets the WRONGTYPE error and None is returned.
+    pub fn lookup_list(&self, key: &str, write: bool) -> Option<Option<Arc<RwLock<RedisObject>>>> {
+        self.lookup_typed(key, write, RedisObject::is_list)
+    }
+    /// The set at `key`, like lookup_list().
+    pub fn lookup_set(&self, key: &str, write: bool) -> Option<Option<Arc<RwLock<RedisObject>>>> {
+        self.lookup_typed(key, write, RedisObject::is_set)
+    }
+
+    /// The hash at `key`, like lookup_list().
+    pub fn lookup_hash(&self, key: &str, write: bool) -> Option<Option<Arc<RwLock<RedisObject>>>> {
+        self.lookup_typed(key, write, RedisObject::is_hash)
+    }
+
+    fn with_value<T, R>(&self, key: &str, missing: Arc<RwLock<RedisObject>>,
+        get: fn(&RedisObject) -> Option<&T>, f: impl FnOnce(&T) -> R) -> Option<R> {
+        let obj = self.lookup_key_read_or_reply(key, missing)?;
+        let obj_r = obj.read().unwrap();
+        match get(&obj_r) {
+            Some(v) => Some(f(v)),
+            None => {
+                self.add_reply_wrong_type();
+                None
+            },
+        }
+    }
+    /// `missing` is either how to create the value or the reply for a
+    /// missing key.
+    fn with_value_mut<T, R>(&self, key: &str, missing: Result<&dyn Fn() -> RedisObject, Arc<RwLock<RedisObject>>>,
+        get_mut: fn(&mut RedisObject) -> Option<&mut T>, is_empty: fn(&T) -> bool, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let obj = match (self.lookup_key_write_or_insert(key, missing.as_ref().ok().copied()), missing) {
+            (Some(obj), _) => obj,
+            (None, Ok(_)) => unreachable!("created when missing"),
+            (None, Err(reply)) => {
+                self.add_reply(reply);
+                return None;
+            },
+        };
+        let mut obj_w = obj.write().unwrap();
+        let (ret, empty) = match get_mut(&mut obj_w) {
+            Some(v) => {
+                let ret = f(v);
+                (ret, is_empty(v))
+            },
+            None => {
+                self.add_reply_wrong_type();
+                return None;
+            },
+        };
+        drop(obj_w);
+        if empty {
+            self.delete_key(key);
+        }
+        Some(ret)
+    }
+    fn lookup_typed(&self, key: &str, write: bool, is_type: fn(&RedisObject) -> bool) -> Option<Option<Arc<RwLock<RedisObject>>>> {
+        let obj = if write { self.lookup_key_write(key) } else { self.lookup_key_read(key) };
+        match obj {
+            Some(obj) if !is_type(&obj.read().unwrap()) => {
+                self.add_reply_wrong_type();
+                None
+            },
+            obj => Some(obj),
+        }
+    }
+
     pub fn lookup_key_read(&self, key: &str) -> Option<Arc<RwLock<RedisObject>>> {
         self.expire_if_needed(key);
         self.lookup_key(key)
     }
     pub fn lookup_key_write(&self, key: &str) -> Option<Arc<RwLock<RedisObject>>> {
-        self.delete_if_volatile(key);
-        self.lookup_key(key)
+        self.lookup_key_write_or_insert(key, None)
+    }
+    /// Look the key up for writing, adding the value made by `create` if
+    /// it's missing, with a single write lock on the db.
+    pub fn lookup_key_write_or_insert(&self, key: &str, create: Option<&dyn Fn() -> RedisObject>) -> Option<Arc<RwLock<RedisObject>>> {
+        let db = self.db.clone().expect("db doesn't exist");
+        let (obj, deleted) = {
+            let mut db_w = db.write().unwrap();
+            let deleted = Self::delete_if_volatile_locked(&mut db_w, key);
+            let obj = match (db_w.dict.get(key), create) {
+                (Some(obj), _) => Some(obj.clone()),
+                (None, Some(create)) => {
+                    let obj = Arc::new(RwLock::new(create().tracked()));
+                    db_w.dict.insert(key.to_string(), obj.clone());
+                    Some(obj)
+                },
+                (None, None) => None,
+            };
+            (obj, deleted)
+        };
+        if deleted {
+            server_write().dirty += 1;
+            self.notify_key_event("del", key);
+        }
+        obj
+    }
+    /// Set the key to `value` and clear its expire, with a single write
+    /// lock on the db. With `nx` a volatile key is deleted first and an
+    /// existing one is left alone: returns false then.
+    pub fn set_key(&self, key: &str, value: Arc<RwLock<RedisObject>>, nx: bool) -> bool {
+        let db = self.db.clone().expect("db doesn't exist");
+        let (set, deleted) = {
+            let mut db_w = db.write().unwrap();
+            let deleted = nx && Self::delete_if_volatile_locked(&mut db_w, key);
+            let set = !(nx && db_w.dict.contains_key(key));
+            if set {
+                db_w.dict.insert(key.to_string(), value);
+                db_w.expires.remove(key);
+            }
+            (set, deleted)
+        };
+        if deleted {
+            server_write().dirty += 1;
+            self.notify_key_event("del", key);
+        }
+        set
     }
     fn lookup_key(&self, key: &str) -> Option<Arc<RwLock<RedisObject>>> {
         let db = self.db.clone().expect("db doesn't exist");
@@ -476,21 +926,17 @@ impl RedisClient {
             }
         }
     }
-    pub fn lookup_blocking_key(&self, key: &str) -> Option<Arc<LinkedList<Arc<RwLock<RedisClient>>>>> {
+    /// Ids of the clients blocked on `key`, the longest waiting first.
+    pub fn clients_blocked_on(&self, key: &str) -> Vec<u64> {
         let db = self.db.clone().expect("db doesn't exist");
         let db_r = db.read().unwrap();
-        db_r.blocking_keys.get(key).map(|e| e.clone())
+        db_r.blocking_keys.get(key).map(|ids| ids.iter().cloned().collect()).unwrap_or_default()
     }
     pub fn insert(&self, key: &str, value: Arc<RwLock<RedisObject>>) -> Option<Arc<RwLock<RedisObject>>> {
         let db = self.db.clone().expect("db doesn't exist");
         let mut db_w = db.write().unwrap();
         db_w.dict.insert(key.to_string(), value)
     }
-    pub fn remove(&self, key: &str) -> Option<Arc<RwLock<RedisObject>>> {
-        let db = self.db.clone().expect("db doesn't exist");
-        let mut db_w = db.write().unwrap();
-        db_w.dict.remove(key)
-    }
     pub fn remove_expire(&self, key: &str) {
         let db = self.db.clone().expect("db doesn't exist");
         let mut db_w = db.write().unwrap();
@@ -501,25 +947,48 @@ impl RedisClient {
         let db_r = db.read().unwrap();
         db_r.dict.contains_key(key)
     }
-    fn remove_blocking_key(&self, key: &str) {
+    /// Remove the key and its expire. Returns true only if the key was in
+    /// the dict: a stale expire entry alone doesn't count as a deletion.
+    pub fn delete_key(&self, key: &str) -> bool {
         let db = self.db.clone().expect("db doesn't exist");
-        let mut db_w = db.write().unwrap();
-        db_w.blocking_keys.remove(key);
+        let removed = {
+            let mut db_w = db.write().unwrap();
+            db_w.expires.remove(key);
+            db_w.dict.remove(key).is_some()
+        };
+        if removed {
+            self.notify_key_event("del", key);
+        }
+        removed
     }
-    pub fn delete_key(&self, key: &str) -> Option<Arc<RwLock<RedisObject>>> {
-        let db = self.db.clone().expect("db doesn't exist");
-        let mut db_w = db.write().unwrap();
-        db_w.expires.remove(key);
-        db_w.dict.remove(key)
+    /// Propagate the current command to the AOF as `args` instead of the
+    /// command itself, e.g. a DEL for an EXPIRE deleting the key.
+    pub fn propagate_as(&mut self, args: &[&str]) {
+        self.propagate_argv = Some(args.iter()
+            .map(|a| Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(a.to_string()) }.tracked())))
+            .collect());
+    }
+    pub fn take_propagate_argv(&mut self) -> Option<Vec<Arc<RwLock<RedisObject>>>> {
+        self.propagate_argv.take()
+    }
+    /// Every time a key in the database is modified this function is called.
+    pub fn signal_modified_key(&self, _key: &str) {
+        #[cfg(feature = "testing-hooks")]
+        self.notify_key_event(&self.argv[0].read().unwrap().as_key().to_ascii_lowercase(), _key);
+    }
+    /// Record a keyspace event against the selected DB, see KeyEvent.
+    #[inline]
+    fn notify_key_event(&self, _op: &str, _key: &str) {
+        #[cfg(feature = "testing-hooks")]
+        {
+            let id = self.db.as_ref().expect("db doesn't exist").read().unwrap().id;
+            server_write().record_key_event(_op, id, _key);
+        }
     }
     pub fn get_random_key(&self) -> Option<String> {
         let db = self.db.clone().expect("db doesn't exist");
         let db_r = db.read().unwrap();
-        let mut idx = 0;
-        if db_r.dict.len() > 1 {
-            idx = rand::thread_rng().gen_range(0..db_r.dict.len());
-        }
-        db_r.dict.keys().cloned().nth(idx)
+        db_r.dict.random_entry().map(|(key, _)| key.clone())
     }
     pub fn set_expire(&self, key: &str, when: u64) -> bool {
         let db = self.db.clone().expect("db doesn't exist");
@@ -537,107 +1006,402 @@ impl RedisClient {
     }
     pub fn clear(&self) {
         let db = self.db.clone().expect("db doesn't exist");
-        let mut db_w = db.write().unwrap();
-        db_w.dict.clear();
-        db_w.expires.clear();
+        {
+            let mut db_w = db.write().unwrap();
+            db_w.dict.clear();
+            db_w.expires.clear();
+        }
+        self.notify_key_event("flushdb", "");
+    }
+
+    /// Block the client on `keys` until an element is pushed to one of them
+    /// (BLPOP) or `timeout` is reached, a unix time in ms, 0 for never.
+    pub fn block_for_keys(&mut self, keys: &[String], timeout: u64) {
+        {
+            let db = self.db.clone().expect("db doesn't exist");
+            let mut db_w = db.write().unwrap();
+            for key in keys {
+                // A key given twice is waited for once
+                if self.blocking_keys.contains(key) {
+                    continue;
+                }
+                db_w.blocking_keys.entry(key.clone()).or_default().push_back(self.id);
+                self.blocking_keys.push(key.clone());
+            }
+        }
+        self.blocking_timeout = timeout;
+        self.flags.enable(ClientFlags::blocked());
+        server_write().blpop_blocked_clients += 1;
     }
 
     /// Unblock a client that's waiting in a blocking operation such as BLPOP
-    pub fn unblock_client_waiting_data(&self) {
-        // TODO: assert
-        assert!(!self.blocking_keys.read().unwrap().is_empty());
-
-        // The client may wait for multiple keys, so unblock it for every key.
-        for key in self.blocking_keys.read().unwrap().iter() {
-            // Remove this client from the list of clients waiting for this key.
-            let remaining: LinkedList<Arc<RwLock<RedisClient>>> = self.lookup_blocking_key(key.as_key())
-                .expect("blocking clients doesn't exist")
-                .iter().filter(|l| l.read().unwrap().fd != self.fd)
-                .map(|e| e.clone()).collect();
-            // If the list is empty we need to remove it to avoid wasting memory
-            if remaining.is_empty() {
-                self.remove_blocking_key(key.as_key());
+    pub fn unblock_client_waiting_data(&mut self) {
+        assert!(self.flags.is_blocked(), "unblocking a client that isn't blocked");
+        {
+            // The client may wait for multiple keys, so unblock it for every key.
+            let db = self.db.clone().expect("db doesn't exist");
+            let mut db_w = db.write().unwrap();
+            for key in self.blocking_keys.drain(..) {
+                if let Some(ids) = db_w.blocking_keys.get_mut(&key) {
+                    ids.retain(|id| *id != self.id);
+                    // If the list is empty we need to remove it to avoid wasting memory
+                    if ids.is_empty() {
+                        db_w.blocking_keys.remove(&key);
+                    }
+                }
             }
         }
-        self.blocking_keys.write().unwrap().clear();
+        self.blocking_timeout = 0;
         self.flags.disable(ClientFlags::blocked());
         server_write().blpop_blocked_clients -= 1;
-        // We want to process data if there is some command waiting
-        // in the input buffer. Note that this is safe even if
-        // unblockClientWaitingData() gets called from freeClient() because
-        // freeClient() will be smart enough to call this function
-        // *after* c->querybuf was set to NULL.
-        // TODO: 
-        /* if !self.query_buf.is_empty() {
-            self.process_input_buf();
-        } */
-    }
-
-    pub fn delete_if_volatile(&self, key: &str) {
-        let db = self.db.clone().expect("db doesn't exist");
-        let db_r = db.read().unwrap();
-        let when_expire = db_r.expires.get(key);
+        // Commands that arrived while blocked are processed in
+        // before_sleep(), not here: the caller is running a command.
+        unblocked_clients_write().push(self.id);
+    }
 
-        // No expire? return ASAP
-        if db_r.expires.is_empty() || when_expire.is_none() {
-            return;
-        }
+    pub fn blocking_timeout(&self) -> u64 {
+        self.blocking_timeout
+    }
 
-        server_write().dirty += 1;
-        let mut db_w = db.write().unwrap();
-        db_w.expires.remove(key);
+    /// Delete a key with an expire before writing it, the Redis 1.x
+    /// semantic for writes against volatile keys. Returns true if the key
+    /// was deleted.
+    pub fn delete_if_volatile(&self, key: &str) -> bool {
+        let db = self.db.clone().expect("db doesn't exist");
+        let deleted = {
+            let mut db_w = db.write().unwrap();
+            Self::delete_if_volatile_locked(&mut db_w, key)
+        };
+        if deleted {
+            server_write().dirty += 1;
+            self.notify_key_event("del", key);
+        }
+        deleted
+    }
+    fn delete_if_volatile_locked(db_w: &mut RedisDB, key: &str) -> bool {
+        if db_w.expires.remove(key).is_none() {
+            return false;
+        }
         db_w.dict.remove(key);
+        true
     }
-    pub fn expire_if_needed(&self, key: &str) -> Option<Arc<RwLock<RedisObject>>> {
+    pub fn expire_if_needed(&self, key: &str) -> bool {
         let db = self.db.clone().expect("db doesn't exist");
-        {
-            let db_r = db.read().unwrap();
-            let when_expire = db_r.expires.get(key);
+        // A key is expired from the very instant of its expire time.
+        let expired = |db: &RedisDB| db.expires.get(key).is_some_and(|when| timestamp().as_millis() as u64 >= *when);
 
-            // No expire? return ASAP
-            if db_r.expires.is_empty() || when_expire.is_none() {
-                return None;
+        // Most keys have no expire: check with the read lock first.
+        if !expired(&db.read().unwrap()) {
+            return false;
+        }
+        let removed = {
+            let mut db_w = db.write().unwrap();
+            // Another client may have changed it in the meantime
+            if !expired(&db_w) {
+                return false;
             }
-            if timestamp().as_secs() <= *when_expire.unwrap() {
-                return None;
+            db_w.expires.remove(key);
+            db_w.dict.remove(key)
+        };
+        match removed {
+            Some(obj) => {
+                self.notify_key_event("expired", key);
+                let lazy = {
+                    let mut server = server_write();
+                    server.stat_expiredkeys += 1;
+                    server.lazyfree_lazy_expire
+                };
+                free_object(obj, lazy);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Bytes held by the query buffer and by the replies not yet sent.
+    pub fn buffers_size(&self) -> usize {
+        self.query_buf.capacity() + self.omem().saturating_sub(self.sent_len)
+    }
+
+    /// Bytes of the output buffer and of the reply list, the omem of CLIENT
+    /// LIST and what the output buffer limits are checked against.
+    pub fn omem(&self) -> usize {
+        self.buf.len() + self.reply_bytes.load(Ordering::Relaxed)
+    }
+
+    /// The class of output buffer limits the client is subject to: 0 for
+    /// normal clients, 1 for replicas, 2 for pubsub subscribers.
+    fn obuf_limit_class(&self) -> usize {
+        if self.flags.is_slave() && !self.flags.is_monitor() {
+            1
+        } else if self.is_subscribed() {
+            2
+        } else {
+            0
+        }
+    }
+
+    /// Schedule the client to be closed if its output buffer is over the
+    /// hard limit of its class, or has been over the soft limit for longer
+    /// than allowed. From then on its replies are dropped.
+    fn check_output_buffer_limits(&self) {
+        let limit = CLIENT_OBUF_LIMITS.read().unwrap()[self.obuf_limit_class()];
+        let omem = self.omem() as u64;
+        let hard = limit.hard > 0 && omem >= limit.hard;
+        let mut soft = false;
+        if limit.soft > 0 && omem >= limit.soft {
+            let now = timestamp().as_secs();
+            let since = self.obuf_soft_limit_reached_time.load(Ordering::Relaxed);
+            if since == 0 {
+                self.obuf_soft_limit_reached_time.store(now, Ordering::Relaxed);
+            } else {
+                soft = now - since > limit.soft_seconds;
             }
+        } else {
+            self.obuf_soft_limit_reached_time.store(0, Ordering::Relaxed);
         }
-        
-        let mut db_w = db.write().unwrap();
-        db_w.expires.remove(key);
-        db_w.dict.remove(key)
+        if (hard || soft) && !self.close_asap.swap(true, Ordering::Relaxed) {
+            // Not the whole info_line(): the caller may hold the DB lock
+            log(LogLevel::Warning, &format!("Client id={} addr={} omem={} scheduled to be closed ASAP for overcoming of output buffer limits.",
+                self.id, self.addr(), omem));
+            deleted_clients_write().insert(self.id);
+        }
+    }
+
+    /// The CLIENT LIST line of the client, without the newline.
+    pub fn info_line(&self) -> String {
+        let now = timestamp().as_secs();
+        let mut flags = String::new();
+        if self.flags.is_monitor() {
+            flags.push('O');
+        } else if self.flags.is_slave() {
+            flags.push('S');
+        }
+        if self.flags.is_master() {
+            flags.push('M');
+        }
+        if self.flags.is_multi() {
+            flags.push('x');
+        }
+        if self.flags.is_blocked() {
+            flags.push('b');
+        }
+        if self.is_subscribed() {
+            flags.push('P');
+        }
+        if flags.is_empty() {
+            flags.push('N');
+        }
+        let db = self.db.as_ref().map_or(-1, |db| db.read().unwrap().id);
+        let multi = if self.flags.is_multi() { self.mstate.commands.len() as i64 } else { -1 };
+        format!("id={} addr={} fd={} age={} idle={} flags={} db={} sub={} psub={} ssub={} multi={} qbuf={} obl={} oll={} omem={} cmd={}",
+            self.id, self.addr(), self.fd, now.saturating_sub(self.ctime), now.saturating_sub(self.last_interaction),
+            flags, db, self.pubsub_channels.len(), self.pubsub_patterns.len(), self.pubsub_shard_channels.len(),
+            multi, self.query_buf.len(), self.buf.len(), self.reply.read().unwrap().len(), self.omem(), self.last_cmd)
+    }
+
+    /// Where the commands of this client come from and the states they
+    /// run in, checked against the allowed contexts of every command.
+    pub fn context(&self) -> CmdContext {
+        let mut ctx = if self.flags.is_aof_client() {
+            CmdContext::aof_load()
+        } else if self.flags.is_master() {
+            CmdContext::master()
+        } else {
+            CmdContext::normal()
+        };
+        if self.flags.is_multi() {
+            ctx = ctx | CmdContext::multi();
+        }
+        if self.is_subscribed() {
+            ctx = ctx | CmdContext::pubsub();
+        }
+        if server_read().loading && !self.flags.is_aof_client() {
+            ctx = ctx | CmdContext::loading();
+        }
+        ctx
+    }
+
+    /// Unregister and close the client socket. The client itself stays
+    /// around, it's just not connected anymore.
+    pub fn close_connection(&mut self) {
+        if self.fd != -1 {
+            delete_file_event(self.fd, Mask::Readable);
+            delete_file_event(self.fd, Mask::Writable);
+            unsafe { close(self.fd); }
+            self.fd = -1;
+        }
+    }
+
+    fn can_fast_ping(&self) -> bool {
+        !self.flags.is_multi() && !self.flags.is_blocked() && !self.is_subscribed() &&
+            (self.authenticated || server_read().require_pass.is_empty())
+    }
+
+    /// Close the connection now and free the client in before_sleep(): the
+    /// caller holds the client lock, so it can't be dropped here. Calling it
+    /// again for the same client is harmless.
+    pub fn free_client_async(&mut self) {
+        self.close_connection();
+        deleted_clients_write().insert(self.id);
+    }
+
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Number of channels and patterns the client is subscribed to.
+    pub fn subscription_count(&self) -> usize {
+        self.pubsub_channels.len() + self.pubsub_patterns.len()
+    }
+
+    /// Number of sharded channels the client is subscribed to.
+    pub fn shard_subscription_count(&self) -> usize {
+        self.pubsub_shard_channels.len()
+    }
+
+    /// Subscribed to anything, sharded channels included.
+    pub fn is_subscribed(&self) -> bool {
+        self.subscription_count() + self.shard_subscription_count() > 0
+    }
+
+    pub fn repl_state(&self) -> &ReplState {
+        &self.repl_state
     }
 
     pub fn has_reply(&self) -> bool {
-        !self.reply.read().unwrap().is_empty()
+        !self.buf.is_empty() || !self.reply.read().unwrap().is_empty()
+    }
+    /// The bytes of the output buffer not sent yet.
+    pub fn buf(&self) -> &[u8] {
+        &self.buf
+    }
+    /// Drop the first n bytes of the output buffer, once they are sent.
+    pub fn buf_consume(&mut self, n: usize) {
+        self.buf.drain(..n);
+    }
+    /// Drop every pending reply, sent or not.
+    pub fn clear_replies(&mut self) {
+        self.buf.clear();
+        self.reply.write().unwrap().clear();
+        self.reply_bytes.store(0, Ordering::Relaxed);
+        self.sent_len = 0;
     }
     pub fn reply_front(&self) -> Option<Arc<RedisObject>> {
         self.reply.read().unwrap().front().map(|r| r.clone())
     }
     pub fn reply_pop_front(&self) {
-        self.reply.write().unwrap().pop_front();
+        if let Some(obj) = self.reply.write().unwrap().pop_front() {
+            self.reply_bytes.fetch_sub(obj.string().map_or(0, |s| s.as_bytes().len()), Ordering::Relaxed);
+        }
     }
 
+    /// The id of the selected DB.
+    pub fn db_id(&self) -> i32 {
+        self.db.as_ref().expect("db doesn't exist").read().unwrap().id
+    }
+    /// Switch to the DB, returns false if there's no such DB. The bound is
+    /// the DBs that exist rather than the configured number, in case they
+    /// ever differ.
     pub fn select_db(&mut self, id: i32) -> bool {
-        if id < 0 || id >= server_read().dbnum {
-            log(LogLevel::Warning, &format!("Invalid db #{} out of [0, {})", id, server_read().dbnum));
+        let Some(db) = usize::try_from(id).ok().and_then(|id| server_read().dbs.get(id).cloned()) else {
             return false;
+        };
+        self.db = Some(db);
+        true
+    }
+
+    /// Would buffering a `len` bytes payload for the command push the
+    /// server over maxmemory? Only the deny-oom commands are refused, as at
+    /// dispatch. The payload is buffered and then copied in the argument
+    /// object, hence the headroom.
+    fn bulk_would_oom(&self, name: &str, len: usize) -> bool {
+        let max_memory = server_read().max_memory;
+        max_memory > 0 &&
+            lookup_command(name).is_some_and(|cmd| cmd.flags().is_deny_oom()) &&
+            (used_memory() + len * BULK_OOM_HEADROOM) as u128 > max_memory
+    }
+
+    /// Refuse the current command with an OOM error and skip its `len`
+    /// bytes payload, plus the rest of its arguments for a multi bulk.
+    fn discard_bulk(&mut self, len: usize) {
+        if !self.discarding {
+            self.add_reply_error(&errors::oom());
+            self.flag_transaction();
+            self.discarding = true;
         }
-        self.db = Some(server_read().dbs[id as usize].clone());
+        self.discard_len = len + 2;     // the payload and its CRLF
+        self.bulk_len = -1;
+        self.argv.clear();
+    }
+
+    /// Reply with an error to the command being processed, flagging the
+    /// transaction if inside MULTI.
+    fn reject_command(&mut self, e: &str) {
+        self.add_reply_error(e);
+        self.flag_transaction();
+        self.reset();
+    }
+
+    /// A command refused inside MULTI makes EXEC discard the transaction.
+    fn flag_transaction(&self) {
+        if self.flags.is_multi() {
+            self.flags.enable(ClientFlags::dirty_exec());
+        }
+    }
+
+    pub fn queue_multi_command(&mut self, cmd: Arc<RedisCommand>) {
+        self.mstate.commands.push(MultiCmd { argv: self.argv.clone(), cmd });
+    }
+
+    pub fn is_multi(&self) -> bool {
+        self.flags.is_multi()
+    }
+
+    /// Enter MULTI. Returns false if the client already is in it.
+    pub fn start_multi(&mut self) -> bool {
+        if self.flags.is_multi() {
+            return false;
+        }
+        self.flags.enable(ClientFlags::multi());
         true
     }
 
+    /// The queued commands, or an EXECABORT error if some command was
+    /// refused while queueing. The client stays in MULTI until end_multi():
+    /// the commands run by EXEC can tell they are in a transaction.
+    pub fn take_multi_commands(&mut self) -> Result<Vec<MultiCmd>, String> {
+        let aborted = self.flags.is_dirty_exec();
+        let commands = std::mem::take(&mut self.mstate.commands);
+        self.flags.disable(ClientFlags::dirty_exec());
+        if aborted { Err(errors::exec_abort()) } else { Ok(commands) }
+    }
+
+    /// Leave MULTI, dropping the commands still queued.
+    pub fn end_multi(&mut self) {
+        self.mstate.commands.clear();
+        self.flags.disable(ClientFlags::multi());
+        self.flags.disable(ClientFlags::dirty_exec());
+    }
+
     /// reset prepare the client to process the next command
     fn reset(&mut self) {
         self.argv.clear();
         self.mbargv.clear();
         self.bulk_len = -1;
         self.multi_bulk = 0;
+        self.discarding = false;
+        self.discard_len = 0;
     }
 
     pub fn fd(&self) -> i32 {
         self.fd
     }
+    /// The "ip:port" the client is connected from, "?:0" if it's unknown,
+    /// e.g. for fake clients.
+    pub fn addr(&self) -> String {
+        peer_to_string(self.fd).unwrap_or_else(|_| "?:0".to_string())
+    }
     pub fn set_argv(&mut self, argv: Vec<Arc<RwLock<RedisObject>>>) {
         self.argv = argv;
     }
@@ -648,31 +1412,60 @@ impl RedisClient {
     }
 }
 
+/// A command argument borrowed from argv, see RedisClient::arg().
+pub struct ArgRef<'a>(RwLockReadGuard<'a, RedisObject>);
+impl Deref for ArgRef<'_> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.0.as_key()
+    }
+}
+
 impl Drop for RedisClient {
     fn drop(&mut self) {
-        // Note that if the client we are freeing is blocked into a blocking
-        // call, we have to set querybuf to NULL *before* to call
-        // unblockClientWaitingData() to avoid processInputBuffer() will get
-        // called. Also it is important to remove the file events after
-        // this, because this call adds the READABLE event.
-        // TODO: blocked
+        // A client blocked in BLPOP must leave the queues of its keys
+        if self.flags.is_blocked() {
+            self.unblock_client_waiting_data();
+        }
 
-        if self.fd != -1 {
-            delete_file_event(self.fd, Mask::Readable);
-            delete_file_event(self.fd, Mask::Writable);
-            unsafe { close(self.fd); }
+        if self.connected {
+            log(LogLevel::Debug, &format!("Client id={} fd={} freed", self.id, self.fd));
+        }
+        self.close_connection();
+        if self.connected {
+            CONNECTED_CLIENTS.fetch_sub(1, Ordering::Relaxed);
         }
 
         // Remove from the list of clients waiting for swapped keys
         // TODO
 
+        // Unsubscribe from all the pubsub channels
+        pubsub_unsubscribe_all_channels(self, false, false);
+        pubsub_unsubscribe_all_channels(self, true, false);
+        pubsub_unsubscribe_all_patterns(self, false);
+
+        // Stop feeding the commands to a monitor
+        if self.flags.is_monitor() {
+            let mut server = server_write();
+            server.monitors = take(&mut server.monitors).into_iter().filter(|id| *id != self.id).collect();
+        }
+
         // Other cleanup
         if self.flags.is_slave() {
-            // TODO
+            let mut server = server_write();
+            server.slaves = take(&mut server.slaves).into_iter().filter(|id| *id != self.id).collect();
         }
+        // Lost the link with our master: connect again, unless SLAVEOF
+        // changed the master meanwhile
         if self.flags.is_master() {
-            server_write().master = None;
-            server_write().repl_state = ReplState::Connect;
+            let mut server = server_write();
+            if server.master == Some(self.id) {
+                server.master = None;
+                if server.is_slave() {
+                    server.repl_state = ReplState::Connect;
+                }
+            }
         }
     }
 }
@@ -686,15 +1479,15 @@ pub struct MultiState {
 pub struct ClientFlags(RwLock<u8>);
 impl ClientFlags {
     /// This client is a slave server
-    fn slave() -> ClientFlags {
+    pub fn slave() -> ClientFlags {
         ClientFlags(RwLock::new(1))
     }
     /// This client is a master server
-    fn master() -> ClientFlags {
+    pub fn master() -> ClientFlags {
         ClientFlags(RwLock::new(2))
     }
     /// This client is a slave monitor, see MONITOR
-    fn monitor() -> ClientFlags {
+    pub fn monitor() -> ClientFlags {
         ClientFlags(RwLock::new(4))
     }
     /// This client is in a MULTI context
@@ -709,12 +1502,23 @@ impl ClientFlags {
     fn io_wait() -> ClientFlags {
         ClientFlags(RwLock::new(32))
     }
+    /// The fake client replaying the append only file
+    pub fn aof_client() -> ClientFlags {
+        ClientFlags(RwLock::new(64))
+    }
+    /// A command was refused inside MULTI: EXEC must fail
+    fn dirty_exec() -> ClientFlags {
+        ClientFlags(RwLock::new(128))
+    }
     pub fn is_slave(&self) -> bool {
         (*self.0.read().unwrap() & *Self::slave().0.read().unwrap()) != 0
     }
     pub fn is_master(&self) -> bool {
         (*self.0.read().unwrap() & *Self::master().0.read().unwrap()) != 0
     }
+    pub fn is_monitor(&self) -> bool {
+        (*self.0.read().unwrap() & *Self::monitor().0.read().unwrap()) != 0
+    }
     pub fn is_blocked(&self) -> bool {
         (*self.0.read().unwrap() & *Self::blocked().0.read().unwrap()) != 0
     }
@@ -724,8 +1528,531 @@ impl ClientFlags {
     fn is_multi(&self) -> bool {
         (*self.0.read().unwrap() & *Self::multi().0.read().unwrap()) != 0
     }
+    fn is_dirty_exec(&self) -> bool {
+        (*self.0.read().unwrap() & *Self::dirty_exec().0.read().unwrap()) != 0
+    }
+    pub fn is_aof_client(&self) -> bool {
+        (*self.0.read().unwrap() & *Self::aof_client().0.read().unwrap()) != 0
+    }
+    pub fn enable(&self, f: ClientFlags) {
+        *self.0.write().unwrap() |= *f.0.read().unwrap()
+    }
     fn disable(&self, f: ClientFlags) {
         *self.0.write().unwrap() &= *f.0.read().unwrap() ^ u8::MAX
     }
 }
 
+
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use std::{io::{Read, Write}, net::{TcpListener, TcpStream}, os::fd::{AsRawFd, IntoRawFd}, sync::{Arc, RwLock}, thread, time::{Duration, Instant}};
+    use crate::{eventloop::Mask, handler::{accept_handler, before_sleep, read_query_from_client, send_reply_to_client}, net::reserve_fd, obj::{RedisObject, StringStorageType, PONG_BYTES}, server::{server_read, server_write, tests::setup}};
+    use super::{clients_read, clients_write, connected_clients, connected_clients_peak, rejected_connections, used_memory, ClientFlags, RedisClient};
+
+    /// A fake client ready to parse commands, bound to DB 0.
+    pub(crate) fn test_client() -> RedisClient {
+        let mut c = RedisClient::create_fake_client();
+        c.reset();
+        c
+    }
+
+    /// Feed the command to the client using the multi bulk protocol and
+    /// return the raw reply it produced.
+    pub(crate) fn run(c: &mut RedisClient, args: &[&str]) -> String {
+        c.query_buf.push_str(&format!("*{}\r\n", args.len()));
+        for arg in args {
+            c.query_buf.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+        }
+        c.process_input_buf();
+        take_replies(c)
+    }
+
+    /// A client in the client list, so that a push or a published message
+    /// can reach it. Take it out with `unlist`.
+    pub(crate) fn listed_client() -> Arc<RwLock<RedisClient>> {
+        let c = Arc::new(RwLock::new(test_client()));
+        clients_write().push_back(c.clone());
+        c
+    }
+
+    pub(crate) fn unlist(c: &Arc<RwLock<RedisClient>>) {
+        let mut clients = clients_write();
+        *clients = std::mem::take(&mut *clients).into_iter().filter(|e| !Arc::ptr_eq(e, c)).collect();
+    }
+
+    /// The pending replies as they would be written on the socket: the
+    /// output buffer, then the reply list. They are cleared.
+    pub(crate) fn take_replies(c: &mut RedisClient) -> String {
+        let mut s = String::from_utf8_lossy(c.buf()).to_string();
+        s.extend(c.reply.read().unwrap().iter().map(|o| o.as_key().to_string()));
+        c.clear_replies();
+        s
+    }
+
+    #[test]
+    fn pending_replies_count_against_maxmemory() {
+        let _guard = setup();
+        let mut c = test_client();
+        // A client that never reads its replies.
+        let mut slow = test_client();
+
+        server_write().max_memory = (used_memory() + (4 << 20)) as u128;
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+OK\r\n");
+
+        let chunk = "x".repeat(1 << 20);
+        for _ in 0..16 {
+            slow.add_reply_bulk_str(&chunk);
+        }
+        assert!(slow.buffers_size() >= 16 << 20);
+        assert!(run(&mut c, &["SET", "k", "w"]).starts_with("-OOM "));
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\nv\r\n");
+
+        // Once the consumer catches up writes are accepted again.
+        slow.clear_replies();
+        assert_eq!(run(&mut c, &["SET", "k", "w"]), "+OK\r\n");
+        server_write().max_memory = 0;
+    }
+
+    #[test]
+    fn commands_are_checked_against_the_client_context() {
+        let _guard = setup();
+
+        let mut c = test_client();
+        c.flags.enable(ClientFlags::multi());
+        assert_eq!(run(&mut c, &["SAVE"]), "-ERR Command not allowed inside a transaction\r\n");
+        assert_eq!(run(&mut c, &["DEBUG", "SLEEP", "0"]), "-ERR Command not allowed inside a transaction\r\n");
+        // Allowed commands get past the check (and are queued).
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["BGREWRITEAOF"]), "+QUEUED\r\n");
+
+        let mut master = test_client();
+        master.flags.enable(ClientFlags::master());
+        assert_eq!(run(&mut master, &["SHUTDOWN"]), "-ERR 'shutdown' command not allowed from the master link\r\n");
+        assert_eq!(run(&mut master, &["SET", "k", "v"]), "+OK\r\n");
+
+        let mut c = test_client();
+        server_write().loading = true;
+        assert!(run(&mut c, &["GET", "k"]).starts_with("-LOADING "));
+        assert_eq!(run(&mut c, &["PING"]), "+PONG\r\n");
+        server_write().loading = false;
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\nv\r\n");
+    }
+
+    #[test]
+    fn reconnecting_after_quit_gets_a_working_client() {
+        let _guard = setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let connect = || {
+            let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            (peer, listener.accept().unwrap().0.into_raw_fd())
+        };
+
+        let (mut peer, fd) = connect();
+        let quitting = RedisClient::create(fd).unwrap();
+        peer.write_all(b"QUIT\r\n").unwrap();
+        read_query_from_client(fd, Mask::Readable);
+        assert_eq!(quitting.read().unwrap().fd(), -1);
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+
+        // The new connection is accepted before the deferred free runs, and
+        // most likely gets the same fd.
+        let (mut peer, fd) = connect();
+        let client = RedisClient::create(fd).unwrap();
+        before_sleep();
+        before_sleep();
+        assert!(clients_read().iter().any(|c| Arc::ptr_eq(c, &client)));
+        assert!(!clients_read().iter().any(|c| Arc::ptr_eq(c, &quitting)));
+
+        peer.write_all(b"PING\r\n").unwrap();
+        read_query_from_client(fd, Mask::Readable);
+        send_reply_to_client(fd, Mask::Writable);
+        let mut reply = [0u8; 7];
+        peer.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"+PONG\r\n");
+
+        unlist(&client);
+    }
+
+    #[test]
+    fn string_mutations_decode_integers() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["INCRBY", "n", "12"]);
+        let n = c.lookup_key_read("n").unwrap();
+        assert!(matches!(n.read().unwrap().string(), Some(StringStorageType::Integer(12))));
+
+        // As if run by a command, the key is signaled with its name.
+        c.set_argv(vec![Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("append".to_string()) }))]);
+        let dirty = server_read().dirty;
+        assert_eq!(c.with_string_mut("n", |b| { b.extend_from_slice(b"34"); b.len() }), Some(4));
+        assert!(matches!(n.read().unwrap().string(), Some(StringStorageType::String(s)) if s == "1234"));
+        assert_eq!(server_read().dirty, dirty + 1);
+
+        // A missing key starts empty, other types are refused.
+        assert_eq!(c.with_string_mut("new", |b| b.len()), Some(0));
+        assert!(c.contains("new"));
+        c.set_argv(vec![]);
+        run(&mut c, &["LPUSH", "l", "a"]);
+        assert_eq!(c.with_string_mut("l", |b| b.len()), None);
+        assert!(take_replies(&mut c).starts_with("-WRONGTYPE"));
+
+        // Cutting a character in two is refused, the value is untouched.
+        run(&mut c, &["SET", "s", "\u{e9}"]);
+        assert_eq!(c.with_string_mut("s", |b| b.truncate(1)), None);
+        assert_eq!(take_replies(&mut c), "-ERR string values must be valid UTF-8\r\n");
+        assert_eq!(run(&mut c, &["GET", "s"]), "$2\r\n\u{e9}\r\n");
+        assert_eq!(run(&mut c, &["INCR", "n"]), ":1235\r\n");
+    }
+
+    /// Feed `data` to the client in socket sized chunks, as the event loop
+    /// would, returning the replies.
+    fn feed(c: &mut RedisClient, data: &str) -> String {
+        for chunk in data.as_bytes().chunks(16 * 1024) {
+            c.query_buf.push_str(std::str::from_utf8(chunk).unwrap());
+            c.process_input_buf();
+        }
+        take_replies(c)
+    }
+
+    #[test]
+    fn big_payloads_are_refused_before_being_buffered() {
+        let _guard = setup();
+        let mut c = test_client();
+        let payload = "x".repeat(100 << 20);
+        server_write().max_memory = (used_memory() + (8 << 20)) as u128;
+
+        // Multi bulk: the OOM error comes with the header, the payload is
+        // skipped as it arrives and the next command is parsed normally.
+        let before = used_memory();
+        assert_eq!(feed(&mut c, &format!("*3\r\n$3\r\nSET\r\n$1\r\nk\r\n${}\r\n", payload.len())),
+            "-OOM command not allowed when used memory > 'maxmemory'.\r\n");
+        let mut peak = 0;
+        for chunk in payload.as_bytes().chunks(1 << 20) {
+            assert_eq!(feed(&mut c, std::str::from_utf8(chunk).unwrap()), "");
+            peak = peak.max(used_memory().saturating_sub(before));
+        }
+        assert_eq!(feed(&mut c, "\r\n*2\r\n$3\r\nGET\r\n$1\r\nk\r\n"), "$-1\r\n");
+        assert!(peak < 4 << 20, "memory grew by {} bytes", peak);
+        assert!(c.query_buf.is_empty());
+
+        // The rest of a refused multi bulk is skipped too.
+        assert_eq!(feed(&mut c, &format!("*4\r\n$4\r\nMSET\r\n$1\r\na\r\n${}\r\n{}\r\n$1\r\nb\r\n*1\r\n$4\r\nPING\r\n",
+            10 << 20, &payload[..10 << 20])),
+            "-OOM command not allowed when used memory > 'maxmemory'.\r\n+PONG\r\n");
+
+        // Inline bulk commands, the length on the command line.
+        assert_eq!(feed(&mut c, &format!("SET k {}\r\n{}\r\nGET k\r\n", 10 << 20, &payload[..10 << 20])),
+            "-OOM command not allowed when used memory > 'maxmemory'.\r\n$-1\r\n");
+
+        // Small payloads and commands that don't grow the dataset still go.
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+OK\r\n");
+        assert_eq!(feed(&mut c, &format!("*2\r\n$6\r\nEXISTS\r\n${}\r\n{}\r\n", 10 << 20, &payload[..10 << 20])), ":0\r\n");
+        server_write().max_memory = 0;
+    }
+
+    /// Readers and writers hammering the same keys of a db, volatile ones
+    /// included: a lock taken out of order or upgraded in place hangs here.
+    #[test]
+    fn concurrent_access_to_the_same_db_does_not_deadlock() {
+        let _guard = setup();
+        let workers: Vec<_> = (0..8).map(|t| thread::spawn(move || {
+            let mut c = test_client();
+            for i in 0..300 {
+                let (key, other) = (format!("k{}", i % 5), format!("k{}", (i + 1) % 5));
+                let args: Vec<&str> = match (t + i) % 12 {
+                    0 => vec!["SET", &key, "1"],
+                    1 => vec!["EXPIRE", &key, "100"],
+                    2 => vec!["SETNX", &key, "1"],
+                    3 => vec!["INCR", &key],
+                    4 => vec!["GET", &key],
+                    5 => vec!["KEYS", "*"],
+                    6 => vec!["RENAME", &key, &other],
+                    7 => vec!["LPUSH", &key, "v"],
+                    8 => vec!["SADD", &key, "m"],
+                    9 => vec!["ZADD", &key, "1", "m"],
+                    10 => vec!["MSET", &key, "1", &other, "2"],
+                    _ => vec!["DEL", &key],
+                };
+                run(&mut c, &args);
+            }
+        })).collect();
+
+        let start = Instant::now();
+        while !workers.iter().all(|w| w.is_finished()) {
+            assert!(start.elapsed() < Duration::from_secs(30), "deadlock on the keyspace locks");
+            thread::sleep(Duration::from_millis(10));
+        }
+        for w in workers {
+            w.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn maxclients_rejects_and_counts_connections() {
+        let _guard = setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let connect = || {
+            let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            accept_handler(listener.as_raw_fd(), Mask::Readable);
+            peer
+        };
+        let (connected, rejected) = (connected_clients(), rejected_connections());
+        server_write().max_clients = clients_read().len() as u32 + 1;
+
+        let _accepted = connect();
+        assert_eq!(connected_clients(), connected + 1);
+        assert!(connected_clients_peak() > connected);
+        assert_eq!(rejected_connections(), rejected);
+
+        let mut refused = connect();
+        let mut reply = String::new();
+        refused.read_to_string(&mut reply).unwrap();
+        assert_eq!(reply, "-ERR max number of clients reached\r\n");
+        before_sleep();
+        assert_eq!(rejected_connections(), rejected + 1);
+        assert_eq!(connected_clients(), connected + 1);
+        let mut c = test_client();
+        let info = crate::server::tests::parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["rejected_connections"], (rejected + 1).to_string());
+        assert_eq!(info["connected_clients"], (connected + 1).to_string());
+
+        server_write().max_clients = 0;
+        let last = clients_read().back().unwrap().clone();
+        last.write().unwrap().free_client_async();
+        drop(last);
+        before_sleep();
+        assert_eq!(connected_clients(), connected);
+    }
+
+    #[test]
+    fn a_burst_of_connections_is_accepted_in_one_event() {
+        let _guard = setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let (connected, listed) = (connected_clients(), clients_read().len());
+
+        // Below the std listener backlog of 128, so that no connect waits
+        let _peers: Vec<TcpStream> = (0..100).map(|_| TcpStream::connect(listener.local_addr().unwrap()).unwrap()).collect();
+        accept_handler(listener.as_raw_fd(), Mask::Readable);
+        assert_eq!(connected_clients(), connected + 100);
+        // Nothing pending anymore: no blocking, no new client
+        accept_handler(listener.as_raw_fd(), Mask::Readable);
+        assert_eq!(connected_clients(), connected + 100);
+
+        for c in clients_read().iter().skip(listed) {
+            c.write().unwrap().free_client_async();
+        }
+        before_sleep();
+        assert_eq!(connected_clients(), connected);
+    }
+
+    #[test]
+    fn out_of_fds_pending_connections_are_refused() {
+        let _guard = setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+
+        // The fd limit is lowered and exhausted in a child, not to starve
+        // the other tests.
+        let child = unsafe { libc::fork() };
+        if child == 0 {
+            let ok = unsafe {
+                server_write().reserved_fd = reserve_fd().unwrap();
+                let limit = libc::rlimit { rlim_cur: 256, rlim_max: libc::RLIM_INFINITY };
+                let mut old: libc::rlimit = std::mem::zeroed();
+                libc::getrlimit(libc::RLIMIT_NOFILE, &mut old);
+                libc::setrlimit(libc::RLIMIT_NOFILE, &libc::rlimit { rlim_max: old.rlim_max, ..limit });
+                while reserve_fd().is_ok() {}
+                let (connected, rejected) = (connected_clients(), rejected_connections());
+                accept_handler(listener.as_raw_fd(), Mask::Readable);
+                // Nothing pending anymore
+                accept_handler(listener.as_raw_fd(), Mask::Readable);
+                connected_clients() == connected && rejected_connections() == rejected + 1 &&
+                    server_read().reserved_fd != -1
+            };
+            unsafe { libc::_exit(if ok { 0 } else { 1 }); }
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(child, &mut status, 0); }
+        assert!(libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0, "status {}", status);
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn ping_fast_path_replies_like_the_command() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        // A bare PING is written to the output buffer, no reply object.
+        let before = server_read().stat_numcommands;
+        c.query_buf.push_str("PING\r\nping\r\n*1\r\n$4\r\nPING\r\n");
+        c.process_input_buf();
+        assert_eq!(c.buf(), PONG_BYTES.repeat(3));
+        assert!(c.reply.read().unwrap().is_empty());
+        assert_eq!(server_read().stat_numcommands, before + 3);
+        assert_eq!(take_replies(&mut c), "+PONG\r\n".repeat(3));
+
+        // Replies stay in order when the list is in use.
+        c.query_buf.push_str("PING\r\nGET k\r\nPING\r\nGET k\r\n");
+        c.process_input_buf();
+        assert_eq!(c.buf(), PONG_BYTES);
+        assert_eq!(take_replies(&mut c), "+PONG\r\n$-1\r\n+PONG\r\n$-1\r\n");
+
+        // Arguments and client states go through the regular path.
+        assert_eq!(run(&mut c, &["PING", "x"]), "-ERR wrong number of arguments for 'ping' command (too many)\r\n");
+        server_write().require_pass = "secret".to_string();
+        assert!(run(&mut c, &["PING"]).starts_with("-NOAUTH "));
+        c.authenticated = true;
+        assert_eq!(run(&mut c, &["PING"]), "+PONG\r\n");
+        server_write().require_pass.clear();
+        c.flags.enable(ClientFlags::multi());
+        assert_eq!(run(&mut c, &["PING"]), "+QUEUED\r\n");
+    }
+
+    #[test]
+    fn ping_is_byte_identical_on_the_wire() {
+        let _guard = setup();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let fd = listener.accept().unwrap().0.into_raw_fd();
+        let client = RedisClient::create(fd).unwrap();
+
+        // Fast path, regular path (with an argument, and after a reply in
+        // the list) and inline and multi bulk forms, in one pipeline.
+        peer.write_all(b"PING\r\n*1\r\n$4\r\nPING\r\nGET nokey\r\nPING\r\nPING x\r\nPING\r\n").unwrap();
+        read_query_from_client(fd, Mask::Readable);
+        send_reply_to_client(fd, Mask::Writable);
+        let expected: &[u8] = b"+PONG\r\n+PONG\r\n$-1\r\n+PONG\r\n-ERR wrong number of arguments for 'ping' command (too many)\r\n+PONG\r\n";
+        let mut reply = vec![0u8; expected.len()];
+        peer.read_exact(&mut reply).unwrap();
+        assert_eq!(reply, expected);
+        assert!(!client.read().unwrap().has_reply());
+
+        unlist(&client);
+    }
+
+    /// RANDOMKEY on a million keys: a draw costs the same whatever the size
+    /// of the db. Run with
+    /// `cargo test --release -- --ignored randomkey_throughput --nocapture`.
+    #[test]
+    #[ignore]
+    fn randomkey_throughput() {
+        let _guard = setup();
+        let mut c = test_client();
+        {
+            let db = c.db.clone().unwrap();
+            let mut db_w = db.write().unwrap();
+            for i in 0..1_000_000 {
+                db_w.dict.insert(format!("key:{i}"), Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("v".to_string()) })));
+            }
+        }
+        let n = 10_000;
+        let start = std::time::Instant::now();
+        for _ in 0..n {
+            c.query_buf.push_str("RANDOMKEY\r\n");
+            c.process_input_buf();
+            c.clear_replies();
+        }
+        println!("RANDOMKEY on 1M keys: {:.0} ops/sec", n as f64 / start.elapsed().as_secs_f64());
+    }
+
+    /// Writers on distinct keys of the same db, one thread and eight. The
+    /// event loop runs every command on one thread, so only code driving a
+    /// db from several threads, like the tests, contends on its lock.
+    /// `cargo test --release -- --ignored distinct_key_writers_throughput --nocapture`.
+    #[test]
+    #[ignore]
+    fn distinct_key_writers_throughput() {
+        let _guard = setup();
+        let n = 200_000;
+        for threads in [1, 8] {
+            let start = Instant::now();
+            let workers: Vec<_> = (0..threads).map(|t| thread::spawn(move || {
+                let mut c = test_client();
+                for i in 0..n / threads {
+                    let key = format!("key:{}:{}", t, i);
+                    c.query_buf.push_str(&format!("*3\r\n$3\r\nSET\r\n${}\r\n{}\r\n$1\r\nv\r\n", key.len(), key));
+                    c.process_input_buf();
+                    c.clear_replies();
+                }
+            })).collect();
+            for w in workers {
+                w.join().unwrap();
+            }
+            println!("SET from {} threads: {:.0} ops/sec", threads, n as f64 / start.elapsed().as_secs_f64());
+        }
+    }
+
+    /// PING throughput through the input buffer, one command per read like
+    /// a health checker sends them. Run with
+    /// `cargo test --release -- --ignored ping_throughput --nocapture`.
+    #[test]
+    #[ignore]
+    fn ping_throughput() {
+        let _guard = setup();
+        let mut c = test_client();
+        for (name, cmd) in [("inline", "PING\r\n"), ("multibulk", "*1\r\n$4\r\nPING\r\n")] {
+            let n = 1_000_000;
+            let start = std::time::Instant::now();
+            for _ in 0..n {
+                c.query_buf.push_str(cmd);
+                c.process_input_buf();
+                c.clear_replies();
+            }
+            println!("PING {}: {:.0} ops/sec", name, n as f64 / start.elapsed().as_secs_f64());
+        }
+    }
+
+    #[test]
+    fn blank_inline_lines_are_skipped() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        c.query_buf.push_str("\r\n");
+        c.process_input_buf();
+        assert!(!c.has_reply());
+        assert!(c.query_buf.is_empty());
+
+        c.query_buf.push_str("\r\nPING\r\n");
+        c.process_input_buf();
+        assert_eq!(take_replies(&mut c), "+PONG\r\n");
+
+        c.query_buf.push_str(&"\r\n".repeat(100_000));
+        c.query_buf.push_str("PING\r\n");
+        c.process_input_buf();
+        assert_eq!(take_replies(&mut c), "+PONG\r\n");
+    }
+
+    #[test]
+    fn large_pipelines_are_processed_iteratively() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        c.query_buf.push_str(&"PING\r\n".repeat(50_000));
+        c.process_input_buf();
+        assert_eq!(c.buf().len(), 50_000 * PONG_BYTES.len());
+        assert!(c.query_buf.is_empty());
+        c.clear_replies();
+
+        c.query_buf.push_str(&"*3\r\n$4\r\nSADD\r\n$1\r\ns\r\n$1\r\nx\r\n".repeat(50_000));
+        c.query_buf.push_str("SCARD s\r\nSCARD");
+        c.process_input_buf();
+        assert_eq!(c.reply.read().unwrap().len(), 50_001);
+        assert_eq!(c.reply.read().unwrap().back().unwrap().as_key(), ":1\r\n");
+        assert_eq!(c.query_buf, "SCARD");
+        c.clear_replies();
+        c.query_buf.clear();
+
+        // Inline bulk commands, the last one with an empty payload.
+        c.query_buf.push_str("SET a 1\r\nx\r\nSET b 0\r\n\r\nGET b\r\n");
+        c.process_input_buf();
+        assert_eq!(take_replies(&mut c), "+OK\r\n+OK\r\n$0\r\n\r\n");
+    }
+}
@@ -0,0 +1,123 @@
+//! Cluster.
+//!
+//! rudis has no cluster support. Clients and orchestration tools probe
+//! CLUSTER INFO when they connect, so the few read only subcommands they
+//! use are answered as a standalone instance would, and everything else
+//! gets the same error Redis sends when cluster mode is disabled.
+
+use crate::{client::RedisClient, cmd::{check_arity, reply_subcommand_help}, errors, resp::Value, server::server_read};
+
+
+type ClusterSubcommand = (&'static str, i32, fn(&mut RedisClient));
+
+/// The CLUSTER subcommands we know about: (name, arity, handler). The arity
+/// follows the command table convention, negative means "at least".
+static CLUSTER_SUBCOMMANDS: &[ClusterSubcommand] = &[
+    ("info", 2, cluster_info),
+    ("myid", 2, cluster_myid),
+    ("slots", 2, cluster_empty_array),
+    ("shards", 2, cluster_empty_array),
+    ("help", 2, cluster_help),
+];
+
+static CLUSTER_HELP: &[(&str, &str)] = &[
+    ("INFO", "Return information about the cluster."),
+    ("MYID", "Return the node id."),
+    ("SLOTS", "Return information about slots range mappings, empty on a standalone instance."),
+    ("SHARDS", "Return information about slot range mappings and the nodes serving them, empty on a standalone instance."),
+];
+
+/// CLUSTER INFO
+/// CLUSTER MYID
+/// CLUSTER SLOTS
+/// CLUSTER SHARDS
+/// CLUSTER HELP
+pub fn cluster_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match CLUSTER_SUBCOMMANDS.iter().find(|s| s.0 == sub) {
+        Some((_, arity, proc)) => match check_arity(*arity, c.argv.len()) {
+            Ok(()) => { proc(c); },
+            Err(e) => { c.add_reply_error(&errors::wrong_arity(&format!("cluster|{}", sub), e)); },
+        },
+        None => {
+            c.add_reply_error(&errors::err("This instance has cluster support disabled"));
+        },
+    }
+}
+
+/// The CLUSTER INFO fields of a node that is not part of a cluster.
+fn cluster_info_string() -> String {
+    let fields: [(&str, &str); 13] = [
+        ("cluster_enabled", "0"),
+        ("cluster_state", "ok"),
+        ("cluster_slots_assigned", "0"),
+        ("cluster_slots_ok", "0"),
+        ("cluster_slots_pfail", "0"),
+        ("cluster_slots_fail", "0"),
+        ("cluster_known_nodes", "1"),
+        ("cluster_size", "0"),
+        ("cluster_current_epoch", "0"),
+        ("cluster_my_epoch", "0"),
+        ("cluster_stats_messages_sent", "0"),
+        ("cluster_stats_messages_received", "0"),
+        ("total_cluster_links_buffer_limit_exceeded", "0"),
+    ];
+    fields.iter().map(|(name, value)| format!("{}:{}\r\n", name, value)).collect()
+}
+
+fn cluster_info(c: &mut RedisClient) {
+    c.add_reply_bulk_str(&cluster_info_string());
+}
+
+fn cluster_myid(c: &mut RedisClient) {
+    let run_id = server_read().run_id.clone();
+    c.add_reply_bulk_str(&run_id);
+}
+
+fn cluster_empty_array(c: &mut RedisClient) {
+    c.add_reply_value(&Value::Array(Some(Vec::new())));
+}
+
+fn cluster_help(c: &mut RedisClient) {
+    reply_subcommand_help(c, CLUSTER_HELP);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::tests::{run, test_client}, server::{server_read, tests::setup, RUN_ID_SIZE}};
+
+    #[test]
+    fn cluster_info_reports_a_disabled_cluster() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        let info = run(&mut c, &["CLUSTER", "INFO"]);
+        let (len, body) = info[1..].split_once("\r\n").unwrap();
+        assert_eq!(len.parse::<usize>().unwrap() + 2, body.len());
+        let fields: Vec<(&str, &str)> = body.trim_end().split("\r\n").map(|l| l.split_once(':').unwrap()).collect();
+        assert_eq!(fields[0], ("cluster_enabled", "0"));
+        assert!(fields.contains(&("cluster_state", "ok")));
+        assert!(fields.contains(&("cluster_known_nodes", "1")));
+        assert!(fields.contains(&("cluster_slots_assigned", "0")));
+    }
+
+    #[test]
+    fn cluster_subcommands_dispatch() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        let run_id = server_read().run_id.clone();
+        assert_eq!(run_id.len(), RUN_ID_SIZE);
+        assert!(run_id.chars().all(|ch| ch.is_ascii_hexdigit()));
+        assert_eq!(run(&mut c, &["CLUSTER", "MYID"]), format!("${}\r\n{}\r\n", RUN_ID_SIZE, run_id));
+        assert_eq!(run(&mut c, &["cluster", "slots"]), "*0\r\n");
+        assert_eq!(run(&mut c, &["CLUSTER", "SHARDS"]), "*0\r\n");
+
+        assert_eq!(run(&mut c, &["CLUSTER", "NODES"]), "-ERR This instance has cluster support disabled\r\n");
+        assert_eq!(run(&mut c, &["CLUSTER", "ADDSLOTS", "1"]), "-ERR This instance has cluster support disabled\r\n");
+        assert_eq!(run(&mut c, &["CLUSTER", "INFO", "x"]), "-ERR wrong number of arguments for 'cluster|info' command (too many)\r\n");
+        let help = run(&mut c, &["CLUSTER", "HELP"]);
+        assert!(help.starts_with("*11\r\n+CLUSTER <subcommand> [<arg> [value] [opt] ...]. Subcommands are:\r\n+INFO\r\n"), "{}", help);
+    }
+}
@@ -1,8 +1,8 @@
-use std::{collections::{HashMap, HashSet, LinkedList}, fs::{remove_file, OpenOptions}, ops::{BitOr, Deref}, process::exit, sync::{Arc, RwLock}};
+use std::{cmp::Reverse, collections::{BTreeMap, BinaryHeap, HashMap, LinkedList}, fs::{remove_file, OpenOptions}, ops::{BitOr, Deref}, process::exit, str::FromStr, sync::{Arc, RwLock}, thread::sleep, time::{Duration, Instant}};
 use libc::{kill, SIGKILL};
 use once_cell::sync::Lazy;
-use crate::{aof::feed_append_only_file, obj::{NULL_BULK, PONG, WRONG_TYPE_ERR}, server::{server_read, server_write}, util::{log, string_pattern_match, timestamp, LogLevel}, zmalloc::MemCounter};
-use super::{aof::rewrite_append_only_file_background, client::RedisClient, obj::{try_object_encoding, ListStorageType, RedisObject, SetStorageType, StringStorageType, ZSetStorageType, COLON, CRLF, C_ONE, C_ZERO, EMPTY_MULTI_BULK, ERR, NO_KEY_ERR, NULL_MULTI_BULK, OK, OUT_OF_RANGE_ERR, PLUS, SAME_OBJECT_ERR, SYNTAX_ERR}, rdb::{rdb_remove_temp_file, rdb_save, rdb_save_background}, skiplist::SkipList};
+use crate::{aof::feed_append_only_file, dict::scan_hash, cluster::cluster_command, replication::{close_master_link, replication_feed_slaves, sync_command}, config::config_command, errors, resp::{Value, MAX_BULK_LEN}, latency::{latency_add_sample_if_needed, latency_command}, client::{clients_read, ClientFlags}, obj::{EMPTY_BULK, NULL_BULK, NULL_MULTI_BULK, PONG}, pubsub::{psubscribe_command, pubsub_unsubscribe_all_channels, pubsub_unsubscribe_all_patterns, publish_command, pubsub_command, punsubscribe_command, spublish_command, ssubscribe_command, subscribe_command, sunsubscribe_command, unsubscribe_command}, server::{server_read, server_teardown, server_write, DebugFaults}, util::{log, string_pattern_match, timestamp, LogLevel}, zmalloc::MemCounter};
+use super::{aof::rewrite_append_only_file_background, client::RedisClient, obj::{try_object_encoding, ListStorageType, ObjSet, RedisObject, SetStorageType, StringStorageType, ZSetStorageType, COLON, CRLF, C_ONE, C_ZERO, EMPTY_MULTI_BULK, ERR, NO_KEY_ERR, OK, OUT_OF_RANGE_ERR, PLUS, SAME_OBJECT_ERR, SYNTAX_ERR}, rdb::{rdb_remove_temp_file, rdb_save, rdb_save_background}, skiplist::SkipList};
 
 
 /// 
@@ -16,84 +16,279 @@ pub static MAX_SIZE_INLINE_CMD: usize = 1024 * 1024 * 256;  // max bytes in inli
 /// Command Table 
 static CMD_TABLE: Lazy<HashMap<&str, Arc<RedisCommand>>> = Lazy::new(|| {
     HashMap::from([
-        ("ping", Arc::new(RedisCommand { name: "ping", proc: Arc::new(ping_command), arity: 1, flags: CmdFlags::inline()})),
-        ("exec", Arc::new(RedisCommand { name: "exec", proc: Arc::new(exec_command), arity: 1, flags: CmdFlags::inline()})),
-        ("discard", Arc::new(RedisCommand { name: "discard", proc: Arc::new(discard_command), arity: 1, flags: CmdFlags::inline()})),
-        ("auth", Arc::new(RedisCommand { name: "auth", proc: Arc::new(auth_command), arity: 2, flags: CmdFlags::inline()})),
-        ("exists", Arc::new(RedisCommand { name: "exists", proc: Arc::new(exists_command), arity: 2, flags: CmdFlags::inline()})),
-        ("del", Arc::new(RedisCommand { name: "del", proc: Arc::new(del_command), arity: -2, flags: CmdFlags::inline()})),
-        ("type", Arc::new(RedisCommand { name: "type", proc: Arc::new(type_command), arity: 2, flags: CmdFlags::inline()})),
-        ("keys", Arc::new(RedisCommand { name: "keys", proc: Arc::new(keys_command), arity: 2, flags: CmdFlags::inline()})),
-        ("randomkey", Arc::new(RedisCommand { name: "randomkey", proc: Arc::new(randomkey_command), arity: 1, flags: CmdFlags::inline()})),
-        ("rename", Arc::new(RedisCommand { name: "rename", proc: Arc::new(rename_command), arity: 3, flags: CmdFlags::inline()})),
-        ("renamenx", Arc::new(RedisCommand { name: "renamenx", proc: Arc::new(renamenx_command), arity: 3, flags: CmdFlags::inline()})),
-        ("dbsize", Arc::new(RedisCommand { name: "dbsize", proc: Arc::new(dbsize_command), arity: 1, flags: CmdFlags::inline()})),
-        ("expire", Arc::new(RedisCommand { name: "expire", proc: Arc::new(expire_command), arity: 3, flags: CmdFlags::inline()})),
-        ("ttl", Arc::new(RedisCommand { name: "ttl", proc: Arc::new(ttl_command), arity: 2, flags: CmdFlags::inline()})),
-        ("select", Arc::new(RedisCommand { name: "select", proc: Arc::new(select_command), arity: 2, flags: CmdFlags::inline()})),
-        ("move", Arc::new(RedisCommand { name: "move", proc: Arc::new(move_command), arity: 3, flags: CmdFlags::inline()})),
-        ("flushdb", Arc::new(RedisCommand { name: "flushdb", proc: Arc::new(flushdb_command), arity: 1, flags: CmdFlags::inline()})),
-        ("flushall", Arc::new(RedisCommand { name: "flushall", proc: Arc::new(flushall_command), arity: 1, flags: CmdFlags::inline()})),
-
-        ("set", Arc::new(RedisCommand { name: "set", proc: Arc::new(set_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("get", Arc::new(RedisCommand { name: "get", proc: Arc::new(get_command), arity: 2, flags: CmdFlags::inline()})),
-        ("getset", Arc::new(RedisCommand { name: "getset", proc: Arc::new(getset_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("mget", Arc::new(RedisCommand { name: "mget", proc: Arc::new(mget_command), arity: -2, flags: CmdFlags::inline()})),
-        ("setnx", Arc::new(RedisCommand { name: "setnx", proc: Arc::new(setnx_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("mset", Arc::new(RedisCommand { name: "mset", proc: Arc::new(mset_command), arity: -3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("msetnx", Arc::new(RedisCommand { name: "msetnx", proc: Arc::new(msetnx_command), arity: -3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("incr", Arc::new(RedisCommand { name: "incr", proc: Arc::new(incr_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("incrby", Arc::new(RedisCommand { name: "incrby", proc: Arc::new(incrby_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("decr", Arc::new(RedisCommand { name: "decr", proc: Arc::new(decr_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("decrby", Arc::new(RedisCommand { name: "decrby", proc: Arc::new(decrby_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("rpush", Arc::new(RedisCommand { name: "rpush", proc: Arc::new(rpush_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("lpush", Arc::new(RedisCommand { name: "lpush", proc: Arc::new(lpush_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("llen", Arc::new(RedisCommand { name: "llen", proc: Arc::new(llen_command), arity: 2, flags: CmdFlags::inline()})),
-        ("lrange", Arc::new(RedisCommand { name: "lrange", proc: Arc::new(lrange_command), arity: 4, flags: CmdFlags::inline()})),
-        ("ltrim", Arc::new(RedisCommand { name: "ltrim", proc: Arc::new(ltrim_command), arity: 4, flags: CmdFlags::inline()})),
-        ("lindex", Arc::new(RedisCommand { name: "lindex", proc: Arc::new(lindex_command), arity: 3, flags: CmdFlags::inline()})),
-        ("lset", Arc::new(RedisCommand { name: "lset", proc: Arc::new(lset_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("lrem", Arc::new(RedisCommand { name: "lrem", proc: Arc::new(lrem_command), arity: 4, flags: CmdFlags::bulk()})),
-        ("lpop", Arc::new(RedisCommand { name: "lpop", proc: Arc::new(lpop_command), arity: 2, flags: CmdFlags::inline()})),
-        ("rpop", Arc::new(RedisCommand { name: "rpop", proc: Arc::new(rpop_command), arity: 2, flags: CmdFlags::inline()})),
-        ("rpoplpush", Arc::new(RedisCommand { name: "rpoplpush", proc: Arc::new(rpoplpush_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("sadd", Arc::new(RedisCommand { name: "sadd", proc: Arc::new(sadd_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("srem", Arc::new(RedisCommand { name: "srem", proc: Arc::new(srem_command), arity: 3, flags: CmdFlags::bulk()})),
-        ("spop", Arc::new(RedisCommand { name: "spop", proc: Arc::new(spop_command), arity: 2, flags: CmdFlags::inline()})),
-        ("smove", Arc::new(RedisCommand { name: "smove", proc: Arc::new(smove_command), arity: 4, flags: CmdFlags::bulk()})),
-        ("scard", Arc::new(RedisCommand { name: "scard", proc: Arc::new(scard_command), arity: 2, flags: CmdFlags::inline()})),
-        ("sismember", Arc::new(RedisCommand { name: "sismember", proc: Arc::new(sismember_command), arity: 3, flags: CmdFlags::bulk()})),
-        ("sinter", Arc::new(RedisCommand { name: "sinter", proc: Arc::new(sinter_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("sinterstore", Arc::new(RedisCommand { name: "sinterstore", proc: Arc::new(sinterstore_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("sunion", Arc::new(RedisCommand { name: "sunion", proc: Arc::new(sunion_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("sunionstore", Arc::new(RedisCommand { name: "sunionstore", proc: Arc::new(sunionstore_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("sdiff", Arc::new(RedisCommand { name: "sdiff", proc: Arc::new(sdiff_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("sdiffstore", Arc::new(RedisCommand { name: "sdiffstore", proc: Arc::new(sdiffstore_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::deny_oom()})),
-        ("smembers", Arc::new(RedisCommand { name: "smembers", proc: Arc::new(sinter_command), arity: 2, flags: CmdFlags::inline()})),
-        ("srandmember", Arc::new(RedisCommand { name: "srandmember", proc: Arc::new(srandmember_command), arity: 2, flags: CmdFlags::inline()})),
-        ("zadd", Arc::new(RedisCommand { name: "zadd", proc: Arc::new(zadd_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("zrem", Arc::new(RedisCommand { name: "zrem", proc: Arc::new(zrem_command), arity: 3, flags: CmdFlags::bulk()})),
-        ("zincrby", Arc::new(RedisCommand { name: "zincrby", proc: Arc::new(zincrby_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("zrange", Arc::new(RedisCommand { name: "zrange", proc: Arc::new(zrange_command), arity: -4, flags: CmdFlags::inline()})),
-        ("zrevrange", Arc::new(RedisCommand { name: "zrevrange", proc: Arc::new(zrevrange_command), arity: -4, flags: CmdFlags::inline()})),
-        ("zrangebyscore", Arc::new(RedisCommand { name: "zrangebyscore", proc: Arc::new(zrangebyscore_command), arity: -4, flags: CmdFlags::inline()})),
-        ("zcard", Arc::new(RedisCommand { name: "zcard", proc: Arc::new(zcard_command), arity: 2, flags: CmdFlags::inline()})),
-        ("zscore", Arc::new(RedisCommand { name: "zscore", proc: Arc::new(zscore_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom()})),
-        ("zremrangebyscore", Arc::new(RedisCommand { name: "zremrangebyscore", proc: Arc::new(zremrangebyscore_command), arity: 4, flags: CmdFlags::inline()})),
-
-        ("save", Arc::new(RedisCommand { name: "save", proc: Arc::new(save_command), arity: 1, flags: CmdFlags::inline()})),
-        ("bgsave", Arc::new(RedisCommand { name: "bgsave", proc: Arc::new(bgsave_command), arity: 1, flags: CmdFlags::inline()})),
-        ("lastsave", Arc::new(RedisCommand { name: "lastsave", proc: Arc::new(lastsave_command), arity: 1, flags: CmdFlags::inline()})),
-        ("shutdown", Arc::new(RedisCommand { name: "shutdown", proc: Arc::new(shutdown_command), arity: 1, flags: CmdFlags::inline()})),
-        ("bgrewriteaof", Arc::new(RedisCommand { name: "bgrewriteaof", proc: Arc::new(bgrewriteaof_command), arity: 1, flags: CmdFlags::inline()})),
-        ("info", Arc::new(RedisCommand { name: "info", proc: Arc::new(info_command), arity: 1, flags: CmdFlags::inline()})),
-        ("monitor", Arc::new(RedisCommand { name: "monitor", proc: Arc::new(monitor_command), arity: 1, flags: CmdFlags::inline()})),
-        ("slaveof", Arc::new(RedisCommand { name: "slaveof", proc: Arc::new(slaveof_command), arity: 3, flags: CmdFlags::inline()})),
+        ("ping", Arc::new(RedisCommand { name: "ping", proc: Arc::new(ping_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::any() | CmdContext::pubsub()})),
+        ("multi", Arc::new(RedisCommand { name: "multi", proc: Arc::new(multi_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("exec", Arc::new(RedisCommand { name: "exec", proc: Arc::new(exec_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("discard", Arc::new(RedisCommand { name: "discard", proc: Arc::new(discard_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("reset", Arc::new(RedisCommand { name: "reset", proc: Arc::new(reset_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::any() | CmdContext::pubsub()})),
+        ("auth", Arc::new(RedisCommand { name: "auth", proc: Arc::new(auth_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::any()})),
+        ("exists", Arc::new(RedisCommand { name: "exists", proc: Arc::new(exists_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("del", Arc::new(RedisCommand { name: "del", proc: Arc::new(del_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("type", Arc::new(RedisCommand { name: "type", proc: Arc::new(type_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("keys", Arc::new(RedisCommand { name: "keys", proc: Arc::new(keys_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("scan", Arc::new(RedisCommand { name: "scan", proc: Arc::new(scan_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("randomkey", Arc::new(RedisCommand { name: "randomkey", proc: Arc::new(randomkey_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("rename", Arc::new(RedisCommand { name: "rename", proc: Arc::new(rename_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("renamenx", Arc::new(RedisCommand { name: "renamenx", proc: Arc::new(renamenx_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("dbsize", Arc::new(RedisCommand { name: "dbsize", proc: Arc::new(dbsize_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("expire", Arc::new(RedisCommand { name: "expire", proc: Arc::new(expire_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("expireat", Arc::new(RedisCommand { name: "expireat", proc: Arc::new(expireat_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("pexpire", Arc::new(RedisCommand { name: "pexpire", proc: Arc::new(pexpire_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("pexpireat", Arc::new(RedisCommand { name: "pexpireat", proc: Arc::new(pexpireat_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("ttl", Arc::new(RedisCommand { name: "ttl", proc: Arc::new(ttl_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("pttl", Arc::new(RedisCommand { name: "pttl", proc: Arc::new(pttl_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("persist", Arc::new(RedisCommand { name: "persist", proc: Arc::new(persist_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("select", Arc::new(RedisCommand { name: "select", proc: Arc::new(select_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("move", Arc::new(RedisCommand { name: "move", proc: Arc::new(move_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("flushdb", Arc::new(RedisCommand { name: "flushdb", proc: Arc::new(flushdb_command), arity: 1, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("flushall", Arc::new(RedisCommand { name: "flushall", proc: Arc::new(flushall_command), arity: 1, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+
+        ("set", Arc::new(RedisCommand { name: "set", proc: Arc::new(set_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("get", Arc::new(RedisCommand { name: "get", proc: Arc::new(get_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("getex", Arc::new(RedisCommand { name: "getex", proc: Arc::new(getex_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("getset", Arc::new(RedisCommand { name: "getset", proc: Arc::new(getset_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("mget", Arc::new(RedisCommand { name: "mget", proc: Arc::new(mget_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("setnx", Arc::new(RedisCommand { name: "setnx", proc: Arc::new(setnx_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("setex", Arc::new(RedisCommand { name: "setex", proc: Arc::new(setex_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("psetex", Arc::new(RedisCommand { name: "psetex", proc: Arc::new(psetex_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("mset", Arc::new(RedisCommand { name: "mset", proc: Arc::new(mset_command), arity: -3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("msetnx", Arc::new(RedisCommand { name: "msetnx", proc: Arc::new(msetnx_command), arity: -3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("incr", Arc::new(RedisCommand { name: "incr", proc: Arc::new(incr_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("incrby", Arc::new(RedisCommand { name: "incrby", proc: Arc::new(incrby_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("decr", Arc::new(RedisCommand { name: "decr", proc: Arc::new(decr_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("decrby", Arc::new(RedisCommand { name: "decrby", proc: Arc::new(decrby_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("append", Arc::new(RedisCommand { name: "append", proc: Arc::new(append_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("setrange", Arc::new(RedisCommand { name: "setrange", proc: Arc::new(setrange_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("strlen", Arc::new(RedisCommand { name: "strlen", proc: Arc::new(strlen_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("getrange", Arc::new(RedisCommand { name: "getrange", proc: Arc::new(getrange_command), arity: 4, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("substr", Arc::new(RedisCommand { name: "substr", proc: Arc::new(getrange_command), arity: 4, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("rpush", Arc::new(RedisCommand { name: "rpush", proc: Arc::new(rpush_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("lpush", Arc::new(RedisCommand { name: "lpush", proc: Arc::new(lpush_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("llen", Arc::new(RedisCommand { name: "llen", proc: Arc::new(llen_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("lrange", Arc::new(RedisCommand { name: "lrange", proc: Arc::new(lrange_command), arity: 4, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("ltrim", Arc::new(RedisCommand { name: "ltrim", proc: Arc::new(ltrim_command), arity: 4, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("lindex", Arc::new(RedisCommand { name: "lindex", proc: Arc::new(lindex_command), arity: 3, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("lset", Arc::new(RedisCommand { name: "lset", proc: Arc::new(lset_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("lrem", Arc::new(RedisCommand { name: "lrem", proc: Arc::new(lrem_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("lpop", Arc::new(RedisCommand { name: "lpop", proc: Arc::new(lpop_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("rpop", Arc::new(RedisCommand { name: "rpop", proc: Arc::new(rpop_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("blpop", Arc::new(RedisCommand { name: "blpop", proc: Arc::new(blpop_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("brpop", Arc::new(RedisCommand { name: "brpop", proc: Arc::new(brpop_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("rpoplpush", Arc::new(RedisCommand { name: "rpoplpush", proc: Arc::new(rpoplpush_command), arity: 3, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("sadd", Arc::new(RedisCommand { name: "sadd", proc: Arc::new(sadd_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("srem", Arc::new(RedisCommand { name: "srem", proc: Arc::new(srem_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("spop", Arc::new(RedisCommand { name: "spop", proc: Arc::new(spop_command), arity: 2, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("smove", Arc::new(RedisCommand { name: "smove", proc: Arc::new(smove_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("scard", Arc::new(RedisCommand { name: "scard", proc: Arc::new(scard_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("sismember", Arc::new(RedisCommand { name: "sismember", proc: Arc::new(sismember_command), arity: 3, flags: CmdFlags::bulk(), contexts: CmdContext::default()})),
+        ("sinter", Arc::new(RedisCommand { name: "sinter", proc: Arc::new(sinter_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom(), contexts: CmdContext::default()})),
+        ("sinterstore", Arc::new(RedisCommand { name: "sinterstore", proc: Arc::new(sinterstore_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("sunion", Arc::new(RedisCommand { name: "sunion", proc: Arc::new(sunion_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom(), contexts: CmdContext::default()})),
+        ("sunionstore", Arc::new(RedisCommand { name: "sunionstore", proc: Arc::new(sunionstore_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("sdiff", Arc::new(RedisCommand { name: "sdiff", proc: Arc::new(sdiff_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom(), contexts: CmdContext::default()})),
+        ("sdiffstore", Arc::new(RedisCommand { name: "sdiffstore", proc: Arc::new(sdiffstore_command), arity: -3, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("smembers", Arc::new(RedisCommand { name: "smembers", proc: Arc::new(sinter_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("srandmember", Arc::new(RedisCommand { name: "srandmember", proc: Arc::new(srandmember_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("zadd", Arc::new(RedisCommand { name: "zadd", proc: Arc::new(zadd_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("zrem", Arc::new(RedisCommand { name: "zrem", proc: Arc::new(zrem_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("zincrby", Arc::new(RedisCommand { name: "zincrby", proc: Arc::new(zincrby_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("zrange", Arc::new(RedisCommand { name: "zrange", proc: Arc::new(zrange_command), arity: -4, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("zrevrange", Arc::new(RedisCommand { name: "zrevrange", proc: Arc::new(zrevrange_command), arity: -4, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("zrangebyscore", Arc::new(RedisCommand { name: "zrangebyscore", proc: Arc::new(zrangebyscore_command), arity: -4, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("zcard", Arc::new(RedisCommand { name: "zcard", proc: Arc::new(zcard_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("zscore", Arc::new(RedisCommand { name: "zscore", proc: Arc::new(zscore_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::deny_oom(), contexts: CmdContext::default()})),
+        ("zremrangebyscore", Arc::new(RedisCommand { name: "zremrangebyscore", proc: Arc::new(zremrangebyscore_command), arity: 4, flags: CmdFlags::inline() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("hset", Arc::new(RedisCommand { name: "hset", proc: Arc::new(hset_command), arity: 4, flags: CmdFlags::bulk() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("hget", Arc::new(RedisCommand { name: "hget", proc: Arc::new(hget_command), arity: 3, flags: CmdFlags::bulk(), contexts: CmdContext::default()})),
+        ("hdel", Arc::new(RedisCommand { name: "hdel", proc: Arc::new(hdel_command), arity: 3, flags: CmdFlags::bulk() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("hexists", Arc::new(RedisCommand { name: "hexists", proc: Arc::new(hexists_command), arity: 3, flags: CmdFlags::bulk(), contexts: CmdContext::default()})),
+        ("hlen", Arc::new(RedisCommand { name: "hlen", proc: Arc::new(hlen_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("hkeys", Arc::new(RedisCommand { name: "hkeys", proc: Arc::new(hkeys_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("hvals", Arc::new(RedisCommand { name: "hvals", proc: Arc::new(hvals_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("hgetall", Arc::new(RedisCommand { name: "hgetall", proc: Arc::new(hgetall_command), arity: 2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("hincrby", Arc::new(RedisCommand { name: "hincrby", proc: Arc::new(hincrby_command), arity: 4, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+        ("hscan", Arc::new(RedisCommand { name: "hscan", proc: Arc::new(hscan_command), arity: -3, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("sort", Arc::new(RedisCommand { name: "sort", proc: Arc::new(sort_command), arity: -2, flags: CmdFlags::inline() | CmdFlags::deny_oom() | CmdFlags::write(), contexts: CmdContext::default()})),
+
+        ("save", Arc::new(RedisCommand { name: "save", proc: Arc::new(save_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::admin()})),
+        ("bgsave", Arc::new(RedisCommand { name: "bgsave", proc: Arc::new(bgsave_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::admin() | CmdContext::multi()})),
+        ("lastsave", Arc::new(RedisCommand { name: "lastsave", proc: Arc::new(lastsave_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("shutdown", Arc::new(RedisCommand { name: "shutdown", proc: Arc::new(shutdown_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::admin() | CmdContext::loading()})),
+        ("bgrewriteaof", Arc::new(RedisCommand { name: "bgrewriteaof", proc: Arc::new(bgrewriteaof_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::admin() | CmdContext::multi()})),
+        ("info", Arc::new(RedisCommand { name: "info", proc: Arc::new(info_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::any()})),
+        ("monitor", Arc::new(RedisCommand { name: "monitor", proc: Arc::new(monitor_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::admin()})),
+        ("slaveof", Arc::new(RedisCommand { name: "slaveof", proc: Arc::new(slaveof_command), arity: 3, flags: CmdFlags::inline(), contexts: CmdContext::admin()})),
+        ("replicaof", Arc::new(RedisCommand { name: "replicaof", proc: Arc::new(slaveof_command), arity: 3, flags: CmdFlags::inline(), contexts: CmdContext::admin()})),
+        ("sync", Arc::new(RedisCommand { name: "sync", proc: Arc::new(sync_command), arity: 1, flags: CmdFlags::inline(), contexts: CmdContext::admin()})),
+        ("debug", Arc::new(RedisCommand { name: "debug", proc: Arc::new(debug_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::admin()})),
+        ("config", Arc::new(RedisCommand { name: "config", proc: Arc::new(config_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("latency", Arc::new(RedisCommand { name: "latency", proc: Arc::new(latency_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::any()})),
+        ("command", Arc::new(RedisCommand { name: "command", proc: Arc::new(command_command), arity: -1, flags: CmdFlags::inline(), contexts: CmdContext::any()})),
+        ("client", Arc::new(RedisCommand { name: "client", proc: Arc::new(client_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("object", Arc::new(RedisCommand { name: "object", proc: Arc::new(object_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::default()})),
+        ("cluster", Arc::new(RedisCommand { name: "cluster", proc: Arc::new(cluster_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::any()})),
+        ("subscribe", Arc::new(RedisCommand { name: "subscribe", proc: Arc::new(subscribe_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::normal() | CmdContext::pubsub()})),
+        ("unsubscribe", Arc::new(RedisCommand { name: "unsubscribe", proc: Arc::new(unsubscribe_command), arity: -1, flags: CmdFlags::inline(), contexts: CmdContext::normal() | CmdContext::pubsub()})),
+        ("psubscribe", Arc::new(RedisCommand { name: "psubscribe", proc: Arc::new(psubscribe_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::normal() | CmdContext::pubsub()})),
+        ("punsubscribe", Arc::new(RedisCommand { name: "punsubscribe", proc: Arc::new(punsubscribe_command), arity: -1, flags: CmdFlags::inline(), contexts: CmdContext::normal() | CmdContext::pubsub()})),
+        ("publish", Arc::new(RedisCommand { name: "publish", proc: Arc::new(publish_command), arity: 3, flags: CmdFlags::bulk(), contexts: CmdContext::default()})),
+        ("ssubscribe", Arc::new(RedisCommand { name: "ssubscribe", proc: Arc::new(ssubscribe_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::normal() | CmdContext::pubsub()})),
+        ("sunsubscribe", Arc::new(RedisCommand { name: "sunsubscribe", proc: Arc::new(sunsubscribe_command), arity: -1, flags: CmdFlags::inline(), contexts: CmdContext::normal() | CmdContext::pubsub()})),
+        ("spublish", Arc::new(RedisCommand { name: "spublish", proc: Arc::new(spublish_command), arity: 3, flags: CmdFlags::bulk(), contexts: CmdContext::default()})),
+        ("pubsub", Arc::new(RedisCommand { name: "pubsub", proc: Arc::new(pubsub_command), arity: -2, flags: CmdFlags::inline(), contexts: CmdContext::any()})),
     ])
 });
+/// Command documentation reported by COMMAND DOCS: (name, group, since, summary)
+static CMD_DOCS: &[(&str, &str, &str, &str)] = &[
+    ("ping", "connection", "1.0.0", "Returns the server's liveliness response."),
+    ("multi", "transactions", "1.2.0", "Starts a transaction."),
+    ("exec", "transactions", "1.2.0", "Executes all commands in a transaction."),
+    ("discard", "transactions", "2.0.0", "Discards a transaction."),
+    ("reset", "connection", "6.2.0", "Resets the connection."),
+    ("auth", "connection", "1.0.0", "Authenticates the connection."),
+    ("exists", "generic", "1.0.0", "Determines whether a key exists."),
+    ("del", "generic", "1.0.0", "Deletes one or more keys."),
+    ("type", "generic", "1.0.0", "Determines the type of value stored at a key."),
+    ("keys", "generic", "1.0.0", "Returns all key names that match a pattern."),
+    ("scan", "generic", "2.8.0", "Iterates over the key names in the database."),
+    ("randomkey", "generic", "1.0.0", "Returns a random key name from the database."),
+    ("rename", "generic", "1.0.0", "Renames a key and overwrites the destination."),
+    ("renamenx", "generic", "1.0.0", "Renames a key only when the target key name doesn't exist."),
+    ("dbsize", "server", "1.0.0", "Returns the number of keys in the database."),
+    ("expire", "generic", "1.0.0", "Sets the expiration time of a key in seconds."),
+    ("expireat", "generic", "1.2.0", "Sets the expiration time of a key to a Unix timestamp."),
+    ("pexpire", "generic", "2.6.0", "Sets the expiration time of a key in milliseconds."),
+    ("pexpireat", "generic", "2.6.0", "Sets the expiration time of a key to a Unix milliseconds timestamp."),
+    ("ttl", "generic", "1.0.0", "Returns the expiration time in seconds of a key."),
+    ("pttl", "generic", "2.6.0", "Returns the expiration time in milliseconds of a key."),
+    ("persist", "generic", "2.2.0", "Removes the expiration time of a key."),
+    ("select", "connection", "1.0.0", "Changes the selected database."),
+    ("move", "generic", "1.0.0", "Moves a key to another database."),
+    ("flushdb", "server", "1.0.0", "Removes all keys from the current database."),
+    ("flushall", "server", "1.0.0", "Removes all keys from all databases."),
+    ("set", "string", "1.0.0", "Sets the string value of a key."),
+    ("get", "string", "1.0.0", "Returns the string value of a key."),
+    ("getex", "string", "6.2.0", "Returns the string value of a key after setting its expiration time."),
+    ("getset", "string", "1.0.0", "Returns the previous string value of a key after setting it to a new value."),
+    ("mget", "string", "1.0.0", "Atomically returns the string values of one or more keys."),
+    ("setnx", "string", "1.0.0", "Set the string value of a key only when the key doesn't exist."),
+    ("setex", "string", "2.0.0", "Sets the string value and expiration time of a key."),
+    ("psetex", "string", "2.6.0", "Sets both string value and expiration time in milliseconds of a key."),
+    ("mset", "string", "1.0.1", "Atomically creates or modifies the string values of one or more keys."),
+    ("msetnx", "string", "1.0.1", "Atomically modifies the string values of one or more keys only when all keys don't exist."),
+    ("incr", "string", "1.0.0", "Increments the integer value of a key by one."),
+    ("incrby", "string", "1.0.0", "Increments the integer value of a key by a number."),
+    ("decr", "string", "1.0.0", "Decrements the integer value of a key by one."),
+    ("decrby", "string", "1.0.0", "Decrements a number from the integer value of a key."),
+    ("append", "string", "2.0.0", "Appends a string to the value of a key. Creates the key if it doesn't exist."),
+    ("setrange", "string", "2.2.0", "Overwrites a part of a string value with another by an offset. Creates the key if it doesn't exist."),
+    ("strlen", "string", "2.2.0", "Returns the length of a string value."),
+    ("getrange", "string", "2.4.0", "Returns a substring of the string stored at a key."),
+    ("substr", "string", "1.0.0", "Returns a substring from a string value. Deprecated, use GETRANGE."),
+    ("rpush", "list", "1.0.0", "Appends an element to a list."),
+    ("lpush", "list", "1.0.0", "Prepends an element to a list."),
+    ("llen", "list", "1.0.0", "Returns the length of a list."),
+    ("lrange", "list", "1.0.0", "Returns a range of elements from a list."),
+    ("ltrim", "list", "1.0.0", "Removes elements from both ends of a list."),
+    ("lindex", "list", "1.0.0", "Returns an element from a list by its index."),
+    ("lset", "list", "1.0.0", "Sets the value of an element in a list by its index."),
+    ("lrem", "list", "1.0.0", "Removes elements from a list."),
+    ("lpop", "list", "1.0.0", "Returns the first element of a list after removing it."),
+    ("rpop", "list", "1.0.0", "Returns and removes the last element of a list."),
+    ("blpop", "list", "2.0.0", "Removes and returns the first element in a list. Blocks until an element is available otherwise."),
+    ("brpop", "list", "2.0.0", "Removes and returns the last element in a list. Blocks until an element is available otherwise."),
+    ("rpoplpush", "list", "1.2.0", "Returns the last element of a list after removing and pushing it to another list."),
+    ("sadd", "set", "1.0.0", "Adds a member to a set."),
+    ("srem", "set", "1.0.0", "Removes a member from a set."),
+    ("spop", "set", "1.0.0", "Returns a random member from a set after removing it."),
+    ("smove", "set", "1.0.0", "Moves a member from one set to another."),
+    ("scard", "set", "1.0.0", "Returns the number of members in a set."),
+    ("sismember", "set", "1.0.0", "Determines whether a member belongs to a set."),
+    ("sinter", "set", "1.0.0", "Returns the intersect of multiple sets."),
+    ("sinterstore", "set", "1.0.0", "Stores the intersect of multiple sets in a key."),
+    ("sunion", "set", "1.0.0", "Returns the union of multiple sets."),
+    ("sunionstore", "set", "1.0.0", "Stores the union of multiple sets in a key."),
+    ("sdiff", "set", "1.0.0", "Returns the difference of multiple sets."),
+    ("sdiffstore", "set", "1.0.0", "Stores the difference of multiple sets in a key."),
+    ("smembers", "set", "1.0.0", "Returns all members of a set."),
+    ("srandmember", "set", "1.0.0", "Returns a random member from a set."),
+    ("zadd", "sorted_set", "1.2.0", "Adds a member to a sorted set, or updates its score if it already exists."),
+    ("zrem", "sorted_set", "1.2.0", "Removes a member from a sorted set."),
+    ("zincrby", "sorted_set", "1.2.0", "Increments the score of a member in a sorted set."),
+    ("zrange", "sorted_set", "1.2.0", "Returns members in a sorted set within a range of indexes."),
+    ("zrevrange", "sorted_set", "1.2.0", "Returns members in a sorted set within a range of indexes in reverse order."),
+    ("zrangebyscore", "sorted_set", "1.0.5", "Returns members in a sorted set within a range of scores."),
+    ("zcard", "sorted_set", "1.2.0", "Returns the number of members in a sorted set."),
+    ("zscore", "sorted_set", "1.2.0", "Returns the score of a member in a sorted set."),
+    ("zremrangebyscore", "sorted_set", "1.2.0", "Removes members in a sorted set within a range of scores."),
+    ("hset", "hash", "2.0.0", "Sets the value of a field in a hash."),
+    ("hget", "hash", "2.0.0", "Returns the value of a field in a hash."),
+    ("hdel", "hash", "2.0.0", "Deletes a field from a hash."),
+    ("hexists", "hash", "2.0.0", "Determines whether a field exists in a hash."),
+    ("hlen", "hash", "2.0.0", "Returns the number of fields in a hash."),
+    ("hkeys", "hash", "2.0.0", "Returns all fields in a hash."),
+    ("hvals", "hash", "2.0.0", "Returns all values in a hash."),
+    ("hgetall", "hash", "2.0.0", "Returns all fields and values in a hash."),
+    ("hincrby", "hash", "2.0.0", "Increments the integer value of a field in a hash by a number."),
+    ("hscan", "hash", "2.8.0", "Iterates over fields and values of a hash."),
+    ("sort", "generic", "1.0.0", "Sorts the elements in a list, a set, or a sorted set, optionally storing the result."),
+    ("save", "server", "1.0.0", "Synchronously saves the database(s) to disk."),
+    ("bgsave", "server", "1.0.0", "Asynchronously saves the database(s) to disk."),
+    ("lastsave", "server", "1.0.0", "Returns the Unix timestamp of the last successful save to disk."),
+    ("shutdown", "server", "1.0.0", "Synchronously saves the database(s) to disk and shuts down the server."),
+    ("bgrewriteaof", "server", "1.0.0", "Asynchronously rewrites the append-only file to disk."),
+    ("info", "server", "1.0.0", "Returns information and statistics about the server."),
+    ("monitor", "server", "1.0.0", "Listens for all requests received by the server in real-time."),
+    ("slaveof", "server", "1.0.0", "Sets a server as a replica of another, or promotes it to being a master. Deprecated, use REPLICAOF."),
+    ("replicaof", "server", "5.0.0", "Configures a server as replica of another, or promotes it to a master."),
+    ("sync", "server", "1.0.0", "An internal command used in replication."),
+    ("debug", "server", "1.0.0", "A container for debugging commands."),
+    ("config", "server", "2.0.0", "A container for server configuration commands."),
+    ("latency", "server", "2.8.13", "A container for latency diagnostics commands."),
+    ("command", "server", "2.8.13", "A container for command introspection commands."),
+    ("client", "connection", "2.4.0", "A container for client connection commands."),
+    ("object", "generic", "2.2.3", "A container for object introspection commands."),
+    ("cluster", "cluster", "3.0.0", "A container for Redis Cluster commands."),
+    ("subscribe", "pubsub", "2.0.0", "Listens for messages published to channels."),
+    ("unsubscribe", "pubsub", "2.0.0", "Stops listening to messages posted to channels."),
+    ("psubscribe", "pubsub", "2.0.0", "Listens for messages published to channels that match one or more patterns."),
+    ("punsubscribe", "pubsub", "2.0.0", "Stops listening to messages published to channels that match one or more patterns."),
+    ("publish", "pubsub", "2.0.0", "Posts a message to a channel."),
+    ("ssubscribe", "pubsub", "7.0.0", "Listens for messages published to shard channels. Without a cluster, the same as SUBSCRIBE."),
+    ("sunsubscribe", "pubsub", "7.0.0", "Stops listening to messages posted to shard channels."),
+    ("spublish", "pubsub", "7.0.0", "Posts a message to a shard channel. Without a cluster, the same as PUBLISH."),
+    ("pubsub", "pubsub", "2.8.0", "Inspects the state of the Pub/Sub subsystem."),
+];
+
+/// The command a client calls by `name`, after rename-command: a renamed
+/// command answers only to its new name, a disabled one to none.
 pub fn lookup_command(name: &str) -> Option<Arc<RedisCommand>> {
-    let name = name.to_lowercase();
-    CMD_TABLE.get(&name[..]).map(|e| e.clone())
+    {
+        let server = server_read();
+        for (cmd, new_name) in &server.command_renames {
+            if new_name.eq_ignore_ascii_case(name) {
+                return lookup_original_command(cmd);
+            }
+            if cmd.eq_ignore_ascii_case(name) {
+                return None;
+            }
+        }
+    }
+    lookup_original_command(name)
+}
+
+/// No command name is longer: a longer name can't be in CMD_TABLE.
+const MAX_COMMAND_NAME_LEN: usize = 32;
+
+/// The command by its own name, whatever rename-command says. This is what
+/// the server uses internally and what the AOF holds.
+pub fn lookup_original_command(name: &str) -> Option<Arc<RedisCommand>> {
+    // Called for every command: lowercase on the stack, not in a String.
+    let mut buf = [0u8; MAX_COMMAND_NAME_LEN];
+    let lower = buf.get_mut(..name.len())?;
+    lower.copy_from_slice(name.as_bytes());
+    lower.make_ascii_lowercase();
+    CMD_TABLE.get(std::str::from_utf8(lower).ok()?).cloned()
 }
 
 
@@ -102,28 +297,104 @@ pub fn lookup_command(name: &str) -> Option<Arc<RedisCommand>> {
 pub fn call(c: &mut RedisClient, cmd: Arc<RedisCommand>) {
     let dirty = server_read().dirty;
 
+    // The AOF is replayed before any client connects, no need to check
+    if !c.flags.is_aof_client() && !server_read().monitors.is_empty() {
+        feed_monitors(c);
+    }
+
+    c.last_cmd = cmd.name();
+    let start = Instant::now();
     let f = &cmd.proc;
     f(c);
-
-    if server_read().append_only && server_read().dirty != dirty {
-        feed_append_only_file(cmd.clone(), c.db.clone().unwrap().read().unwrap().id, &c.argv);
+    latency_add_sample_if_needed("command", start.elapsed().as_millis() as u64);
+
+    // The commands replayed from the AOF are already in it
+    let propagate_argv = c.take_propagate_argv();
+    if server_read().dirty != dirty && !c.flags.is_aof_client() {
+        let db_id = c.db.clone().unwrap().read().unwrap().id;
+        match propagate_argv {
+            Some(argv) => {
+                let cmd = lookup_original_command(argv[0].read().unwrap().as_key()).expect("propagated command doesn't exist");
+                propagate(cmd, db_id, &argv);
+            },
+            None => { propagate(cmd.clone(), db_id, &c.argv); },
+        }
     }
 
     server_write().stat_numcommands += 1;
 }
 
+/// Feed a write command to the AOF and to the slaves.
+fn propagate(cmd: Arc<RedisCommand>, db_id: i32, argv: &[Arc<RwLock<RedisObject>>]) {
+    if server_read().append_only {
+        feed_append_only_file(cmd.clone(), db_id, argv);
+    }
+    if !server_read().slaves().is_empty() {
+        replication_feed_slaves(&cmd, db_id, argv);
+    }
+}
+
+/// Send the command the client is about to run to the monitors, as
+/// `+1339518083.107412 [0 127.0.0.1:60866] "set" "key" "value"`.
+/// The caller holds the lock of the client, so it's skipped: a monitor
+/// doesn't see its own commands, starting from MONITOR.
+fn feed_monitors(c: &RedisClient) {
+    let monitors = server_read().monitors.clone();
+    let now = timestamp();
+    let mut line = format!("+{}.{:06} [{} {}]", now.as_secs(), now.subsec_micros(), c.db_id(), c.addr());
+    for arg in &c.argv {
+        line.push_str(&format!(" \"{}\"", arg.read().unwrap().as_key().as_bytes().escape_ascii()));
+    }
+    line.push_str("\r\n");
+
+    for m in clients_read().iter() {
+        let Ok(m) = m.try_read() else { continue; };
+        if monitors.contains(&m.id()) {
+            m.add_reply_str(&line);
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ArityError {
+    TooFew,
+    TooMany,
+}
+
+/// Check argc against an arity in the command table convention, also used
+/// for the subcommands of container commands.
+pub fn check_arity(arity: i32, argc: usize) -> Result<(), ArityError> {
+    let min = arity.unsigned_abs() as usize;
+    if argc < min {
+        Err(ArityError::TooFew)
+    } else if arity >= 0 && argc > min {
+        Err(ArityError::TooMany)
+    } else {
+        Ok(())
+    }
+}
 
 pub struct RedisCommand {
     name: &'static str,
     proc: CommandProc,
     arity: i32,
     flags: CmdFlags,
+    contexts: CmdContext,                   // where the command is allowed to run
 }
 impl RedisCommand {
     pub fn arity(&self) -> i32 {
         self.arity
     }
-    pub fn name(&self) -> &str {
+    /// A positive arity is the exact number of arguments (command name
+    /// included), a negative one is the minimum: -N means N or more.
+    pub fn arity_ok(&self, argc: usize) -> bool {
+        self.check_arity(argc).is_ok()
+    }
+    /// Like arity_ok(), telling too few arguments from too many.
+    pub fn check_arity(&self, argc: usize) -> Result<(), ArityError> {
+        check_arity(self.arity, argc)
+    }
+    pub fn name(&self) -> &'static str {
         self.name
     }
     pub fn flags(&self) -> &CmdFlags {
@@ -135,13 +406,21 @@ impl RedisCommand {
     pub fn proc(&self) -> CommandProc {
         self.proc.clone()
     }
+    /// Check the command can run in the given context, returning the error
+    /// to send otherwise.
+    pub fn check_context(&self, ctx: CmdContext) -> Result<(), String> {
+        match ctx.denied_by(self.contexts) {
+            None => Ok(()),
+            Some(denied) => Err(denied.error(self.name)),
+        }
+    }
 }
 
 
-/// Client MULTI/EXEC state
+/// A command queued inside MULTI, run by EXEC.
 pub struct MultiCmd {
-    argv: Vec<Arc<RedisObject>>,
-    cmd: RedisCommand,
+    pub argv: Vec<Arc<RwLock<RedisObject>>>,
+    pub cmd: Arc<RedisCommand>,
 }
 
 type CommandProc = Arc<dyn Fn(&mut RedisClient) -> () + Sync + Send>;
@@ -164,12 +443,20 @@ impl CmdFlags {
     fn deny_oom() -> CmdFlags {
         CmdFlags(4)
     }
+    /// Command that may modify the dataset. Such commands are refused while
+    /// the server is unable to persist them (see aof_last_write_status).
+    fn write() -> CmdFlags {
+        CmdFlags(8)
+    }
     pub fn is_bulk(&self) -> bool {
         (self.0 & Self::bulk().0) != 0
     }
     pub fn is_deny_oom(&self) -> bool {
         (self.0 & Self::deny_oom().0) != 0
     }
+    pub fn is_write(&self) -> bool {
+        (self.0 & Self::write().0) != 0
+    }
 }
 impl BitOr for CmdFlags {
     type Output = Self;
@@ -179,15 +466,165 @@ impl BitOr for CmdFlags {
     }
 }
 
+/// Command contexts. The context of a client (see RedisClient::context())
+/// has one bit telling where the command comes from (normal, aof_load or
+/// master) plus the states it is in (multi, pubsub, loading). Every command
+/// has the mask of the contexts it's allowed in, and runs only if all the
+/// bits of the client context are in the mask.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct CmdContext(u8);
+impl CmdContext {
+    /// A regular client connection
+    pub fn normal() -> CmdContext {
+        CmdContext(1)
+    }
+    /// A client with the ClientFlags::aof_client() flag
+    pub fn aof_load() -> CmdContext {
+        CmdContext(2)
+    }
+    /// Queued in a MULTI block
+    pub fn multi() -> CmdContext {
+        CmdContext(4)
+    }
+    /// The link with our master
+    pub fn master() -> CmdContext {
+        CmdContext(8)
+    }
+    /// A client subscribed to some channel or pattern
+    pub fn pubsub() -> CmdContext {
+        CmdContext(16)
+    }
+    /// The server is still loading the dataset
+    pub fn loading() -> CmdContext {
+        CmdContext(32)
+    }
+    /// Introspection commands are fine anywhere, but a subscribed client
+    /// only gets the pubsub commands, PING and QUIT.
+    fn any() -> CmdContext {
+        CmdContext(!Self::pubsub().0)
+    }
+    /// Persistence and administrative commands: only from a real client.
+    /// Replaying them from the AOF or from the master would fork, shutdown
+    /// or reconfigure the server behind the user's back.
+    fn admin() -> CmdContext {
+        Self::normal()
+    }
+    /// The first bit of this context that is not in `allowed`, if any.
+    /// States come first, so that a client gets the most useful error.
+    fn denied_by(self, allowed: CmdContext) -> Option<CmdContext> {
+        [Self::loading(), Self::pubsub(), Self::multi(), Self::aof_load(), Self::master(), Self::normal()]
+            .into_iter()
+            .find(|c| (self.0 & c.0) != 0 && (allowed.0 & c.0) == 0)
+    }
+    fn error(self, name: &str) -> String {
+        if self == Self::loading() {
+            errors::loading()
+        } else if self == Self::pubsub() {
+            errors::err(&format!("Can't execute '{}': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context", name))
+        } else if self == Self::multi() {
+            errors::err("Command not allowed inside a transaction")
+        } else if self == Self::aof_load() {
+            errors::err(&format!("'{}' command not allowed while loading the append only file", name))
+        } else if self == Self::master() {
+            errors::err(&format!("'{}' command not allowed from the master link", name))
+        } else {
+            errors::err(&format!("'{}' command not allowed in this context", name))
+        }
+    }
+}
+impl Default for CmdContext {
+    /// Data commands run everywhere but in the pubsub and loading states.
+    fn default() -> Self {
+        Self::normal() | Self::aof_load() | Self::multi() | Self::master()
+    }
+}
+impl BitOr for CmdContext {
+    type Output = Self;
+
+    fn bitor(self, rhs: Self) -> Self::Output {
+        CmdContext(self.0 | rhs.0)
+    }
+}
+
 
 fn ping_command(c: &mut RedisClient) {
-    c.add_reply(PONG.clone());
+    // A subscribed client reads messages, so PING answers with one
+    if c.is_subscribed() {
+        c.add_reply_str("*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+    } else {
+        c.add_reply(PONG.clone());
+    }
 }
+fn multi_command(c: &mut RedisClient) {
+    if !c.start_multi() {
+        c.add_reply_error(&errors::err("MULTI calls can not be nested"));
+        return;
+    }
+    c.add_reply(OK.clone());
+}
+
+/// Run the queued commands, replying with a multi bulk of their replies.
+/// The transaction goes to the AOF wrapped in MULTI/EXEC so that it's
+/// replayed as a whole.
 pub fn exec_command(c: &mut RedisClient) {
-    todo!()
+    if !c.is_multi() {
+        c.add_reply_error(&errors::err("EXEC without MULTI"));
+        return;
+    }
+    let commands = match c.take_multi_commands() {
+        Ok(commands) => commands,
+        Err(e) => {
+            c.end_multi();
+            c.add_reply_error(&e);
+            return;
+        },
+    };
+
+    let propagated = !c.flags.is_aof_client() && {
+        let server = server_read();
+        server.append_only || !server.slaves().is_empty()
+    };
+    if propagated {
+        let db_id = c.db_id();
+        let multi = vec![Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("MULTI".to_string()) }.tracked()))];
+        propagate(lookup_original_command("multi").unwrap(), db_id, &multi);
+    }
+    let exec_argv = std::mem::take(&mut c.argv);
+    c.add_reply_str(&format!("*{}\r\n", commands.len()));
+    for MultiCmd { argv, cmd } in commands {
+        c.argv = argv;
+        call(c, cmd);
+    }
+    c.argv = exec_argv;
+    c.end_multi();
+    // Make sure the EXEC itself is propagated, after the MULTI
+    if propagated {
+        server_write().dirty += 1;
+    }
 }
+
 pub fn discard_command(c: &mut RedisClient) {
-    todo!()
+    if !c.is_multi() {
+        c.add_reply_error(&errors::err("DISCARD without MULTI"));
+        return;
+    }
+    c.end_multi();
+    c.add_reply(OK.clone());
+}
+
+/// Bring the connection back to the state of a new one: no transaction,
+/// no subscriptions, db 0 and not authenticated. A client blocked in BLPOP
+/// is unblocked before it gets here, see process_commands().
+fn reset_command(c: &mut RedisClient) {
+    if c.is_multi() {
+        c.end_multi();
+    }
+    pubsub_unsubscribe_all_channels(c, false, false);
+    pubsub_unsubscribe_all_channels(c, true, false);
+    pubsub_unsubscribe_all_patterns(c, false);
+    c.select_db(0);
+    c.authenticated = false;
+    c.add_reply_str("+RESET\r\n");
 }
 
 fn auth_command(c: &mut RedisClient) {
@@ -196,7 +633,7 @@ fn auth_command(c: &mut RedisClient) {
         c.add_reply(OK.clone());
     } else {
         c.authenticated = false;
-        c.add_reply_str("-ERR invalid password\r\n");
+        c.add_reply_error(&errors::err("invalid password"));
     }
 }
 
@@ -211,49 +648,198 @@ fn exists_command(c: &mut RedisClient) {
 fn del_command(c: &mut RedisClient) {
     let mut deleted = 0;
     for i in 1..c.argv.len() {
-        match c.delete_key(c.argv[i].read().unwrap().as_key()) {
-            Some(_) => {
-                server_write().dirty += 1;
-                deleted += 1;
-            },
-            None => {},
+        let key = c.argv[i].read().unwrap().as_key().to_string();
+        // An expired key is already gone, even if it's not reaped yet
+        c.expire_if_needed(&key);
+        if c.delete_key(&key) {
+            server_write().dirty += 1;
+            deleted += 1;
         }
     }
     c.add_reply_u64(deleted);
 }
 
+/// The type name reported by TYPE and filtered by SCAN TYPE.
+fn type_name(obj: &RedisObject) -> &'static str {
+    if obj.is_string() { "string" }
+    else if obj.is_list() { "list" }
+    else if obj.is_set() { "set" }
+    else if obj.is_zset() { "zset" }
+    else if obj.is_hash() { "hash" }
+    else { "unknown" }
+}
+
 fn type_command(c: &mut RedisClient) {
     let ret = match c.lookup_key_read(c.argv[1].read().unwrap().as_key()) {
-        Some(obj) => {
-            if obj.read().unwrap().is_string() { "+string" }
-            else if obj.read().unwrap().is_list() { "+list" }
-            else if obj.read().unwrap().is_set() { "+set" }
-            else if obj.read().unwrap().is_zset() { "+zset" }
-            else { "+unknown" }
-        },
-        None => { "+none" },
+        Some(obj) => type_name(&obj.read().unwrap()),
+        None => "none",
     };
-    c.add_reply_str(ret);
+    c.add_reply_str(&format!("+{}", ret));
     c.add_reply(CRLF.clone());
 }
 
+/// Options shared by SCAN and HSCAN. TYPE is only accepted by SCAN,
+/// NOVALUES only by HSCAN.
+struct ScanOptions {
+    pattern: Option<String>,
+    count: usize,
+    type_filter: Option<String>,
+    novalues: bool,
+}
+
+/// Parse the cursor at `first` and the options following it. On error the
+/// client already got the reply.
+fn parse_scan_args(c: &RedisClient, first: usize, hscan: bool) -> Option<(u64, ScanOptions)> {
+    let Ok(cursor) = c.arg(first).parse::<u64>() else {
+        c.add_reply_error(&errors::err("invalid cursor"));
+        return None;
+    };
+    let mut opts = ScanOptions { pattern: None, count: 10, type_filter: None, novalues: false };
+    let mut j = first + 1;
+    while j < c.argv.len() {
+        let opt = c.arg(j).to_ascii_lowercase();
+        if opt == "novalues" && hscan {
+            opts.novalues = true;
+            j += 1;
+            continue;
+        }
+        if j + 1 >= c.argv.len() {
+            c.add_reply(SYNTAX_ERR.clone());
+            return None;
+        }
+        match &opt[..] {
+            "match" => { opts.pattern = Some(c.arg(j + 1).to_string()); },
+            "count" => {
+                match c.arg(j + 1).parse::<usize>() {
+                    Ok(n) if n >= 1 => { opts.count = n; },
+                    _ => {
+                        c.add_reply(SYNTAX_ERR.clone());
+                        return None;
+                    },
+                }
+            },
+            "type" if !hscan => {
+                let name = c.arg(j + 1).to_ascii_lowercase();
+                if !matches!(&name[..], "string" | "list" | "set" | "zset" | "hash") {
+                    c.add_reply_error(&errors::err(&format!("unknown type name '{}'", name)));
+                    return None;
+                }
+                opts.type_filter = Some(name);
+            },
+            _ => {
+                c.add_reply(SYNTAX_ERR.clone());
+                return None;
+            },
+        }
+        j += 2;
+    }
+    Some((cursor, opts))
+}
+
+/// The next batch of an iteration over `names` in the order of their
+/// hashes, like Dict::scan() for collections without a scan index: every
+/// call walks the whole collection.
+fn scan_batch<'a, T>(names: impl Iterator<Item = (&'a String, T)> + Clone, cursor: u64, count: usize) -> (Vec<(&'a String, T)>, u64) {
+    let mut smallest: BinaryHeap<u64> = BinaryHeap::with_capacity(count + 1);
+    for (name, _) in names.clone() {
+        let hash = scan_hash(name);
+        if hash >= cursor {
+            smallest.push(hash);
+            if smallest.len() > count {
+                smallest.pop();
+            }
+        }
+    }
+    if smallest.len() < count {
+        return (names.filter(|(name, _)| scan_hash(name) >= cursor).collect(), 0);
+    }
+    let last = *smallest.peek().unwrap();
+    let batch = names.filter(|(name, _)| (cursor..=last).contains(&scan_hash(name))).collect();
+    (batch, last.checked_add(1).unwrap_or(0))
+}
+
+/// SCAN cursor [MATCH pattern] [COUNT count] [TYPE type]
+///
+/// Keys are visited in the order of a hash of their names, and the cursor
+/// is the hash to continue from. That order doesn't depend on the other
+/// keys, so a key present from the start to the end of an iteration is
+/// returned exactly once however the keyspace changes meanwhile; keys
+/// added or removed during the iteration may or may not be. Expired keys
+/// are skipped, not deleted. COUNT is the number of keys looked at per
+/// call, MATCH and TYPE are applied afterwards and may leave a batch
+/// empty.
+fn scan_command(c: &mut RedisClient) {
+    let Some((cursor, opts)) = parse_scan_args(c, 1, false) else { return; };
+
+    let now = timestamp().as_millis() as u64;
+    let (keys, next) = {
+        let db = c.db.clone().expect("db doesn't exist");
+        let db_r = db.read().unwrap();
+        let (batch, next) = db_r.dict.scan(cursor, opts.count);
+        let keys: Vec<String> = batch.into_iter()
+            .filter(|(key, _)| !db_r.expires.get(key).is_some_and(|when| *when <= now))
+            .filter(|(key, _)| !opts.pattern.as_ref().is_some_and(|p| !string_pattern_match(p, key)))
+            .filter(|(_, obj)| !opts.type_filter.as_ref().is_some_and(|t| t != type_name(&obj.read().unwrap())))
+            .map(|(key, _)| key.clone())
+            .collect();
+        (keys, next)
+    };
+
+    c.add_reply_str("*2\r\n");
+    c.add_reply_bulk_str(&next.to_string());
+    c.add_reply_str(&format!("*{}\r\n", keys.len()));
+    for key in keys {
+        c.add_reply_bulk_str(&key);
+    }
+}
+
+/// HSCAN key cursor [MATCH pattern] [COUNT count] [NOVALUES]
+///
+/// Like SCAN over the fields of a hash. The reply has the fields and their
+/// values, or only the fields with NOVALUES.
+fn hscan_command(c: &mut RedisClient) {
+    let Some((cursor, opts)) = parse_scan_args(c, 2, true) else { return; };
+    let Some(hash) = c.lookup_hash(&c.arg(1), false) else { return; };
+
+    let (entries, next) = match hash {
+        None => (Vec::new(), 0),
+        Some(hash) => {
+            let hash_r = hash.read().unwrap();
+            let (batch, next) = scan_batch(hash_r.hash().unwrap().iter(), cursor, opts.count);
+            let entries: Vec<(String, RedisObject)> = batch.into_iter()
+                .filter(|(field, _)| !opts.pattern.as_ref().is_some_and(|p| !string_pattern_match(p, field)))
+                .map(|(field, value)| (field.clone(), value.get_decoded()))
+                .collect();
+            (entries, next)
+        },
+    };
+
+    c.add_reply_str("*2\r\n");
+    c.add_reply_bulk_str(&next.to_string());
+    let per_entry = if opts.novalues { 1 } else { 2 };
+    c.add_reply_str(&format!("*{}\r\n", entries.len() * per_entry));
+    for (field, value) in entries {
+        c.add_reply_bulk_str(&field);
+        if !opts.novalues {
+            c.add_reply_bulk(Arc::new(RwLock::new(value)));
+        }
+    }
+}
+
 fn keys_command(c: &mut RedisClient) {
-    let arg_r = c.argv[1].read().unwrap();
-    let pattern = arg_r.as_key();
+    let pattern = c.argv[1].read().unwrap().as_key().to_string();
+    let pattern = &pattern[..];
     let mut keys: Vec<&str> = Vec::new();
-    if !pattern.eq("*") {
-        c.add_reply_str("-ERR only support '*' for now\r\n");
-        return;
-    }
 
+    // Collect the keys first: expiring them takes the db write lock.
     let db = c.db.clone().unwrap();
-    let db_r = db.read().unwrap();
-    let mut iter = db_r.dict.keys();
-    while let Some(key) = iter.next() {
-        if pattern.eq("*") || string_pattern_match(pattern, key) {
-            if c.expire_if_needed(key).is_none() {
-                keys.push(key);
-            }
+    let matching: Vec<String> = db.read().unwrap().dict.keys()
+        .filter(|key| pattern.eq("*") || string_pattern_match(pattern, key))
+        .cloned()
+        .collect();
+    for key in &matching {
+        if !c.expire_if_needed(key) {
+            keys.push(key);
         }
     }
     c.add_reply_str(&format!("*{}\r\n", keys.len()));
@@ -266,7 +852,7 @@ fn randomkey_command(c: &mut RedisClient) {
     let mut key: Option<String> = None;
     loop {
         key = c.get_random_key();
-        if key.is_none() || c.expire_if_needed(key.as_ref().unwrap()).is_none() {
+        if key.is_none() || !c.expire_if_needed(key.as_ref().unwrap()) {
             break;
         }
     }
@@ -301,14 +887,14 @@ fn rename_generic_command(c: &mut RedisClient, nx: bool) {
 
     match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), NO_KEY_ERR.clone()) {
         Some(obj) => {
-            c.delete_if_volatile(c.argv[2].read().unwrap().as_key());
-            if c.contains(c.argv[2].read().unwrap().as_key()) && nx {
+            if !c.set_key(c.argv[2].read().unwrap().as_key(), obj.clone(), nx) {
                 c.add_reply(C_ZERO.clone());
                 return;
             }
-            c.insert(c.argv[2].read().unwrap().as_key(), obj.clone());
-            c.remove(c.argv[1].read().unwrap().as_key());
+            c.delete_key(c.argv[1].read().unwrap().as_key());
             server_write().dirty += 1;
+            c.signal_modified_key(c.argv[1].read().unwrap().as_key());
+            c.signal_modified_key(c.argv[2].read().unwrap().as_key());
             match nx {
                 true => { c.add_reply(C_ONE.clone()); },
                 false => { c.add_reply(OK.clone()); },
@@ -322,77 +908,179 @@ fn dbsize_command(c: &mut RedisClient) {
     c.add_reply_str(&format!(":{}\r\n", c.len()));
 }
 
+/// The EXPIRE conditions: NX sets the expire only if the key has none, XX
+/// only if it has one, GT only if the new expire is later and LT only if
+/// it's earlier. A key without expire counts as one that never expires.
+/// With a condition an existing expire is replaced, a plain EXPIRE never
+/// overwrites it.
+#[derive(Default)]
+struct ExpireFlags {
+    nx: bool,
+    xx: bool,
+    gt: bool,
+    lt: bool,
+}
+impl ExpireFlags {
+    fn parse(args: &[Arc<RwLock<RedisObject>>]) -> Result<ExpireFlags, String> {
+        let mut flags = ExpireFlags::default();
+        for arg in args {
+            let arg = arg.read().unwrap().as_key().to_ascii_lowercase();
+            match &arg[..] {
+                "nx" => { flags.nx = true; },
+                "xx" => { flags.xx = true; },
+                "gt" => { flags.gt = true; },
+                "lt" => { flags.lt = true; },
+                _ => { return Err(errors::err(&format!("Unsupported option {}", arg))); },
+            }
+        }
+        if flags.nx && (flags.xx || flags.gt || flags.lt) {
+            return Err(errors::err("NX and XX, GT or LT options at the same time are not compatible"));
+        }
+        if flags.gt && flags.lt {
+            return Err(errors::err("GT and LT options at the same time are not compatible"));
+        }
+        Ok(flags)
+    }
+    fn any(&self) -> bool {
+        self.nx || self.xx || self.gt || self.lt
+    }
+    /// Whether the expire of a key can be set to `when`, given the one it
+    /// has now.
+    fn allow(&self, current: Option<u64>, when: i64) -> bool {
+        match current {
+            None => !self.xx && !self.gt,
+            Some(current) => !self.nx &&
+                (!self.gt || when > current as i64) &&
+                (!self.lt || when < current as i64),
+        }
+    }
+}
+
+/// EXPIRE key seconds [NX|XX|GT|LT]
 fn expire_command(c: &mut RedisClient) {
-    let mut seconds = 0i64;
-    match c.argv[2].read().unwrap().as_key().parse() {
-        Ok(secs) => { seconds = secs; },
+    expire_generic_command(c, timestamp().as_millis() as i64, 1000);
+}
+
+/// EXPIREAT key unix-time-seconds [NX|XX|GT|LT]
+fn expireat_command(c: &mut RedisClient) {
+    expire_generic_command(c, 0, 1000);
+}
+
+/// PEXPIRE key milliseconds [NX|XX|GT|LT]
+fn pexpire_command(c: &mut RedisClient) {
+    expire_generic_command(c, timestamp().as_millis() as i64, 1);
+}
+
+/// PEXPIREAT key unix-time-milliseconds [NX|XX|GT|LT]
+fn pexpireat_command(c: &mut RedisClient) {
+    expire_generic_command(c, 0, 1);
+}
+
+/// The expire commands set the deadline `basetime + argv[2] * unit`, both
+/// in milliseconds: the base is now for the relative forms and 0 for the
+/// absolute ones.
+fn expire_generic_command(c: &mut RedisClient, basetime: i64, unit: i64) {
+    let Some(when) = c.arg(2).parse::<i64>().ok()
+        .and_then(|v| v.checked_mul(unit))
+        .and_then(|v| v.checked_add(basetime)) else {
+        c.add_reply_error(&errors::err("value is not an integer or out of range"));
+        return;
+    };
+    let flags = match ExpireFlags::parse(&c.argv[3..]) {
+        Ok(flags) => flags,
         Err(e) => {
-            log(LogLevel::Warning, &format!("failed to parse seconds '{}': {}", c.argv[2].read().unwrap().as_key(), e));
+            c.add_reply_error(&e);
             return;
         },
-    }
+    };
     
-    if !c.contains(c.argv[1].read().unwrap().as_key()) {
+    let key = c.argv[1].read().unwrap().as_key().to_string();
+    c.expire_if_needed(&key);
+    if !c.contains(&key) {
         c.add_reply(C_ZERO.clone());
         return;
     }
 
-    if seconds < 0 {
-        if c.delete_key(c.argv[1].read().unwrap().as_key()).is_some() {
+    if !flags.allow(c.get_expire(&key), when) {
+        c.add_reply(C_ZERO.clone());
+        return;
+    }
+    if when <= timestamp().as_millis() as i64 {
+        // Same as a DEL, AOF included: replaying the EXPIRE later would
+        // not delete anything.
+        if c.delete_key(&key) {
             server_write().dirty += 1;
+            c.propagate_as(&["DEL", &key]);
         }
         c.add_reply(C_ONE.clone());
     } else {
-        let when = timestamp().as_secs() + seconds as u64;
-        if c.set_expire(c.argv[1].read().unwrap().as_key(), when) {
+        if flags.any() {
+            c.remove_expire(&key);
+        }
+        if c.set_expire(&key, when as u64) {
             c.add_reply(C_ONE.clone());
             server_write().dirty += 1;
+            c.signal_modified_key(&key);
         } else {
             c.add_reply(C_ZERO.clone());
         }
     }
 }
 
+/// TTL reports -2 for a missing (or just expired) key, -1 for a key without
+/// expire. Otherwise the time left is rounded up to the next second, so a
+/// key still readable never reports 0.
 fn ttl_command(c: &mut RedisClient) {
-    let mut ttl = -1;
-    match c.get_expire(c.argv[1].read().unwrap().as_key()) {
+    ttl_generic_command(c, false);
+}
+
+fn pttl_command(c: &mut RedisClient) {
+    ttl_generic_command(c, true);
+}
+
+/// The time left before the key expires, in seconds rounded up or in
+/// milliseconds. -2 if the key doesn't exist, -1 if it has no expire.
+fn ttl_generic_command(c: &mut RedisClient, ms: bool) {
+    let key = c.argv[1].read().unwrap().as_key().to_string();
+    if c.lookup_key_read(&key).is_none() {
+        c.add_reply_str(":-2\r\n");
+        return;
+    }
+    let ttl = match c.get_expire(&key) {
         Some(when) => {
-            let now = timestamp().as_secs();
-            if when >= now {
-                ttl = (when - now) as i32;
-            }
+            let ms_left = when.saturating_sub(timestamp().as_millis() as u64);
+            if ms { ms_left as i64 } else { ms_left.div_ceil(1000) as i64 }
         },
-        None => {},
-    }
+        None => -1,
+    };
     c.add_reply_str(&format!(":{}\r\n", ttl));
 }
 
-fn select_command(c: &mut RedisClient) {
-    let mut id = 0;
-    match c.argv[1].read().unwrap().as_key().parse() {
-        Ok(i) => { id = i; },
-        Err(e) => {
-            log(LogLevel::Warning, &format!("failed to parse DB index '{}': {}", c.argv[1].read().unwrap().as_key(), e));
-            return;
-        },
+/// PERSIST key: 1 if the key had an expire and now has none, 0 otherwise.
+fn persist_command(c: &mut RedisClient) {
+    let key = c.arg(1).to_string();
+    c.expire_if_needed(&key);
+    if c.get_expire(&key).is_none() {
+        c.add_reply(C_ZERO.clone());
+        return;
     }
+    c.remove_expire(&key);
+    server_write().dirty += 1;
+    c.signal_modified_key(&key);
+    c.add_reply(C_ONE.clone());
+}
 
+fn select_command(c: &mut RedisClient) {
+    let Some(id) = int_arg_or_reply::<i32>(c, 1) else { return; };
     if c.select_db(id) {
         c.add_reply(OK.clone());
     } else {
-        c.add_reply_str("-ERR invalid DB index\r\n");
+        c.add_reply_error(&errors::err("DB index is out of range"));
     }
 }
 
 fn move_command(c: &mut RedisClient) {
-    let mut dst_id = 0;
-    match c.argv[2].read().unwrap().as_key().parse() {
-        Ok(i) => { dst_id = i; },
-        Err(e) => {
-            log(LogLevel::Warning, &format!("failed to parse DB index '{}': {}", c.argv[2].read().unwrap().as_key(), e));
-            return;
-        },
-    }
+    let Some(dst_id) = int_arg_or_reply::<i32>(c, 2) else { return; };
 
     // Obtain source and target DB pointers
     let src_id = c.db.as_ref().unwrap().read().unwrap().id;
@@ -421,12 +1109,12 @@ fn move_command(c: &mut RedisClient) {
 
     // Try to add the element to the target DB
     c.select_db(dst_id);
-    if c.contains(c.argv[1].read().unwrap().as_key()) {
+    if !c.set_key(c.argv[1].read().unwrap().as_key(), obj.unwrap(), true) {
+        c.select_db(src_id);
         c.add_reply(C_ZERO.clone());
         return;
     }
-    c.delete_if_volatile(c.argv[1].read().unwrap().as_key());
-    c.insert(c.argv[1].read().unwrap().as_key(), obj.unwrap());
+    c.signal_modified_key(c.argv[1].read().unwrap().as_key());
 
     // OK! key moved, free the entry in the source DB
     c.select_db(src_id);
@@ -444,10 +1132,15 @@ fn flushdb_command(c: &mut RedisClient) {
 
 fn flushall_command(c: &mut RedisClient) {
     let removed = server_write().clear();
+    if server_read().flushall_saves {
+        // Old behaviour: persist the empty dataset right away. The save
+        // resets dirty, so account the removed keys only afterwards, this
+        // way the command still gets propagated to the AOF.
+        let file = server_read().db_filename.clone();
+        rdb_save(&file);
+    }
     server_write().dirty += removed;
     c.add_reply(OK.clone());
-    rdb_save(&server_read().db_filename);
-    server_write().dirty += 1;
 }
 
 // 
@@ -455,25 +1148,95 @@ fn flushall_command(c: &mut RedisClient) {
 // 
 
 fn get_command(c: &mut RedisClient) {
-    match get_generic_command(c) {
-        Ok(_) => {},
-        Err(e) => {
-            log(LogLevel::Warning, &e);
-        },
-    }
+    get_generic_command(c);
 }
-fn get_generic_command(c: &RedisClient) -> Result<(), String> {
+/// Reply with the string value of the key, nil if it's missing. Returns
+/// false if the key holds another type: WRONGTYPE was replied.
+fn get_generic_command(c: &RedisClient) -> bool {
     match c.lookup_key_read_or_reply(c.argv[1].read().unwrap().as_key(), NULL_BULK.clone()) {
-        None => Ok(()),
+        None => true,
         Some(obj) => {
             if obj.read().unwrap().is_string() {
                 c.add_reply_bulk(obj);
-                Ok(())
+                true
             } else {
-                c.add_reply(WRONG_TYPE_ERR.clone());
-                Err("WRONG TYPE ERROR".to_string())
+                c.add_reply_wrong_type();
+                false
+            }
+        },
+    }
+}
+
+/// GETEX key [EX seconds | PX milliseconds | EXAT unix-time-seconds |
+/// PXAT unix-time-milliseconds | PERSIST]
+///
+/// A deadline already in the past deletes the key, the value is still
+/// returned.
+fn getex_command(c: &mut RedisClient) {
+    // The new deadline in unix milliseconds, None for PERSIST
+    let mut deadline: Option<Option<u64>> = None;
+    let mut j = 2;
+    while j < c.argv.len() {
+        let opt = c.arg(j).to_ascii_lowercase();
+        if opt == "persist" && deadline.is_none() {
+            deadline = Some(None);
+            j += 1;
+            continue;
+        }
+        if !matches!(&opt[..], "ex" | "px" | "exat" | "pxat") || deadline.is_some() || j + 1 >= c.argv.len() {
+            c.add_reply(SYNTAX_ERR.clone());
+            return;
+        }
+        let Some(value) = int_arg_or_reply::<i64>(c, j + 1) else { return; };
+        if value <= 0 {
+            c.add_reply_error(&errors::err("invalid expire time in 'getex' command"));
+            return;
+        }
+        let now_ms = timestamp().as_millis() as i64;
+        let when_ms = match &opt[..] {
+            "ex" => value.checked_mul(1000).and_then(|ms| ms.checked_add(now_ms)),
+            "px" => value.checked_add(now_ms),
+            "exat" => value.checked_mul(1000),
+            _ => Some(value),
+        };
+        let Some(when_ms) = when_ms else {
+            c.add_reply_error(&errors::err("invalid expire time in 'getex' command"));
+            return;
+        };
+        deadline = Some(Some(when_ms as u64));
+        j += 2;
+    }
+
+    let key = c.arg(1).to_string();
+    let Some(obj) = c.lookup_key_read_or_reply(&key, NULL_BULK.clone()) else { return; };
+    if !obj.read().unwrap().is_string() {
+        c.add_reply_wrong_type();
+        return;
+    }
+    c.add_reply_bulk(obj);
+
+    match deadline {
+        None => {},
+        Some(None) => {
+            if c.get_expire(&key).is_some() {
+                c.remove_expire(&key);
+                server_write().dirty += 1;
+                c.signal_modified_key(&key);
             }
         },
+        Some(Some(when)) if when <= timestamp().as_millis() as u64 => {
+            c.delete_key(&key);
+            server_write().dirty += 1;
+            c.propagate_as(&["DEL", &key]);
+        },
+        Some(Some(when)) => {
+            c.remove_expire(&key);
+            c.set_expire(&key, when);
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            // A relative TTL must not restart when the AOF is replayed
+            c.propagate_as(&["PEXPIREAT", &key, &when.to_string()]);
+        },
     }
 }
 
@@ -481,38 +1244,30 @@ fn set_command(c: &mut RedisClient) {
     set_generic_command(c, false);
 }
 fn set_generic_command(c: &mut RedisClient, nx: bool) {
-    if nx {
-        c.delete_if_volatile(c.argv[1].read().unwrap().as_key());
-    }
-
-    if c.contains(c.argv[1].read().unwrap().as_key()) {
-        if nx {
-            c.add_reply(C_ZERO.clone());
-            return;
-        }
+    if !c.set_key(c.argv[1].read().unwrap().as_key(), c.argv[2].clone(), nx) {
+        c.add_reply(C_ZERO.clone());
+        return;
     }
-    c.insert(c.argv[1].read().unwrap().as_key(), c.argv[2].clone());
 
     server_write().dirty += 1;
-    c.remove_expire(c.argv[1].read().unwrap().as_key());
+    c.signal_modified_key(c.argv[1].read().unwrap().as_key());
     match nx {
         true => { c.add_reply(C_ONE.clone()); }
         false => { c.add_reply(OK.clone()); }
     }
 }
 
+/// GETSET key value
+/// The old value is replied before the new one replaces it, with no expire.
+/// A key of another type is left untouched.
 fn getset_command(c: &mut RedisClient) {
-    match get_generic_command(c) {
-        Ok(_) => {},
-        Err(e) => {
-            log(LogLevel::Warning, &e);
-            return;
-        }
+    if !get_generic_command(c) {
+        return;
     }
 
-    c.insert(c.argv[1].read().unwrap().as_key(), c.argv[2].clone());
+    c.set_key(c.argv[1].read().unwrap().as_key(), c.argv[2].clone(), false);
     server_write().dirty += 1;
-    c.remove_expire(c.argv[1].read().unwrap().as_key());
+    c.signal_modified_key(c.argv[1].read().unwrap().as_key());
 }
 
 fn mget_command(c: &mut RedisClient) {
@@ -535,13 +1290,44 @@ fn setnx_command(c: &mut RedisClient) {
     set_generic_command(c, true);
 }
 
+/// SETEX key seconds value
+fn setex_command(c: &mut RedisClient) {
+    setex_generic_command(c, "setex", 1000);
+}
+
+/// PSETEX key milliseconds value
+fn psetex_command(c: &mut RedisClient) {
+    setex_generic_command(c, "psetex", 1);
+}
+
+/// Set the value and an expire `argv[2]` times `unit` milliseconds from now,
+/// as a single step.
+fn setex_generic_command(c: &mut RedisClient, name: &str, unit: u64) {
+    let Some(ttl) = int_arg_or_reply::<i64>(c, 2) else { return; };
+    let when = (ttl > 0).then(|| (ttl as u64).checked_mul(unit))
+        .flatten()
+        .and_then(|ms| ms.checked_add(timestamp().as_millis() as u64));
+    let Some(when) = when else {
+        c.add_reply_error(&errors::err(&format!("invalid expire time in '{}' command", name)));
+        return;
+    };
+
+    let key = c.arg(1).to_string();
+    c.set_key(&key, c.argv[3].clone(), false);
+    c.set_expire(&key, when);
+    server_write().dirty += 1;
+    c.signal_modified_key(&key);
+    c.add_reply(OK.clone());
+}
+
 fn mset_command(c: &mut RedisClient) {
     mset_generic_command(c, false);
 }
 
 fn mset_generic_command(c: &mut RedisClient, nx: bool) {
+    // A key without its value
     if c.argv.len() % 2 == 0 {
-        c.add_reply_str("-ERR wrong number of arguments for MSET\r\n");
+        c.add_reply_error(&errors::wrong_arity(if nx { "msetnx" } else { "mset" }, ArityError::TooFew));
         return;
     }
 
@@ -561,9 +1347,12 @@ fn mset_generic_command(c: &mut RedisClient, nx: bool) {
     }
 
     for i in (1..c.argv.len()).step_by(2) {
-        c.argv[i + 1] = try_object_encoding(c.argv[i + 1].clone());
-        c.insert(c.argv[i].read().unwrap().as_key(), c.argv[i + 1].clone());
-        c.remove_expire(c.argv[i].read().unwrap().as_key());
+        // Moved out of argv for a moment: a shared object isn't encoded
+        let key = c.argv[i].clone();
+        let value = std::mem::replace(&mut c.argv[i + 1], key);
+        c.argv[i + 1] = try_object_encoding(value);
+        c.set_key(c.argv[i].read().unwrap().as_key(), c.argv[i + 1].clone(), false);
+        c.signal_modified_key(c.argv[i].read().unwrap().as_key());
     }
     server_write().dirty += (c.argv.len() as u128 - 1) / 2;
     match nx {
@@ -619,8 +1408,8 @@ fn incr_decr_command(c: &mut RedisClient, incr: i128) {
                         StringStorageType::String(s) => {
                             match s.parse() {
                                 Ok(v) => { value = v; },
-                                Err(e) => {
-                                    log(LogLevel::Warning, &e.to_string());
+                                Err(_) => {
+                                    c.add_reply_error(&errors::err("value is not an integer or out of range"));
                                     return;
                                 },
                             }
@@ -628,86 +1417,186 @@ fn incr_decr_command(c: &mut RedisClient, incr: i128) {
                         StringStorageType::Integer(n) => { value = *n as i128; },
                     }
                 },
-                None => {},
+                None => {
+                    c.add_reply_wrong_type();
+                    return;
+                },
             }
         },
     }
 
     value += incr;
-    let obj = RedisObject::String { ptr: StringStorageType::String(value.to_string()) };
+    let obj = RedisObject::String { ptr: StringStorageType::String(value.to_string()) }.tracked();
     let encoded_obj = try_object_encoding(Arc::new(RwLock::new(obj)));
-    c.insert(c.argv[1].read().unwrap().as_key(), encoded_obj.clone());
-
-    c.remove_expire(c.argv[1].read().unwrap().as_key());
+    c.set_key(c.argv[1].read().unwrap().as_key(), encoded_obj.clone(), false);
     server_write().dirty += 1;
+    c.signal_modified_key(c.argv[1].read().unwrap().as_key());
     c.add_reply(COLON.clone());
     c.add_reply(encoded_obj);
     c.add_reply(CRLF.clone());
 }
 
-// 
-// list
-// 
-
-enum ListWhere {
-    Head,
-    Tail,
-}
-
-fn rpush_command(c: &mut RedisClient) {
-    push_generic_command(c, ListWhere::Tail);
+/// APPEND key value
+/// Like SET if the key is missing. Replies with the new length.
+fn append_command(c: &mut RedisClient) {
+    let key = c.arg(1).to_string();
+    let value = match c.lookup_key_write(&key) {
+        None => c.arg(2).to_string(),
+        Some(obj) => {
+            let obj_r = obj.read().unwrap();
+            let Some(s) = obj_r.string() else {
+                drop(obj_r);
+                c.add_reply_wrong_type();
+                return;
+            };
+            let mut value = String::from_utf8_lossy(&s.as_bytes()).into_owned();
+            value.push_str(&c.arg(2));
+            value
+        },
+    };
+    // A numeric result is stored integer encoded, like SET does
+    let len = value.len();
+    let obj = try_object_encoding(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(value) }.tracked())));
+    c.set_key(&key, obj, false);
+    server_write().dirty += 1;
+    c.signal_modified_key(&key);
+    c.add_reply_u64(len as u64);
 }
 
-fn lpush_command(c: &mut RedisClient) {
-    push_generic_command(c, ListWhere::Head);
-}
+/// SETRANGE key offset value
+/// Overwrite the string from offset on with value, padding it with zero
+/// bytes if it's shorter than offset. Replies with the new length. Values
+/// are kept as UTF-8: an overwrite splitting a multi byte character is
+/// refused and the string left as it was.
+fn setrange_command(c: &mut RedisClient) {
+    let Some(offset) = int_arg_or_reply::<i64>(c, 2) else { return; };
+    if offset < 0 {
+        c.add_reply_error(&errors::err("offset is out of range"));
+        return;
+    }
+    let offset = offset as usize;
+    let key = c.arg(1).to_string();
+    let patch = c.arg(3).as_bytes().to_vec();
+    // Nothing to write: the key isn't created, nor changed
+    if patch.is_empty() {
+        let Some(len) = c.with_string(&key, C_ZERO.clone(), |s| s.as_bytes().len()) else { return; };
+        c.add_reply_u64(len as u64);
+        return;
+    }
+    if offset.saturating_add(patch.len()) > MAX_BULK_LEN {
+        c.add_reply_error(&errors::err("string exceeds maximum allowed size (512MB)"));
+        return;
+    }
 
-fn push_generic_command(c: &mut RedisClient, place: ListWhere) {
-    let mut len = 0usize;
-    match c.lookup_key_write(c.argv[1].read().unwrap().as_key()) {
+    let len = c.with_string_mut(&key, |bytes| {
+        if bytes.len() < offset + patch.len() {
+            bytes.resize(offset + patch.len(), 0);
+        }
+        bytes[offset..offset + patch.len()].copy_from_slice(&patch);
+        bytes.len()
+    });
+    if let Some(len) = len {
+        c.add_reply_u64(len as u64);
+    }
+}
+
+/// STRLEN key
+fn strlen_command(c: &mut RedisClient) {
+    let key = c.arg(1).to_string();
+    let Some(obj) = c.lookup_key_read_or_reply(&key, C_ZERO.clone()) else { return; };
+    let len = match obj.read().unwrap().string() {
+        Some(s) => s.as_bytes().len(),
         None => {
-            match handle_clients_waiting_list_push(c, c.argv[1].read().unwrap().as_key(), c.argv[2].clone()) {
-                ListWaiting::Waiting => {
-                    c.add_reply(C_ONE.clone());
-                    return;
-                },
-                ListWaiting::NoWait => {
-                    let mut l = ListStorageType::LinkedList(LinkedList::new());
-                    match place {
-                        ListWhere::Head => { l.push_front(c.argv[2].clone()); },
-                        ListWhere::Tail => { l.push_back(c.argv[2].clone()); },
-                    }
-                    len = l.len();
-                    c.insert(c.argv[1].read().unwrap().as_key(), Arc::new(RwLock::new(RedisObject::List { l })));
-                },
-            }
+            c.add_reply_wrong_type();
+            return;
         },
-        Some(lobj) => {
-            match lobj.write().unwrap().list_mut() {
-                Some(l_storage) => {
-                    match handle_clients_waiting_list_push(c, c.argv[1].read().unwrap().as_key(), c.argv[2].clone()) {
-                        ListWaiting::Waiting => {
-                            c.add_reply(C_ONE.clone());
-                            return;
-                        },
-                        ListWaiting::NoWait => {
-                            match place {
-                                ListWhere::Head => { l_storage.push_front(c.argv[2].clone()); },
-                                ListWhere::Tail => { l_storage.push_back(c.argv[2].clone()); },
-                            }
-                            len = l_storage.len();
-                        },
-                    }
-                },
-                None => {
-                    c.add_reply(WRONG_TYPE_ERR.clone());
-                    return;
-                },
-            }
+    };
+    c.add_reply_u64(len as u64);
+}
+
+/// GETRANGE key start end
+/// The bytes from start to end included, negative indexes count from the
+/// end of the string like in LRANGE. SUBSTR is the legacy name. Values
+/// are kept as UTF-8: a range splitting a multi byte character is refused.
+fn getrange_command(c: &mut RedisClient) {
+    let Some(mut start) = int_arg_or_reply::<i64>(c, 2) else { return; };
+    let Some(mut end) = int_arg_or_reply::<i64>(c, 3) else { return; };
+    let key = c.arg(1).to_string();
+    let Some(obj) = c.lookup_key_read_or_reply(&key, EMPTY_BULK.clone()) else { return; };
+    let bytes = match obj.read().unwrap().string() {
+        Some(s) => s.as_bytes().into_owned(),
+        None => {
+            c.add_reply_wrong_type();
+            return;
         },
+    };
+    let len = bytes.len() as i64;
+
+    // convert negative indexes
+    if start < 0 { start += len; }
+    if end < 0 { end += len; }
+    if start < 0 { start = 0; }
+    if end < 0 { end = 0; }
+
+    // indexes sanity checks
+    if start > end || start >= len {
+        // Out of range start or start > end result in an empty string
+        c.add_reply(EMPTY_BULK.clone());
+        return;
+    }
+    if end >= len {
+        end = len - 1;
+    }
+    let range = &bytes[start as usize..=end as usize];
+    if std::str::from_utf8(range).is_err() {
+        c.add_reply_error(&errors::err("string values must be valid UTF-8"));
+        return;
+    }
+    c.add_reply_value(&Value::Bulk(Some(range.to_vec())));
+}
+
+// 
+// list
+// 
+
+enum ListWhere {
+    Head,
+    Tail,
+}
+
+fn rpush_command(c: &mut RedisClient) {
+    push_generic_command(c, ListWhere::Tail);
+}
+
+fn lpush_command(c: &mut RedisClient) {
+    push_generic_command(c, ListWhere::Head);
+}
+
+fn push_generic_command(c: &mut RedisClient, place: ListWhere) {
+    let key = c.arg(1).to_string();
+    // Clients can only be waiting on a missing key. The element goes
+    // straight to the one waiting the longest, if any.
+    let Some(list) = c.lookup_list(&key, true) else { return; };
+    if list.is_none() {
+        if let ListWaiting::Waiting = handle_clients_waiting_list_push(c, &key, c.argv[2].clone()) {
+            c.add_reply(C_ONE.clone());
+            return;
+        }
+    }
+    drop(list);
+
+    let pushed = c.with_list_or_create(&key, |l| {
+        match place {
+            ListWhere::Head => { l.push_front(c.argv[2].clone()); },
+            ListWhere::Tail => { l.push_back(c.argv[2].clone()); },
+        }
+        l.len()
+    });
+    if let Some(len) = pushed {
+        server_write().dirty += 1;
+        c.signal_modified_key(&key);
+        c.add_reply_str(&format!(":{len}\r\n"));
     }
-    server_write().dirty += 1;
-    c.add_reply_str(&format!(":{len}\r\n"));
 }
 
 enum ListWaiting {
@@ -725,222 +1614,225 @@ enum ListWaiting {
 /// If the function returns `Waiting` there was a client waiting for a list push
 /// against this key, the element was passed to this client thus it's not
 /// needed to actually add it to the list and the caller should return asap.
+///
+/// It must be called with no lock held on the db or the list: the waiting
+/// client is unblocked, which updates the db.
 fn handle_clients_waiting_list_push(c: &RedisClient, key: &str, value: Arc<RwLock<RedisObject>>) -> ListWaiting {
-    match c.lookup_blocking_key(key) {
-        None => { ListWaiting::NoWait },
-        Some(l) => {
-            let client = l.front().unwrap().write().unwrap();
-            client.add_reply_str("*2\r\n");
-            client.add_reply_bulk(c.argv[1].clone());
-            client.add_reply_bulk(value);
-            client.unblock_client_waiting_data();
-            ListWaiting::Waiting
-        },
+    for id in c.clients_blocked_on(key) {
+        let waiting = clients_read().iter()
+            .find(|w| w.try_read().map(|w| w.id() == id).unwrap_or(false))
+            .cloned();
+        let Some(waiting) = waiting else { continue; };
+        let Ok(mut waiting) = waiting.try_write() else { continue; };
+        waiting.add_reply_str("*2\r\n");
+        waiting.add_reply_bulk_str(key);
+        waiting.add_reply_bulk(value);
+        waiting.unblock_client_waiting_data();
+        return ListWaiting::Waiting;
     }
+    ListWaiting::NoWait
 }
 
-fn llen_command(c: &mut RedisClient) {
-    match c.lookup_key_read_or_reply(c.argv[1].read().unwrap().as_key(), C_ZERO.clone()) {
-        Some(v) => {
-            match v.read().unwrap().list() {
-                Some(l_storage) => { c.add_reply_u64(l_storage.len() as u64); },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
-    }
+fn blpop_command(c: &mut RedisClient) {
+    blocking_pop_generic_command(c, ListWhere::Head);
 }
 
-fn lrange_command(c: &mut RedisClient) {
-    let mut start = 0;
-    let mut end = 0;
-    match (c.argv[2].read().unwrap().as_key().parse(), c.argv[3].read().unwrap().as_key().parse()) {
-        (Ok(s), Ok(e)) => {
-            start = s;
-            end = e;
+fn brpop_command(c: &mut RedisClient) {
+    blocking_pop_generic_command(c, ListWhere::Tail);
+}
+
+/// BLPOP key [key ...] timeout
+/// BRPOP key [key ...] timeout
+///
+/// Pop from the first non-empty list among the keys, or block until an
+/// element is pushed to one of them. The timeout is in seconds, 0 to block
+/// forever. Inside MULTI there is no blocking: the reply is nil right away.
+fn blocking_pop_generic_command(c: &mut RedisClient, place: ListWhere) {
+    let timeout = match c.arg(c.argv.len() - 1).parse::<f64>() {
+        Ok(t) if t < 0f64 => {
+            c.add_reply_error(&errors::err("timeout is negative"));
+            return;
         },
+        Ok(t) if t.is_finite() => t,
         _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}', '{}'", c.argv[2].read().unwrap().as_key(), c.argv[3].read().unwrap().as_key()));
+            c.add_reply_error(&errors::err("timeout is not a float or out of range"));
             return;
+        },
+    };
+
+    let keys: Vec<String> = (1..c.argv.len() - 1).map(|i| c.arg(i).to_string()).collect();
+    for key in &keys {
+        let Some(list) = c.lookup_list(key, true) else { return; };
+        let Some(list) = list else { continue; };
+        // Lists in the keyspace are never empty
+        let ele = {
+            let mut list_w = list.write().unwrap();
+            let l = list_w.list_mut().unwrap();
+            let ele = match place {
+                ListWhere::Head => l.pop_front(),
+                ListWhere::Tail => l.pop_back(),
+            };
+            (ele, l.len() == 0)
+        };
+        drop(list);
+        let (Some(ele), emptied) = ele else { continue; };
+        if emptied {
+            c.delete_key(key);
         }
+        server_write().dirty += 1;
+        c.signal_modified_key(key);
+        // Replayed, a blocking pop could block: it's propagated as a pop
+        c.propagate_as(&[match place { ListWhere::Head => "LPOP", ListWhere::Tail => "RPOP" }, key]);
+        c.add_reply_str("*2\r\n");
+        c.add_reply_bulk_str(key);
+        c.add_reply_bulk(Arc::new(RwLock::new(ele)));
+        return;
     }
 
-    match c.lookup_key_read_or_reply(c.argv[1].read().unwrap().as_key(), NULL_MULTI_BULK.clone()) {
-        Some(v) => {
-            match v.read().unwrap().list() {
-                Some(l_storage) => {
-                    let len = l_storage.len();
-                    // convert negative indexes
-                    if start < 0 { start += len as i32; }
-                    if end < 0 { end += len as i32; }
-                    if start < 0 { start = 0; }
-                    if end < 0 { end = 0; }
-
-                    // indexes sanity checks
-                    if start > end || start >= len as i32 {
-                        // Out of range start or start > end result in empty list
-                        c.add_reply(EMPTY_MULTI_BULK.clone());
-                        return;
-                    }
-                    if end >= len as i32 {
-                        end = len as i32 - 1;
-                    }
-                    let range_len = end - start + 1;
+    if c.is_multi() {
+        c.add_reply(NULL_MULTI_BULK.clone());
+        return;
+    }
+    let timeout = if timeout == 0f64 { 0 } else { (timestamp().as_secs_f64() * 1000f64 + timeout * 1000f64) as u64 };
+    c.block_for_keys(&keys, timeout);
+}
 
-                    // Return the result in form of a multi-bulk reply
-                    c.add_reply_str(&format!("*{}\r\n", range_len));
-                    let items = l_storage.range(start, end);
-                    for e in items {
-                        c.add_reply_bulk(Arc::new(RwLock::new(e)));
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
+fn llen_command(c: &mut RedisClient) {
+    if let Some(len) = c.with_list(&c.arg(1), C_ZERO.clone(), |l| l.len()) {
+        c.add_reply_u64(len as u64);
     }
 }
 
-fn ltrim_command(c: &mut RedisClient) {
-    let mut start = 0;
-    let mut end = 0;
-    match (c.argv[2].read().unwrap().as_key().parse(), c.argv[3].read().unwrap().as_key().parse()) {
-        (Ok(s), Ok(e)) => {
-            start = s;
-            end = e;
+/// Parse the integer argument at `idx`, replying with an error if it isn't
+/// one or doesn't fit the target type. Out of range indexes are refused here,
+/// before any work is done on the value.
+fn int_arg_or_reply<T: FromStr>(c: &RedisClient, idx: usize) -> Option<T> {
+    match c.argv[idx].read().unwrap().as_key().parse() {
+        Ok(n) => Some(n),
+        Err(_) => {
+            c.add_reply_error(&errors::err("value is not an integer or out of range"));
+            None
         },
-        _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}', '{}'", c.argv[2].read().unwrap().as_key(), c.argv[3].read().unwrap().as_key()));
+    }
+}
+
+fn lrange_command(c: &mut RedisClient) {
+    let Some(mut start) = int_arg_or_reply::<i32>(c, 2) else { return; };
+    let Some(mut end) = int_arg_or_reply::<i32>(c, 3) else { return; };
+
+    c.with_list(&c.arg(1), EMPTY_MULTI_BULK.clone(), |l| {
+        let len = l.len();
+        // convert negative indexes
+        if start < 0 { start += len as i32; }
+        if end < 0 { end += len as i32; }
+        if start < 0 { start = 0; }
+        if end < 0 { end = 0; }
+
+        // indexes sanity checks
+        if start > end || start >= len as i32 {
+            // Out of range start or start > end result in empty list
+            c.add_reply(EMPTY_MULTI_BULK.clone());
             return;
         }
-    }
+        if end >= len as i32 {
+            end = len as i32 - 1;
+        }
+        let range_len = end - start + 1;
 
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), OK.clone()) {
-        Some(v) => {
-            match v.write().unwrap().list_mut() {
-                Some(l_storage) => {
-                    let len = l_storage.len();
-                    let mut ltrim = 0usize;
-                    let mut rtrim = 0usize;
-                    // convert negative indexes
-                    if start < 0 { start += len as i32; }
-                    if end < 0 { end += len as i32; }
-                    if start < 0 { start = 0; }
-                    if end < 0 { end = 0; }
-
-                    // indexes sanity checks
-                    if start > end || start >= len as i32 {
-                        ltrim = len;
-                        rtrim = 0;
-                    } else {
-                        if end > len as i32 { end = len as i32; }
-                        ltrim = start as usize;
-                        rtrim = len - (end as usize);
-                    }
+        // Return the result in form of a multi-bulk reply
+        c.add_reply_str(&format!("*{}\r\n", range_len));
+        for e in l.range(start, end) {
+            c.add_reply_bulk(Arc::new(RwLock::new(e)));
+        }
+    });
+}
 
-                    // Remove list elements to perform the trim
-                    l_storage.retain_range(ltrim as i32, rtrim as i32);
-                    server_write().dirty += 1;
-                    c.add_reply(OK.clone());
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
+fn ltrim_command(c: &mut RedisClient) {
+    let Some(mut start) = int_arg_or_reply::<i32>(c, 2) else { return; };
+    let Some(mut end) = int_arg_or_reply::<i32>(c, 3) else { return; };
+
+    let key = c.arg(1).to_string();
+    let trimmed = c.with_list_mut(&key, OK.clone(), |l| {
+        let len = l.len();
+        // convert negative indexes
+        if start < 0 { start += len as i32; }
+        if end < 0 { end += len as i32; }
+        if start < 0 { start = 0; }
+        if end < 0 { end = 0; }
+
+        // indexes sanity checks
+        let (ltrim, rtrim) = if start > end || start >= len as i32 {
+            (len, 0)
+        } else {
+            if end >= len as i32 { end = len as i32 - 1; }
+            (start as usize, len - (end as usize) - 1)
+        };
+
+        // Remove list elements to perform the trim
+        l.retain_range(ltrim as i32, rtrim as i32);
+    });
+    if trimmed.is_some() {
+        server_write().dirty += 1;
+        c.signal_modified_key(&key);
+        c.add_reply(OK.clone());
     }
 }
 
 fn lindex_command(c: &mut RedisClient) {
-    let mut index = 0;
-    match c.argv[2].read().unwrap().as_key().parse() {
-        Ok(i) => { index = i; },
-        _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}'", c.argv[2].read().unwrap().as_key()));
-            return;
-        }
-    }
+    let Some(mut index) = int_arg_or_reply::<i32>(c, 2) else { return; };
 
-    match c.lookup_key_read_or_reply(c.argv[1].read().unwrap().as_key(), NULL_BULK.clone()) {
-        Some(v) => {
-            match v.read().unwrap().list() {
-                Some(l_storage) => {
-                    if index < 0 {
-                        index += l_storage.len() as i32;
-                    }
-                    match l_storage.index(index) {
-                        Some(e) => { c.add_reply_bulk(Arc::new(RwLock::new(e))); },
-                        None => { c.add_reply(NULL_BULK.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
+    let ele = c.with_list(&c.arg(1), NULL_BULK.clone(), |l| {
+        if index < 0 {
+            index += l.len() as i32;
+        }
+        l.index(index)
+    });
+    match ele {
+        Some(Some(e)) => { c.add_reply_bulk(Arc::new(RwLock::new(e))); },
+        Some(None) => { c.add_reply(NULL_BULK.clone()); },
         None => {},
     }
 }
 
 fn lset_command(c: &mut RedisClient) {
-    let mut index = 0;
-    match c.argv[2].read().unwrap().as_key().parse() {
-        Ok(i) => { index = i; },
-        _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}'", c.argv[2].read().unwrap().as_key()));
-            return;
-        }
-    }
+    let Some(mut index) = int_arg_or_reply::<i32>(c, 2) else { return; };
 
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), NO_KEY_ERR.clone()) {
-        Some(v) => {
-            match v.write().unwrap().list_mut() {
-                Some(l_storage) => {
-                    // TODO: range checking more strictly
-                    if index < 0 {
-                        index += l_storage.len() as i32;
-                    }
-                    match l_storage.set(index, c.argv[3].clone()) {
-                        true => {
-                            server_write().dirty += 1;
-                            c.add_reply(OK.clone());
-                        },
-                        false => { c.add_reply(OUT_OF_RANGE_ERR.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
+    let key = c.arg(1).to_string();
+    let set = c.with_list_mut(&key, NO_KEY_ERR.clone(), |l| {
+        // TODO: range checking more strictly
+        if index < 0 {
+            index += l.len() as i32;
+        }
+        l.set(index, c.argv[3].clone())
+    });
+    match set {
+        Some(true) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply(OK.clone());
         },
+        Some(false) => { c.add_reply(OUT_OF_RANGE_ERR.clone()); },
         None => {},
     }
 }
 
 fn lrem_command(c: &mut RedisClient) {
-    let mut to_remove = 0;
-    match c.argv[2].read().unwrap().as_key().parse() {
-        Ok(i) => { to_remove = i; },
-        _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}'", c.argv[2].read().unwrap().as_key()));
-            return;
-        }
-    }
+    let Some(to_remove) = int_arg_or_reply::<i32>(c, 2) else { return; };
 
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), C_ZERO.clone()) {
-        Some(v) => {
-            match v.write().unwrap().list_mut() {
-                Some(l_storage) => {
-                    let mut from_tail = false;
-                    if to_remove < 0 {
-                        to_remove = -to_remove;
-                        from_tail = true;
-                    }
-                    let removed = match from_tail {
-                        false => { l_storage.remove_head(to_remove, c.argv[3].clone()) },
-                        true => { l_storage.remove_tail(to_remove, c.argv[3].clone()) },
-                    };
-                    c.add_reply_str(&format!(":{}\r\n", removed));
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
+    let key = c.arg(1).to_string();
+    let removed = c.with_list_mut(&key, C_ZERO.clone(), |l| {
+        if to_remove < 0 {
+            l.remove_tail(-to_remove, c.argv[3].clone())
+        } else {
+            l.remove_head(to_remove, c.argv[3].clone())
+        }
+    });
+    if let Some(removed) = removed {
+        if removed > 0 {
+            c.signal_modified_key(&key);
+            server_write().dirty += removed as u128;
+        }
+        c.add_reply_str(&format!(":{}\r\n", removed));
     }
 }
 
@@ -953,25 +1845,20 @@ fn rpop_command(c: &mut RedisClient) {
 }
 
 fn pop_generic_command(c: &mut RedisClient, place: ListWhere) {
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), NULL_BULK.clone()) {
-        Some(v) => {
-            match v.write().unwrap().list_mut() {
-                Some(l_storage) => {
-                    let ele = match place {
-                        ListWhere::Head => { l_storage.pop_front() },
-                        ListWhere::Tail => { l_storage.pop_back() },
-                    };
-                    match ele {
-                        Some(v) => {
-                            c.add_reply_bulk(Arc::new(RwLock::new(v)));
-                            server_write().dirty += 1;
-                        },
-                        None => { c.add_reply(NULL_BULK.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
+    let key = c.arg(1).to_string();
+    let ele = c.with_list_mut(&key, NULL_BULK.clone(), |l| {
+        match place {
+            ListWhere::Head => { l.pop_front() },
+            ListWhere::Tail => { l.pop_back() },
+        }
+    });
+    match ele {
+        Some(Some(v)) => {
+            c.add_reply_bulk(Arc::new(RwLock::new(v)));
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
         },
+        Some(None) => { c.add_reply(NULL_BULK.clone()); },
         None => {},
     }
 }
@@ -991,62 +1878,34 @@ fn pop_generic_command(c: &mut RedisClient, place: ListWhere) {
 /// since the element is not just returned but pushed against another list
 /// as well. This command was originally proposed by Ezra Zygmuntowicz.
 fn rpoplpush_command(c: &mut RedisClient) {
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), NULL_BULK.clone()) {
-        Some(v) => {
-            match v.write().unwrap().list_mut() {
-                Some(l_storage) => {
-                    match l_storage.pop_back() {
-                        Some(ele) => {
-                            // element type of destination list isn't correct
-                            let mut obj: Option<Arc<RwLock<RedisObject>>> = None;
-                            match c.lookup_key_write(c.argv[2].read().unwrap().as_key()) {
-                                Some(d_obj) => {
-                                    if d_obj.read().unwrap().is_list() {
-                                        obj = Some(d_obj.clone());
-                                    } else {
-                                        c.add_reply(WRONG_TYPE_ERR.clone());
-                                        return;
-                                    }
-                                },
-                                None => {},
-                            }
-
-                            // Add the element to the target list (unless it's directly
-                            // passed to some BLPOP-ing client
-                            match handle_clients_waiting_list_push(c, c.argv[2].read().unwrap().as_key(), Arc::new(RwLock::new(ele.clone()))) {
-                                ListWaiting::NoWait => {
-                                    match obj {
-                                        None => {
-                                            // Create the list if the key does not exist
-                                            let mut new_l = ListStorageType::LinkedList(LinkedList::new());
-                                            new_l.push_front(Arc::new(RwLock::new(ele.clone())));
-                                            c.insert(c.argv[2].read().unwrap().as_key(), Arc::new(RwLock::new(RedisObject::List { l: new_l })));
-                                        },
-                                        Some(v) => {
-                                            match v.write().unwrap().list_mut() {
-                                                Some(l_storage) => {
-                                                    l_storage.push_front(Arc::new(RwLock::new(ele.clone())));
-                                                },
-                                                None => { /* impossible */ },
-                                            }
-                                        },
-                                    }
-                                },
-                                ListWaiting::Waiting => {},
-                            }
-
-                            // Send the element to the client as reply as well
-                            server_write().dirty += 1;
-                            c.add_reply_bulk(Arc::new(RwLock::new(ele.clone())));
-                        },
-                        None => { c.add_reply(NULL_BULK.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
+    let src = c.arg(1).to_string();
+    let dst = c.arg(2).to_string();
+    let Some(len) = c.with_list(&src, NULL_BULK.clone(), |l| l.len()) else { return; };
+    if len == 0 {
+        c.add_reply(NULL_BULK.clone());
+        return;
     }
+    // Check the destination type before popping, not to lose the element
+    let Some(dst_list) = c.lookup_list(&dst, true) else { return; };
+    let dst_exists = dst_list.is_some();
+    drop(dst_list);
+    let Some(Some(ele)) = c.with_list_mut(&src, NULL_BULK.clone(), |l| l.pop_back()) else { return; };
+
+    // Add the element to the target list (unless it's directly
+    // passed to some BLPOP-ing client
+    let ele = Arc::new(RwLock::new(ele));
+    if dst_exists || matches!(handle_clients_waiting_list_push(c, &dst, ele.clone()), ListWaiting::NoWait) {
+        c.with_list_or_create(&dst, |l| l.push_front(ele.clone()));
+    } else {
+        // The element left the dataset with the waiting client
+        c.propagate_as(&["RPOP", &src]);
+    }
+
+    // Send the element to the client as reply as well
+    server_write().dirty += 1;
+    c.signal_modified_key(&src);
+    c.signal_modified_key(&dst);
+    c.add_reply_bulk(ele);
 }
 
 // 
@@ -1054,167 +1913,77 @@ fn rpoplpush_command(c: &mut RedisClient) {
 // 
 
 fn sadd_command(c: &mut RedisClient) {
-    let mut set: Option<Arc<RwLock<RedisObject>>> = None;
-    let arg_r = c.argv[1].read().unwrap();
-    let key = arg_r.as_key();
-    match c.lookup_key_write(key) {
-        Some(v) => {
-            if !v.read().unwrap().is_set() {
-                c.add_reply(WRONG_TYPE_ERR.clone());
-                return;
-            }
-            set = Some(v);
-        },
-        None => {
-            let new_set = Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(HashSet::new()) }));
-            c.insert(key, new_set.clone());
-            set = Some(new_set);
-        },
-    }
-
-    match set.unwrap().write().unwrap().set_mut() {
-        Some(s_storage) => {
-            if s_storage.insert(c.argv[2].clone()) {
-                server_write().dirty += 1;
-                c.add_reply(C_ONE.clone());
-            } else {
-                c.add_reply(C_ZERO.clone());
-            }
+    let key = c.arg(1).to_string();
+    match c.with_set_or_create(&key, |s| s.insert(c.argv[2].clone())) {
+        Some(true) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply(C_ONE.clone());
         },
-        None => { assert!(false, "impossible code"); },
+        Some(false) => { c.add_reply(C_ZERO.clone()); },
+        None => {},
     }
 }
 
 fn srem_command(c: &mut RedisClient) {
-    let arg_r = c.argv[1].read().unwrap();
-    let key = arg_r.as_key();
-    match c.lookup_key_write_or_reply(key, C_ZERO.clone()) {
-        Some(obj) => {
-            match obj.write().unwrap().set_mut() {
-                Some(s_storage) => {
-                    if s_storage.remove(c.argv[2].clone()) {
-                        server_write().dirty += 1;
-                        c.add_reply(C_ONE.clone());
-                    } else {
-                        c.add_reply(C_ZERO.clone());
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
+    let key = c.arg(1).to_string();
+    match c.with_set_mut(&key, C_ZERO.clone(), |s| s.remove(c.argv[2].clone())) {
+        Some(true) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply(C_ONE.clone());
         },
+        Some(false) => { c.add_reply(C_ZERO.clone()); },
         None => {},
     }
 }
 
 fn spop_command(c: &mut RedisClient) {
-    let arg_r = c.argv[1].read().unwrap();
-    let key = arg_r.as_key();
-    match c.lookup_key_write_or_reply(key, NULL_BULK.clone()) {
-        Some(obj) => {
-            match obj.write().unwrap().set_mut() {
-                Some(s_storage) => {
-                    match s_storage.get_random_key() {
-                        Some(ele) => {
-                            if s_storage.remove(ele.clone()) {
-                                server_write().dirty += 1;
-                                c.add_reply_bulk(ele);
-                            } else {
-                                log(LogLevel::Warning, "failed to remove random element");
-                            }
-                        },
-                        None => { c.add_reply(NULL_BULK.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
+    let key = c.arg(1).to_string();
+    let ele = c.with_set_mut(&key, NULL_BULK.clone(), |s| {
+        let ele = s.get_random_key()?;
+        s.remove(ele.clone());
+        Some(ele)
+    });
+    match ele {
+        Some(Some(ele)) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply_bulk(ele);
         },
+        Some(None) => { c.add_reply(NULL_BULK.clone()); },
         None => {},
     }
 }
 
 fn smove_command(c: &mut RedisClient) {
-    let sarg_r = c.argv[1].read().unwrap();
-    let skey = sarg_r.as_key();
-    let darg_r = c.argv[2].read().unwrap();
-    let dkey = darg_r.as_key();
-    match c.lookup_key_write(skey) {
-        Some(obj) => {
-            match obj.write().unwrap().set_mut() {
-                Some(s_storage) => {
-                    let mut dset: Option<Arc<RwLock<RedisObject>>> = None;
-                    // check destination set type
-                    let mut existed = false;
-                    match c.lookup_key_write(dkey) {
-                        Some(d_obj) => {
-                            match d_obj.write().unwrap().set_mut() {
-                                Some(_ds_storage) => {
-                                    existed = true;
-                                    dset = Some(d_obj.clone());
-                                },
-                                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-                            }
-                        },
-                        None => {},
-                    }
+    let src = c.arg(1).to_string();
+    let dst = c.arg(2).to_string();
+    // Both types are checked before anything is moved
+    if c.with_set(&src, C_ZERO.clone(), |_| ()).is_none() || c.lookup_set(&dst, true).is_none() {
+        return;
+    }
+    if c.with_set_mut(&src, C_ZERO.clone(), |s| s.remove(c.argv[3].clone())) != Some(true) {
+        c.add_reply(C_ZERO.clone());
+        return;
+    }
+    c.with_set_or_create(&dst, |s| s.insert(c.argv[3].clone()));
+    server_write().dirty += 1;
+    c.signal_modified_key(&src);
+    c.signal_modified_key(&dst);
+    c.add_reply(C_ONE.clone());
+}
 
-                    if !s_storage.remove(c.argv[3].clone()) {
-                        c.add_reply(C_ZERO.clone());
-                        return;
-                    }
-
-                    if !existed {
-                        let new_set = Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(HashSet::new()) }));
-                        c.insert(dkey, new_set.clone());
-                        dset = Some(new_set);
-                    }
-                    match dset.unwrap().write().unwrap().set_mut() {
-                        Some(ds_storage) => {
-                            ds_storage.insert(c.argv[3].clone());
-                        },
-                        None => { assert!(false, "impossible code"); }
-                    }
-                    server_write().dirty += 1;
-                    c.add_reply(C_ONE.clone());
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => { c.add_reply(C_ZERO.clone()); },
-    }
-}
-
-fn scard_command(c: &mut RedisClient) {
-    let arg_r = c.argv[1].read().unwrap();
-    let key = arg_r.as_key();
-    match c.lookup_key_read_or_reply(key, C_ZERO.clone()) {
-        Some(obj) => {
-            match obj.read().unwrap().set() {
-                Some(s_storage) => {
-                    c.add_reply_u64(s_storage.len() as u64);
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
-    }
-}
+fn scard_command(c: &mut RedisClient) {
+    if let Some(len) = c.with_set(&c.arg(1), C_ZERO.clone(), |s| s.len()) {
+        c.add_reply_u64(len as u64);
+    }
+}
 
 fn sismember_command(c: &mut RedisClient) {
-    let arg_r = c.argv[1].read().unwrap();
-    let key = arg_r.as_key();
-    match c.lookup_key_read_or_reply(key, C_ZERO.clone()) {
-        Some(obj) => {
-            match obj.read().unwrap().set() {
-                Some(s_storage) => {
-                    if s_storage.contains(c.argv[2].clone()) {
-                        c.add_reply(C_ONE.clone());
-                    } else {
-                        c.add_reply(C_ZERO.clone());
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
+    match c.with_set(&c.arg(1), C_ZERO.clone(), |s| s.contains(c.argv[2].clone())) {
+        Some(true) => { c.add_reply(C_ONE.clone()); },
+        Some(false) => { c.add_reply(C_ZERO.clone()); },
         None => {},
     }
 }
@@ -1227,41 +1996,25 @@ fn sinterstore_command(c: &mut RedisClient) {
     sinter_generic_command(c, 2, Some(c.argv[1].clone()));
 }
 
+#[allow(clippy::mutable_key_type)] // see ObjSet
 fn sinter_generic_command(c: &mut RedisClient, idx: usize, dst: Option<Arc<RwLock<RedisObject>>>) {
     let mut sets: Vec<Arc<RwLock<RedisObject>>> = Vec::new();
 
     for i in idx..c.argv.len() {
-        let arg_r = c.argv[i].read().unwrap();
-        let key = arg_r.as_key();
-        let mut set_obj = c.lookup_key_read(key);
-        if dst.is_some() {
-            set_obj = c.lookup_key_write(key);
-        }
-
+        let Some(set_obj) = c.lookup_set(&c.arg(i), dst.is_some()) else { return; };
         match set_obj {
-            Some(s_obj) => {
-                match s_obj.read().unwrap().set() {
-                    Some(_) => { sets.push(s_obj.clone()); },
-                    None => {
-                        c.add_reply(WRONG_TYPE_ERR.clone());
-                        return;
-                    },
-                }
-            },
+            Some(s_obj) => { sets.push(s_obj); },
             None => {
+                // A missing key is an empty set: the intersection is empty.
                 match dst {
                     Some(ref dkey) => {
-                        match dkey.write().unwrap().set_mut() {
-                            Some(_) => {
-                                if c.remove(c.argv[i].read().unwrap().as_key()).is_some() {
-                                    server_write().dirty += 1;
-                                }
-                                c.add_reply(C_ZERO.clone());
-                            },
-                            None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
+                        if c.delete_key(dkey.read().unwrap().as_key()) {
+                            server_write().dirty += 1;
+                            c.signal_modified_key(dkey.read().unwrap().as_key());
                         }
+                        c.add_reply(C_ZERO.clone());
                     },
-                    None => { c.add_reply(NULL_MULTI_BULK.clone()); },
+                    None => { c.add_reply(EMPTY_MULTI_BULK.clone()); },
                 }
                 return;
             },
@@ -1278,7 +2031,7 @@ fn sinter_generic_command(c: &mut RedisClient, idx: usize, dst: Option<Arc<RwLoc
     // not include the element it is discarded
     let set0_r = sets[0].read().unwrap();
     let mut iter = set0_r.set().unwrap().iter();
-    let mut acc: HashSet<RedisObject> = HashSet::new();
+    let mut acc = ObjSet::new();
     let mut j = 0usize;
     while let Some(ele) = iter.next() {
         j = 1;
@@ -1293,19 +2046,10 @@ fn sinter_generic_command(c: &mut RedisClient, idx: usize, dst: Option<Arc<RwLoc
     let len = acc.len();
     match dst {
         Some(dkey) => {
-            c.delete_key(dkey.read().unwrap().as_key());
-            let new_s = Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(acc) }));
-            c.insert(dkey.read().unwrap().as_key(), new_s);
-
-            server_write().dirty += 1;
+            store_set_result(c, dkey.read().unwrap().as_key(), acc);
             c.add_reply_str(&format!(":{}\r\n", len));
         },
-        None => {
-            c.add_reply_str(&format!("*{}\r\n", len));
-            for ele in &acc {
-                c.add_reply_bulk(Arc::new(RwLock::new(ele.clone())));
-            }
-        },
+        None => { add_reply_set(c, &acc); },
     }
 }
 
@@ -1320,6 +2064,28 @@ fn sunion_command(c: &mut RedisClient) {
     sunion_diff_generic_command(c, 1, None, SetOp::Union);
 }
 
+/// Replace the destination of SINTERSTORE, SUNIONSTORE and SDIFFSTORE with
+/// the result. An empty result deletes the destination, like in Redis there
+/// are no empty sets in the keyspace. The destination counts as modified,
+/// and as a dirty change, whenever something is stored or a previous value
+/// is replaced or deleted, even if the members are the same as before. An
+/// empty result over a missing destination changes nothing. The AOF gets
+/// the command itself, see call().
+#[allow(clippy::mutable_key_type)] // see ObjSet
+fn store_set_result(c: &mut RedisClient, dkey: &str, acc: ObjSet) {
+    let existed = c.delete_key(dkey);
+    let stored = !acc.is_empty();
+    if stored {
+        let new_s = Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(acc) }.tracked()));
+        c.insert(dkey, new_s);
+    }
+
+    if stored || existed {
+        server_write().dirty += 1;
+        c.signal_modified_key(dkey);
+    }
+}
+
 fn sunionstore_command(c: &mut RedisClient) {
     sunion_diff_generic_command(c, 2, Some(c.argv[1].clone()), SetOp::Union);
 }
@@ -1332,32 +2098,16 @@ fn sdiffstore_command(c: &mut RedisClient) {
     sunion_diff_generic_command(c, 2, Some(c.argv[1].clone()), SetOp::Diff);
 }
 
+#[allow(clippy::mutable_key_type)] // see ObjSet
 fn sunion_diff_generic_command(c: &mut RedisClient, idx: usize, dst: Option<Arc<RwLock<RedisObject>>>, op: SetOp) {
     let mut sets: Vec<Option<Arc<RwLock<RedisObject>>>> = Vec::new();
 
     for i in idx..c.argv.len() {
-        let arg_r = c.argv[i].read().unwrap();
-        let key = arg_r.as_key();
-        let mut set_obj = c.lookup_key_read(key);
-        if dst.is_some() {
-            set_obj = c.lookup_key_write(key);
-        }
-
-        match set_obj {
-            Some(s_obj) => {
-                match s_obj.read().unwrap().set() {
-                    Some(_) => { sets.push(Some(s_obj.clone())); },
-                    None => {
-                        c.add_reply(WRONG_TYPE_ERR.clone());
-                        return;
-                    },
-                }
-            },
-            None => { sets.push(None); },
-        }
+        let Some(set_obj) = c.lookup_set(&c.arg(i), dst.is_some()) else { return; };
+        sets.push(set_obj);
     }
 
-    let mut acc: HashSet<RedisObject> = HashSet::new();
+    let mut acc = ObjSet::new();
     let mut cardinality = 0;
     for i in 0..sets.len() {
         if op == SetOp::Diff && i == 0 && sets[i].is_none() { break; }
@@ -1384,39 +2134,32 @@ fn sunion_diff_generic_command(c: &mut RedisClient, idx: usize, dst: Option<Arc<
 
     match dst {
         Some(dkey) => {
-            c.delete_key(dkey.read().unwrap().as_key());
-            let new_s = Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(acc) }));
-            c.insert(dkey.read().unwrap().as_key(), new_s);
-
-            server_write().dirty += 1;
+            store_set_result(c, dkey.read().unwrap().as_key(), acc);
             c.add_reply_str(&format!(":{}\r\n", cardinality));
         },
-        None => {
-            c.add_reply_str(&format!("*{}\r\n", cardinality));
-            for ele in &acc {
-                c.add_reply_bulk(Arc::new(RwLock::new(ele.clone())));
-            }
-        },
+        None => { add_reply_set(c, &acc); },
+    }
+}
+
+/// Reply with the elements of a set as a multi bulk. The order is
+/// unspecified, unless DEBUG SET-SORTED-REPLIES is on: then elements are
+/// sorted by their string value so tests can assert the exact output.
+#[allow(clippy::mutable_key_type)] // see ObjSet
+fn add_reply_set(c: &mut RedisClient, set: &ObjSet) {
+    let mut elements: Vec<RedisObject> = set.iter().map(|ele| ele.get_decoded()).collect();
+    if server_read().debug_sorted_replies {
+        elements.sort_by(|a, b| a.as_key().cmp(b.as_key()));
+    }
+    c.add_reply_str(&format!("*{}\r\n", elements.len()));
+    for ele in elements {
+        c.add_reply_bulk(Arc::new(RwLock::new(ele)));
     }
 }
 
 fn srandmember_command(c: &mut RedisClient) {
-    let arg_r = c.argv[1].read().unwrap();
-    let key = arg_r.as_key();
-    match c.lookup_key_read_or_reply(key, NULL_BULK.clone()) {
-        Some(obj) => {
-            match obj.read().unwrap().set() {
-                Some(s_storage) => {
-                    match s_storage.get_random_key() {
-                        Some(ele) => {
-                            c.add_reply_bulk(ele);
-                        },
-                        None => { c.add_reply(NULL_BULK.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
+    match c.with_set(&c.arg(1), NULL_BULK.clone(), |s| s.get_random_key()) {
+        Some(Some(ele)) => { c.add_reply_bulk(ele); },
+        Some(None) => { c.add_reply(NULL_BULK.clone()); },
         None => {},
     }
 }
@@ -1441,23 +2184,25 @@ fn zadd_command(c: &mut RedisClient) {
 }
 
 fn zrem_command(c: &mut RedisClient) {
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), C_ZERO.clone()) {
-        Some(z_obj) => {
-            match z_obj.write().unwrap().zset_mut() {
-                Some(zs_storage) => {
-                    match zs_storage.dict().get(&c.argv[2].read().unwrap()).cloned() {
-                        Some(old_score) => {
-                            zs_storage.skiplist_mut().delete(old_score, Arc::new(c.argv[2].read().unwrap().clone()));
-                            zs_storage.dict_mut().remove(&c.argv[2].read().unwrap());
-                            server_write().dirty += 1;
-                            c.add_reply(C_ONE.clone());
-                        },
-                        None => { c.add_reply(C_ZERO.clone()); },
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
+    let key = c.arg(1).to_string();
+    let removed = c.with_zset_mut(&key, C_ZERO.clone(), |zs| {
+        let ele = c.argv[2].read().unwrap();
+        match zs.dict().get(&ele).cloned() {
+            Some(old_score) => {
+                zs.skiplist_mut().delete(old_score, Arc::new(ele.clone()));
+                zs.dict_mut().remove(&ele);
+                true
+            },
+            None => false,
+        }
+    });
+    match removed {
+        Some(true) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply(C_ONE.clone());
         },
+        Some(false) => { c.add_reply(C_ZERO.clone()); },
         None => {},
     }
 }
@@ -1466,55 +2211,48 @@ fn zrem_command(c: &mut RedisClient) {
 /// `score_val` is the score if the operation is a ZADD (do_incr == false) or
 /// the increment if the operation is a ZINCRBY (do_incr == true).
 fn zadd_generic_command(c: &mut RedisClient, key: &str, obj: Arc<RwLock<RedisObject>>, score_val: f64, do_incr: bool) {
-    let zset = match c.lookup_key_write(key) {
-        Some(z_obj) => {
-            match z_obj.read().unwrap().zset() {
-                Some(_) => {},
-                None => {
-                    c.add_reply(WRONG_TYPE_ERR.clone());
-                    return;
-                },
-            }
-            z_obj
-        },
-        None => {
-            let new_zset = Arc::new(RwLock::new(RedisObject::ZSet { zs: ZSetStorageType::SkipList(HashMap::new(), SkipList::new()) }));
-            c.insert(key, new_zset.clone());
-            new_zset
-        },
-    };
+    let create = || RedisObject::ZSet { zs: ZSetStorageType::SkipList(HashMap::new(), SkipList::new()) };
+    let zset = c.lookup_key_write_or_insert(key, Some(&create)).expect("created when missing");
+    let mut zset_w = zset.write().unwrap();
+    if zset_w.zset().is_none() {
+        drop(zset_w);
+        c.add_reply_wrong_type();
+        return;
+    }
 
     let mut score = score_val;
     if do_incr {
-        match zset.read().unwrap().zset().unwrap().dict().get(&obj.read().unwrap()) {
-            Some(old_s) => { score += old_s; },
-            None => {},
+        if let Some(old_s) = zset_w.zset().unwrap().dict().get(&obj.read().unwrap()) {
+            score += old_s;
         }
     }
 
-    let mut zset_w = zset.write().unwrap();
     let ele = Arc::new(obj.read().unwrap().clone());
-    match zset_w.zset_mut().unwrap().dict_mut().insert(obj.read().unwrap().clone(), score) {
+    let (added, changed) = match zset_w.zset_mut().unwrap().dict_mut().insert(obj.read().unwrap().clone(), score) {
         None => {
             zset_w.zset_mut().unwrap().skiplist_mut().insert(score, ele.clone());
-            server_write().dirty += 1;
-            if do_incr {
-                c.add_reply_f64(score);
-            } else {
-                c.add_reply(C_ONE.clone());
-            }
+            (true, true)
         },
         Some(old_s) => {
             if old_s != score {
                 zset_w.zset_mut().unwrap().skiplist_mut().delete(old_s, ele.clone());
                 zset_w.zset_mut().unwrap().skiplist_mut().insert(score, ele.clone());
             }
-            if do_incr {
-                c.add_reply_f64(score);
-            } else {
-                c.add_reply(C_ZERO.clone());
-            }
+            (false, old_s != score)
         },
+    };
+    // The object lock comes after SERVER and the db, see server.rs
+    drop(zset_w);
+    if changed {
+        server_write().dirty += 1;
+        c.signal_modified_key(key);
+    }
+    if do_incr {
+        c.add_reply_f64(score);
+    } else if added {
+        c.add_reply(C_ONE.clone());
+    } else {
+        c.add_reply(C_ZERO.clone());
     }
 }
 
@@ -1533,189 +2271,232 @@ fn zincrby_command(c: &mut RedisClient) {
     zadd_generic_command(c, &key, obj, incr, true);
 }
 
-fn zrange_command(c: &mut RedisClient) {
-    zrange_generic_command(c, false);
+/// How ZRANGE reads its <start> <stop> arguments.
+#[derive(Clone, Copy, PartialEq)]
+enum ZRangeType {
+    Rank,
+    Score,
+    Lex,
 }
 
-fn zrevrange_command(c: &mut RedisClient) {
-    zrange_generic_command(c, true);
+/// A BYSCORE or BYLEX interval, ends included unless marked exclusive.
+enum ZRangeSpec {
+    Rank(i32, i32),
+    Score { min: f64, min_ex: bool, max: f64, max_ex: bool },
+    Lex { min: LexBound, max: LexBound },
 }
 
-fn zrange_generic_command(c: &mut RedisClient, reverse: bool) {
-    let mut start = 0i32;
-    let mut end = 0i32;
-    match (c.argv[2].read().unwrap().as_key().parse(), c.argv[3].read().unwrap().as_key().parse()) {
-        (Ok(s), Ok(e)) => {
-            start = s;
-            end = e;
-        },
-        _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}', '{}'", c.argv[2].read().unwrap().as_key(), c.argv[3].read().unwrap().as_key()));
-            return;
-        }
-    }
+/// One end of a BYLEX interval: - and + are the infinities.
+enum LexBound {
+    NegInf,
+    PosInf,
+    Value(String, bool),   // (element, exclusive)
+}
 
-    let mut with_score = false;
-    if c.argv.len() == 5 && c.argv[4].read().unwrap().as_key().eq_ignore_ascii_case("withscores") {
-        with_score = true;
-    } else if c.argv.len() >= 5 {
-        c.add_reply(SYNTAX_ERR.clone());
-        return;
+/// Parse a BYSCORE interval end: a float, "(" in front makes it exclusive.
+fn zset_parse_score_bound(s: &str) -> Option<(f64, bool)> {
+    let (s, exclusive) = match s.strip_prefix('(') {
+        Some(rest) => (rest, true),
+        None => (s, false),
+    };
+    match s.parse::<f64>() {
+        Ok(f) if !f.is_nan() => Some((f, exclusive)),
+        _ => None,
     }
+}
 
-    match c.lookup_key_read_or_reply(c.argv[1].read().unwrap().as_key(), NULL_MULTI_BULK.clone()) {
-        Some(z_obj) => {
-            match z_obj.read().unwrap().zset() {
-                Some(zs_storage) => {
-                    let zsl = zs_storage.skiplist();
-                    let len = zsl.len();
-                    // convert negative indexes
-                    if start < 0 { start += len as i32; }
-                    if end < 0 { end += len as i32; }
-                    if start < 0 { start = 0; }
-                    if end < 0 { end = 0; }
-
-                    // indexes sanity checks
-                    if start > end || start >= len as i32 {
-                        c.add_reply(EMPTY_MULTI_BULK.clone());
-                        return;
-                    }
-                    if end >= len as i32 { end = len as i32; }
-                    let range_len = end - start;
-
-                    let mut ln = match reverse {
-                        true => match start == 0 {
-                            true => zsl.tail(),
-                            false => zsl.get_ele_by_rank(len - start as usize),
-                        },
-                        false => match start == 0 {
-                            true => zsl.header(0),
-                            false => zsl.get_ele_by_rank(start as usize + 1),
-                        },
-                    };
-
-                    match with_score {
-                        true => c.add_reply_str(&format!("*{}\r\n", 2 * range_len)),
-                        false => c.add_reply_str(&format!("*{}\r\n", range_len)),
-                    };
-                    for _ in 0..range_len {
-                        let node = ln.clone().unwrap();
-                        let obj = node.read().unwrap().obj();
-                        c.add_reply_bulk(Arc::new(RwLock::new(obj.unwrap().deref().clone())));
-                        if with_score {
-                            c.add_reply_f64(node.read().unwrap().score());
-                        }
-                        ln = match reverse {
-                            true => { node.read().unwrap().backward() },
-                            false => { node.read().unwrap().forward(0) },
-                        };
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
+fn zset_parse_lex_bound(s: &str) -> Option<LexBound> {
+    match s.as_bytes().first() {
+        Some(b'-') if s.len() == 1 => Some(LexBound::NegInf),
+        Some(b'+') if s.len() == 1 => Some(LexBound::PosInf),
+        Some(b'[') => Some(LexBound::Value(s[1..].to_string(), false)),
+        Some(b'(') => Some(LexBound::Value(s[1..].to_string(), true)),
+        _ => None,
     }
 }
 
-fn zrangebyscore_command(c: &mut RedisClient) {
-    let mut min = 0f64;
-    let mut max = 0f64;
-    match (c.argv[2].read().unwrap().as_key().parse(), c.argv[3].read().unwrap().as_key().parse()) {
-        (Ok(s), Ok(e)) => {
-            min = s;
-            max = e;
-        },
-        _ => {
-            log(LogLevel::Warning, &format!("failed to parse args: '{}', '{}'", c.argv[2].read().unwrap().as_key(), c.argv[3].read().unwrap().as_key()));
-            return;
+impl ZRangeSpec {
+    fn above_min(&self, ele: &str, score: f64) -> bool {
+        match self {
+            Self::Rank(..) => true,
+            Self::Score { min, min_ex, .. } => if *min_ex { score > *min } else { score >= *min },
+            Self::Lex { min, .. } => match min {
+                LexBound::NegInf => true,
+                LexBound::PosInf => false,
+                LexBound::Value(v, ex) => if *ex { ele > v.as_str() } else { ele >= v.as_str() },
+            },
         }
     }
+    fn below_max(&self, ele: &str, score: f64) -> bool {
+        match self {
+            Self::Rank(..) => true,
+            Self::Score { max, max_ex, .. } => if *max_ex { score < *max } else { score <= *max },
+            Self::Lex { max, .. } => match max {
+                LexBound::NegInf => false,
+                LexBound::PosInf => true,
+                LexBound::Value(v, ex) => if *ex { ele < v.as_str() } else { ele <= v.as_str() },
+            },
+        }
+    }
+}
 
-    // Parse "WITHSCORES"
-    let mut with_score = false;
-    let mut bad_syntax = false;
-    if c.argv.len() == 5 || c.argv.len() == 8 {
-        if c.argv[c.argv.len() - 1].read().unwrap().as_key().eq_ignore_ascii_case("withscores") {
-            with_score = true;
-        } else {
-            bad_syntax = true;
+/// ZRANGE key start stop [BYSCORE|BYLEX] [REV] [LIMIT offset count] [WITHSCORES]
+fn zrange_command(c: &mut RedisClient) {
+    zrange_generic_command(c, None, None);
+}
+
+/// ZREVRANGE key start stop [WITHSCORES]
+fn zrevrange_command(c: &mut RedisClient) {
+    zrange_generic_command(c, Some(ZRangeType::Rank), Some(true));
+}
+
+/// ZRANGEBYSCORE key min max [WITHSCORES] [LIMIT offset count]
+fn zrangebyscore_command(c: &mut RedisClient) {
+    zrange_generic_command(c, Some(ZRangeType::Score), Some(false));
+}
+
+/// The ZRANGE family. The legacy commands fix the range type and the
+/// direction, ZRANGE takes them from its options.
+fn zrange_generic_command(c: &mut RedisClient, range_type: Option<ZRangeType>, reverse: Option<bool>) {
+    let (mut range_type_opt, mut reverse_opt) = (range_type, reverse);
+    let mut with_scores = false;
+    let mut limit: Option<(i32, i32)> = None;
+    let mut j = 4;
+    while j < c.argv.len() {
+        let opt = c.arg(j).to_ascii_lowercase();
+        let left = c.argv.len() - j - 1;
+        match &opt[..] {
+            "withscores" => { with_scores = true; },
+            "limit" if left >= 2 => {
+                let Some(offset) = int_arg_or_reply::<i32>(c, j + 1) else { return; };
+                let Some(count) = int_arg_or_reply::<i32>(c, j + 2) else { return; };
+                limit = Some((offset, count));
+                j += 2;
+            },
+            "rev" if reverse.is_none() => { reverse_opt = Some(true); },
+            "byscore" if range_type.is_none() && range_type_opt.is_none() => { range_type_opt = Some(ZRangeType::Score); },
+            "bylex" if range_type.is_none() && range_type_opt.is_none() => { range_type_opt = Some(ZRangeType::Lex); },
+            _ => {
+                c.add_reply(SYNTAX_ERR.clone());
+                return;
+            },
         }
+        j += 1;
     }
-    let n: usize = if with_score {1} else {0};
-    if c.argv.len() != 4 + n && c.argv.len() != 7 + n {
-        bad_syntax = true;
+    let range_type = range_type_opt.unwrap_or(ZRangeType::Rank);
+    let reverse = reverse_opt.unwrap_or(false);
+    if limit.is_some() && range_type == ZRangeType::Rank {
+        c.add_reply_error(&errors::err("syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX"));
+        return;
     }
-    if bad_syntax {
-        c.add_reply_str("-ERR wrong number of arguments for ZRANGEBYSCORE\r\n");
+    if with_scores && range_type == ZRangeType::Lex {
+        c.add_reply_error(&errors::err("syntax error, WITHSCORES not supported in combination with BYLEX"));
         return;
     }
 
-    // Parse "LIMIT"
-    let mut limit = -1;
-    let mut offset = 0;
-    if c.argv.len() == 7 + n && !c.argv[4].read().unwrap().as_key().eq_ignore_ascii_case("limit") {
-        c.add_reply(SYNTAX_ERR.clone());
-        return;
-    } else if c.argv.len() == 7 + n {
-        match (c.argv[5].read().unwrap().as_key().parse(), c.argv[6].read().unwrap().as_key().parse()) {
-            (Ok(o), Ok(l)) => {
-                offset = o;
-                limit = l;
-                if limit < 0 { offset = 0; }
+    // A reverse interval is given max first
+    let (lo, hi) = if reverse && range_type != ZRangeType::Rank { (3, 2) } else { (2, 3) };
+    let spec = match range_type {
+        ZRangeType::Rank => {
+            let Some(start) = int_arg_or_reply::<i32>(c, 2) else { return; };
+            let Some(end) = int_arg_or_reply::<i32>(c, 3) else { return; };
+            ZRangeSpec::Rank(start, end)
+        },
+        ZRangeType::Score => match (zset_parse_score_bound(&c.arg(lo)), zset_parse_score_bound(&c.arg(hi))) {
+            (Some((min, min_ex)), Some((max, max_ex))) => ZRangeSpec::Score { min, min_ex, max, max_ex },
+            _ => {
+                c.add_reply_error(&errors::err("min or max is not a float"));
+                return;
             },
+        },
+        ZRangeType::Lex => match (zset_parse_lex_bound(&c.arg(lo)), zset_parse_lex_bound(&c.arg(hi))) {
+            (Some(min), Some(max)) => ZRangeSpec::Lex { min, max },
             _ => {
-                log(LogLevel::Warning, &format!("failed to parse args: '{}', '{}'", c.argv[5].read().unwrap().as_key(), c.argv[6].read().unwrap().as_key()));
+                c.add_reply_error(&errors::err("min or max not valid string range item"));
                 return;
-            }
-        }
-    }
-
-    match c.lookup_key_read(c.argv[1].read().unwrap().as_key()) {
-        Some(z_obj) => {
-            match z_obj.read().unwrap().zset() {
-                Some(zset) => {
-                    let mut ln = zset.skiplist().first_with_score(min);
-                    if ln.is_none() {
-                        c.add_reply(EMPTY_MULTI_BULK.clone());
-                    }
-                    
-                    let mut objs: Vec<Arc<RedisObject>> = Vec::new();
-                    let mut scores: Vec<f64> = Vec::new();
-                    while ln.is_some() {
-                        let node = ln.clone().unwrap();
-                        if node.read().unwrap().score() > max {
-                            break;
-                        }
-
-                        if offset > 0 {
-                            offset -= 1;
-                            ln = ln.unwrap().read().unwrap().forward(0);
-                            continue;
-                        }
+            },
+        },
+    };
 
-                        if limit == 0 { break; }
-                        objs.push(node.read().unwrap().obj().unwrap());
-                        if with_score { scores.push(node.read().unwrap().score()); }
-                        ln = ln.unwrap().read().unwrap().forward(0);
+    let Some(z_obj) = c.lookup_key_read_or_reply(&c.arg(1), EMPTY_MULTI_BULK.clone()) else { return; };
+    let range = match z_obj.read().unwrap().zset() {
+        Some(zset) => zset_range(zset.skiplist(), &spec, reverse, limit),
+        None => {
+            c.add_reply_wrong_type();
+            return;
+        },
+    };
 
-                        if limit > 0 { limit -= 1; }
-                    }
+    c.add_reply_str(&format!("*{}\r\n", if with_scores { range.len() * 2 } else { range.len() }));
+    for (obj, score) in range {
+        c.add_reply_bulk(Arc::new(RwLock::new(obj.deref().clone())));
+        if with_scores {
+            c.add_reply_f64(score);
+        }
+    }
+}
 
-                    match with_score {
-                        true => { c.add_reply_str(&format!("*{}\r\n", objs.len() * 2)); },
-                        false => { c.add_reply_str(&format!("*{}\r\n", objs.len())); },
-                    }
-                    for i in 0..objs.len() {
-                        c.add_reply_bulk(Arc::new(RwLock::new(objs[i].deref().clone())));
-                        if with_score { c.add_reply_f64(scores[i]); }
-                    }
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
+/// The (element, score) pairs of `spec` in reply order, after LIMIT.
+fn zset_range(zsl: &SkipList, spec: &ZRangeSpec, reverse: bool, limit: Option<(i32, i32)>) -> Vec<(Arc<RedisObject>, f64)> {
+    let len = zsl.len() as i32;
+    let (mut ln, mut count) = match spec {
+        ZRangeSpec::Rank(start, end) => {
+            // convert negative indexes
+            let start = if *start < 0 { (*start).saturating_add(len).max(0) } else { *start };
+            let end = if *end < 0 { (*end).saturating_add(len).max(0) } else { *end };
+            // indexes sanity checks
+            if start > end || start >= len {
+                return Vec::new();
             }
+            let end = end.min(len - 1);
+            let first = match reverse {
+                true => zsl.get_ele_by_rank((len - start) as usize),
+                false => zsl.get_ele_by_rank(start as usize + 1),
+            };
+            (first, (end - start + 1) as i64)
         },
-        None => { c.add_reply(NULL_MULTI_BULK.clone()); },
+        ZRangeSpec::Score { min, .. } if !reverse => (zsl.first_with_score(*min), -1),
+        _ => (if reverse { zsl.tail() } else { zsl.header(0) }, -1),
+    };
+    let (mut offset, limit) = limit.unwrap_or((0, -1));
+    if offset < 0 {
+        return Vec::new();
+    }
+
+    let mut range = Vec::new();
+    while let Some(node) = ln {
+        if count == 0 || (limit >= 0 && range.len() == limit as usize) {
+            break;
+        }
+        let (obj, score) = {
+            let node = node.read().unwrap();
+            (node.obj().unwrap(), node.score())
+        };
+        let ele = obj.get_decoded();
+        let ele = ele.as_key();
+        // Walking away from the interval: skip until it starts, stop once
+        // it's over.
+        let (entered, passed) = match reverse {
+            true => (spec.below_max(ele, score), !spec.above_min(ele, score)),
+            false => (spec.above_min(ele, score), !spec.below_max(ele, score)),
+        };
+        if passed {
+            break;
+        }
+        if entered {
+            if offset > 0 {
+                offset -= 1;
+            } else {
+                range.push((obj.clone(), score));
+            }
+            count -= 1;
+        }
+        ln = match reverse {
+            true => node.read().unwrap().backward(),
+            false => node.read().unwrap().forward(0),
+        };
     }
+    range
 }
 
 fn zcard_command(c: &mut RedisClient) {
@@ -1723,7 +2504,7 @@ fn zcard_command(c: &mut RedisClient) {
         Some(z_obj) => {
             match z_obj.read().unwrap().zset() {
                 Some(zset) => { c.add_reply_u64(zset.skiplist().len() as u64); },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
+                None => { c.add_reply_wrong_type(); },
             }
         },
         None => {},
@@ -1740,7 +2521,7 @@ fn zscore_command(c: &mut RedisClient) {
                         None => { c.add_reply(NULL_BULK.clone()); },
                     }
                 },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
+                None => { c.add_reply_wrong_type(); },
             }
         },
         None => {},
@@ -1761,62 +2542,380 @@ fn zremrangebyscore_command(c: &mut RedisClient) {
         }
     }
 
-    match c.lookup_key_write_or_reply(c.argv[1].read().unwrap().as_key(), C_ZERO.clone()) {
-        Some(z_obj) => {
-            match z_obj.write().unwrap().zset_mut() {
-                Some(zset) => {
-                    let deleted = zset.delete_range_by_score(min, max);
-                    server_write().dirty += deleted as u128;
-                    c.add_reply_u64(deleted as u64);
-                },
-                None => { c.add_reply(WRONG_TYPE_ERR.clone()); },
-            }
-        },
-        None => {},
+    let key = c.arg(1).to_string();
+    if let Some(deleted) = c.with_zset_mut(&key, C_ZERO.clone(), |zs| zs.delete_range_by_score(min, max)) {
+        if deleted > 0 {
+            c.signal_modified_key(&key);
+        }
+        server_write().dirty += deleted as u128;
+        c.add_reply_u64(deleted as u64);
     }
 }
 
-fn save_command(c: &mut RedisClient) {
-    if server_read().bg_save_child_pid != -1 {
-        c.add_reply_str("-ERR background save in progress\r\n");
-        return;
+// 
+// hash
+// 
+
+fn hset_command(c: &mut RedisClient) {
+    let key = c.arg(1).to_string();
+    let value = c.argv[3].read().unwrap().get_decoded();
+    if let Some(new) = c.with_hash_or_create(&key, |h| h.insert(&c.arg(2), value)) {
+        server_write().dirty += 1;
+        c.signal_modified_key(&key);
+        c.add_reply(if new { C_ONE.clone() } else { C_ZERO.clone() });
     }
-    let file = server_read().db_filename.clone();
-    if rdb_save(&file) {
-        c.add_reply(OK.clone());
-    } else {
-        c.add_reply(ERR.clone());
+}
+
+fn hget_command(c: &mut RedisClient) {
+    match c.with_hash(&c.arg(1), NULL_BULK.clone(), |h| h.get(&c.arg(2)).map(|v| v.get_decoded())) {
+        Some(Some(value)) => { c.add_reply_bulk(Arc::new(RwLock::new(value))); },
+        Some(None) => { c.add_reply(NULL_BULK.clone()); },
+        None => {},
     }
 }
 
-fn bgsave_command(c: &mut RedisClient) {
-    if server_read().bg_save_child_pid != -1 {
-        c.add_reply_str("-ERR background save already in progress\r\n");
-        return;
+fn hdel_command(c: &mut RedisClient) {
+    let key = c.arg(1).to_string();
+    match c.with_hash_mut(&key, C_ZERO.clone(), |h| h.remove(&c.arg(2))) {
+        Some(true) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply(C_ONE.clone());
+        },
+        Some(false) => { c.add_reply(C_ZERO.clone()); },
+        None => {},
     }
-    let file = server_read().db_filename.clone();
-    if rdb_save_background(&file) {
-        c.add_reply_str("+Background saving started\r\n");
-    } else {
-        c.add_reply(ERR.clone());
+}
+
+fn hexists_command(c: &mut RedisClient) {
+    match c.with_hash(&c.arg(1), C_ZERO.clone(), |h| h.contains(&c.arg(2))) {
+        Some(true) => { c.add_reply(C_ONE.clone()); },
+        Some(false) => { c.add_reply(C_ZERO.clone()); },
+        None => {},
     }
 }
 
-fn lastsave_command(c: &mut RedisClient) {
-    c.add_reply_str(&format!(":{}\r\n", server_read().last_save));
+fn hlen_command(c: &mut RedisClient) {
+    if let Some(len) = c.with_hash(&c.arg(1), C_ZERO.clone(), |h| h.len()) {
+        c.add_reply_u64(len as u64);
+    }
 }
 
-fn shutdown_command(c: &mut RedisClient) {
-    log(LogLevel::Warning, "User requested shutdown, saving DB...");
-    // Kill the saving child if there is a background saving in progress.
-    // We want to avoid race conditions, for instance our saving child may
-    // overwrite the synchronous saving did by SHUTDOWN.
-    if server_read().bg_save_child_pid != -1 {
-        log(LogLevel::Warning, "There is a live saving child. Killing it!");
-        unsafe {
-            kill(server_read().bg_save_child_pid, SIGKILL);
-        }
-        rdb_remove_temp_file(server_read().bg_save_child_pid);
+fn hkeys_command(c: &mut RedisClient) {
+    hgetall_generic_command(c, true, false);
+}
+
+fn hvals_command(c: &mut RedisClient) {
+    hgetall_generic_command(c, false, true);
+}
+
+fn hgetall_command(c: &mut RedisClient) {
+    hgetall_generic_command(c, true, true);
+}
+
+/// Reply with the fields and/or the values of a hash. Like sets, the order
+/// is unspecified unless DEBUG SET-SORTED-REPLIES is on: then the fields
+/// are sorted, and the values follow the order of their fields.
+fn hgetall_generic_command(c: &mut RedisClient, fields: bool, values: bool) {
+    let entries = c.with_hash(&c.arg(1), EMPTY_MULTI_BULK.clone(), |h| {
+        h.iter().map(|(f, v)| (f.clone(), v.get_decoded())).collect::<Vec<_>>()
+    });
+    let Some(mut entries) = entries else { return; };
+    if server_read().debug_sorted_replies {
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+    }
+    let per_entry = fields as usize + values as usize;
+    c.add_reply_str(&format!("*{}\r\n", entries.len() * per_entry));
+    for (field, value) in entries {
+        if fields {
+            c.add_reply_bulk(Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(field) }.tracked())));
+        }
+        if values {
+            c.add_reply_bulk(Arc::new(RwLock::new(value)));
+        }
+    }
+}
+
+fn hincrby_command(c: &mut RedisClient) {
+    let key = c.arg(1).to_string();
+    let Some(incr) = int_arg_or_reply::<i64>(c, 3) else { return; };
+    let ret = c.with_hash_or_create(&key, |h| {
+        let field = c.arg(2);
+        let value = match h.get(&field) {
+            None => 0,
+            Some(v) => {
+                match v.string().unwrap() {
+                    StringStorageType::Integer(n) => *n as i64,
+                    StringStorageType::String(s) => s.parse::<i64>().map_err(|_| "hash value is not an integer")?,
+                }
+            },
+        };
+        let value = value.checked_add(incr).ok_or("increment or decrement would overflow")?;
+        h.insert(&field, RedisObject::String { ptr: StringStorageType::Integer(value as isize) }.tracked());
+        Ok(value)
+    });
+    match ret {
+        Some(Ok(value)) => {
+            server_write().dirty += 1;
+            c.signal_modified_key(&key);
+            c.add_reply_str(&format!(":{}\r\n", value));
+        },
+        Some(Err(msg)) => { c.add_reply_error(&errors::err(msg)); },
+        None => {},
+    }
+}
+
+// 
+// sort
+// 
+
+/// An element being sorted and the value it's compared by.
+struct SortItem {
+    ele: RedisObject,
+    score: f64,
+    cmp: Option<String>,
+}
+
+/// The value the SORT pattern `pattern` points to for `ele`: the string
+/// at the key with the first '*' replaced by the element, or the element
+/// itself for "#". A pattern like "key*->field" points to the field of the
+/// hash at that key instead. None if there is no such value.
+fn lookup_key_by_pattern(c: &RedisClient, pattern: &str, ele: &RedisObject) -> Option<RedisObject> {
+    if pattern == "#" {
+        return Some(ele.clone());
+    }
+    // The field is what follows the first "->", if anything does
+    let (key_pattern, field) = match pattern.find("->") {
+        Some(arrow) if arrow + 2 < pattern.len() => (&pattern[..arrow], Some(&pattern[arrow + 2..])),
+        _ => (pattern, None),
+    };
+    // Fetching a fixed key for every element makes no sense
+    let star = key_pattern.find('*')?;
+    let key = format!("{}{}{}", &key_pattern[..star], ele.as_key(), &key_pattern[star + 1..]);
+    let obj = c.lookup_key_read(&key)?;
+    let obj_r = obj.read().unwrap();
+    match field {
+        Some(field) => obj_r.hash()?.get(field).map(|v| v.get_decoded()),
+        None if obj_r.is_string() => Some(obj_r.get_decoded()),
+        None => None,
+    }
+}
+
+/// SORT key [BY pattern] [LIMIT offset count] [GET pattern [GET pattern ...]]
+///      [ASC|DESC] [ALPHA] [STORE dstkey]
+fn sort_command(c: &mut RedisClient) {
+    let mut desc = false;
+    let mut store: Option<String> = None;
+    let mut alpha = false;
+    let mut limit: Option<(i64, i64)> = None;
+    let mut sort_by: Option<String> = None;
+    let mut dont_sort = false;
+    let mut gets: Vec<String> = Vec::new();
+
+    let mut j = 2;
+    while j < c.argv.len() {
+        let opt = c.arg(j).to_ascii_lowercase();
+        let left = c.argv.len() - j - 1;
+        match &opt[..] {
+            "asc" => { desc = false; },
+            "desc" => { desc = true; },
+            "alpha" => { alpha = true; },
+            "limit" if left >= 2 => {
+                match (c.arg(j + 1).parse(), c.arg(j + 2).parse()) {
+                    (Ok(offset), Ok(count)) => { limit = Some((offset, count)); },
+                    _ => {
+                        c.add_reply_error(&errors::err("value is not an integer or out of range"));
+                        return;
+                    },
+                }
+                j += 2;
+            },
+            "by" if left >= 1 => {
+                let pattern = c.arg(j + 1).to_string();
+                // Without a '*' every element gets the same weight: don't
+                // bother sorting.
+                dont_sort = !pattern.contains('*');
+                sort_by = Some(pattern);
+                j += 1;
+            },
+            "get" if left >= 1 => {
+                gets.push(c.arg(j + 1).to_string());
+                j += 1;
+            },
+            "store" if left >= 1 => {
+                store = Some(c.arg(j + 1).to_string());
+                j += 1;
+            },
+            _ => {
+                c.add_reply(SYNTAX_ERR.clone());
+                return;
+            },
+        }
+        j += 1;
+    }
+
+    // Copy the elements out first: the patterns are looked up in the db,
+    // which can't be locked while holding the value.
+    let obj = c.lookup_key_read(&c.arg(1));
+    let elements: Vec<RedisObject> = match &obj {
+        None => Vec::new(),
+        Some(obj) => {
+            let obj_r = obj.read().unwrap();
+            if let Some(l) = obj_r.list() {
+                l.range(0, l.len() as i32 - 1)
+            } else if let Some(s) = obj_r.set() {
+                s.iter().map(|ele| ele.get_decoded()).collect()
+            } else if let Some(zs) = obj_r.zset() {
+                let mut elements = Vec::with_capacity(zs.len());
+                let mut ln = zs.skiplist().header(0);
+                while let Some(node) = ln {
+                    elements.push(node.read().unwrap().obj().unwrap().deref().clone());
+                    ln = node.read().unwrap().forward(0);
+                }
+                elements
+            } else {
+                drop(obj_r);
+                c.add_reply_wrong_type();
+                return;
+            }
+        },
+    };
+    drop(obj);
+
+    let mut items: Vec<SortItem> = Vec::with_capacity(elements.len());
+    for ele in elements {
+        let ele = ele.get_decoded();
+        let mut item = SortItem { ele, score: 0f64, cmp: None };
+        if !dont_sort {
+            let by = match &sort_by {
+                Some(pattern) => lookup_key_by_pattern(c, pattern, &item.ele),
+                None => Some(item.ele.clone()),
+            };
+            if let Some(by) = by {
+                if alpha {
+                    item.cmp = Some(by.as_key().to_string());
+                } else {
+                    match by.as_key().trim().parse() {
+                        Ok(score) => { item.score = score; },
+                        Err(_) => {
+                            c.add_reply_error(&errors::err("One or more scores can't be converted into double"));
+                            return;
+                        },
+                    }
+                }
+            }
+        }
+        items.push(item);
+    }
+
+    if !dont_sort {
+        // A stable sort: elements comparing equal keep their order
+        items.sort_by(|a, b| {
+            let ord = match alpha {
+                true => a.cmp.cmp(&b.cmp),
+                false => a.score.partial_cmp(&b.score).unwrap_or(std::cmp::Ordering::Equal),
+            };
+            if desc { ord.reverse() } else { ord }
+        });
+    }
+
+    let len = items.len() as i64;
+    let (mut start, mut end) = match limit {
+        Some((offset, count)) => {
+            let start = offset.max(0);
+            (start, if count < 0 { len - 1 } else { start.saturating_add(count) - 1 })
+        },
+        None => (0, len - 1),
+    };
+    if start >= len {
+        start = len;
+        end = len - 1;
+    }
+    end = end.min(len - 1);
+    let range = &items[start as usize..(end + 1).max(start) as usize];
+
+    let mut output: Vec<Option<RedisObject>> = Vec::with_capacity(range.len() * gets.len().max(1));
+    for item in range {
+        if gets.is_empty() {
+            output.push(Some(item.ele.clone()));
+            continue;
+        }
+        for pattern in &gets {
+            output.push(lookup_key_by_pattern(c, pattern, &item.ele));
+        }
+    }
+
+    match store {
+        None => {
+            c.add_reply_str(&format!("*{}\r\n", output.len()));
+            for val in output {
+                match val {
+                    Some(val) => { c.add_reply_bulk(Arc::new(RwLock::new(val))); },
+                    None => { c.add_reply(NULL_BULK.clone()); },
+                }
+            }
+        },
+        Some(dst) => {
+            // Missing values are stored as empty strings, a list can't
+            // hold nils. An empty result just deletes the destination.
+            let len = output.len();
+            let existed = c.delete_key(&dst);
+            if len > 0 {
+                let list: LinkedList<RedisObject> = output.into_iter()
+                    .map(|val| val.unwrap_or_else(|| RedisObject::String { ptr: StringStorageType::String(String::new()) }.tracked()))
+                    .collect();
+                c.insert(&dst, Arc::new(RwLock::new(RedisObject::List { l: ListStorageType::LinkedList(list) }.tracked())));
+            }
+            if len > 0 || existed {
+                server_write().dirty += 1;
+                c.signal_modified_key(&dst);
+            }
+            c.add_reply_u64(len as u64);
+        },
+    }
+}
+
+fn save_command(c: &mut RedisClient) {
+    if server_read().bg_save_child_pid != -1 {
+        c.add_reply_error(&errors::err("background save in progress"));
+        return;
+    }
+    let file = server_read().db_filename.clone();
+    if rdb_save(&file) {
+        c.add_reply(OK.clone());
+    } else {
+        c.add_reply(ERR.clone());
+    }
+}
+
+fn bgsave_command(c: &mut RedisClient) {
+    if server_read().bg_save_child_pid != -1 {
+        c.add_reply_error(&errors::err("background save already in progress"));
+        return;
+    }
+    let file = server_read().db_filename.clone();
+    if rdb_save_background(&file) {
+        c.add_reply_str("+Background saving started\r\n");
+    } else {
+        c.add_reply(ERR.clone());
+    }
+}
+
+fn lastsave_command(c: &mut RedisClient) {
+    c.add_reply_str(&format!(":{}\r\n", server_read().last_save));
+}
+
+fn shutdown_command(c: &mut RedisClient) {
+    log(LogLevel::Warning, "User requested shutdown, saving DB...");
+    // Kill the saving child if there is a background saving in progress.
+    // We want to avoid race conditions, for instance our saving child may
+    // overwrite the synchronous saving did by SHUTDOWN.
+    if server_read().bg_save_child_pid != -1 {
+        log(LogLevel::Warning, "There is a live saving child. Killing it!");
+        unsafe {
+            kill(server_read().bg_save_child_pid, SIGKILL);
+        }
+        rdb_remove_temp_file(server_read().bg_save_child_pid);
     }
     if server_read().append_only {
         // Append only file: fsync() the AOF and exit
@@ -1835,6 +2934,8 @@ fn shutdown_command(c: &mut RedisClient) {
                 return;
             },
         }
+        server_teardown();
+        c.close_connection();
         exit(0);
     } else {
         // Snapshotting. Perform a SYNC SAVE and exit
@@ -1852,6 +2953,8 @@ fn shutdown_command(c: &mut RedisClient) {
 
             log(LogLevel::Warning, &format!("{} bytes used at exit", MemCounter::used_memory()));
             log(LogLevel::Warning, "Server exit now, bye bye...");
+            server_teardown();
+            c.close_connection();
             exit(0);
         } else {
             // Ooops.. error saving! The best we can do is to continue
@@ -1860,14 +2963,14 @@ fn shutdown_command(c: &mut RedisClient) {
             // saving aborted, handling special stuff like slaves pending for
             // synchronization...
             log(LogLevel::Warning, "Error trying to save the DB, can't exit");
-            c.add_reply_str("-ERR can't quit, problems saving the DB\r\n");
+            c.add_reply_error(&errors::err("can't quit, problems saving the DB"));
         }
     }
 }
 
 fn bgrewriteaof_command(c: &mut RedisClient) {
     if server_read().bg_rewrite_child_pid != -1 {
-        c.add_reply_str("-ERR background append only file rewriting already in progress\r\n");
+        c.add_reply_error(&errors::err("background append only file rewriting already in progress"));
         return;
     }
     if rewrite_append_only_file_background() {
@@ -1878,13 +2981,2182 @@ fn bgrewriteaof_command(c: &mut RedisClient) {
 }
 
 fn info_command(c: &mut RedisClient) {
-    
+    let info = server_read().gen_redis_info_string();
+    c.add_reply_bulk_str(&info);
 }
 
+/// MONITOR
+/// From now on the client gets every command the server processes.
 fn monitor_command(c: &mut RedisClient) {
-    
+    // Ignore MONITOR if already slave or in monitor mode
+    if c.flags.is_slave() || c.flags.is_monitor() {
+        return;
+    }
+    c.flags.enable(ClientFlags::monitor());
+    server_write().monitors.push_back(c.id());
+    c.add_reply(OK.clone());
 }
 
+/// REPLICAOF <host> <port>
+/// REPLICAOF NO ONE
+/// SLAVEOF is the legacy name of the same command.
 fn slaveof_command(c: &mut RedisClient) {
-    
+    let host = c.argv[1].read().unwrap().as_key().to_string();
+    let port = c.argv[2].read().unwrap().as_key().to_string();
+    if host.eq_ignore_ascii_case("no") && port.eq_ignore_ascii_case("one") {
+        let was_slave = server_read().is_slave();
+        if was_slave {
+            close_master_link();
+            server_write().unset_master();
+            log(LogLevel::Notice, "MASTER MODE enabled (user request)");
+        }
+    } else {
+        match port.parse() {
+            Ok(port) => {
+                close_master_link();
+                server_write().set_master(&host, port);
+                log(LogLevel::Notice, &format!("SLAVE OF {}:{} enabled (user request)", host, port));
+            },
+            Err(_) => {
+                c.add_reply_error(&errors::err("value is not an integer or out of range"));
+                return;
+            },
+        }
+    }
+    c.add_reply(OK.clone());
+}
+
+/// Reply to <CMD> HELP: a header, then every subcommand usage followed by
+/// its description, then HELP itself. Lines are status replies.
+pub fn reply_subcommand_help(c: &mut RedisClient, subcommands: &[(&str, &str)]) {
+    let cmd = c.argv[0].read().unwrap().as_key().to_uppercase();
+    c.add_reply_str(&format!("*{}\r\n", subcommands.len() * 2 + 3));
+    c.add_reply_str(&format!("+{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:\r\n", cmd));
+    for (usage, description) in subcommands.iter().chain([("HELP", "Print this help.")].iter()) {
+        c.add_reply_str(&format!("+{}\r\n+    {}\r\n", usage, description));
+    }
+}
+
+static COMMAND_HELP: &[(&str, &str)] = &[
+    ("COUNT", "Return the total number of commands in this Redis server."),
+    ("DOCS [<command-name> ...]", "Return documentation details about multiple Redis commands."),
+];
+
+/// COMMAND
+/// COMMAND COUNT
+/// COMMAND DOCS [name ...]
+///
+/// Without a subcommand, reply every command of the table as its name, its
+/// arity and its flags, sorted by name.
+fn command_command(c: &mut RedisClient) {
+    if c.argv.len() == 1 {
+        let mut names: Vec<&str> = CMD_TABLE.keys().copied().collect();
+        names.sort();
+        let table = names.into_iter().map(|name| {
+            let cmd = lookup_original_command(name).unwrap();
+            let mut flags = vec![Value::bulk(if cmd.flags().is_write() { "write" } else { "readonly" })];
+            if cmd.flags().is_deny_oom() {
+                flags.push(Value::bulk("denyoom"));
+            }
+            if (cmd.contexts.0 & CmdContext::master().0) == 0 {
+                flags.push(Value::bulk("admin"));
+            }
+            Value::Array(Some(vec![
+                Value::bulk(name),
+                Value::Integer(cmd.arity() as i64),
+                Value::Array(Some(flags)),
+            ]))
+        }).collect();
+        c.add_reply_value(&Value::Array(Some(table)));
+        return;
+    }
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, COMMAND_HELP);
+        },
+        "count" if c.argv.len() == 2 => {
+            c.add_reply_u64(CMD_TABLE.len() as u64);
+        },
+        "docs" => {
+            // Unknown names are just left out of the reply
+            let mut names: Vec<String> = c.argv[2..].iter()
+                .map(|n| n.read().unwrap().as_key().to_lowercase())
+                .filter(|n| CMD_TABLE.contains_key(&n[..]))
+                .collect();
+            if c.argv.len() == 2 {
+                names = CMD_TABLE.keys().map(|n| n.to_string()).collect();
+                names.sort();
+            }
+
+            let mut docs = Vec::with_capacity(names.len() * 2);
+            for name in names {
+                let cmd = lookup_original_command(&name).unwrap();
+                let (group, since, summary) = CMD_DOCS.iter()
+                    .find(|d| d.0 == name)
+                    .map_or(("generic", "1.0.0", ""), |d| (d.1, d.2, d.3));
+                docs.push(Value::bulk(&name));
+                docs.push(Value::Array(Some(vec![
+                    Value::bulk("summary"), Value::bulk(summary),
+                    Value::bulk("since"), Value::bulk(since),
+                    Value::bulk("group"), Value::bulk(group),
+                    Value::bulk("arity"), Value::Integer(cmd.arity() as i64),
+                ])));
+            }
+            c.add_reply_value(&Value::Array(Some(docs)));
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("command", &c.arg(1)));
+        },
+    }
+}
+
+static CLIENT_HELP: &[(&str, &str)] = &[
+    ("LIST", "Return information about client connections."),
+    ("INFO", "Return information about the current client connection."),
+];
+
+/// CLIENT LIST
+/// CLIENT INFO
+fn client_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, CLIENT_HELP);
+        },
+        "list" if c.argv.len() == 2 => {
+            // The caller is write locked: it's the client the lock fails on
+            let mut list = String::new();
+            for other in clients_read().iter() {
+                match other.try_read() {
+                    Ok(other) => list.push_str(&other.info_line()),
+                    Err(_) => list.push_str(&c.info_line()),
+                }
+                list.push('\n');
+            }
+            c.add_reply_bulk_str(&list);
+        },
+        "info" if c.argv.len() == 2 => {
+            let line = c.info_line() + "\n";
+            c.add_reply_bulk_str(&line);
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("client", &c.arg(1)));
+        },
+    }
+}
+
+static OBJECT_HELP: &[(&str, &str)] = &[
+    ("ENCODING <key>", "Return the kind of internal representation used in order to store the value associated with a <key>."),
+];
+
+/// OBJECT ENCODING <key>
+fn object_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, OBJECT_HELP);
+        },
+        "encoding" if c.argv.len() == 3 => {
+            match c.lookup_key_read(c.argv[2].read().unwrap().as_key()) {
+                Some(obj) => {
+                    let encoding = obj.read().unwrap().encoding();
+                    c.add_reply_bulk_str(encoding);
+                },
+                None => { c.add_reply(NULL_BULK.clone()); },
+            }
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("object", &c.arg(1)));
+        },
+    }
+}
+
+static DEBUG_HELP: &[(&str, &str)] = &[
+    ("SLEEP <seconds>", "Stop the server for <seconds>. Decimals are allowed."),
+    ("SLEEP-AFTER-FORK <seconds>", "Make the next background save child sleep <seconds> before saving."),
+    ("FAIL-NEXT-BGSAVE", "Make the next background save fail."),
+    ("FAIL-NEXT-AOF-WRITE", "Make the next write to the append only file fail."),
+    ("SET-SORTED-REPLIES <0|1>", "Reply with set elements in a deterministic order."),
+    ("EVENTS GET [<count>]|RESET", "Return or clear the latest keyspace events."),
+    ("OBJECT-COUNT", "Return the number of live objects."),
+    ("OBJECT <key>", "Show low level info about the <key> and associated value."),
+    ("DICT-STATS [<dbid>]", "Show statistics about the main dictionary of the databases."),
+    ("POPULATE <count> [<prefix>] [<size>]", "Create <count> string keys named key:<num>, or <prefix>:<num>."),
+];
+
+fn debug_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, DEBUG_HELP);
+        },
+        "sleep" if c.argv.len() == 3 => {
+            let secs: f64 = c.argv[2].read().unwrap().as_key().parse().unwrap_or(0f64);
+            sleep(Duration::from_secs_f64(secs.max(0f64)));
+            c.add_reply(OK.clone());
+        },
+        "sleep-after-fork" if c.argv.len() == 3 => {
+            let secs: Result<u64, _> = c.argv[2].read().unwrap().as_key().parse();
+            match secs {
+                Ok(secs) => { debug_arm_fault(c, |f| f.sleep_after_fork = secs); },
+                Err(_) => { c.add_reply_error(&errors::err("value is not an integer or out of range")); },
+            }
+        },
+        "fail-next-bgsave" if c.argv.len() == 2 => {
+            debug_arm_fault(c, |f| f.fail_next_bgsave = true);
+        },
+        "fail-next-aof-write" if c.argv.len() == 2 => {
+            debug_arm_fault(c, |f| f.fail_next_aof_write = true);
+        },
+        // Test-only: emit set elements in a deterministic order.
+        "set-sorted-replies" if c.argv.len() == 3 => {
+            let on = c.argv[2].read().unwrap().as_key().to_string();
+            match &on[..] {
+                "0" | "1" => {
+                    server_write().debug_sorted_replies = on == "1";
+                    c.add_reply(OK.clone());
+                },
+                _ => { c.add_reply(SYNTAX_ERR.clone()); },
+            }
+        },
+        "events" if c.argv.len() >= 3 => {
+            debug_events(c);
+        },
+        "object-count" if c.argv.len() == 2 => {
+            debug_object_count(c);
+        },
+        "populate" if (3..=5).contains(&c.argv.len()) => {
+            debug_populate(c);
+        },
+        "dict-stats" if c.argv.len() <= 3 => {
+            debug_dict_stats(c);
+        },
+        "object" if c.argv.len() == 3 => {
+            match c.lookup_key_read(c.argv[2].read().unwrap().as_key()) {
+                Some(obj) => {
+                    c.add_reply_str(&format!("+Value at:{:p} refcount:{} encoding:{}\r\n",
+                        Arc::as_ptr(&obj), Arc::strong_count(&obj) - 1, obj.read().unwrap().encoding()));
+                },
+                None => { c.add_reply(NO_KEY_ERR.clone()); },
+            }
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("debug", &c.arg(1)));
+        },
+    }
+}
+
+/// DEBUG EVENTS GET [<count>]: the latest keyspace events, oldest first,
+/// as [op, db, key, unix time in ms] entries.
+/// DEBUG EVENTS RESET
+#[cfg(feature = "testing-hooks")]
+fn debug_events(c: &mut RedisClient) {
+    use crate::server::{KeyEvent, KEY_EVENTS_LEN};
+
+    let sub = c.argv[2].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "get" if c.argv.len() <= 4 => {
+            let mut count = KEY_EVENTS_LEN;
+            if c.argv.len() == 4 {
+                let Some(n) = int_arg_or_reply::<usize>(c, 3) else { return; };
+                count = n;
+            }
+            let events: Vec<KeyEvent> = {
+                let server = server_read();
+                let skip = server.key_events.len().saturating_sub(count);
+                server.key_events.iter().skip(skip).cloned().collect()
+            };
+            c.add_reply_str(&format!("*{}\r\n", events.len()));
+            for event in events {
+                c.add_reply_str("*4\r\n");
+                c.add_reply_bulk_str(&event.op);
+                c.add_reply_str(&format!(":{}\r\n", event.db));
+                c.add_reply_bulk_str(&event.key);
+                c.add_reply_str(&format!(":{}\r\n", event.time));
+            }
+        },
+        "reset" if c.argv.len() == 3 => {
+            server_write().key_events.clear();
+            c.add_reply(OK.clone());
+        },
+        _ => { c.add_reply(SYNTAX_ERR.clone()); },
+    }
+}
+#[cfg(not(feature = "testing-hooks"))]
+fn debug_events(c: &mut RedisClient) {
+    c.add_reply_error(&errors::err("the keyspace events log requires a build with the testing-hooks feature"));
+}
+
+/// DEBUG POPULATE count [prefix] [size]
+///
+/// Seed the current db with `count` keys named "prefix:N", "key:N" by
+/// default, for benchmarks and tests. The value is N, or N padded with
+/// zero bytes (or truncated) to `size` bytes. Existing keys are left
+/// alone. The keys are inserted straight in the dict: nothing is
+/// propagated and dirty isn't bumped.
+fn debug_populate(c: &mut RedisClient) {
+    let Ok(count) = c.arg(2).parse::<u64>() else {
+        c.add_reply_error(&errors::err("value is out of range"));
+        return;
+    };
+    let prefix = if c.argv.len() >= 4 { c.arg(3).to_string() } else { "key".to_string() };
+    let size = if c.argv.len() == 5 {
+        match c.arg(4).parse::<usize>() {
+            Ok(size) => Some(size),
+            Err(_) => {
+                c.add_reply_error(&errors::err("value is out of range"));
+                return;
+            },
+        }
+    } else {
+        None
+    };
+
+    let db = c.db.clone().expect("clients always have a db selected");
+    let mut db_w = db.write().unwrap();
+    for n in 0..count {
+        let key = format!("{}:{}", prefix, n);
+        if db_w.dict.contains_key(&key) {
+            continue;
+        }
+        let ptr = match size {
+            Some(size) => {
+                let mut value = n.to_string();
+                value.truncate(size);
+                value.extend(std::iter::repeat_n('\0', size - value.len()));
+                StringStorageType::String(value)
+            },
+            None => StringStorageType::Integer(n as isize),
+        };
+        db_w.dict.insert(key, Arc::new(RwLock::new(RedisObject::String { ptr }.tracked())));
+    }
+    drop(db_w);
+    c.add_reply(OK.clone());
+}
+
+/// Keys DEBUG DICT-STATS looks at: bigger dbs are sampled.
+const DICT_STATS_SAMPLES: usize = 10_000;
+/// Time DEBUG DICT-STATS may spend walking the values.
+const DICT_STATS_BUDGET: Duration = Duration::from_millis(100);
+/// Elements looked at to estimate the size of a value.
+const DICT_STATS_SIZE_SAMPLES: usize = 5;
+/// Biggest keys reported by DEBUG DICT-STATS.
+const DICT_STATS_BIGGEST: usize = 5;
+
+/// DEBUG DICT-STATS [db]
+///
+/// What the db holds, in the INFO format: keys per type and their average
+/// element count, keys per encoding and the biggest keys by estimated size.
+/// Above DICT_STATS_SAMPLES keys a random sample is looked at, and the walk
+/// stops after DICT_STATS_BUDGET: 'scanned' is the number of keys counted.
+fn debug_dict_stats(c: &mut RedisClient) {
+    let db = if c.argv.len() == 3 {
+        let dbnum = server_read().dbnum;
+        match c.arg(2).parse::<i32>() {
+            Ok(id) if id >= 0 && id < dbnum => server_read().dbs[id as usize].clone(),
+            _ => {
+                c.add_reply_error(&errors::err("DB index is out of range"));
+                return;
+            },
+        }
+    } else {
+        c.db.clone().expect("clients always have a db selected")
+    };
+
+    // Values are read after the db lock is released, see server.rs
+    let (id, keys, entries) = {
+        let db_r = db.read().unwrap();
+        let entries: Vec<(String, Arc<RwLock<RedisObject>>)> = if db_r.dict.len() > DICT_STATS_SAMPLES {
+            db_r.dict.sample(DICT_STATS_SAMPLES)
+                .into_iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        } else {
+            db_r.dict.iter().map(|(k, v)| (k.clone(), v.clone())).collect()
+        };
+        (db_r.id, db_r.dict.len(), entries)
+    };
+
+    let start = Instant::now();
+    let mut scanned = 0usize;
+    let mut types: BTreeMap<&str, (usize, usize)> = BTreeMap::new();
+    let mut encodings: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut biggest: Vec<(usize, &str, String)> = Vec::new();
+    for (key, value) in entries {
+        if start.elapsed() > DICT_STATS_BUDGET {
+            break;
+        }
+        let value_r = value.read().unwrap();
+        let ty = type_name(&value_r);
+        let stats = types.entry(ty).or_default();
+        stats.0 += 1;
+        stats.1 += value_r.element_count();
+        *encodings.entry(value_r.encoding()).or_default() += 1;
+        biggest.push((value_r.estimated_size(DICT_STATS_SIZE_SAMPLES), ty, key));
+        if biggest.len() > 2 * DICT_STATS_BIGGEST {
+            biggest.sort_by_key(|b| Reverse(b.0));
+            biggest.truncate(DICT_STATS_BIGGEST);
+        }
+        scanned += 1;
+    }
+    biggest.sort_by_key(|b| Reverse(b.0));
+    biggest.truncate(DICT_STATS_BIGGEST);
+
+    let mut stats = format!("# Keyspace\r\ndb:{}\r\nkeys:{}\r\nscanned:{}\r\nsampled:{}\r\n",
+        id, keys, scanned, (keys > DICT_STATS_SAMPLES) as u8);
+    stats.push_str("# Types\r\n");
+    for (ty, (count, elements)) in types {
+        stats.push_str(&format!("{}:keys={},avg_elements={:.2}\r\n", ty, count, elements as f64 / count as f64));
+    }
+    stats.push_str("# Encodings\r\n");
+    for (encoding, count) in encodings {
+        stats.push_str(&format!("{}:{}\r\n", encoding, count));
+    }
+    stats.push_str("# Biggest keys\r\n");
+    for (i, (size, ty, key)) in biggest.iter().enumerate() {
+        stats.push_str(&format!("key{}:size={},type={},name={}\r\n", i, size, ty, key));
+    }
+    c.add_reply_bulk_str(&stats);
+}
+
+#[cfg(feature = "testing-hooks")]
+fn debug_object_count(c: &mut RedisClient) {
+    c.add_reply_str(&format!(":{}\r\n", RedisObject::live_objects()));
+}
+#[cfg(not(feature = "testing-hooks"))]
+fn debug_object_count(c: &mut RedisClient) {
+    c.add_reply_error(&errors::err("object counting requires a build with the testing-hooks feature"));
+}
+
+#[cfg(feature = "testing-hooks")]
+fn debug_arm_fault(c: &mut RedisClient, arm: impl FnOnce(&mut DebugFaults)) {
+    arm(&mut server_write().debug_faults);
+    c.add_reply(OK.clone());
+}
+#[cfg(not(feature = "testing-hooks"))]
+fn debug_arm_fault(c: &mut RedisClient, _arm: impl FnOnce(&mut DebugFaults)) {
+    c.add_reply_error(&errors::err("fault injection requires a build with the testing-hooks feature"));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::remove_file, path::Path, process::id, sync::{Arc, RwLock}};
+    use crate::{client::tests::{listed_client, run, take_replies, test_client, unlist}, server::{server_read, server_write, tests::{parse_info, setup}}};
+    use crate::obj::{RedisObject, StringStorageType, EMBSTR_SIZE_LIMIT};
+    use super::{lookup_command, lookup_original_command, ArityError, CMD_DOCS, MAX_COMMAND_NAME_LEN, CMD_TABLE, COMMAND_HELP, DEBUG_HELP, OBJECT_HELP};
+
+    /// `cargo test --release -- --ignored lookup_command_throughput --nocapture`.
+    #[test]
+    #[ignore]
+    fn lookup_command_throughput() {
+        let _guard = setup();
+        for name in ["get", "ZRANGEBYSCORE"] {
+            let n = 10_000_000;
+            let start = std::time::Instant::now();
+            for _ in 0..n {
+                assert!(lookup_command(std::hint::black_box(name)).is_some());
+            }
+            println!("lookup_command {}: {:.0} ops/sec", name, n as f64 / start.elapsed().as_secs_f64());
+        }
+    }
+
+    #[test]
+    fn debug_populate_seeds_the_current_db() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "key:7", "mine"]);
+        assert_eq!(run(&mut c, &["DEBUG", "POPULATE", "1000"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":1000\r\n");
+        assert_eq!(run(&mut c, &["GET", "key:999"]), "$3\r\n999\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "key:999"]), "$3\r\nint\r\n");
+        assert_eq!(run(&mut c, &["GET", "key:7"]), "$4\r\nmine\r\n");
+
+        assert_eq!(run(&mut c, &["DEBUG", "POPULATE", "2", "bench", "4"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["GET", "bench:1"]), "$4\r\n1\0\0\0\r\n");
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":1002\r\n");
+        assert_eq!(run(&mut c, &["DEBUG", "POPULATE", "-1"]), "-ERR value is out of range\r\n");
+    }
+
+    #[test]
+    fn dict_stats_counts_types_and_encodings() {
+        let _guard = setup();
+        let mut c = test_client();
+        for i in 0..3 {
+            run(&mut c, &["INCRBY", &format!("int:{}", i), &i.to_string()]);
+        }
+        run(&mut c, &["SET", "short", "abc"]);
+        run(&mut c, &["SET", "long", &"x".repeat(EMBSTR_SIZE_LIMIT + 1)]);
+        for i in 0..100 {
+            run(&mut c, &["RPUSH", "biglist", &"v".repeat(50 + i)]);
+        }
+        run(&mut c, &["RPUSH", "smalllist", "a"]);
+        run(&mut c, &["RPUSH", "smalllist", "b"]);
+        for ele in ["a", "b", "c", "d"] {
+            run(&mut c, &["SADD", "set", ele]);
+        }
+        run(&mut c, &["ZADD", "zset", "1", "a"]);
+        run(&mut c, &["SELECT", "1"]);
+        run(&mut c, &["SET", "elsewhere", "1"]);
+
+        let info = parse_info(&run(&mut c, &["DEBUG", "DICT-STATS", "0"]));
+        assert_eq!(info["db"], "0");
+        assert_eq!(info["keys"], "9");
+        assert_eq!(info["scanned"], "9");
+        assert_eq!(info["sampled"], "0");
+        assert_eq!(info["string"], "keys=5,avg_elements=10.20");
+        assert_eq!(info["list"], "keys=2,avg_elements=51.00");
+        assert_eq!(info["set"], "keys=1,avg_elements=4.00");
+        assert_eq!(info["zset"], "keys=1,avg_elements=1.00");
+        assert_eq!(info["int"], "3");
+        assert_eq!(info["embstr"], "1");
+        assert_eq!(info["raw"], "1");
+        assert_eq!(info["linkedlist"], "2");
+        assert!(info["key0"].ends_with(",type=list,name=biglist"), "{}", info["key0"]);
+        assert!(info.contains_key("key4") && !info.contains_key("key5"));
+
+        // The current db by default
+        let info = parse_info(&run(&mut c, &["DEBUG", "DICT-STATS"]));
+        assert_eq!(info["db"], "1");
+        assert_eq!(info["keys"], "1");
+        assert_eq!(run(&mut c, &["DEBUG", "DICT-STATS", "99"]), "-ERR DB index is out of range\r\n");
+    }
+
+    #[test]
+    fn sort_numbers_desc_with_limit() {
+        let _guard = setup();
+        let mut c = test_client();
+        for n in ["3", "10", "-1", "2.5", "7"] {
+            run(&mut c, &["RPUSH", "l", n]);
+        }
+        assert_eq!(run(&mut c, &["SORT", "l"]), "*5\r\n$2\r\n-1\r\n$3\r\n2.5\r\n$1\r\n3\r\n$1\r\n7\r\n$2\r\n10\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "DESC", "LIMIT", "1", "2"]), "*2\r\n$1\r\n7\r\n$1\r\n3\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "LIMIT", "4", "10"]), "*1\r\n$2\r\n10\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "LIMIT", "5", "1"]), "*0\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "LIMIT", "x", "1"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "LIMIT", "1"]), "-ERR syntax error\r\n");
+        // The list itself is left alone
+        assert_eq!(run(&mut c, &["LINDEX", "l", "0"]), "$1\r\n3\r\n");
+
+        run(&mut c, &["RPUSH", "l", "abc"]);
+        assert_eq!(run(&mut c, &["SORT", "l"]), "-ERR One or more scores can't be converted into double\r\n");
+        assert_eq!(run(&mut c, &["SORT", "missing"]), "*0\r\n");
+        run(&mut c, &["SET", "s", "v"]);
+        assert_eq!(run(&mut c, &["SORT", "s"]), "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+    }
+
+    #[test]
+    fn sort_alpha_by_and_get() {
+        let _guard = setup();
+        let mut c = test_client();
+        for ele in ["pear", "apple", "fig"] {
+            run(&mut c, &["SADD", "fruits", ele]);
+        }
+        assert_eq!(run(&mut c, &["SORT", "fruits"]), "-ERR One or more scores can't be converted into double\r\n");
+        assert_eq!(run(&mut c, &["SORT", "fruits", "ALPHA"]), "*3\r\n$5\r\napple\r\n$3\r\nfig\r\n$4\r\npear\r\n");
+        assert_eq!(run(&mut c, &["SORT", "fruits", "ALPHA", "DESC", "LIMIT", "0", "1"]), "*1\r\n$4\r\npear\r\n");
+
+        // BY weights, GET the element and another key, a missing one is nil
+        run(&mut c, &["SET", "weight_pear", "1"]);
+        run(&mut c, &["SET", "weight_apple", "3"]);
+        run(&mut c, &["SET", "weight_fig", "2"]);
+        run(&mut c, &["SET", "color_pear", "green"]);
+        run(&mut c, &["SET", "color_apple", "red"]);
+        assert_eq!(run(&mut c, &["SORT", "fruits", "BY", "weight_*", "GET", "#", "GET", "color_*"]),
+            "*6\r\n$4\r\npear\r\n$5\r\ngreen\r\n$3\r\nfig\r\n$-1\r\n$5\r\napple\r\n$3\r\nred\r\n");
+
+        // A BY pattern without '*' doesn't sort, not even numbers
+        run(&mut c, &["RPUSH", "l", "3"]);
+        run(&mut c, &["RPUSH", "l", "1"]);
+        run(&mut c, &["RPUSH", "l", "x"]);
+        assert_eq!(run(&mut c, &["SORT", "l", "BY", "nosort"]), "*3\r\n$1\r\n3\r\n$1\r\n1\r\n$1\r\nx\r\n");
+
+        for (score, ele) in [("2", "b"), ("1", "a")] {
+            run(&mut c, &["ZADD", "z", score, ele]);
+        }
+        assert_eq!(run(&mut c, &["SORT", "z", "BY", "nosort", "GET", "#"]), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(run(&mut c, &["SORT", "z", "ALPHA", "DESC"]), "*2\r\n$1\r\nb\r\n$1\r\na\r\n");
+    }
+
+    #[test]
+    fn sort_by_and_get_hash_fields_and_store() {
+        let _guard = setup();
+        let mut c = test_client();
+        for id in ["1", "2", "3", "4"] {
+            run(&mut c, &["RPUSH", "uids", id]);
+        }
+        run(&mut c, &["HSET", "user:1", "age", "30"]);
+        run(&mut c, &["HSET", "user:2", "age", "20"]);
+        run(&mut c, &["HSET", "user:3", "age", "30"]);
+        run(&mut c, &["HSET", "user:1", "name", "ann"]);
+        run(&mut c, &["HSET", "user:2", "name", "bob"]);
+        run(&mut c, &["HSET", "user:3", "name", "cid"]);
+
+        // user:4 has no age: it weighs 0. Equal ages keep the list order.
+        assert_eq!(run(&mut c, &["SORT", "uids", "BY", "user:*->age"]), "*4\r\n$1\r\n4\r\n$1\r\n2\r\n$1\r\n1\r\n$1\r\n3\r\n");
+        assert_eq!(run(&mut c, &["SORT", "uids", "BY", "user:*->age", "LIMIT", "1", "2", "GET", "user:*->name", "GET", "#"]),
+            "*4\r\n$3\r\nbob\r\n$1\r\n2\r\n$3\r\nann\r\n$1\r\n1\r\n");
+        assert_eq!(run(&mut c, &["SORT", "uids", "LIMIT", "2", "2", "GET", "user:*->name"]), "*2\r\n$3\r\ncid\r\n$-1\r\n");
+        // A field of something else than a hash is nil. Without a field
+        // the "->" is part of the key name.
+        run(&mut c, &["SET", "user:3", "plain"]);
+        run(&mut c, &["SET", "user:3->", "arrow"]);
+        assert_eq!(run(&mut c, &["SORT", "uids", "LIMIT", "2", "1", "GET", "user:*->name", "GET", "user:*->"]), "*2\r\n$-1\r\n$5\r\narrow\r\n");
+
+        // BY string keys, the result stored as a list, nils as empty strings
+        run(&mut c, &["SET", "weight_1", "3"]);
+        run(&mut c, &["SET", "weight_2", "1"]);
+        run(&mut c, &["SET", "weight_3", "2"]);
+        run(&mut c, &["SET", "weight_4", "4"]);
+        assert_eq!(run(&mut c, &["SORT", "uids", "BY", "weight_*", "LIMIT", "0", "3", "STORE", "out"]), ":3\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "out", "0", "-1"]), "*3\r\n$1\r\n2\r\n$1\r\n3\r\n$1\r\n1\r\n");
+        assert_eq!(run(&mut c, &["SORT", "uids", "BY", "weight_*", "DESC", "GET", "user:*->name", "STORE", "out"]), ":4\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "out", "0", "-1"]), "*4\r\n$0\r\n\r\n$3\r\nann\r\n$0\r\n\r\n$3\r\nbob\r\n");
+
+        // An empty result deletes the destination
+        assert_eq!(run(&mut c, &["SORT", "uids", "LIMIT", "10", "1", "STORE", "out"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "out"]), ":0\r\n");
+        run(&mut c, &["SET", "out", "x"]);
+        assert_eq!(run(&mut c, &["SORT", "missing", "STORE", "out"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "out"]), ":0\r\n");
+    }
+
+    #[test]
+    fn sort_store_is_refused_on_a_read_only_replica() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["RPUSH", "l", "2"]);
+        run(&mut c, &["RPUSH", "l", "1"]);
+        assert_eq!(run(&mut c, &["SLAVEOF", "127.0.0.1", "6380"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "STORE", "out"]), "-READONLY You can't write against a read only replica.\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "out"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["SLAVEOF", "no", "one"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SORT", "l", "STORE", "out"]), ":2\r\n");
+    }
+
+    #[test]
+    fn blpop_pops_or_blocks_until_a_push() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["RPUSH", "l", "a"]);
+        run(&mut c, &["RPUSH", "l", "b"]);
+        assert_eq!(run(&mut c, &["BLPOP", "missing", "l", "0"]), "*2\r\n$1\r\nl\r\n$1\r\na\r\n");
+        assert_eq!(run(&mut c, &["BRPOP", "l", "0"]), "*2\r\n$1\r\nl\r\n$1\r\nb\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "l"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["BLPOP", "l", "-1"]), "-ERR timeout is negative\r\n");
+        assert_eq!(run(&mut c, &["BLPOP", "l", "x"]), "-ERR timeout is not a float or out of range\r\n");
+        run(&mut c, &["SET", "s", "v"]);
+        assert_eq!(run(&mut c, &["BLPOP", "s", "0"]), "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n");
+
+        // Blocked with no timeout: the first push goes to the longest waiting
+        let first = listed_client();
+        let second = listed_client();
+        assert_eq!(run(&mut first.write().unwrap(), &["BLPOP", "a", "b", "0"]), "");
+        assert_eq!(run(&mut second.write().unwrap(), &["BRPOP", "b", "0"]), "");
+        assert_eq!(server_read().blpop_blocked_clients, 2);
+        crate::handler::handle_blocked_clients_timeout();
+        assert!(first.read().unwrap().flags.is_blocked());
+
+        assert_eq!(run(&mut c, &["LPUSH", "b", "x"]), ":1\r\n");
+        assert_eq!(take_replies(&mut first.write().unwrap()), "*2\r\n$1\r\nb\r\n$1\r\nx\r\n");
+        assert!(!first.read().unwrap().flags.is_blocked());
+        assert_eq!(run(&mut c, &["EXISTS", "b"]), ":0\r\n");
+
+        run(&mut c, &["RPUSH", "src", "y"]);
+        assert_eq!(run(&mut c, &["RPOPLPUSH", "src", "b"]), "$1\r\ny\r\n");
+        assert_eq!(take_replies(&mut second.write().unwrap()), "*2\r\n$1\r\nb\r\n$1\r\ny\r\n");
+        assert_eq!(server_read().blpop_blocked_clients, 0);
+        assert_eq!(run(&mut c, &["LPUSH", "a", "z"]), ":1\r\n");
+        unlist(&first);
+        unlist(&second);
+    }
+
+    #[test]
+    fn blpop_times_out_and_never_blocks_in_multi() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["BLPOP", "l", "0"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["EXEC"]), "*1\r\n*-1\r\n");
+        assert!(!c.flags.is_blocked());
+
+        let waiting = listed_client();
+        assert_eq!(run(&mut waiting.write().unwrap(), &["BLPOP", "l", "0.01"]), "");
+        std::thread::sleep(std::time::Duration::from_millis(20));
+        crate::handler::handle_blocked_clients_timeout();
+        assert_eq!(take_replies(&mut waiting.write().unwrap()), "*-1\r\n");
+        assert!(!waiting.read().unwrap().flags.is_blocked());
+        assert_eq!(server_read().blpop_blocked_clients, 0);
+
+        // Freeing a blocked client takes it out of the queues
+        let gone = listed_client();
+        run(&mut gone.write().unwrap(), &["BLPOP", "l", "0"]);
+        unlist(&gone);
+        drop(gone);
+        assert_eq!(run(&mut c, &["LPUSH", "l", "v"]), ":1\r\n");
+        assert_eq!(server_read().blpop_blocked_clients, 0);
+        unlist(&waiting);
+    }
+
+    #[test]
+    fn reset_unblocks_and_returns_the_connection_to_baseline() {
+        let _guard = setup();
+        let mut c = test_client();
+        let blocked = listed_client();
+        {
+            // BLPOP and a pipelined RESET arrive in the same read
+            let mut b = blocked.write().unwrap();
+            b.query_buf.push_str("*3\r\n$5\r\nBLPOP\r\n$1\r\nl\r\n$1\r\n0\r\nRESET\r\n");
+            b.process_input_buf();
+            assert_eq!(take_replies(&mut b), "*-1\r\n+RESET\r\n");
+            assert!(!b.flags.is_blocked());
+            assert!(b.db.clone().unwrap().read().unwrap().blocking_keys.is_empty());
+        }
+        assert_eq!(server_read().blpop_blocked_clients, 0);
+        assert_eq!(run(&mut c, &["LPUSH", "l", "v"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["LLEN", "l"]), ":1\r\n");
+
+        // RESET after the block, sent on its own
+        {
+            let mut b = blocked.write().unwrap();
+            assert_eq!(run(&mut b, &["BLPOP", "other", "0"]), "");
+            assert_eq!(run(&mut b, &["RESET"]), "*-1\r\n+RESET\r\n");
+            assert!(!b.flags.is_blocked());
+        }
+        assert_eq!(server_read().blpop_blocked_clients, 0);
+        unlist(&blocked);
+
+        // Subscriptions, transaction and selected db are dropped
+        run(&mut c, &["SUBSCRIBE", "ch"]);
+        run(&mut c, &["PSUBSCRIBE", "p*"]);
+        assert_eq!(run(&mut c, &["RESET"]), "+RESET\r\n");
+        assert!(!c.is_subscribed());
+        assert_eq!(run(&mut c, &["PUBSUB", "NUMPAT"]), ":0\r\n");
+        run(&mut c, &["SELECT", "1"]);
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["RESET"]), "+RESET\r\n");
+        assert!(!c.is_multi());
+        assert_eq!(run(&mut c, &["LLEN", "l"]), ":1\r\n");
+    }
+
+    #[test]
+    fn multi_queues_and_exec_runs_in_order() {
+        let _guard = setup();
+        let mut c = test_client();
+        let mut other = test_client();
+
+        assert_eq!(run(&mut c, &["EXEC"]), "-ERR EXEC without MULTI\r\n");
+        assert_eq!(run(&mut c, &["DISCARD"]), "-ERR DISCARD without MULTI\r\n");
+
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["MULTI"]), "-ERR MULTI calls can not be nested\r\n");
+        assert_eq!(run(&mut c, &["SET", "k", "1"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["INCR", "k"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["LPUSH", "k", "x"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "+QUEUED\r\n");
+        // Nothing runs before EXEC.
+        assert_eq!(run(&mut other, &["EXISTS", "k"]), ":0\r\n");
+        // A runtime error doesn't stop the other commands.
+        assert_eq!(run(&mut c, &["EXEC"]),
+            "*4\r\n+OK\r\n:2\r\n-WRONGTYPE Operation against a key holding the wrong kind of value\r\n$1\r\n2\r\n");
+        assert_eq!(run(&mut c, &["EXEC"]), "-ERR EXEC without MULTI\r\n");
+
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["DEL", "k"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["DISCARD"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\n2\r\n");
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["EXEC"]), "*0\r\n");
+    }
+
+    #[test]
+    fn select_in_multi_applies_at_exec() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SELECT", "2"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+QUEUED\r\n");
+        // Still on db 0 until EXEC
+        assert_eq!(c.db.as_ref().unwrap().read().unwrap().id, 0);
+        assert_eq!(run(&mut c, &["EXEC"]), "*2\r\n+OK\r\n+OK\r\n");
+        assert_eq!(c.db.as_ref().unwrap().read().unwrap().id, 2);
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\nv\r\n");
+        run(&mut c, &["SELECT", "0"]);
+        assert_eq!(run(&mut c, &["EXISTS", "k"]), ":0\r\n");
+
+        run(&mut c, &["SUBSCRIBE", "ch"]);
+        assert_eq!(run(&mut c, &["SELECT", "1"]),
+            "-ERR Can't execute 'select': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n");
+        run(&mut c, &["UNSUBSCRIBE"]);
+        assert_eq!(c.db.as_ref().unwrap().read().unwrap().id, 0);
+    }
+
+    #[test]
+    fn select_is_bounded_by_the_existing_databases() {
+        let _guard = setup();
+        let mut c = test_client();
+        let dbnum = server_read().dbnum;
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "databases", "4"]),
+            "-ERR CONFIG SET failed (possibly related to argument 'databases') - can't set immutable config\r\n");
+        assert_eq!(server_read().dbnum, dbnum);
+
+        assert_eq!(run(&mut c, &["SELECT", &(dbnum - 1).to_string()]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SELECT", &dbnum.to_string()]), "-ERR DB index is out of range\r\n");
+        assert_eq!(run(&mut c, &["SELECT", "-1"]), "-ERR DB index is out of range\r\n");
+        assert_eq!(run(&mut c, &["SELECT", "x"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(c.db_id(), dbnum - 1);
+
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(run(&mut c, &["MOVE", "k", &dbnum.to_string()]), "-ERR index out of range\r\n");
+        assert_eq!(run(&mut c, &["MOVE", "k", "x"]), "-ERR value is not an integer or out of range\r\n");
+
+        // A configured number past the DBs that exist doesn't panic
+        server_write().dbnum += 1;
+        assert_eq!(run(&mut c, &["SELECT", &dbnum.to_string()]), "-ERR DB index is out of range\r\n");
+        server_write().dbnum = dbnum;
+    }
+
+    #[test]
+    fn refused_commands_abort_the_transaction() {
+        let _guard = setup();
+        let mut c = test_client();
+        let abort = "-EXECABORT Transaction discarded because of previous errors.\r\n";
+
+        for bad in [&["NOSUCHCOMMAND"][..], &["GET"][..], &["SAVE"][..]] {
+            assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+            assert_eq!(run(&mut c, &["SET", "k", "v"]), "+QUEUED\r\n");
+            assert!(run(&mut c, bad).starts_with("-ERR "));
+            assert_eq!(run(&mut c, &["EXEC"]), abort);
+            assert_eq!(run(&mut c, &["EXISTS", "k"]), ":0\r\n");
+        }
+        // The next transaction starts clean.
+        assert_eq!(run(&mut c, &["MULTI"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SET", "k", "v"]), "+QUEUED\r\n");
+        assert_eq!(run(&mut c, &["EXEC"]), "*1\r\n+OK\r\n");
+    }
+
+    #[test]
+    fn wrong_type_is_reported_by_every_typed_command() {
+        let _guard = setup();
+        let mut c = test_client();
+        let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+        // "s" is a string, "l" a list, "S" a set: every command below
+        // targets a key holding a type it can't handle.
+        let cases: &[&[&str]] = &[
+            &["GET", "l"], &["GETSET", "l", "x"], &["INCR", "l"], &["INCRBY", "l", "1"], &["DECR", "l"], &["DECRBY", "l", "1"],
+            &["RPUSH", "s", "x"], &["LPUSH", "s", "x"], &["LLEN", "s"], &["LRANGE", "s", "0", "1"], &["LTRIM", "s", "0", "1"],
+            &["LINDEX", "s", "0"], &["LSET", "s", "0", "x"], &["LREM", "s", "0", "x"], &["LPOP", "s"], &["RPOP", "s"],
+            &["RPOPLPUSH", "s", "l"], &["RPOPLPUSH", "l", "s"],
+            &["SADD", "s", "x"], &["SREM", "s", "x"], &["SPOP", "s"], &["SMOVE", "s", "S", "a"], &["SMOVE", "S", "s", "a"],
+            &["SCARD", "s"], &["SISMEMBER", "s", "x"], &["SMEMBERS", "s"], &["SRANDMEMBER", "s"],
+            &["SINTER", "S", "s"], &["SINTERSTORE", "d", "S", "s"], &["SUNION", "S", "s"], &["SUNIONSTORE", "d", "S", "s"],
+            &["SDIFF", "S", "s"], &["SDIFFSTORE", "d", "S", "s"],
+            &["ZADD", "s", "1", "x"], &["ZREM", "s", "x"], &["ZINCRBY", "s", "1", "x"], &["ZRANGE", "s", "0", "1"],
+            &["ZREVRANGE", "s", "0", "1"], &["ZRANGEBYSCORE", "s", "0", "1"], &["ZCARD", "s"], &["ZSCORE", "s", "x"],
+            &["ZREMRANGEBYSCORE", "s", "0", "1"],
+        ];
+        let errors_before = server_read().stat_wrongtype_errors;
+        for case in cases {
+            run(&mut c, &["FLUSHDB"]);
+            run(&mut c, &["SET", "s", "v"]);
+            run(&mut c, &["RPUSH", "l", "a"]);
+            run(&mut c, &["SADD", "S", "a"]);
+            assert_eq!(run(&mut c, case), wrong_type, "{:?}", case);
+        }
+        // Every one of them was counted, and only them.
+        let errors = errors_before + cases.len() as u64;
+        assert_eq!(server_read().stat_wrongtype_errors, errors);
+        let info = crate::server::tests::parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["wrongtype_errors"], errors.to_string());
+        // Nothing was touched by the failed commands.
+        assert_eq!(run(&mut c, &["SMEMBERS", "S"]), "*1\r\n$1\r\na\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "l", "0", "-1"]), "*1\r\n$1\r\na\r\n");
+    }
+
+    #[test]
+    fn missing_keys_reply_consistently() {
+        let _guard = setup();
+        let mut c = test_client();
+        let cases: &[(&[&str], &str)] = &[
+            (&["GET", "m"], "$-1\r\n"), (&["LINDEX", "m", "0"], "$-1\r\n"), (&["LPOP", "m"], "$-1\r\n"),
+            (&["SPOP", "m"], "$-1\r\n"), (&["SRANDMEMBER", "m"], "$-1\r\n"), (&["ZSCORE", "m", "x"], "$-1\r\n"),
+            (&["LRANGE", "m", "0", "1"], "*0\r\n"), (&["SMEMBERS", "m"], "*0\r\n"), (&["SINTER", "m"], "*0\r\n"),
+            (&["SUNION", "m"], "*0\r\n"), (&["SDIFF", "m"], "*0\r\n"), (&["ZRANGE", "m", "0", "1"], "*0\r\n"),
+            (&["ZRANGEBYSCORE", "m", "0", "1"], "*0\r\n"),
+            (&["LLEN", "m"], ":0\r\n"), (&["SCARD", "m"], ":0\r\n"), (&["ZCARD", "m"], ":0\r\n"),
+            (&["SISMEMBER", "m", "x"], ":0\r\n"), (&["SREM", "m", "x"], ":0\r\n"), (&["ZREM", "m", "x"], ":0\r\n"),
+            (&["SMOVE", "m", "d", "x"], ":0\r\n"), (&["SINTERSTORE", "d", "m"], ":0\r\n"), (&["DEL", "m"], ":0\r\n"),
+        ];
+        for (case, reply) in cases {
+            assert_eq!(run(&mut c, case), *reply, "{:?}", case);
+        }
+
+        // Intersecting with a missing key empties the destination.
+        run(&mut c, &["SADD", "d", "x"]);
+        assert_eq!(run(&mut c, &["SINTERSTORE", "d", "m"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "d"]), ":0\r\n");
+    }
+
+    #[test]
+    fn del_counts_only_existing_keys() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["DEL", "missing"]), ":0\r\n");
+
+        run(&mut c, &["SET", "a", "1"]);
+        assert_eq!(run(&mut c, &["DEL", "a", "b", "c"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["DEL", "a"]), ":0\r\n");
+
+        // Expired but not reaped yet: the lazy expire runs first.
+        run(&mut c, &["SET", "e", "1"]);
+        c.set_expire("e", 1);
+        let dirty = server_read().dirty;
+        assert_eq!(run(&mut c, &["DEL", "e"]), ":0\r\n");
+        assert_eq!(server_read().dirty, dirty);
+
+        // A stale expire entry alone is not a key.
+        c.set_expire("ghost", u64::MAX);
+        assert!(!c.delete_key("ghost"));
+        assert_eq!(c.get_expire("ghost"), None);
+    }
+
+    #[test]
+    fn emptied_lists_and_sets_are_deleted() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["RPUSH", "l", "a"]);
+        assert_eq!(run(&mut c, &["LPOP", "l"]), "$1\r\na\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "l"]), ":0\r\n");
+
+        run(&mut c, &["SADD", "s", "a"]);
+        assert_eq!(run(&mut c, &["SREM", "s", "a"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "s"]), ":0\r\n");
+    }
+
+    #[test]
+    fn moves_check_the_destination_first() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["RPUSH", "l", "a"]);
+        run(&mut c, &["SADD", "s", "m"]);
+        run(&mut c, &["SET", "str", "x"]);
+        let wrong_type = "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n";
+
+        // A wrong destination type must not lose the element.
+        assert_eq!(run(&mut c, &["RPOPLPUSH", "l", "str"]), wrong_type);
+        assert_eq!(run(&mut c, &["LLEN", "l"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["SMOVE", "s", "str", "m"]), wrong_type);
+        assert_eq!(run(&mut c, &["SCARD", "s"]), ":1\r\n");
+
+        assert_eq!(run(&mut c, &["SMOVE", "s", "s", "m"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["SISMEMBER", "s", "m"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["RPOPLPUSH", "l", "l"]), "$1\r\na\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "l", "0", "-1"]), "*1\r\n$1\r\na\r\n");
+    }
+
+    /// Run a complete SCAN iteration, returning every batch.
+    fn scan_all(c: &mut crate::client::RedisClient, opts: &[&str]) -> Vec<Vec<String>> {
+        let mut batches = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let mut args = vec!["SCAN", &cursor];
+            args.extend_from_slice(opts);
+            let reply = run(c, &args);
+            let lines: Vec<&str> = reply.split("\r\n").collect();
+            // *2, $len, cursor, *n, then $len/key pairs
+            let next = lines[2].to_string();
+            batches.push(lines[4..].chunks(2).filter(|kv| kv.len() == 2).map(|kv| kv[1].to_string()).collect());
+            if next == "0" {
+                return batches;
+            }
+            cursor = next;
+        }
+    }
+
+    #[test]
+    fn scan_returns_every_key_once() {
+        let _guard = setup();
+        let mut c = test_client();
+        {
+            let db = c.db.clone().unwrap();
+            let mut db_w = db.write().unwrap();
+            for i in 0..50_000 {
+                db_w.dict.insert(format!("key:{i}"), Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("v".to_string()) })));
+            }
+        }
+        let batches = scan_all(&mut c, &["COUNT", "1000"]);
+        assert!(batches.len() >= 50);
+        let keys: Vec<String> = batches.into_iter().flatten().collect();
+        let unique: std::collections::HashSet<&String> = keys.iter().collect();
+        assert_eq!(keys.len(), 50_000);
+        assert_eq!(unique.len(), 50_000);
+
+        let matched: usize = scan_all(&mut c, &["MATCH", "key:1????", "COUNT", "5000"]).iter().map(|b| b.len()).sum();
+        assert_eq!(matched, 10_000);
+        run(&mut c, &["FLUSHDB"]);
+    }
+
+    #[test]
+    fn scan_type_filters_server_side() {
+        let _guard = setup();
+        let mut c = test_client();
+        for i in 0..30 {
+            run(&mut c, &["SET", &format!("s{i}"), "v"]);
+            run(&mut c, &["RPUSH", &format!("l{i}"), "a"]);
+            run(&mut c, &["SADD", &format!("S{i}"), "a"]);
+        }
+        for (name, prefix) in [("list", 'l'), ("set", 'S'), ("string", 's')] {
+            let keys: Vec<String> = scan_all(&mut c, &["TYPE", name]).into_iter().flatten().collect();
+            assert_eq!(keys.len(), 30, "{}", name);
+            assert!(keys.iter().all(|k| k.starts_with(prefix)), "{}: {:?}", name, keys);
+        }
+        assert!(scan_all(&mut c, &["TYPE", "zset"]).iter().all(|b| b.is_empty()));
+        assert_eq!(run(&mut c, &["SCAN", "0", "TYPE", "foo"]), "-ERR unknown type name 'foo'\r\n");
+        assert_eq!(run(&mut c, &["SCAN", "x"]), "-ERR invalid cursor\r\n");
+        assert_eq!(run(&mut c, &["SCAN", "0", "COUNT", "0"]), "-ERR syntax error\r\n");
+        assert_eq!(run(&mut c, &["SCAN", "0", "MATCH"]), "-ERR syntax error\r\n");
+    }
+
+    #[test]
+    fn scan_returns_keys_present_throughout_despite_changes() {
+        let _guard = setup();
+        let mut c = test_client();
+        for i in 0..2000 {
+            run(&mut c, &["SET", &format!("stable:{i}"), "v"]);
+            run(&mut c, &["SET", &format!("doomed:{i}"), "v"]);
+        }
+        // Keys come and go between the calls, enough to resize the dict.
+        let mut seen = std::collections::HashSet::new();
+        let mut cursor = "0".to_string();
+        let mut round = 0;
+        loop {
+            let reply = run(&mut c, &["SCAN", &cursor, "COUNT", "100"]);
+            let lines: Vec<&str> = reply.split("\r\n").collect();
+            seen.extend(lines[4..].chunks(2).filter(|kv| kv.len() == 2).map(|kv| kv[1].to_string()));
+            cursor = lines[2].to_string();
+            if cursor == "0" {
+                break;
+            }
+            for i in 0..200 {
+                run(&mut c, &["SET", &format!("new:{round}:{i}"), "v"]);
+            }
+            for i in round * 40..(round + 1) * 40 {
+                run(&mut c, &["DEL", &format!("doomed:{i}")]);
+            }
+            round += 1;
+        }
+        assert!(round > 20);
+        for i in 0..2000 {
+            assert!(seen.contains(&format!("stable:{i}")), "stable:{i} was never returned");
+        }
+        run(&mut c, &["FLUSHDB"]);
+    }
+
+    #[test]
+    fn hscan_iterates_fields_with_or_without_values() {
+        let _guard = setup();
+        let mut c = test_client();
+        for i in 0..100 {
+            run(&mut c, &["HSET", "h", &format!("f{i}"), &format!("v{i}")]);
+        }
+        let mut fields = Vec::new();
+        let mut cursor = "0".to_string();
+        loop {
+            let reply = run(&mut c, &["HSCAN", "h", &cursor, "COUNT", "7"]);
+            let lines: Vec<&str> = reply.split("\r\n").collect();
+            let values: Vec<&str> = lines[4..].chunks(2).filter(|kv| kv.len() == 2).map(|kv| kv[1]).collect();
+            for pair in values.chunks(2) {
+                assert_eq!(pair[1], format!("v{}", &pair[0][1..]));
+                fields.push(pair[0].to_string());
+            }
+            cursor = lines[2].to_string();
+            if cursor == "0" {
+                break;
+            }
+        }
+        fields.sort();
+        fields.dedup();
+        assert_eq!(fields.len(), 100);
+
+        let reply = run(&mut c, &["HSCAN", "h", "0", "COUNT", "1000", "MATCH", "f1?", "NOVALUES"]);
+        assert!(reply.starts_with("*2\r\n$1\r\n0\r\n*10\r\n"), "{}", reply);
+        assert!(!reply.contains("$2\r\nv"), "{}", reply);
+
+        assert_eq!(run(&mut c, &["HSCAN", "missing", "0"]), "*2\r\n$1\r\n0\r\n*0\r\n");
+        run(&mut c, &["SET", "s", "v"]);
+        assert!(run(&mut c, &["HSCAN", "s", "0"]).starts_with("-WRONGTYPE"));
+        assert_eq!(run(&mut c, &["HSCAN", "h", "0", "TYPE", "hash"]), "-ERR syntax error\r\n");
+        assert_eq!(run(&mut c, &["SCAN", "0", "NOVALUES"]), "-ERR syntax error\r\n");
+    }
+
+    #[test]
+    fn incr_rejects_non_integer_values() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "s", "abc"]);
+        assert_eq!(run(&mut c, &["INCR", "s"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["GET", "s"]), "$3\r\nabc\r\n");
+    }
+
+    #[test]
+    fn setrange_overwrites_pads_and_creates() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "k", "Hello World"]);
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "6", "Redis"]), ":11\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$11\r\nHello Redis\r\n");
+        // Past the end: padded with zero bytes
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "13", "!"]), ":14\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$14\r\nHello Redis\0\0!\r\n");
+
+        // A missing key is created, unless there is nothing to write
+        assert_eq!(run(&mut c, &["SETRANGE", "new", "5", "x"]), ":6\r\n");
+        assert_eq!(run(&mut c, &["GET", "new"]), "$6\r\n\0\0\0\0\0x\r\n");
+        assert_eq!(run(&mut c, &["SETRANGE", "none", "5", ""]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "none"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "100", ""]), ":14\r\n");
+
+        // Integer encoded values are decoded first
+        run(&mut c, &["SET", "n", "1234"]);
+        assert_eq!(run(&mut c, &["SETRANGE", "n", "1", "9"]), ":4\r\n");
+        assert_eq!(run(&mut c, &["INCR", "n"]), ":1935\r\n");
+
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "-1", "x"]), "-ERR offset is out of range\r\n");
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "x", "x"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "536870912", "x"]), "-ERR string exceeds maximum allowed size (512MB)\r\n");
+        run(&mut c, &["LPUSH", "l", "a"]);
+        assert!(run(&mut c, &["SETRANGE", "l", "0", "x"]).starts_with("-WRONGTYPE"));
+        assert!(run(&mut c, &["SETRANGE", "l", "0", ""]).starts_with("-WRONGTYPE"));
+    }
+
+    #[test]
+    fn setrange_refuses_to_split_a_multibyte_character() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "k", "é"]);
+        let dirty = server_read().dirty;
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "1", "x"]), "-ERR string values must be valid UTF-8\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$2\r\né\r\n");
+        assert_eq!(server_read().dirty, dirty);
+
+        // Overwriting the whole character, or writing after it, is fine
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "2", "x"]), ":3\r\n");
+        assert_eq!(run(&mut c, &["SETRANGE", "k", "0", "ab"]), ":3\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$3\r\nabx\r\n");
+    }
+
+    #[test]
+    fn append_creates_or_extends_strings() {
+        let _guard = setup();
+        let mut c = test_client();
+        let dirty = server_read().dirty;
+        assert_eq!(run(&mut c, &["APPEND", "k", "hello"]), ":5\r\n");
+        assert_eq!(run(&mut c, &["APPEND", "k", " world"]), ":11\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$11\r\nhello world\r\n");
+        assert_eq!(server_read().dirty, dirty + 2);
+
+        // Integer encoded values are appended to as strings, and a numeric
+        // result is encoded again
+        run(&mut c, &["INCR", "n"]);
+        assert_eq!(run(&mut c, &["APPEND", "n", "0"]), ":2\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "n"]), "$3\r\nint\r\n");
+        assert_eq!(run(&mut c, &["INCR", "n"]), ":11\r\n");
+        assert_eq!(run(&mut c, &["APPEND", "n", "x"]), ":3\r\n");
+        assert_eq!(run(&mut c, &["GET", "n"]), "$3\r\n11x\r\n");
+
+        run(&mut c, &["LPUSH", "l", "a"]);
+        assert!(run(&mut c, &["APPEND", "l", "b"]).starts_with("-WRONGTYPE"));
+        assert_eq!(run(&mut c, &["LLEN", "l"]), ":1\r\n");
+    }
+
+    #[test]
+    fn getset_replaces_strings_only() {
+        let _guard = setup();
+        let mut c = test_client();
+        // A fresh key is written, the old value is nil
+        assert_eq!(run(&mut c, &["GETSET", "k", "a"]), "$-1\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\na\r\n");
+        // The expire goes with the old value
+        run(&mut c, &["EXPIRE", "k", "100"]);
+        assert_eq!(run(&mut c, &["GETSET", "k", "b"]), "$1\r\na\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\nb\r\n");
+
+        run(&mut c, &["RPUSH", "l", "x"]);
+        run(&mut c, &["RPUSH", "l", "y"]);
+        let dirty = server_read().dirty;
+        assert!(run(&mut c, &["GETSET", "l", "v"]).starts_with("-WRONGTYPE"));
+        assert_eq!(server_read().dirty, dirty);
+        assert_eq!(run(&mut c, &["LRANGE", "l", "0", "-1"]), "*2\r\n$1\r\nx\r\n$1\r\ny\r\n");
+
+        // SET overwrites any type, and drops the expire
+        run(&mut c, &["EXPIRE", "l", "100"]);
+        assert_eq!(run(&mut c, &["SET", "l", "v"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["GET", "l"]), "$1\r\nv\r\n");
+        assert_eq!(run(&mut c, &["TTL", "l"]), ":-1\r\n");
+    }
+
+    #[test]
+    fn mset_and_msetnx_take_pairs() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["MSET", "a", "1", "b"]), "-ERR wrong number of arguments for 'mset' command (too few)\r\n");
+        assert_eq!(run(&mut c, &["MSETNX", "a", "1", "b"]), "-ERR wrong number of arguments for 'msetnx' command (too few)\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "a"]), ":0\r\n");
+
+        assert_eq!(run(&mut c, &["MSETNX", "a", "1", "b", "2"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["MSETNX", "b", "3", "c", "4"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "c"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["MSET", "b", "3", "c", "x"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["MGET", "a", "b", "c"]), "*3\r\n$1\r\n1\r\n$1\r\n3\r\n$1\r\nx\r\n");
+        // Every value is encoded, not only the first one
+        for key in ["a", "b"] {
+            assert_eq!(run(&mut c, &["OBJECT", "ENCODING", key]), "$3\r\nint\r\n");
+        }
+    }
+
+    #[test]
+    fn strlen_and_getrange_read_strings() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "k", "Hello World"]);
+        assert_eq!(run(&mut c, &["STRLEN", "k"]), ":11\r\n");
+        assert_eq!(run(&mut c, &["STRLEN", "missing"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "0", "4"]), "$5\r\nHello\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "0", "-1"]), "$11\r\nHello World\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "-5", "-1"]), "$5\r\nWorld\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "-5", "-3"]), "$3\r\nWor\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "6", "100"]), "$5\r\nWorld\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "-100", "0"]), "$1\r\nH\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "-100", "-100"]), "$1\r\nH\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "5", "2"]), "$0\r\n\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "-1", "-2"]), "$0\r\n\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "11", "20"]), "$0\r\n\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "missing", "0", "1"]), "$0\r\n\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "a", "1"]), "-ERR value is not an integer or out of range\r\n");
+        // SUBSTR is the same command
+        assert_eq!(run(&mut c, &["SUBSTR", "k", "-5", "-1"]), "$5\r\nWorld\r\n");
+        assert_eq!(run(&mut c, &["SUBSTR", "missing", "0", "1"]), "$0\r\n\r\n");
+
+        // Byte offsets, not chars
+        run(&mut c, &["SET", "u", "héllo"]);
+        assert_eq!(run(&mut c, &["GETRANGE", "u", "1", "2"]), "$2\r\né\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "u", "-3", "-1"]), "$3\r\nllo\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "u", "1", "1"]), "-ERR string values must be valid UTF-8\r\n");
+
+        run(&mut c, &["INCRBY", "n", "-123"]);
+        assert_eq!(run(&mut c, &["STRLEN", "n"]), ":4\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "n", "1", "-1"]), "$3\r\n123\r\n");
+
+        run(&mut c, &["LPUSH", "l", "a"]);
+        assert!(run(&mut c, &["STRLEN", "l"]).starts_with("-WRONGTYPE"));
+        assert!(run(&mut c, &["GETRANGE", "l", "0", "1"]).starts_with("-WRONGTYPE"));
+    }
+
+    #[test]
+    fn getrange_splitting_a_character_keeps_the_pipeline_in_sync() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "u", "héllo"]);
+        // The GET reply is queued first, so the GETRANGE one goes to the
+        // reply list: check the exact bytes that would be written.
+        c.query_buf.push_str("*2\r\n$3\r\nGET\r\n$1\r\nu\r\n*4\r\n$8\r\nGETRANGE\r\n$1\r\nu\r\n$1\r\n1\r\n$1\r\n1\r\n");
+        c.process_input_buf();
+        let mut wire = c.buf().to_vec();
+        for reply in c.reply.read().unwrap().iter() {
+            wire.extend_from_slice(&reply.string().unwrap().as_bytes());
+        }
+        c.clear_replies();
+        assert_eq!(wire, "$6\r\nhéllo\r\n-ERR string values must be valid UTF-8\r\n".as_bytes());
+    }
+
+    /// (op, key) pairs out of a DEBUG EVENTS GET reply.
+    #[cfg(feature = "testing-hooks")]
+    fn events(c: &mut crate::client::RedisClient) -> Vec<(String, String)> {
+        let reply = run(c, &["DEBUG", "EVENTS", "GET"]);
+        let lines: Vec<&str> = reply.split("\r\n").collect();
+        lines[1..lines.len() - 1].chunks(7).map(|e| (e[2].to_string(), e[5].to_string())).collect()
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn key_events_record_modifications_and_deletions() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "RESET"]), "+OK\r\n");
+
+        run(&mut c, &["SET", "a", "1"]);
+        run(&mut c, &["RPUSH", "l", "x"]);
+        run(&mut c, &["SADD", "s", "x"]);
+        run(&mut c, &["SADD", "s", "x"]);
+        run(&mut c, &["DEL", "a", "missing"]);
+        c.set_expire("l", 1);
+        run(&mut c, &["LLEN", "l"]);
+        run(&mut c, &["FLUSHDB"]);
+
+        let expected = [("set", "a"), ("rpush", "l"), ("sadd", "s"), ("del", "a"), ("expired", "l"), ("flushdb", "")];
+        let expected: Vec<(String, String)> = expected.iter().map(|(op, key)| (op.to_string(), key.to_string())).collect();
+        assert_eq!(events(&mut c), expected);
+
+        let last = run(&mut c, &["DEBUG", "EVENTS", "GET", "1"]);
+        assert!(last.starts_with("*1\r\n*4\r\n$7\r\nflushdb\r\n:0\r\n$0\r\n\r\n:"), "{}", last);
+        assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "RESET"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "GET"]), "*0\r\n");
+    }
+
+    #[test]
+    fn empty_set_store_deletes_the_destination() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SADD", "a", "x"]);
+        run(&mut c, &["SADD", "b", "y"]);
+
+        let empty_results: [&[&str]; 3] = [
+            &["SINTERSTORE", "dst", "a", "b"],
+            &["SUNIONSTORE", "dst", "missing"],
+            &["SDIFFSTORE", "dst", "a", "a"],
+        ];
+        for args in empty_results {
+            run(&mut c, &["SET", "dst", "old"]);
+            let dirty = server_read().dirty;
+            assert_eq!(run(&mut c, args), ":0\r\n", "{}", args[0]);
+            assert_eq!(run(&mut c, &["EXISTS", "dst"]), ":0\r\n", "{}", args[0]);
+            assert_eq!(server_read().dirty, dirty + 1, "{}", args[0]);
+
+            // Nothing to delete the second time: not a change.
+            assert_eq!(run(&mut c, args), ":0\r\n", "{}", args[0]);
+            assert_eq!(server_read().dirty, dirty + 1, "{}", args[0]);
+        }
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn ttl_at_the_expire_boundary() {
+        use std::time::Duration;
+        use crate::util::set_mock_time;
+        let _guard = setup();
+        let mut c = test_client();
+        let t0 = Duration::from_secs(1_000_000);
+        set_mock_time(Some(t0));
+
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-2\r\n");
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "10"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":10\r\n");
+
+        // One second before the expire, and a fraction of a second later:
+        // the time left is rounded up, never to 0 while the key is there.
+        set_mock_time(Some(t0 + Duration::from_secs(9)));
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":1\r\n");
+        set_mock_time(Some(t0 + Duration::from_millis(9_999)));
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$1\r\nv\r\n");
+
+        // At the expire instant the key is gone.
+        set_mock_time(Some(t0 + Duration::from_secs(10)));
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-2\r\n");
+        assert!(!c.contains("k"));
+        assert_eq!(c.get_expire("k"), None);
+
+        // And one second after.
+        run(&mut c, &["SET", "j", "v"]);
+        assert_eq!(run(&mut c, &["EXPIRE", "j", "1"]), ":1\r\n");
+        set_mock_time(Some(t0 + Duration::from_secs(12)));
+        assert_eq!(run(&mut c, &["TTL", "j"]), ":-2\r\n");
+        assert_eq!(run(&mut c, &["GET", "j"]), "$-1\r\n");
+        set_mock_time(None);
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn expire_conditions() {
+        use std::time::Duration;
+        use crate::util::set_mock_time;
+        let _guard = setup();
+        let mut c = test_client();
+        set_mock_time(Some(Duration::from_secs(1_000_000)));
+
+        // (flag, TTL before, new TTL, reply, TTL after), -1 is no expire.
+        let cases: &[(&str, i64, &str, &str, i64)] = &[
+            ("", -1, "50", ":1", 50), ("", 100, "200", ":0", 100),
+            ("NX", -1, "50", ":1", 50), ("NX", 100, "200", ":0", 100), ("NX", 100, "50", ":0", 100),
+            ("XX", -1, "50", ":0", -1), ("XX", 100, "200", ":1", 200), ("XX", 100, "50", ":1", 50),
+            ("GT", -1, "50", ":0", -1), ("GT", 100, "200", ":1", 200), ("GT", 100, "50", ":0", 100),
+            ("GT", 100, "100", ":0", 100),
+            ("LT", -1, "50", ":1", 50), ("LT", 100, "200", ":0", 100), ("LT", 100, "50", ":1", 50),
+            ("LT", 100, "100", ":0", 100),
+            ("xx", 100, "200", ":1", 200), ("lt", -1, "-1", ":1", -2), ("gt", 100, "-1", ":0", 100),
+        ];
+        for (flag, before, ttl, reply, after) in cases {
+            run(&mut c, &["SET", "k", "v"]);
+            if *before > 0 {
+                run(&mut c, &["EXPIRE", "k", &before.to_string()]);
+            }
+            let mut args = vec!["EXPIRE", "k", ttl];
+            if !flag.is_empty() {
+                args.push(flag);
+            }
+            assert_eq!(run(&mut c, &args), format!("{}\r\n", reply), "{:?}", args);
+            assert_eq!(run(&mut c, &["TTL", "k"]), format!(":{}\r\n", after), "{:?}", args);
+            run(&mut c, &["DEL", "k"]);
+        }
+
+        run(&mut c, &["SET", "k", "v"]);
+        let incompatible = ["-ERR NX and XX, GT or LT options at the same time are not compatible\r\n",
+            "-ERR GT and LT options at the same time are not compatible\r\n"];
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "10", "NX", "XX"]), incompatible[0]);
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "10", "LT", "NX"]), incompatible[0]);
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "10", "GT", "LT"]), incompatible[1]);
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "10", "FOO"]), "-ERR Unsupported option foo\r\n");
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "ten"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+        // XX and GT together both apply.
+        run(&mut c, &["EXPIRE", "k", "100"]);
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "200", "XX", "GT"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "50", "XX", "GT"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":200\r\n");
+        set_mock_time(None);
+    }
+
+    #[test]
+    fn ttl_tells_missing_keys_from_persistent_ones() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-2\r\n");
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&mut c, &["EXPIREAT", "k", "4102444800"]), ":1\r\n");
+        let ttl = run(&mut c, &["TTL", "k"]);
+        assert!(ttl.starts_with(':') && !ttl.starts_with(":-"), "{}", ttl);
+        assert_eq!(run(&mut c, &["PERSIST", "k"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+
+        // A past timestamp deletes the key at once.
+        assert_eq!(run(&mut c, &["EXPIREAT", "k", "1"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-2\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "k"]), ":0\r\n");
+    }
+
+    #[test]
+    fn pttl_reports_milliseconds() {
+        let _guard = setup();
+        let mut c = test_client();
+        let pttl = |c: &mut crate::client::RedisClient| -> i64 {
+            run(c, &["PTTL", "k"]).trim_start_matches(':').trim_end().parse().unwrap()
+        };
+        assert_eq!(pttl(&mut c), -2);
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(pttl(&mut c), -1);
+        run(&mut c, &["EXPIRE", "k", "100"]);
+        let ms = pttl(&mut c);
+        assert!(ms > 99_000 && ms <= 100_000, "{}", ms);
+        run(&mut c, &["PERSIST", "k"]);
+        run(&mut c, &["PEXPIRE", "k", "1500"]);
+        let ms = pttl(&mut c);
+        assert!(ms > 1_000 && ms <= 1_500, "{}", ms);
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":2\r\n");
+    }
+
+    #[test]
+    fn monitors_see_the_commands_of_other_clients() {
+        let _guard = setup();
+        let mut c = test_client();
+        let monitor = listed_client();
+        assert_eq!(run(&mut monitor.write().unwrap(), &["MONITOR"]), "+OK\r\n");
+        // Again is a no-op
+        assert_eq!(run(&mut monitor.write().unwrap(), &["MONITOR"]), "");
+        assert_eq!(server_read().monitors.len(), 1);
+
+        run(&mut c, &["SET", "k", "a \"b\""]);
+        run(&mut c, &["SELECT", "1"]);
+        run(&mut c, &["GET", "k"]);
+        let fed = take_replies(&mut monitor.write().unwrap());
+        let lines: Vec<&str> = fed.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 3, "{:?}", fed);
+        for line in &lines {
+            let (secs, micros) = line[1..].split_once(' ').unwrap().0.split_once('.').unwrap();
+            assert!(line.starts_with('+') && secs.parse::<u64>().is_ok() && micros.len() == 6, "{}", line);
+        }
+        assert!(lines[0].ends_with(r#" [0 ?:0] "SET" "k" "a \"b\"""#), "{}", lines[0]);
+        assert!(lines[1].ends_with(r#" [0 ?:0] "SELECT" "1""#), "{}", lines[1]);
+        assert!(lines[2].ends_with(r#" [1 ?:0] "GET" "k""#), "{}", lines[2]);
+
+        // Neither its own commands nor the AOF replay
+        assert_eq!(run(&mut monitor.write().unwrap(), &["PING"]), "+PONG\r\n");
+        let mut aof = test_client();
+        aof.flags.enable(crate::client::ClientFlags::aof_client());
+        run(&mut aof, &["SET", "k", "v"]);
+        assert_eq!(take_replies(&mut monitor.write().unwrap()), "");
+
+        // A freed monitor leaves the list
+        unlist(&monitor);
+        drop(monitor);
+        assert!(server_read().monitors.is_empty());
+        run(&mut c, &["PING"]);
+    }
+
+    #[test]
+    fn client_list_shows_the_output_buffer_of_slow_clients() {
+        let _guard = setup();
+        let mut c = test_client();
+        let item = "x".repeat(100);
+        for _ in 0..1000 {
+            run(&mut c, &["RPUSH", "l", &item]);
+        }
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "client-output-buffer-limit", "normal 1mb 0 0"]), "+OK\r\n");
+
+        // The (oll, omem) of the client in CLIENT LIST, None once it's gone
+        let list_entry = |c: &mut crate::client::RedisClient, id: u64| -> Option<(usize, usize)> {
+            let list = run(c, &["CLIENT", "LIST"]);
+            let line = list.lines().find(|l| l.starts_with(&format!("id={} ", id)))?;
+            let field = |name: &str| line.split(' ').find_map(|f| f.strip_prefix(name)).unwrap().parse().unwrap();
+            Some((field("oll="), field("omem=")))
+        };
+        // A client that sends LRANGE but never reads the replies
+        let slow = listed_client();
+        let id = slow.read().unwrap().id();
+        assert_eq!(list_entry(&mut c, id), Some((0, 0)));
+        let mut last = (0, 0);
+        let mut floods = 0;
+        while let Some((oll, omem)) = list_entry(&mut c, id) {
+            assert!(oll > last.0 && omem > last.1 || floods == 0, "{:?} after {:?}", (oll, omem), last);
+            assert!(omem < 1 << 20);
+            last = (oll, omem);
+            let mut slow = slow.write().unwrap();
+            slow.query_buf.push_str("*4\r\n$6\r\nLRANGE\r\n$1\r\nl\r\n$1\r\n0\r\n$2\r\n-1\r\n");
+            slow.process_input_buf();
+            floods += 1;
+            if floods > 20 {
+                break;
+            }
+            drop(slow);
+            crate::handler::before_sleep();
+        }
+        // Closed once over 1mb, after ~110kb a flood
+        assert!((9..=11).contains(&floods), "{} floods", floods);
+
+        let info = run(&mut c, &["CLIENT", "INFO"]);
+        assert!(info.contains(" oll=0 omem=0 cmd=client\n"), "{}", info);
+        assert!(run(&mut c, &["CLIENT", "KILL", "x"]).starts_with("-ERR Unknown subcommand or wrong number of arguments for 'KILL'."));
+        run(&mut c, &["CONFIG", "SET", "client-output-buffer-limit", "normal 0 0 0"]);
+    }
+
+    #[test]
+    fn emptied_aggregates_take_their_expire_along() {
+        let _guard = setup();
+        let mut c = test_client();
+        // A write against a volatile key deletes it first: SPOP finds
+        // nothing. Either way the expire must not outlive the set.
+        run(&mut c, &["SADD", "s", "a"]);
+        assert_eq!(run(&mut c, &["EXPIRE", "s", "100"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["SPOP", "s"]), "$-1\r\n");
+        assert_eq!(c.get_expire("s"), None);
+        run(&mut c, &["SADD", "s", "b"]);
+        assert_eq!(run(&mut c, &["TTL", "s"]), ":-1\r\n");
+        assert_eq!(run(&mut c, &["SPOP", "s"]), "$1\r\nb\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "s"]), ":0\r\n");
+
+        // Sorted sets left empty are deleted too.
+        run(&mut c, &["ZADD", "z", "1", "a"]);
+        run(&mut c, &["ZADD", "z", "2", "b"]);
+        assert_eq!(run(&mut c, &["ZREM", "z", "a"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["ZREMRANGEBYSCORE", "z", "0", "10"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "z"]), ":0\r\n");
+        run(&mut c, &["ZADD", "z", "1", "a"]);
+        assert_eq!(run(&mut c, &["ZREM", "z", "a"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "z"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["ZREM", "z", "a"]), ":0\r\n");
+    }
+
+    #[test]
+    fn persist_removes_the_expire() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(run(&mut c, &["PERSIST", "k"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "100"]), ":1\r\n");
+        let dirty = server_read().dirty;
+        assert_eq!(run(&mut c, &["PERSIST", "k"]), ":1\r\n");
+        assert_eq!(server_read().dirty, dirty + 1);
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+        assert_eq!(run(&mut c, &["PERSIST", "k"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["PERSIST", "missing"]), ":0\r\n");
+
+        // Without its expire the key can get a new one.
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "50"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":50\r\n");
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn expireat_and_pexpire_share_the_expire_logic() {
+        use std::time::Duration;
+        use crate::util::set_mock_time;
+        let _guard = setup();
+        let mut c = test_client();
+        let t0 = Duration::from_secs(1_000_000);
+        set_mock_time(Some(t0));
+
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(run(&mut c, &["EXPIREAT", "k", "1000100"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":100\r\n");
+        assert_eq!(run(&mut c, &["EXPIREAT", "k", "1000050", "LT"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":50\r\n");
+        assert_eq!(run(&mut c, &["EXPIREAT", "missing", "1000100"]), ":0\r\n");
+
+        // Sub-second expires: readable until the very millisecond.
+        run(&mut c, &["SET", "p", "v"]);
+        assert_eq!(run(&mut c, &["PEXPIRE", "p", "1500"]), ":1\r\n");
+        assert_eq!(c.get_expire("p"), Some(1_000_001_500));
+        assert_eq!(run(&mut c, &["TTL", "p"]), ":2\r\n");
+        assert_eq!(run(&mut c, &["PTTL", "p"]), ":1500\r\n");
+        set_mock_time(Some(t0 + Duration::from_millis(1499)));
+        assert_eq!(run(&mut c, &["GET", "p"]), "$1\r\nv\r\n");
+        assert_eq!(run(&mut c, &["TTL", "p"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["PTTL", "p"]), ":1\r\n");
+        set_mock_time(Some(t0 + Duration::from_millis(1500)));
+        assert_eq!(run(&mut c, &["GET", "p"]), "$-1\r\n");
+
+        // A deadline already in the past deletes the key right away.
+        set_mock_time(Some(t0));
+        run(&mut c, &["SET", "p", "v"]);
+        assert_eq!(run(&mut c, &["PEXPIREAT", "p", "1000000000"]), ":1\r\n");
+        assert!(!c.contains("p"));
+        run(&mut c, &["SET", "p", "v"]);
+        assert_eq!(run(&mut c, &["EXPIREAT", "p", "999999"]), ":1\r\n");
+        assert!(!c.contains("p"));
+
+        let not_int = "-ERR value is not an integer or out of range\r\n";
+        assert_eq!(run(&mut c, &["EXPIREAT", "k", "soon"]), not_int);
+        assert_eq!(run(&mut c, &["PEXPIRE", "k", "9223372036854775807"]), not_int);
+        assert_eq!(run(&mut c, &["EXPIRE", "k", "9223372036854775807"]), not_int);
+        set_mock_time(None);
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn getex_sets_relative_and_absolute_expires() {
+        use std::time::Duration;
+        use crate::util::set_mock_time;
+        let _guard = setup();
+        let mut c = test_client();
+        set_mock_time(Some(Duration::from_secs(1_000_000)));
+        run(&mut c, &["SET", "k", "v"]);
+
+        let cases: &[(&[&str], &str)] = &[
+            (&["EX", "100"], ":100"), (&["PX", "1500"], ":2"), (&["EXAT", "1000050"], ":50"),
+            (&["PXAT", "1000020001"], ":21"), (&["PERSIST"], ":-1"), (&[], ":-1"),
+        ];
+        for (opts, ttl) in cases {
+            let mut args = vec!["GETEX", "k"];
+            args.extend_from_slice(opts);
+            assert_eq!(run(&mut c, &args), "$1\r\nv\r\n", "{:?}", args);
+            assert_eq!(run(&mut c, &["TTL", "k"]), format!("{}\r\n", ttl), "{:?}", args);
+        }
+
+        // A deadline in the past returns the value and deletes the key.
+        assert_eq!(run(&mut c, &["GETEX", "k", "EXAT", "999999"]), "$1\r\nv\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "k"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["GETEX", "k", "EX", "10"]), "$-1\r\n");
+
+        run(&mut c, &["SET", "k", "v"]);
+        let syntax = "-ERR syntax error\r\n";
+        assert_eq!(run(&mut c, &["GETEX", "k", "EX", "10", "PXAT", "1000020001"]), syntax);
+        assert_eq!(run(&mut c, &["GETEX", "k", "EX", "10", "PERSIST"]), syntax);
+        assert_eq!(run(&mut c, &["GETEX", "k", "EXAT"]), syntax);
+        assert_eq!(run(&mut c, &["GETEX", "k", "KEEPTTL"]), syntax);
+        assert_eq!(run(&mut c, &["GETEX", "k", "EX", "0"]), "-ERR invalid expire time in 'getex' command\r\n");
+        // A deadline past what a millisecond timestamp holds is refused
+        for (opt, value) in [("EX", "9223372036854775807"), ("PX", "9223372036854775807"), ("EXAT", "9223372036854776")] {
+            assert_eq!(run(&mut c, &["GETEX", "k", opt, value]), "-ERR invalid expire time in 'getex' command\r\n");
+        }
+        assert_eq!(run(&mut c, &["GETEX", "k", "EX", "x"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["TTL", "k"]), ":-1\r\n");
+        set_mock_time(None);
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn set_stores_signal_the_destination() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SADD", "a", "x"]);
+        run(&mut c, &["SADD", "b", "x"]);
+        run(&mut c, &["SADD", "b", "y"]);
+        let ev = |op: &str, key: &str| (op.to_string(), key.to_string());
+
+        // Storing the same members again still touches the destination.
+        for _ in 0..2 {
+            assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "RESET"]), "+OK\r\n");
+            assert_eq!(run(&mut c, &["SINTERSTORE", "d", "a", "b"]), ":1\r\n");
+            assert_eq!(events(&mut c).last(), Some(&ev("sinterstore", "d")));
+        }
+        assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "RESET"]), "+OK\r\n");
+        run(&mut c, &["SUNIONSTORE", "d", "a", "b"]);
+        run(&mut c, &["SDIFFSTORE", "d", "b", "a"]);
+        assert_eq!(events(&mut c), vec![ev("del", "d"), ev("sunionstore", "d"), ev("del", "d"), ev("sdiffstore", "d")]);
+
+        // Deleting an existing destination is a modification.
+        assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "RESET"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SINTERSTORE", "d", "a", "missing"]), ":0\r\n");
+        assert_eq!(events(&mut c), vec![ev("del", "d"), ev("sinterstore", "d")]);
+
+        // An empty result over a missing destination changes nothing.
+        assert_eq!(run(&mut c, &["DEBUG", "EVENTS", "RESET"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SINTERSTORE", "e", "a", "missing"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["SDIFFSTORE", "e", "a", "a"]), ":0\r\n");
+        assert_eq!(events(&mut c), vec![]);
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn object_count_returns_to_baseline_after_flushall() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["FLUSHALL"]);
+        let baseline = run(&mut c, &["DEBUG", "OBJECT-COUNT"]);
+
+        run(&mut c, &["SET", "str", "v"]);
+        run(&mut c, &["INCR", "n"]);
+        run(&mut c, &["RPUSH", "l", "a"]);
+        run(&mut c, &["RPOPLPUSH", "l", "l2"]);
+        run(&mut c, &["SADD", "s", "a"]);
+        run(&mut c, &["SINTERSTORE", "s2", "s"]);
+        run(&mut c, &["ZADD", "z", "1", "a"]);
+        run(&mut c, &["ZADD", "z", "2", "b"]);
+        run(&mut c, &["ZRANGE", "z", "0", "-1", "WITHSCORES"]);
+        assert_ne!(run(&mut c, &["DEBUG", "OBJECT-COUNT"]), baseline);
+
+        run(&mut c, &["FLUSHALL"]);
+        assert_eq!(run(&mut c, &["DEBUG", "OBJECT-COUNT"]), baseline);
+    }
+
+    #[test]
+    fn flushes_count_removed_keys_as_dirty() {
+        let _guard = setup();
+        let mut c = test_client();
+        server_write().dirty = 0;
+        assert_eq!(run(&mut c, &["FLUSHALL"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["FLUSHDB"]), "+OK\r\n");
+        assert_eq!(server_read().dirty, 0);
+
+        for key in ["a", "b", "c"] {
+            run(&mut c, &["SET", key, "v"]);
+        }
+        server_write().dirty = 0;
+        assert_eq!(run(&mut c, &["FLUSHDB"]), "+OK\r\n");
+        assert_eq!(server_read().dirty, 3);
+
+        for key in ["a", "b"] {
+            run(&mut c, &["SET", key, "v"]);
+        }
+        server_write().dirty = 0;
+        assert_eq!(run(&mut c, &["FLUSHALL"]), "+OK\r\n");
+        assert_eq!(server_read().dirty, 2);
+    }
+
+    #[test]
+    fn flushall_saves_when_configured() {
+        let _guard = setup();
+        let mut c = test_client();
+        let file = format!("test-flushall-{}.rdb", id());
+        let old_file = std::mem::replace(&mut server_write().db_filename, file.clone());
+        server_write().flushall_saves = true;
+
+        run(&mut c, &["SET", "a", "v"]);
+        server_write().dirty = 5;
+        assert_eq!(run(&mut c, &["FLUSHALL"]), "+OK\r\n");
+        assert!(Path::new(&file).exists());
+        assert_eq!(server_read().dirty, 1);
+
+        server_write().flushall_saves = false;
+        server_write().db_filename = old_file;
+        remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn sorted_replies_make_set_output_deterministic() {
+        let _guard = setup();
+        let mut c = test_client();
+        for ele in ["c", "a", "d", "b"] {
+            run(&mut c, &["SADD", "s1", ele]);
+        }
+        for ele in ["b", "e", "c"] {
+            run(&mut c, &["SADD", "s2", ele]);
+        }
+
+        assert_eq!(run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "1"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SMEMBERS", "s1"]), "*4\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n");
+        assert_eq!(run(&mut c, &["SINTER", "s1", "s2"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["SDIFF", "s1", "s2"]), "*2\r\n$1\r\na\r\n$1\r\nd\r\n");
+        assert_eq!(run(&mut c, &["SUNION", "s1", "s2"]), "*5\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\ne\r\n");
+        assert_eq!(run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "0"]), "+OK\r\n");
+    }
+
+    /// Golden output: with testing-hooks sets keep insertion order, so the
+    /// exact reply bytes are known without sorting.
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn sets_reply_in_insertion_order() {
+        let _guard = setup();
+        let mut c = test_client();
+        for ele in ["c", "a", "d", "b"] {
+            run(&mut c, &["SADD", "s1", ele]);
+        }
+        run(&mut c, &["SREM", "s1", "a"]);
+        assert_eq!(run(&mut c, &["SMEMBERS", "s1"]), "*3\r\n$1\r\nc\r\n$1\r\nd\r\n$1\r\nb\r\n");
+        run(&mut c, &["SADD", "s2", "b"]);
+        run(&mut c, &["SADD", "s2", "c"]);
+        assert_eq!(run(&mut c, &["SINTER", "s1", "s2"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+    }
+
+    #[test]
+    fn every_command_is_documented() {
+        for name in CMD_TABLE.keys() {
+            assert!(CMD_DOCS.iter().any(|d| d.0 == *name), "{} has no COMMAND DOCS entry", name);
+        }
+    }
+
+    #[test]
+    fn lookup_ignores_case_without_allocating() {
+        for name in CMD_TABLE.keys() {
+            assert!(name.len() <= MAX_COMMAND_NAME_LEN, "{} doesn't fit the lookup buffer", name);
+            assert!(lookup_original_command(&name.to_ascii_uppercase()).is_some(), "{}", name);
+        }
+        assert_eq!(lookup_original_command("ZRangeByScore").unwrap().name(), "zrangebyscore");
+        assert!(lookup_original_command("").is_none());
+        assert!(lookup_original_command(&"a".repeat(MAX_COMMAND_NAME_LEN + 1)).is_none());
+        assert!(lookup_original_command("gét").is_none());
+    }
+
+    #[test]
+    fn command_docs_reports_table_arity() {
+        let _guard = setup();
+        let mut c = test_client();
+        let docs = run(&mut c, &["COMMAND", "DOCS", "GET"]);
+        assert!(docs.starts_with("*2\r\n$3\r\nget\r\n*8\r\n"), "{}", docs);
+        let arity = lookup_command("get").unwrap().arity();
+        assert!(docs.ends_with(&format!("$5\r\narity\r\n:{}\r\n", arity)), "{}", docs);
+        assert!(run(&mut c, &["COMMAND", "DOCS", "del"]).ends_with("$5\r\narity\r\n:-2\r\n"));
+
+        assert_eq!(run(&mut c, &["COMMAND", "DOCS", "nosuchcommand"]), "*0\r\n");
+        let all = run(&mut c, &["COMMAND", "DOCS"]);
+        assert!(all.starts_with(&format!("*{}\r\n", CMD_TABLE.len() * 2)));
+        assert_eq!(run(&mut c, &["COMMAND", "COUNT"]), format!(":{}\r\n", CMD_TABLE.len()));
+    }
+
+    #[test]
+    fn bare_command_replies_the_whole_table() {
+        let _guard = setup();
+        let mut c = test_client();
+        let table = run(&mut c, &["COMMAND"]);
+        assert!(table.starts_with(&format!("*{}\r\n", CMD_TABLE.len())), "{}", table);
+        assert!(table.contains("*3\r\n$3\r\nget\r\n:2\r\n*1\r\n$8\r\nreadonly\r\n"), "{}", table);
+        assert!(table.contains("*3\r\n$3\r\nset\r\n:3\r\n*2\r\n$5\r\nwrite\r\n$7\r\ndenyoom\r\n"), "{}", table);
+        assert!(table.contains("*3\r\n$8\r\nshutdown\r\n:1\r\n*2\r\n$8\r\nreadonly\r\n$5\r\nadmin\r\n"), "{}", table);
+    }
+
+    #[test]
+    fn container_commands_share_help_and_errors() {
+        let _guard = setup();
+        let mut c = test_client();
+        for (cmd, help) in [("COMMAND", COMMAND_HELP), ("OBJECT", OBJECT_HELP), ("DEBUG", DEBUG_HELP)] {
+            let reply = run(&mut c, &[cmd, "help"]);
+            let mut expected = format!("*{}\r\n+{} <subcommand> [<arg> [value] [opt] ...]. Subcommands are:\r\n", help.len() * 2 + 3, cmd);
+            for (usage, description) in help {
+                expected.push_str(&format!("+{}\r\n+    {}\r\n", usage, description));
+            }
+            expected.push_str("+HELP\r\n+    Print this help.\r\n");
+            assert_eq!(reply, expected);
+            assert_eq!(run(&mut c, &[cmd, "Nope"]),
+                format!("-ERR Unknown subcommand or wrong number of arguments for 'Nope'. Try {} HELP.\r\n", cmd));
+            assert!(run(&mut c, &[cmd, "HELP", "x"]).starts_with("-ERR Unknown subcommand"));
+        }
+    }
+
+    /// The keys KEYS replies for the pattern, sorted.
+    fn keys(c: &mut crate::client::RedisClient, pattern: &str) -> Vec<String> {
+        let reply = run(c, &["KEYS", pattern]);
+        let mut keys: Vec<String> = reply.split("\r\n").skip(2).step_by(2).filter(|k| !k.is_empty()).map(String::from).collect();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn keys_matches_glob_patterns() {
+        let _guard = setup();
+        let mut c = test_client();
+        for key in ["hello", "hallo", "hxllo", "user:1", "user:22", "a*b"] {
+            run(&mut c, &["SET", key, "v"]);
+        }
+        assert_eq!(keys(&mut c, "*").len(), 6);
+        assert_eq!(keys(&mut c, "user:*"), ["user:1", "user:22"]);
+        assert_eq!(keys(&mut c, "user:?"), ["user:1"]);
+        assert_eq!(keys(&mut c, "h[ae]llo"), ["hallo", "hello"]);
+        assert_eq!(keys(&mut c, "h[^ae]llo"), ["hxllo"]);
+        assert_eq!(keys(&mut c, "a\\*b"), ["a*b"]);
+        assert!(keys(&mut c, "nothing*").is_empty());
+    }
+
+    #[test]
+    fn keys_matches_every_type_of_key() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["SET", "apple", "v"]);
+        run(&mut c, &["RPUSH", "banana", "x"]);
+        run(&mut c, &["SADD", "cherry", "x"]);
+        run(&mut c, &["ZADD", "date", "1", "x"]);
+        run(&mut c, &["INCR", "counter:1"]);
+        assert_eq!(keys(&mut c, "[a-c]*"), ["apple", "banana", "cherry", "counter:1"]);
+        assert_eq!(keys(&mut c, "[^a-c]*"), ["date"]);
+        assert_eq!(keys(&mut c, "*a*e*"), ["apple", "date"]);
+        assert_eq!(keys(&mut c, "c*:?"), ["counter:1"]);
+        assert_eq!(keys(&mut c, "?????"), ["apple"]);
+        // Other databases are not looked at
+        run(&mut c, &["SELECT", "1"]);
+        assert!(keys(&mut c, "*").is_empty());
+        run(&mut c, &["SELECT", "0"]);
+    }
+
+    #[test]
+    fn range_indexes_are_clamped() {
+        let _guard = setup();
+        let mut c = test_client();
+        for ele in ["a", "b", "c"] {
+            run(&mut c, &["RPUSH", "l", ele]);
+        }
+        assert_eq!(run(&mut c, &["LRANGE", "l", "1", "2"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "l", "-2147483648", "2147483647"]), "*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "l", "2147483647", "2147483647"]), "*0\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "l", "0", "99999999999999999999"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["LRANGE", "l", "x", "y"]), "-ERR value is not an integer or out of range\r\n");
+
+        for (score, ele) in [("1", "a"), ("2", "b"), ("3", "c")] {
+            run(&mut c, &["ZADD", "z", score, ele]);
+        }
+        assert_eq!(run(&mut c, &["ZRANGE", "z", "0", "1"]), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(run(&mut c, &["ZRANGE", "z", "1", "2147483647"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["ZREVRANGE", "z", "-2147483648", "0"]), "*1\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["ZRANGE", "z", "5", "-5"]), "*0\r\n");
+
+        run(&mut c, &["LTRIM", "l", "-2147483648", "0"]);
+        assert_eq!(run(&mut c, &["LRANGE", "l", "0", "-1"]), "*1\r\n$1\r\na\r\n");
+    }
+
+    #[test]
+    fn zrangebyscore_pathological_limits() {
+        let _guard = setup();
+        let mut c = test_client();
+        for (score, ele) in [("1", "a"), ("2", "b"), ("3", "c")] {
+            run(&mut c, &["ZADD", "z", score, ele]);
+        }
+        assert_eq!(run(&mut c, &["ZRANGEBYSCORE", "z", "0", "10", "LIMIT", "1", "2147483647"]), "*2\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["ZRANGEBYSCORE", "z", "0", "10", "LIMIT", "2147483647", "2147483647"]), "*0\r\n");
+        assert_eq!(run(&mut c, &["ZRANGEBYSCORE", "z", "0", "10", "LIMIT", "0", "-1"]), "*3\r\n$1\r\na\r\n$1\r\nb\r\n$1\r\nc\r\n");
+        assert_eq!(run(&mut c, &["ZRANGEBYSCORE", "z", "0", "10", "LIMIT", "0", "9223372036854775807"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["ZRANGEBYSCORE", "z", "10", "20"]), "*0\r\n");
+    }
+
+    #[test]
+    fn zrange_option_matrix() {
+        let _guard = setup();
+        let mut c = test_client();
+        for (score, ele) in [("1", "a"), ("2", "b"), ("3", "c"), ("4", "d")] {
+            run(&mut c, &["ZADD", "z", score, ele]);
+            run(&mut c, &["ZADD", "l", "0", ele]);
+        }
+        run(&mut c, &["SET", "s", "v"]);
+        let array = |items: &[&str]| {
+            let mut reply = format!("*{}\r\n", items.len());
+            for item in items {
+                reply.push_str(&format!("${}\r\n{}\r\n", item.len(), item));
+            }
+            reply
+        };
+        let limit_err = "-ERR syntax error, LIMIT is only supported in combination with either BYSCORE or BYLEX\r\n";
+        let syntax_err = "-ERR syntax error\r\n";
+        let cases: &[(&[&str], String)] = &[
+            // By rank
+            (&["ZRANGE", "z", "0", "-1"], array(&["a", "b", "c", "d"])),
+            (&["ZRANGE", "z", "0", "1", "REV"], array(&["d", "c"])),
+            (&["ZRANGE", "z", "0", "0", "rev", "WITHSCORES"], array(&["d", "4"])),
+            (&["ZREVRANGE", "z", "0", "1", "WITHSCORES"], array(&["d", "4", "c", "3"])),
+            (&["ZRANGE", "z", "0", "1", "LIMIT", "0", "1"], limit_err.to_string()),
+            (&["ZREVRANGE", "z", "0", "1", "LIMIT", "0", "1"], limit_err.to_string()),
+            (&["ZREVRANGE", "z", "0", "1", "REV"], syntax_err.to_string()),
+            (&["ZREVRANGE", "z", "0", "1", "BYSCORE"], syntax_err.to_string()),
+            (&["ZRANGE", "z", "x", "1"], "-ERR value is not an integer or out of range\r\n".to_string()),
+            // By score
+            (&["ZRANGE", "z", "2", "3", "BYSCORE"], array(&["b", "c"])),
+            (&["ZRANGE", "z", "(1", "3", "byscore"], array(&["b", "c"])),
+            (&["ZRANGE", "z", "1", "(3", "BYSCORE"], array(&["a", "b"])),
+            (&["ZRANGE", "z", "-inf", "+inf", "BYSCORE", "LIMIT", "1", "2"], array(&["b", "c"])),
+            (&["ZRANGE", "z", "1", "3", "BYSCORE", "LIMIT", "1", "-1"], array(&["b", "c"])),
+            (&["ZRANGE", "z", "1", "3", "BYSCORE", "LIMIT", "-1", "2"], array(&[])),
+            (&["ZRANGE", "z", "3", "1", "BYSCORE", "REV"], array(&["c", "b", "a"])),
+            (&["ZRANGE", "z", "(4", "(1", "REV", "BYSCORE"], array(&["c", "b"])),
+            (&["ZRANGE", "z", "+inf", "-inf", "BYSCORE", "REV", "LIMIT", "0", "1", "WITHSCORES"], array(&["d", "4"])),
+            (&["ZRANGE", "z", "1", "3", "BYSCORE"], array(&["a", "b", "c"])),
+            (&["ZRANGE", "z", "3", "1", "BYSCORE"], array(&[])),
+            (&["ZRANGEBYSCORE", "z", "2", "4", "LIMIT", "1", "1", "WITHSCORES"], array(&["c", "3"])),
+            (&["ZRANGEBYSCORE", "z", "(2", "4"], array(&["c", "d"])),
+            (&["ZRANGEBYSCORE", "z", "0", "1", "REV"], syntax_err.to_string()),
+            (&["ZRANGEBYSCORE", "z", "0", "1", "BYLEX"], syntax_err.to_string()),
+            (&["ZRANGE", "z", "a", "1", "BYSCORE"], "-ERR min or max is not a float\r\n".to_string()),
+            (&["ZRANGE", "z", "nan", "1", "BYSCORE"], "-ERR min or max is not a float\r\n".to_string()),
+            (&["ZRANGE", "z", "1", "2", "BYSCORE", "LIMIT", "x", "1"], "-ERR value is not an integer or out of range\r\n".to_string()),
+            // By lex
+            (&["ZRANGE", "l", "[b", "(d", "BYLEX"], array(&["b", "c"])),
+            (&["ZRANGE", "l", "[b", "[b", "BYLEX"], array(&["b"])),
+            (&["ZRANGE", "l", "-", "+", "BYLEX", "LIMIT", "1", "1"], array(&["b"])),
+            (&["ZRANGE", "l", "+", "-", "BYLEX", "REV"], array(&["d", "c", "b", "a"])),
+            (&["ZRANGE", "l", "(c", "-", "BYLEX", "REV"], array(&["b", "a"])),
+            (&["ZRANGE", "l", "+", "-", "BYLEX"], array(&[])),
+            (&["ZRANGE", "l", "-", "+", "BYLEX", "WITHSCORES"], "-ERR syntax error, WITHSCORES not supported in combination with BYLEX\r\n".to_string()),
+            (&["ZRANGE", "l", "a", "c", "BYLEX"], "-ERR min or max not valid string range item\r\n".to_string()),
+            // Bad mixes
+            (&["ZRANGE", "z", "0", "1", "BYSCORE", "BYLEX"], syntax_err.to_string()),
+            (&["ZRANGE", "z", "0", "1", "BYSCORE", "BYSCORE"], syntax_err.to_string()),
+            (&["ZRANGE", "z", "0", "1", "BYSCORE", "LIMIT", "0"], syntax_err.to_string()),
+            (&["ZRANGE", "z", "0", "1", "FOO"], syntax_err.to_string()),
+            // Missing key and wrong type
+            (&["ZRANGE", "missing", "0", "-1", "BYSCORE"], array(&[])),
+            (&["ZRANGE", "s", "0", "-1"], "-WRONGTYPE Operation against a key holding the wrong kind of value\r\n".to_string()),
+        ];
+        for (args, expected) in cases {
+            assert_eq!(&run(&mut c, args), expected, "{:?}", args);
+        }
+    }
+
+    #[test]
+    fn object_encoding_follows_the_string_representation() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        let short = "a".repeat(EMBSTR_SIZE_LIMIT);
+        let long = "a".repeat(EMBSTR_SIZE_LIMIT + 1);
+        run(&mut c, &["SET", "short", &short]);
+        run(&mut c, &["SET", "long", &long]);
+        run(&mut c, &["INCR", "counter"]);
+        run(&mut c, &["INCRBY", "neg", "-12345"]);
+        run(&mut c, &["RPUSH", "list", "x"]);
+        run(&mut c, &["SADD", "set", "x"]);
+        run(&mut c, &["ZADD", "zset", "1", "x"]);
+
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "short"]), "$6\r\nembstr\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "long"]), "$3\r\nraw\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "counter"]), "$3\r\nint\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "neg"]), "$3\r\nint\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "list"]), "$10\r\nlinkedlist\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "set"]), "$9\r\nhashtable\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "zset"]), "$8\r\nskiplist\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "ENCODING", "nosuchkey"]), "$-1\r\n");
+        assert_eq!(run(&mut c, &["OBJECT", "FREQ", "short"]),
+            "-ERR Unknown subcommand or wrong number of arguments for 'FREQ'. Try OBJECT HELP.\r\n");
+
+        // The integer value reads back as its string form.
+        assert_eq!(run(&mut c, &["GET", "neg"]), "$6\r\n-12345\r\n");
+
+        let debug = run(&mut c, &["DEBUG", "OBJECT", "counter"]);
+        assert!(debug.starts_with("+Value at:") && debug.ends_with(" refcount:1 encoding:int\r\n"), "{}", debug);
+        assert!(run(&mut c, &["DEBUG", "OBJECT", "long"]).ends_with(" encoding:raw\r\n"));
+        assert_eq!(run(&mut c, &["DEBUG", "OBJECT", "nosuchkey"]), "-ERR no such key\r\n");
+    }
+
+    #[test]
+    fn hash_fields_are_set_read_and_deleted() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        assert_eq!(run(&mut c, &["HSET", "h", "a", "1"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["HSET", "h", "b", "2"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["HSET", "h", "a", "one"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["TYPE", "h"]), "+hash\r\n");
+        assert_eq!(run(&mut c, &["HGET", "h", "a"]), "$3\r\none\r\n");
+        assert_eq!(run(&mut c, &["HGET", "h", "nosuchfield"]), "$-1\r\n");
+        assert_eq!(run(&mut c, &["HGET", "nosuchkey", "a"]), "$-1\r\n");
+        assert_eq!(run(&mut c, &["HEXISTS", "h", "b"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["HEXISTS", "h", "c"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["HLEN", "h"]), ":2\r\n");
+        assert_eq!(run(&mut c, &["HLEN", "nosuchkey"]), ":0\r\n");
+
+        run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "1"]);
+        assert_eq!(run(&mut c, &["HKEYS", "h"]), "*2\r\n$1\r\na\r\n$1\r\nb\r\n");
+        assert_eq!(run(&mut c, &["HVALS", "h"]), "*2\r\n$3\r\none\r\n$1\r\n2\r\n");
+        assert_eq!(run(&mut c, &["HGETALL", "h"]), "*4\r\n$1\r\na\r\n$3\r\none\r\n$1\r\nb\r\n$1\r\n2\r\n");
+        assert_eq!(run(&mut c, &["HGETALL", "nosuchkey"]), "*0\r\n");
+        run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "0"]);
+
+        // The last field deleted takes the key with it.
+        assert_eq!(run(&mut c, &["HDEL", "h", "a"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["HDEL", "h", "a"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["HDEL", "h", "b"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "h"]), ":0\r\n");
+
+        run(&mut c, &["SET", "s", "v"]);
+        assert!(run(&mut c, &["HSET", "s", "a", "1"]).starts_with("-WRONGTYPE"));
+        assert!(run(&mut c, &["HGETALL", "s"]).starts_with("-WRONGTYPE"));
+        run(&mut c, &["HSET", "h", "a", "1"]);
+        assert!(run(&mut c, &["GET", "h"]).starts_with("-WRONGTYPE"));
+    }
+
+    #[test]
+    fn hincrby_counts_in_a_field() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "n", "5"]), ":5\r\n");
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "n", "-7"]), ":-2\r\n");
+        assert_eq!(run(&mut c, &["HGET", "h", "n"]), "$2\r\n-2\r\n");
+        run(&mut c, &["HSET", "h", "s", "10"]);
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "s", "1"]), ":11\r\n");
+
+        run(&mut c, &["HSET", "h", "word", "abc"]);
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "word", "1"]), "-ERR hash value is not an integer\r\n");
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "n", "x"]), "-ERR value is not an integer or out of range\r\n");
+        run(&mut c, &["HSET", "h", "max", &i64::MAX.to_string()]);
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "max", "1"]), "-ERR increment or decrement would overflow\r\n");
+        assert_eq!(run(&mut c, &["HGET", "h", "max"]), format!("${}\r\n{}\r\n", i64::MAX.to_string().len(), i64::MAX));
+
+        // A failed increment doesn't leave an empty hash behind.
+        run(&mut c, &["HSET", "other", "word", "abc"]);
+        run(&mut c, &["HDEL", "other", "word"]);
+        assert_eq!(run(&mut c, &["HINCRBY", "other", "n", "x"]), "-ERR value is not an integer or out of range\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "other"]), ":0\r\n");
+    }
+
+    #[test]
+    fn arity_is_checked_at_every_boundary() {
+        let _guard = setup();
+        let mut c = test_client();
+        let few = |name: &str| format!("-ERR wrong number of arguments for '{}' command (too few)\r\n", name);
+        let many = |name: &str| format!("-ERR wrong number of arguments for '{}' command (too many)\r\n", name);
+
+        // Variadic: DEL needs at least one key.
+        assert_eq!(run(&mut c, &["DEL"]), few("del"));
+        assert_eq!(run(&mut c, &["DEL", "a"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["DEL", "a", "b", "c"]), ":0\r\n");
+
+        // Fixed: GET takes exactly one key.
+        assert_eq!(run(&mut c, &["GET"]), few("get"));
+        assert_eq!(run(&mut c, &["GET", "a"]), "$-1\r\n");
+        assert_eq!(run(&mut c, &["GET", "a", "b"]), many("get"));
+
+        // Fixed bulk: SET key value.
+        assert_eq!(run(&mut c, &["SET", "a"]), few("set"));
+        assert_eq!(run(&mut c, &["SET", "a", "v"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["SET", "a", "v", "w"]), many("set"));
+
+        // No arguments at all.
+        assert_eq!(run(&mut c, &["PING"]), "+PONG\r\n");
+        assert_eq!(run(&mut c, &["PING", "x"]), many("ping"));
+
+        let del = lookup_command("del").unwrap();
+        assert!(!del.arity_ok(1) && del.arity_ok(2) && del.arity_ok(100));
+        assert_eq!(del.check_arity(1), Err(ArityError::TooFew));
+        let get = lookup_command("get").unwrap();
+        assert!(!get.arity_ok(1) && get.arity_ok(2) && !get.arity_ok(3));
+        assert_eq!(get.check_arity(1), Err(ArityError::TooFew));
+        assert_eq!(get.check_arity(3), Err(ArityError::TooMany));
+    }
 }
@@ -0,0 +1,528 @@
+//! Configuration parameters. Every directive the server understands is
+//! described once in CONFIG_PARAMS: the config file loader, CONFIG GET/SET
+//! and CONFIG REWRITE all go through that table so they can't drift apart.
+
+use std::{collections::HashSet, env::{current_dir, set_current_dir}, fmt::Display, fs::{self, OpenOptions}, str::FromStr};
+use crate::{client::{set_client_obuf_limits, RedisClient}, cmd::{lookup_original_command, reply_subcommand_help}, errors, obj::OK, server::{server_read, server_write, AppendFsync, ClientBufferLimit, RedisServer, CLIENT_OBUF_LIMIT_CLASSES, MAX_HZ, MIN_HZ}, util::{mem_to_bytes, set_log_level, string_pattern_match, yes_no_to_bool, LogLevel}};
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ConfigKind {
+    Bool,
+    Integer,
+    Memory,
+    String,
+    Enum,
+    /// A directive that can be repeated, every config file line adds `args`
+    /// more values (e.g. save). CONFIG SET replaces all of them at once.
+    List,
+}
+
+pub struct ConfigParam {
+    pub name: &'static str,
+    pub kind: ConfigKind,
+    pub args: usize,                                                // values taken by a config file line
+    pub mutable: bool,                                              // can be changed with CONFIG SET
+    pub get: fn(&RedisServer) -> Vec<String>,                       // one item per config file line
+    pub set: fn(&mut RedisServer, &[&str]) -> Result<(), String>,   // parses and validates too
+}
+
+/// Legacy spellings still accepted in the config file and by CONFIG SET.
+static CONFIG_ALIASES: &[(&str, &str)] = &[
+    ("slaveof", "replicaof"),
+    ("slave-read-only", "replica-read-only"),
+];
+
+pub fn lookup_config(name: &str) -> Option<&'static ConfigParam> {
+    let mut name = name.to_ascii_lowercase();
+    if let Some((_, canonical)) = CONFIG_ALIASES.iter().find(|(alias, _)| *alias == name) {
+        name = canonical.to_string();
+    }
+    CONFIG_PARAMS.iter().find(|p| p.name == name)
+}
+
+/// The names `param` goes by, its own first.
+fn config_names(param: &ConfigParam) -> impl Iterator<Item = &'static str> + '_ {
+    std::iter::once(param.name).chain(CONFIG_ALIASES.iter().filter(|(_, c)| *c == param.name).map(|(alias, _)| *alias))
+}
+
+fn yes_no(b: bool) -> Vec<String> {
+    vec![if b { "yes" } else { "no" }.to_string()]
+}
+fn one<T: Display>(v: T) -> Vec<String> {
+    vec![v.to_string()]
+}
+fn parse_in<T: FromStr + PartialOrd + Display>(v: &str, min: T, max: T) -> Result<T, String> {
+    match v.parse() {
+        Ok(n) if n >= min && n <= max => Ok(n),
+        Ok(_) => Err(format!("argument must be between {} and {} inclusive", min, max)),
+        Err(_) => Err("argument couldn't be parsed into an integer".to_string()),
+    }
+}
+
+pub static CONFIG_PARAMS: &[ConfigParam] = &[
+    ConfigParam { name: "timeout", kind: ConfigKind::Integer, args: 1, mutable: true,
+        get: |s| one(s.max_idle_time),
+        set: |s, v| { s.max_idle_time = parse_in(v[0], 0, i32::MAX).map_err(|_| "Invalid timeout value".to_string())?; Ok(()) } },
+    ConfigParam { name: "hz", kind: ConfigKind::Integer, args: 1, mutable: true,
+        get: |s| one(s.hz),
+        set: |s, v| {
+            s.hz = parse_in(v[0], MIN_HZ, MAX_HZ).map_err(|_| format!("Invalid hz value, must be between {} and {}", MIN_HZ, MAX_HZ))?;
+            Ok(())
+        } },
+    ConfigParam { name: "port", kind: ConfigKind::Integer, args: 1, mutable: false,
+        get: |s| one(s.port),
+        set: |s, v| { s.port = parse_in(v[0], 0, u16::MAX).map_err(|_| "Invalid port".to_string())?; Ok(()) } },
+    ConfigParam { name: "bind-retries", kind: ConfigKind::Integer, args: 1, mutable: false,
+        get: |s| one(s.bind_retries),
+        set: |s, v| { s.bind_retries = parse_in(v[0], 0, u32::MAX).map_err(|_| "Invalid bind-retries value".to_string())?; Ok(()) } },
+    ConfigParam { name: "tcp-backlog", kind: ConfigKind::Integer, args: 1, mutable: false,
+        get: |s| one(s.tcp_backlog),
+        set: |s, v| { s.tcp_backlog = parse_in(v[0], 1, i32::MAX).map_err(|_| "Invalid tcp-backlog value".to_string())?; Ok(()) } },
+    ConfigParam { name: "bind", kind: ConfigKind::String, args: 1, mutable: false,
+        get: |s| one(&s.bind_addr),
+        set: |s, v| { s.bind_addr = v[0].to_string(); Ok(()) } },
+    ConfigParam { name: "save", kind: ConfigKind::List, args: 2, mutable: true,
+        get: |s| s.save_params().iter().map(|p| format!("{} {}", p.seconds(), p.changes())).collect(),
+        set: |s, v| {
+            let mut params = Vec::with_capacity(v.len() / 2);
+            for pair in v.chunks(2) {
+                match (pair[0].parse::<u64>(), pair[1].parse::<i32>()) {
+                    (Ok(seconds), Ok(changes)) if seconds >= 1 && changes >= 0 => { params.push((seconds, changes)); },
+                    (Ok(_), Ok(_)) => { return Err("Invalid save parameters".to_string()); },
+                    _ => { return Err("seconds or changes parsing failed".to_string()); },
+                }
+            }
+            s.reset_server_save_params();
+            for (seconds, changes) in params {
+                s.append_server_save_params(seconds, changes);
+            }
+            Ok(())
+        } },
+    ConfigParam { name: "dir", kind: ConfigKind::String, args: 1, mutable: true,
+        get: |_| one(current_dir().map(|d| d.display().to_string()).unwrap_or_default()),
+        set: |_, v| set_current_dir(v[0]).map_err(|e| format!("Can't chdir to '{}': {}", v[0], e)) },
+    ConfigParam { name: "loglevel", kind: ConfigKind::Enum, args: 1, mutable: true,
+        get: |s| one(match s.verbosity {
+            LogLevel::Debug => "debug",
+            LogLevel::Verbose => "verbose",
+            LogLevel::Notice => "notice",
+            LogLevel::Warning => "warning",
+        }),
+        set: |s, v| {
+            s.verbosity = match &v[0].to_ascii_lowercase()[..] {
+                "debug" => LogLevel::Debug,
+                "verbose" => LogLevel::Verbose,
+                "notice" => LogLevel::Notice,
+                "warning" => LogLevel::Warning,
+                _ => { return Err("Invalid log level. Must be one of debug, verbose, notice or warning".to_string()); },
+            };
+            set_log_level(s.verbosity);
+            Ok(())
+        } },
+    ConfigParam { name: "logfile", kind: ConfigKind::String, args: 1, mutable: false,
+        get: |s| one(if s.log_file.is_empty() { "stdout" } else { &s.log_file }),
+        set: |s, v| {
+            if v[0].eq_ignore_ascii_case("stdout") {
+                s.log_file = String::new();
+                return Ok(());
+            }
+            // Test if we are able to open the file. The server will not
+            // be able to abort just for this problem later...
+            OpenOptions::new().append(true).open(v[0]).map_err(|e| format!("Can't open the log file: {}", e))?;
+            s.log_file = v[0].to_string();
+            Ok(())
+        } },
+    ConfigParam { name: "databases", kind: ConfigKind::Integer, args: 1, mutable: false,
+        get: |s| one(s.dbnum),
+        set: |s, v| { s.dbnum = parse_in(v[0], 1, i32::MAX).map_err(|_| "Invalid number of databases".to_string())?; Ok(()) } },
+    ConfigParam { name: "maxclients", kind: ConfigKind::Integer, args: 1, mutable: true,
+        get: |s| one(s.max_clients),
+        set: |s, v| { s.max_clients = parse_in(v[0], 0, u32::MAX)?; Ok(()) } },
+    ConfigParam { name: "client-output-buffer-limit", kind: ConfigKind::List, args: 4, mutable: true,
+        get: |s| s.client_obuf_limits.iter().zip(CLIENT_OBUF_LIMIT_CLASSES)
+            .map(|(l, class)| format!("{} {} {} {}", class, l.hard, l.soft, l.soft_seconds)).collect(),
+        set: |s, v| {
+            // Only the classes given change
+            let mut limits = s.client_obuf_limits;
+            for line in v.chunks(4) {
+                let class = match &line[0].to_ascii_lowercase()[..] {
+                    "slave" => 1,
+                    name => CLIENT_OBUF_LIMIT_CLASSES.iter().position(|c| *c == name)
+                        .ok_or_else(|| format!("Invalid client class '{}'", line[0]))?,
+                };
+                let bytes = |v: &str| mem_to_bytes(v).and_then(|b| u64::try_from(b).map_err(|_| "argument out of range".to_string()));
+                limits[class] = ClientBufferLimit { hard: bytes(line[1])?, soft: bytes(line[2])?, soft_seconds: parse_in(line[3], 0, u64::MAX)? };
+            }
+            s.client_obuf_limits = limits;
+            set_client_obuf_limits(limits);
+            Ok(())
+        } },
+    ConfigParam { name: "maxmemory", kind: ConfigKind::Memory, args: 1, mutable: true,
+        get: |s| one(s.max_memory),
+        set: |s, v| { s.max_memory = mem_to_bytes(v[0])?; Ok(()) } },
+    ConfigParam { name: "lazyfree-lazy-expire", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.lazyfree_lazy_expire),
+        set: |s, v| { s.lazyfree_lazy_expire = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "lazyfree-lazy-eviction", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.lazyfree_lazy_eviction),
+        set: |s, v| { s.lazyfree_lazy_eviction = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "latency-monitor-threshold", kind: ConfigKind::Integer, args: 1, mutable: true,
+        get: |s| one(s.latency_monitor_threshold),
+        set: |s, v| { s.latency_monitor_threshold = parse_in(v[0], 0, u64::MAX)?; Ok(()) } },
+    ConfigParam { name: "replicaof", kind: ConfigKind::String, args: 2, mutable: false,
+        get: |s| if s.is_slave { vec![format!("{} {}", s.master_host, s.master_port)] } else { vec![] },
+        set: |s, v| { s.set_master(v[0], parse_in(v[1], 1, u16::MAX)?); Ok(()) } },
+    ConfigParam { name: "replica-read-only", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.repl_slave_ro),
+        set: |s, v| { s.repl_slave_ro = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "repl-diskless-sync", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.repl_diskless_sync),
+        set: |s, v| { s.repl_diskless_sync = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "masterauth", kind: ConfigKind::String, args: 1, mutable: true,
+        get: |s| one(&s.master_auth),
+        set: |s, v| { s.master_auth = v[0].to_string(); Ok(()) } },
+    ConfigParam { name: "glueoutputbuf", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.glue_output_buf),
+        set: |s, v| { s.glue_output_buf = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "shareobjects", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.share_objects),
+        set: |s, v| { s.share_objects = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "rdbcompression", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.rdb_compression),
+        set: |s, v| { s.rdb_compression = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "flushall-saves", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.flushall_saves),
+        set: |s, v| { s.flushall_saves = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "shareobjectspoolsize", kind: ConfigKind::Integer, args: 1, mutable: false,
+        get: |s| one(s.sharing_pool_size),
+        set: |s, v| {
+            s.sharing_pool_size = parse_in(v[0], 1, u32::MAX).map_err(|_| "invalid object sharing pool size".to_string())?;
+            Ok(())
+        } },
+    ConfigParam { name: "daemonize", kind: ConfigKind::Bool, args: 1, mutable: false,
+        get: |s| yes_no(s.daemonize),
+        set: |s, v| { s.daemonize = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "appendonly", kind: ConfigKind::Bool, args: 1, mutable: false,
+        get: |s| yes_no(s.append_only),
+        set: |s, v| { s.append_only = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "appendfsync", kind: ConfigKind::Enum, args: 1, mutable: true,
+        get: |s| one(match s.append_fsync {
+            AppendFsync::No => "no",
+            AppendFsync::Always => "always",
+            AppendFsync::EverySec => "everysec",
+        }),
+        set: |s, v| {
+            s.append_fsync = match &v[0].to_ascii_lowercase()[..] {
+                "no" => AppendFsync::No,
+                "always" => AppendFsync::Always,
+                "everysec" => AppendFsync::EverySec,
+                _ => { return Err("argument must be 'no', 'always' or 'everysec'".to_string()); },
+            };
+            Ok(())
+        } },
+    ConfigParam { name: "aof-load-truncated", kind: ConfigKind::Bool, args: 1, mutable: true,
+        get: |s| yes_no(s.aof_load_truncated),
+        set: |s, v| { s.aof_load_truncated = yes_no_to_bool(v[0])?; Ok(()) } },
+    ConfigParam { name: "requirepass", kind: ConfigKind::String, args: 1, mutable: true,
+        get: |s| one(&s.require_pass),
+        set: |s, v| { s.require_pass = v[0].to_string(); Ok(()) } },
+    ConfigParam { name: "rename-command", kind: ConfigKind::List, args: 2, mutable: false,
+        get: |s| s.command_renames.iter().map(|(cmd, name)| format!("{} {}", cmd, if name.is_empty() { "\"\"" } else { name })).collect(),
+        set: |s, v| {
+            let mut renames: Vec<(String, String)> = Vec::with_capacity(v.len() / 2);
+            for pair in v.chunks(2) {
+                let cmd = pair[0].to_lowercase();
+                // The config file has no quoting, "" is taken literally
+                let name = if pair[1] == "\"\"" { String::new() } else { pair[1].to_lowercase() };
+                if lookup_original_command(&cmd).is_none() {
+                    return Err("No such command in rename-command".to_string());
+                }
+                if lookup_original_command(&name).is_some() || renames.iter().any(|(_, n)| !n.is_empty() && *n == name) {
+                    return Err("Target command name already exists".to_string());
+                }
+                renames.retain(|(c, _)| *c != cmd);
+                renames.push((cmd, name));
+            }
+            s.command_renames = renames;
+            Ok(())
+        } },
+    ConfigParam { name: "pidfile", kind: ConfigKind::String, args: 1, mutable: false,
+        get: |s| one(&s.pid_file),
+        set: |s, v| { s.pid_file = v[0].to_string(); Ok(()) } },
+    ConfigParam { name: "dbfilename", kind: ConfigKind::String, args: 1, mutable: true,
+        get: |s| one(&s.db_filename),
+        set: |s, v| { s.db_filename = v[0].to_string(); Ok(()) } },
+    ConfigParam { name: "hash-max-zipmap-entries", kind: ConfigKind::Integer, args: 1, mutable: true,
+        get: |s| one(s.hash_max_zipmap_entries),
+        set: |s, v| { s.hash_max_zipmap_entries = parse_in(v[0], 0, usize::MAX)?; Ok(()) } },
+    ConfigParam { name: "hash-max-zipmap-value", kind: ConfigKind::Integer, args: 1, mutable: true,
+        get: |s| one(s.hash_max_zipmap_value),
+        set: |s, v| { s.hash_max_zipmap_value = parse_in(v[0], 0, usize::MAX)?; Ok(()) } },
+];
+
+/// Apply one config file line (the directive name excluded) to `server`.
+/// List directives add their values to the ones already set.
+pub fn apply_config_line(server: &mut RedisServer, param: &ConfigParam, args: &[&str]) -> Result<(), String> {
+    if param.kind != ConfigKind::List {
+        return (param.set)(server, args);
+    }
+    let current = (param.get)(server);
+    let mut values: Vec<&str> = current.iter().flat_map(|l| l.split_whitespace()).collect();
+    values.extend_from_slice(args);
+    (param.set)(server, &values)
+}
+
+/// CONFIG GET pattern [pattern ...]: the parameters matching any of the
+/// patterns, by any of their names, every one reported once, in table order.
+fn config_get(patterns: &[String]) -> Vec<(&'static str, String)> {
+    let server = server_read();
+    CONFIG_PARAMS.iter()
+        .filter(|p| patterns.iter().any(|pat| config_names(p).any(|name| string_pattern_match(pat, name))))
+        .map(|p| (p.name, (p.get)(&server).join(" ")))
+        .collect()
+}
+
+fn config_set(name: &str, value: &str) -> Result<(), String> {
+    let Some(param) = lookup_config(name) else {
+        return Err(errors::err(&format!("Unknown option or number of arguments for CONFIG SET - '{}'", name)));
+    };
+    let failed = |reason: &str| errors::err(&format!("CONFIG SET failed (possibly related to argument '{}') - {}", param.name, reason));
+    if !param.mutable {
+        return Err(failed("can't set immutable config"));
+    }
+    let values: Vec<&str> = if param.args == 1 { vec![value] } else { value.split_whitespace().collect() };
+    let count_ok = match param.kind {
+        ConfigKind::List => values.len().is_multiple_of(param.args),
+        _ => values.len() == param.args,
+    };
+    if !count_ok {
+        return Err(failed("wrong number of arguments"));
+    }
+    (param.set)(&mut server_write(), &values).map_err(|e| failed(&e))
+}
+
+/// Rewrite the config file the server was started with so that it matches
+/// the current configuration. Comments and unknown lines are kept, the first
+/// line of every known directive is replaced by its current value(s) and
+/// the following ones dropped. Directives missing from the file are appended
+/// only if they differ from the default.
+pub fn rewrite_config(server: &RedisServer) -> Result<(), String> {
+    let Some(path) = server.config_file.clone() else {
+        return Err("The server is running without a config file".to_string());
+    };
+    let old = fs::read_to_string(&path).unwrap_or_default();
+    let defaults = RedisServer::new();
+
+    let mut lines: Vec<String> = Vec::new();
+    let mut rewritten: HashSet<&'static str> = HashSet::new();
+    let directive = |param: &ConfigParam, server: &RedisServer| -> Vec<String> {
+        (param.get)(server).into_iter().map(|v| format!("{} {}", param.name, v)).collect()
+    };
+    for line in old.lines() {
+        let name = line.split_whitespace().next().unwrap_or("");
+        match lookup_config(name) {
+            Some(param) if !line.trim_start().starts_with('#') => {
+                if rewritten.insert(param.name) {
+                    lines.extend(directive(param, server));
+                }
+            },
+            _ => { lines.push(line.to_string()); },
+        }
+    }
+    for param in CONFIG_PARAMS.iter().filter(|p| !rewritten.contains(p.name)) {
+        if (param.get)(server) != (param.get)(&defaults) {
+            lines.extend(directive(param, server));
+        }
+    }
+
+    let tmp = format!("{}.tmp-{}", path, std::process::id());
+    let mut content = lines.join("\n");
+    content.push('\n');
+    fs::write(&tmp, content).and_then(|_| fs::rename(&tmp, &path)).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e.to_string()
+    })
+}
+
+static CONFIG_HELP: &[(&str, &str)] = &[
+    ("GET <pattern> [<pattern> ...]", "Return parameters matching any of the glob-like patterns and their values."),
+    ("SET <directive> <value>", "Set the configuration <directive> to <value>."),
+    ("REWRITE", "Rewrite the configuration file."),
+];
+
+pub fn config_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "get" if c.argv.len() >= 3 => {
+            let patterns: Vec<String> = c.argv[2..].iter().map(|p| p.read().unwrap().as_key().to_ascii_lowercase()).collect();
+            let found = config_get(&patterns);
+            c.add_reply_str(&format!("*{}\r\n", found.len() * 2));
+            for (name, value) in found {
+                c.add_reply_bulk_str(name);
+                c.add_reply_bulk_str(&value);
+            }
+        },
+        "set" if c.argv.len() == 4 => {
+            let name = c.argv[2].read().unwrap().as_key().to_string();
+            let value = c.argv[3].read().unwrap().as_key().to_string();
+            match config_set(&name, &value) {
+                Ok(_) => { c.add_reply(OK.clone()); },
+                Err(e) => { c.add_reply_error(&e); },
+            }
+        },
+        "rewrite" if c.argv.len() == 2 => {
+            let res = rewrite_config(&server_read());
+            match res {
+                Ok(_) => { c.add_reply(OK.clone()); },
+                Err(e) => { c.add_reply_error(&errors::err(&format!("Rewriting config file: {}", e))); },
+            }
+        },
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, CONFIG_HELP);
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("config", &c.arg(1)));
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::tests::{run, test_client}, server::{server_read, server_write, tests::setup}};
+    use super::{apply_config_line, lookup_config, CONFIG_PARAMS};
+
+    fn bulks(items: &[&str]) -> String {
+        let mut reply = format!("*{}\r\n", items.len());
+        for item in items {
+            reply.push_str(&format!("${}\r\n{}\r\n", item.len(), item));
+        }
+        reply
+    }
+
+    #[test]
+    fn every_mutable_parameter_round_trips() {
+        let _guard = setup();
+        let mut c = test_client();
+        for param in CONFIG_PARAMS {
+            let value = (param.get)(&server_read()).join(" ");
+            let reply = run(&mut c, &["CONFIG", "SET", param.name, &value]);
+            if param.mutable {
+                assert_eq!(reply, "+OK\r\n", "{}", param.name);
+            } else {
+                assert!(reply.contains("can't set immutable config"), "{}: {}", param.name, reply);
+            }
+            assert_eq!(run(&mut c, &["CONFIG", "GET", param.name]), bulks(&[param.name, &value]), "{}", param.name);
+        }
+    }
+
+    #[test]
+    fn set_validates_and_names_the_parameter() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "hz", "20"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "hz"]), bulks(&["hz", "20"]));
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "hz", "0"]),
+            "-ERR CONFIG SET failed (possibly related to argument 'hz') - Invalid hz value, must be between 1 and 500\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "HZ", "10"]), "+OK\r\n");
+
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "no-such-thing", "1"]),
+            "-ERR Unknown option or number of arguments for CONFIG SET - 'no-such-thing'\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "port", "7000"]),
+            "-ERR CONFIG SET failed (possibly related to argument 'port') - can't set immutable config\r\n");
+
+        let save = (lookup_config("save").unwrap().get)(&server_read()).join(" ");
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "save", "900 1 300"]),
+            "-ERR CONFIG SET failed (possibly related to argument 'save') - wrong number of arguments\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "save", "900 1 300 10"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "save"]), bulks(&["save", "900 1 300 10"]));
+        run(&mut c, &["CONFIG", "SET", "save", &save]);
+    }
+
+    #[test]
+    fn get_merges_patterns_without_duplicates() {
+        let _guard = setup();
+        let mut c = test_client();
+        let reply = run(&mut c, &["CONFIG", "GET", "hash-max-*", "*-entries", "HZ"]);
+        let entries = server_read().hash_max_zipmap_entries.to_string();
+        let value = server_read().hash_max_zipmap_value.to_string();
+        assert_eq!(reply, bulks(&["hz", "10", "hash-max-zipmap-entries", &entries, "hash-max-zipmap-value", &value]));
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "nothing*"]), "*0\r\n");
+
+        let help = run(&mut c, &["CONFIG", "HELP"]);
+        assert!(help.starts_with("*9\r\n+CONFIG <subcommand>"), "{}", help);
+        assert!(help.ends_with("+HELP\r\n+    Print this help.\r\n"), "{}", help);
+        assert_eq!(run(&mut c, &["CONFIG", "FOO"]),
+            "-ERR Unknown subcommand or wrong number of arguments for 'FOO'. Try CONFIG HELP.\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "REWRITE", "x"]),
+            "-ERR Unknown subcommand or wrong number of arguments for 'REWRITE'. Try CONFIG HELP.\r\n");
+    }
+
+    #[test]
+    fn rename_command_renames_or_disables() {
+        let _guard = setup();
+        let mut c = test_client();
+        let param = lookup_config("rename-command").unwrap();
+        for line in [["flushall", "wipe-it"], ["CONFIG", "\"\""]] {
+            apply_config_line(&mut server_write(), param, &line).unwrap();
+        }
+        assert_eq!((param.get)(&server_read()), ["flushall wipe-it", "config \"\""]);
+
+        run(&mut c, &["SET", "k", "v"]);
+        assert_eq!(run(&mut c, &["FLUSHALL"]), "-ERR unknown command 'FLUSHALL'\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "k"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["WIPE-IT"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "k"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "port"]), "-ERR unknown command 'CONFIG'\r\n");
+
+        let mut server = crate::server::RedisServer::new();
+        assert_eq!(apply_config_line(&mut server, param, &["nosuchcommand", "x"]), Err("No such command in rename-command".to_string()));
+        assert_eq!(apply_config_line(&mut server, param, &["del", "get"]), Err("Target command name already exists".to_string()));
+        server_write().command_renames.clear();
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "rename-command"]), bulks(&["rename-command", ""]));
+    }
+
+    #[test]
+    fn legacy_spellings_are_aliases() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(lookup_config("SLAVEOF").unwrap().name, "replicaof");
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "slave-read-only", "no"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "replica-read-only"]), bulks(&["replica-read-only", "no"]));
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "slave-read-only"]), bulks(&["replica-read-only", "no"]));
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "replica-read-only", "yes"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["CONFIG", "GET", "slaveof"]), bulks(&["replicaof", ""]));
+
+        let mut server = crate::server::RedisServer::new();
+        for line in ["slaveof 10.0.0.1 6380", "slave-read-only no", "repl-diskless-sync yes"] {
+            let args: Vec<&str> = line.split_whitespace().collect();
+            super::apply_config_line(&mut server, lookup_config(args[0]).unwrap(), &args[1..]).unwrap();
+        }
+        assert!(server.is_slave());
+        assert_eq!(server.master_port, 6380);
+        assert!(!server.repl_slave_ro);
+        assert!(server.repl_diskless_sync);
+    }
+
+    #[test]
+    fn rewrite_keeps_comments_and_updates_values() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["CONFIG", "REWRITE"]), "-ERR Rewriting config file: The server is running without a config file\r\n");
+
+        let conf = std::env::temp_dir().join(format!("rudis-rewrite-{}.conf", std::process::id()));
+        std::fs::write(&conf, "# my comment\nhz 10\nhz 30\nunknown-directive 1\ninclude other.conf\n").unwrap();
+        server_write().config_file = Some(conf.to_str().unwrap().to_string());
+        run(&mut c, &["CONFIG", "SET", "hz", "25"]);
+        run(&mut c, &["CONFIG", "SET", "timeout", "42"]);
+        assert_eq!(run(&mut c, &["CONFIG", "REWRITE"]), "+OK\r\n");
+        let rewritten = std::fs::read_to_string(&conf).unwrap();
+
+        run(&mut c, &["CONFIG", "SET", "hz", "10"]);
+        run(&mut c, &["CONFIG", "SET", "timeout", &crate::server::RedisServer::new().max_idle_time.to_string()]);
+        server_write().config_file = None;
+        std::fs::remove_file(&conf).unwrap();
+        assert!(rewritten.starts_with("# my comment\nhz 25\nunknown-directive 1\ninclude other.conf\n"), "{}", rewritten);
+        assert!(rewritten.contains("\ntimeout 42\n"), "{}", rewritten);
+    }
+}
@@ -0,0 +1,229 @@
+//! A hash table keyed by strings that can also draw random entries in
+//! constant time, used for the keyspace and the expires of a DB.
+//!
+//! A std HashMap can only be walked, so picking a random key out of it
+//! costs a walk to a random position. Here the entries live in a Vec and
+//! the map only holds the position of every key in it: a random entry is a
+//! random position. Removing an entry moves the last one in its place.
+//!
+//! A dict can also keep its positions ordered by the hash of their key, for
+//! SCAN: a batch of the iteration is then a range of that index.
+
+use std::{collections::{BTreeSet, HashMap}, hash::{DefaultHasher, Hash, Hasher}};
+use rand::{seq::index, Rng};
+
+/// The hash SCAN orders keys by, and the cursor is made of. It must not
+/// depend on the process so that a cursor stays valid across restarts.
+pub fn scan_hash(name: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    name.hash(&mut hasher);
+    hasher.finish()
+}
+
+pub struct Dict<V> {
+    index: HashMap<String, usize>,          // position of every key in entries
+    entries: Vec<(String, V)>,
+    by_hash: Option<BTreeSet<(u64, usize)>>,    // positions by scan_hash() of their key, see scan()
+}
+
+impl<V> Dict<V> {
+    pub fn new() -> Dict<V> {
+        Dict { index: HashMap::new(), entries: Vec::new(), by_hash: None }
+    }
+
+    /// A dict that can be iterated with scan().
+    pub fn with_scan_index() -> Dict<V> {
+        Dict { by_hash: Some(BTreeSet::new()), ..Self::new() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Number of keys the table can hold without growing.
+    pub fn capacity(&self) -> usize {
+        self.index.capacity()
+    }
+
+    pub fn get(&self, key: &str) -> Option<&V> {
+        self.index.get(key).map(|&i| &self.entries[i].1)
+    }
+
+    pub fn get_mut(&mut self, key: &str) -> Option<&mut V> {
+        self.index.get(key).map(|&i| &mut self.entries[i].1)
+    }
+
+    pub fn contains_key(&self, key: &str) -> bool {
+        self.index.contains_key(key)
+    }
+
+    /// Add or replace the value of `key`, returning the replaced one.
+    pub fn insert(&mut self, key: String, val: V) -> Option<V> {
+        match self.index.get(&key) {
+            Some(&i) => Some(std::mem::replace(&mut self.entries[i].1, val)),
+            None => {
+                if let Some(by_hash) = self.by_hash.as_mut() {
+                    by_hash.insert((scan_hash(&key), self.entries.len()));
+                }
+                self.index.insert(key.clone(), self.entries.len());
+                self.entries.push((key, val));
+                None
+            },
+        }
+    }
+
+    pub fn remove(&mut self, key: &str) -> Option<V> {
+        let i = self.index.remove(key)?;
+        let last = self.entries.len() - 1;
+        let (_, val) = self.entries.swap_remove(i);
+        if let Some(by_hash) = self.by_hash.as_mut() {
+            by_hash.remove(&(scan_hash(key), i));
+        }
+        if let Some((moved, _)) = self.entries.get(i) {
+            *self.index.get_mut(moved).unwrap() = i;
+            if let Some(by_hash) = self.by_hash.as_mut() {
+                let hash = scan_hash(moved);
+                by_hash.remove(&(hash, last));
+                by_hash.insert((hash, i));
+            }
+        }
+        Some(val)
+    }
+
+    pub fn clear(&mut self) {
+        self.index.clear();
+        self.entries.clear();
+        if let Some(by_hash) = self.by_hash.as_mut() {
+            by_hash.clear();
+        }
+    }
+
+    pub fn iter(&self) -> impl ExactSizeIterator<Item = (&String, &V)> + Clone {
+        self.entries.iter().map(|(key, val)| (key, val))
+    }
+
+    pub fn keys(&self) -> impl ExactSizeIterator<Item = &String> + Clone {
+        self.entries.iter().map(|(key, _)| key)
+    }
+
+    /// The next batch of an iteration in the order of scan_hash(): the
+    /// `count` entries with the smallest hashes from `cursor` on, plus any
+    /// other entry sharing the last hash, and the next cursor. 0 means the
+    /// iteration is complete. A batch costs O(count log n). Only for dicts
+    /// made with with_scan_index().
+    pub fn scan(&self, cursor: u64, count: usize) -> (Vec<(&String, &V)>, u64) {
+        let by_hash = self.by_hash.as_ref().expect("dict without a scan index");
+        let count = count.max(1);
+        let mut batch = Vec::with_capacity(count);
+        let mut last = None;
+        for &(hash, i) in by_hash.range((cursor, 0)..) {
+            if batch.len() >= count && last != Some(hash) {
+                let next = last.and_then(|last: u64| last.checked_add(1)).unwrap_or(0);
+                return (batch, next);
+            }
+            batch.push((&self.entries[i].0, &self.entries[i].1));
+            last = Some(hash);
+        }
+        (batch, 0)
+    }
+
+    /// A random entry, None if the dict is empty.
+    pub fn random_entry(&self) -> Option<(&String, &V)> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let (key, val) = &self.entries[rand::thread_rng().gen_range(0..self.entries.len())];
+        Some((key, val))
+    }
+
+    /// Up to `k` distinct random entries, fewer only if the dict is smaller.
+    pub fn sample(&self, k: usize) -> Vec<(&String, &V)> {
+        let k = k.min(self.entries.len());
+        index::sample(&mut rand::thread_rng(), self.entries.len(), k).into_iter()
+            .map(|i| (&self.entries[i].0, &self.entries[i].1))
+            .collect()
+    }
+}
+
+impl<V> Default for Dict<V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use super::*;
+
+    #[test]
+    fn remove_keeps_the_positions_consistent() {
+        let mut dict = Dict::new();
+        for i in 0..100 {
+            assert_eq!(dict.insert(format!("k{}", i), i), None);
+        }
+        assert_eq!(dict.insert("k7".to_string(), 700), Some(7));
+        for i in (0..100).step_by(3) {
+            assert_eq!(dict.remove(&format!("k{}", i)), Some(if i == 7 { 700 } else { i }));
+        }
+        assert_eq!(dict.remove("k0"), None);
+        assert_eq!(dict.len(), 66);
+        for i in 0..100 {
+            let expected = if i % 3 == 0 { None } else if i == 7 { Some(700) } else { Some(i) };
+            assert_eq!(dict.get(&format!("k{}", i)).copied(), expected);
+        }
+        assert_eq!(dict.iter().count(), 66);
+    }
+
+    #[test]
+    fn scan_walks_the_index_in_hash_order() {
+        let mut dict = Dict::with_scan_index();
+        for i in 0..1000 {
+            dict.insert(format!("k{}", i), i);
+        }
+        for i in (0..1000).step_by(2) {
+            dict.remove(&format!("k{}", i));
+        }
+        let mut hashes = Vec::new();
+        let mut cursor = 0;
+        loop {
+            let (batch, next) = dict.scan(cursor, 10);
+            assert!(batch.len() >= 10 || next == 0);
+            for (key, val) in batch {
+                assert_eq!(*key, format!("k{}", val));
+                assert!(scan_hash(key) >= cursor);
+                hashes.push(scan_hash(key));
+            }
+            if next == 0 {
+                break;
+            }
+            cursor = next;
+        }
+        assert_eq!(hashes.len(), 500);
+        assert!(hashes.windows(2).all(|w| w[0] < w[1]));
+        dict.clear();
+        assert_eq!(dict.scan(0, 10), (vec![], 0));
+    }
+
+    #[test]
+    fn samples_are_distinct_and_cover_the_dict() {
+        let mut dict = Dict::new();
+        assert!(dict.random_entry().is_none());
+        assert!(dict.sample(5).is_empty());
+        for i in 0..10 {
+            dict.insert(i.to_string(), i);
+        }
+        let sample = dict.sample(20);
+        assert_eq!(sample.iter().map(|(k, _)| k).collect::<HashSet<_>>().len(), 10);
+
+        let mut seen = HashSet::new();
+        for _ in 0..1000 {
+            seen.insert(dict.random_entry().unwrap().0.clone());
+        }
+        assert_eq!(seen.len(), 10);
+    }
+}
@@ -0,0 +1,183 @@
+use crate::cmd::ArityError;
+
+
+///
+/// Error Replies.
+///
+/// The first word of an error reply is its class. Clients dispatch on it
+/// (e.g. to retry on LOADING or to re-authenticate on NOAUTH), so every
+/// error we send is built here with the same code Redis uses.
+///
+
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum ErrorClass {
+    Err,
+    WrongType,
+    NoAuth,
+    NoPerm,
+    ExecAbort,
+    BusyKey,
+    Oom,
+    Misconf,
+    ReadOnly,
+    Loading,
+}
+impl ErrorClass {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            Self::Err => "ERR",
+            Self::WrongType => "WRONGTYPE",
+            Self::NoAuth => "NOAUTH",
+            Self::NoPerm => "NOPERM",
+            Self::ExecAbort => "EXECABORT",
+            Self::BusyKey => "BUSYKEY",
+            Self::Oom => "OOM",
+            Self::Misconf => "MISCONF",
+            Self::ReadOnly => "READONLY",
+            Self::Loading => "LOADING",
+        }
+    }
+}
+
+/// Error message of the given class. The protocol framing ('-' and CRLF)
+/// is added by error_line(), see RedisClient::add_reply_error().
+pub fn error_reply(class: ErrorClass, msg: &str) -> String {
+    format!("{} {}", class.prefix(), msg)
+}
+
+/// Frame an error message as a protocol line: make sure it starts with '-'
+/// and ends with exactly one CRLF, whatever the caller passed.
+pub fn error_line(msg: &str) -> String {
+    let msg = msg.trim_end_matches(['\r', '\n']);
+    match msg.starts_with('-') {
+        true => format!("{}\r\n", msg),
+        false => format!("-{}\r\n", msg),
+    }
+}
+
+/// Generic error.
+pub fn err(msg: &str) -> String {
+    error_reply(ErrorClass::Err, msg)
+}
+
+/// The standard arity error, followed by which way the count is off so
+/// that clients matching the standard text still recognize it.
+pub fn wrong_arity(cmd: &str, e: ArityError) -> String {
+    let which = match e {
+        ArityError::TooFew => "too few",
+        ArityError::TooMany => "too many",
+    };
+    err(&format!("wrong number of arguments for '{}' command ({})", cmd, which))
+}
+
+/// The reply of a container command (CONFIG, DEBUG...) to a subcommand it
+/// doesn't know, or called with the wrong number of arguments.
+pub fn unknown_subcommand(cmd: &str, sub: &str) -> String {
+    err(&format!("Unknown subcommand or wrong number of arguments for '{}'. Try {} HELP.", sub, cmd.to_uppercase()))
+}
+
+pub fn wrong_type() -> String {
+    error_reply(ErrorClass::WrongType, "Operation against a key holding the wrong kind of value")
+}
+
+pub fn no_auth() -> String {
+    error_reply(ErrorClass::NoAuth, "Authentication required.")
+}
+
+pub fn no_perm(msg: &str) -> String {
+    error_reply(ErrorClass::NoPerm, msg)
+}
+
+pub fn exec_abort() -> String {
+    error_reply(ErrorClass::ExecAbort, "Transaction discarded because of previous errors.")
+}
+
+pub fn busy_key() -> String {
+    error_reply(ErrorClass::BusyKey, "Target key name already exists.")
+}
+
+pub fn oom() -> String {
+    error_reply(ErrorClass::Oom, "command not allowed when used memory > 'maxmemory'.")
+}
+
+/// The server can't persist writes: `what` tells which file is failing and
+/// `e` why.
+pub fn misconf(what: &str, e: &str) -> String {
+    error_reply(ErrorClass::Misconf, &format!("Errors writing to the {}: {}", what, e))
+}
+
+pub fn read_only() -> String {
+    error_reply(ErrorClass::ReadOnly, "You can't write against a read only replica.")
+}
+
+pub fn loading() -> String {
+    error_reply(ErrorClass::Loading, "Redis is loading the dataset in memory")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::tests::{run, test_client}, server::{server_write, tests::setup}};
+    use super::{busy_key, err, error_line, exec_abort, loading, misconf, no_auth, no_perm, oom, read_only, wrong_type};
+
+    fn class(reply: &str) -> &str {
+        assert!(reply.starts_with('-') && reply.ends_with("\r\n"), "{}", reply);
+        reply[1..].split(' ').next().unwrap()
+    }
+
+    #[test]
+    fn constructors_use_canonical_prefixes() {
+        assert_eq!(class(&error_line(&err("syntax error"))), "ERR");
+        assert_eq!(class(&error_line(&wrong_type())), "WRONGTYPE");
+        assert_eq!(class(&error_line(&no_auth())), "NOAUTH");
+        assert_eq!(class(&error_line(&no_perm("this user has no permissions"))), "NOPERM");
+        assert_eq!(class(&error_line(&exec_abort())), "EXECABORT");
+        assert_eq!(class(&error_line(&busy_key())), "BUSYKEY");
+        assert_eq!(class(&error_line(&oom())), "OOM");
+        assert_eq!(class(&error_line(&misconf("AOF file", "No space left on device"))), "MISCONF");
+        assert_eq!(class(&error_line(&read_only())), "READONLY");
+        assert_eq!(class(&error_line(&loading())), "LOADING");
+    }
+
+    #[test]
+    fn error_line_normalizes_framing() {
+        assert_eq!(error_line("ERR oops"), "-ERR oops\r\n");
+        assert_eq!(error_line("-ERR oops"), "-ERR oops\r\n");
+        assert_eq!(error_line("-ERR oops\r\n"), "-ERR oops\r\n");
+        assert_eq!(error_line("ERR oops\n"), "-ERR oops\r\n");
+    }
+
+    #[test]
+    fn add_reply_error_adds_prefix_and_crlf() {
+        let _guard = setup();
+        let c = test_client();
+        c.add_reply_error("ERR invalid password");
+        c.add_reply_error("-ERR already framed\r\n");
+        let reply: Vec<String> = c.reply.read().unwrap().iter().map(|o| o.as_key().to_string()).collect();
+        assert_eq!(reply, vec!["-ERR invalid password\r\n", "-ERR already framed\r\n"]);
+    }
+
+    #[test]
+    fn commands_reply_with_canonical_prefixes() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        assert_eq!(class(&run(&mut c, &["NOSUCHCOMMAND"])), "ERR");
+        assert_eq!(class(&run(&mut c, &["GET"])), "ERR");
+
+        run(&mut c, &["SET", "s", "v"]);
+        assert_eq!(class(&run(&mut c, &["LPUSH", "s", "x"])), "WRONGTYPE");
+        assert_eq!(class(&run(&mut c, &["SADD", "s", "x"])), "WRONGTYPE");
+        run(&mut c, &["LPUSH", "l", "x"]);
+        assert_eq!(class(&run(&mut c, &["GET", "l"])), "WRONGTYPE");
+
+        server_write().require_pass = "secret".to_string();
+        c.authenticated = false;
+        assert_eq!(class(&run(&mut c, &["GET", "s"])), "NOAUTH");
+        assert_eq!(class(&run(&mut c, &["AUTH", "wrong"])), "ERR");
+        assert_eq!(run(&mut c, &["AUTH", "secret"]), "+OK\r\n");
+        assert_eq!(run(&mut c, &["GET", "s"]), "$1\r\nv\r\n");
+        server_write().require_pass.clear();
+    }
+}
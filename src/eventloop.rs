@@ -4,10 +4,11 @@
 
 use std::{any::Any, ops::{BitAnd, BitOr, Deref}, process::exit, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
 use once_cell::sync::Lazy;
-use crate::{handler::proc_holder, ioevent::io_event::ApiState, util::{add_ms_to_now, get_time_ms, log, LogLevel}};
+use crate::{handler::proc_holder, ioevent::io_event::ApiState, log_debug, util::{add_ms_to_now, get_time_ms, log, LogLevel}};
 
 pub const SET_SIZE: usize = 1024 * 10;    // Max number of fd supported
-static NO_MORE: i32 = -1;
+/// Returned by a time proc to have its event deleted instead of rescheduled.
+pub const NO_MORE: i32 = -1;
 
 
 /// 
@@ -139,6 +140,9 @@ pub fn tevent_nid_w() -> RwLockWriteGuard<'static, u128> {
 
 
 pub type FileProc = Arc<dyn Fn(i32, Mask) -> () + Sync + Send>;
+/// Called with the event id and its client data when the timer fires. The
+/// return value is the number of milliseconds until the next invocation, or
+/// `NO_MORE` to delete the event (server_cron returns `1000 / hz`).
 pub type TimeProc = Arc<dyn Fn(u128, Option<Arc<dyn Any + Sync + Send>>) -> i32 + Sync + Send>;
 pub type EventFinalizerProc = Arc<dyn Fn(Option<Arc<dyn Any + Sync + Send>>) -> () + Sync + Send>;
 pub type BeforeSleepProc = Arc<dyn Fn() -> () + Sync + Send>;
@@ -280,7 +284,10 @@ pub fn process_events(flags: EventFlag) -> u32 {
     if flags.contains_time_event() {
         processed += process_time_events();
     }
-    
+    if processed > 0 {
+        log_debug!("{} events fired", processed);
+    }
+
     processed
 }
 
@@ -505,3 +512,34 @@ impl BitAnd for EventFlag {
     }
 }
 
+
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{atomic::{AtomicU32, Ordering}, Arc}, thread::sleep, time::Duration};
+    use crate::{server::tests::setup, util::get_time_ms};
+    use super::{create_time_event, process_time_events, tevent_head_r, NO_MORE};
+
+    #[test]
+    fn time_event_is_rescheduled_by_its_return_value() {
+        let _guard = setup();
+        let fired = Arc::new(AtomicU32::new(0));
+        let count = fired.clone();
+        let id = create_time_event(0, Arc::new(move |_, _| {
+            if count.fetch_add(1, Ordering::SeqCst) < 1 { 50 } else { NO_MORE }
+        }), None, None);
+
+        assert_eq!(process_time_events(), 1);
+        let when = tevent_head_r().as_ref().unwrap().read().unwrap().when_ms;
+        let left = when.saturating_sub(get_time_ms());
+        assert!((40..=50).contains(&left), "rescheduled in {left}ms");
+        // Not due yet.
+        assert_eq!(process_time_events(), 0);
+
+        sleep(Duration::from_millis(60));
+        assert_eq!(process_time_events(), 1);
+        assert_eq!(fired.load(Ordering::SeqCst), 2);
+        // NO_MORE deleted the event.
+        assert!(tevent_head_r().as_ref().map_or(true, |e| e.read().unwrap().id != id));
+    }
+}
@@ -1,8 +1,16 @@
-use std::{any::Any, borrow::Borrow, collections::LinkedList, fs::{rename, File, OpenOptions}, io::{BufWriter, Write}, net::Ipv4Addr, ptr::null_mut, sync::{Arc, RwLock}};
-use libc::{c_void, close, pid_t, read, strerror, wait4, write, EAGAIN, WEXITSTATUS, WIFSIGNALED, WNOHANG};
-use crate::{aof::aof_remove_temp_file, client::{clients_read, clients_write, deleled_clients_read, deleted_clients_write, RedisClient}, eventloop::{delete_file_event, Mask}, net::accept, obj::{RedisObject, StringStorageType}, rdb::{rdb_remove_temp_file, rdb_save_background}, server::{server_read, server_write, IO_BUF_LEN}, util::{error, log, timestamp, LogLevel}, zmalloc::MemCounter};
+use std::{any::Any, borrow::Borrow, collections::LinkedList, fs::{rename, File, OpenOptions}, io::{BufWriter, Write}, mem::take, net::Ipv4Addr, ptr::null_mut, sync::{atomic::{AtomicU64, Ordering}, Arc, RwLock}};
+use libc::{c_void, close, pid_t, read, strerror, wait4, write, EAGAIN, EMFILE, ENFILE, EWOULDBLOCK, WEXITSTATUS, WIFSIGNALED, WNOHANG};
+use crate::{aof::{aof_remove_temp_file, feed_append_only_expired_keys, flush_append_only_file}, errors, client::{clients_buffers_size, clients_read, clients_write, count_rejected_connection, deleted_clients_write, unblocked_clients_write, used_memory, RedisClient}, eventloop::{create_file_event, create_time_event, delete_file_event, Mask, NO_MORE}, net::{accept, reserve_fd}, obj::{RedisObject, NULL_MULTI_BULK}, rdb::{rdb_read_background_save_info, rdb_remove_temp_file, rdb_save_background}, replication::{replication_cron, update_slaves_waiting_bgsave}, server::{server_read, server_write, IO_BUF_LEN}, util::{error, log, timestamp, LogLevel}, zmalloc::MemCounter};
 
 static MAX_WRITE_PER_EVENT: usize = 1024 * 64;
+/// Unix time of the last "max number of clients reached" warning.
+static LAST_REJECT_WARNING: AtomicU64 = AtomicU64::new(0);
+/// Unix time of the last warning about accept() running out of fds.
+static LAST_ACCEPT_WARNING: AtomicU64 = AtomicU64::new(0);
+/// Connections accepted at most per readable event of the listening socket.
+const MAX_ACCEPTS_PER_CALL: usize = 1000;
+/// How long accepting is paused when out of file descriptors.
+const ACCEPT_BACKOFF_MS: u128 = 100;
 
 
 /// 
@@ -14,26 +22,48 @@ static MAX_WRITE_PER_EVENT: usize = 1024 * 64;
 /// main loop of the event driven library, that is, before to sleep
 /// for ready file descriptors.
 pub fn before_sleep() {
-    // Remove deleted clients
-    if deleled_clients_read().len() > 0 {
+    // Remove deleted clients. The set is drained every time, ids of clients
+    // already gone are just ignored.
+    let deleted = take(&mut *deleted_clients_write());
+    if !deleted.is_empty() {
+        let freed: LinkedList<Arc<RwLock<RedisClient>>>;
         {
-            let set = deleled_clients_read();
-            let mut existed: LinkedList<Arc<RwLock<RedisClient>>> = clients_read().iter()
-                .filter(|c| !set.contains(&c.read().unwrap().fd()))
-                .map(|c| c.clone()).collect();
-            clients_write().clear();
-            clients_write().append(&mut existed);
+            let mut clients = clients_write();
+            let kept;
+            (freed, kept) = take(&mut *clients).into_iter()
+                .partition(|c| deleted.contains(&c.read().unwrap().id()));
+            *clients = kept;
+        }
+        // Dropped with no lock held: freeing a master client updates the
+        // server state.
+        drop(freed);
+    }
+
+    // Run the commands the unblocked clients received while blocked
+    let unblocked = take(&mut *unblocked_clients_write());
+    for id in unblocked {
+        let client = clients_read().iter().find(|c| c.try_read().map(|c| c.id() == id).unwrap_or(false)).cloned();
+        let Some(client) = client else { continue; };
+        let mut client = client.write().unwrap();
+        if !client.flags.is_blocked() && !client.query_buf.is_empty() {
+            client.process_input_buf();
         }
-        deleted_clients_write().clear();
     }
 }
 
 
-/// Time Event handler: server cron tasks
-///  
+/// Time Event handler: server cron tasks. It runs hz times per second, the
+/// value returned is the delay in milliseconds until the next call.
 pub fn server_cron(id: u128, client_data: Option<Arc<dyn Any + Sync + Send>>) -> i32 {
     let loops = server_read().cron_loops();
-    server_write().set_cron_loops(loops + 1);
+    server_write().set_cron_loops(loops.wrapping_add(1));
+    let period = 1000 / server_read().hz as i32;
+    // Some tasks don't need to run at every call: true once every `ms`
+    // milliseconds, whatever the hz.
+    let run_with_period = |ms: i32| ms <= period || loops % (ms / period) == 0;
+
+    // Track the memory peak for INFO
+    MemCounter::update_peak();
 
     // Show some info about non-empty databases
     {
@@ -42,7 +72,7 @@ pub fn server_cron(id: u128, client_data: Option<Arc<dyn Any + Sync + Send>>) ->
             let size = server.dbs()[i as usize].read().unwrap().dict.capacity();
             let used = server.dbs()[i as usize].read().unwrap().dict.len();
             let vkeys = server.dbs()[i as usize].read().unwrap().expires.len();
-            if (loops % 5 == 0) && (used != 0 || vkeys != 0) {
+            if run_with_period(5000) && (used != 0 || vkeys != 0) {
                 log(LogLevel::Verbose, &format!("DB {}: {} keys ({} volatile) in {} slots HT.", i, used, vkeys, size));
             }
         }
@@ -59,16 +89,25 @@ pub fn server_cron(id: u128, client_data: Option<Arc<dyn Any + Sync + Send>>) ->
     }
 
     // Show information about connected clients
-    if loops % 5 == 0 {
+    if run_with_period(5000) {
         let server = server_read();
-        log(LogLevel::Verbose, &format!("{} clients connected ({} slaves), {} bytes in use, {} shared objects", 
+        log(LogLevel::Verbose, &format!("{} clients connected ({} slaves), {} bytes in use ({} in client buffers), {} shared objects", 
             clients_read().len() - server.slaves().len(), 
             server.slaves().len(),
-            MemCounter::used_memory(),
+            used_memory(),
+            clients_buffers_size(),
             server.sharing_pool().len()));
     }
 
     // Close connections of timedout clients
+    if server_read().max_idle_time > 0 && run_with_period(1000) {
+        close_timedout_clients();
+    }
+
+    // Wake up the clients whose BLPOP timed out
+    if server_read().blpop_blocked_clients > 0 {
+        handle_blocked_clients_timeout();
+    }
 
     // Check if a background saving or AOF rewrite in progress terminated
     if server_read().bg_save_child_pid() != -1 || server_read().bg_rewrite_child_pid() != -1 {
@@ -101,14 +140,59 @@ pub fn server_cron(id: u128, client_data: Option<Arc<dyn Any + Sync + Send>>) ->
         }
     }
 
+    // If the last write to the append only file failed, try again: the
+    // error condition clears itself as soon as a flush succeeds.
+    if server_read().aof_last_write_status.is_err() {
+        flush_append_only_file();
+    }
+
     // Try to expire a few timed out keys. The algorithm used is adaptive and
     // will use few CPU cycles if there are few expiring keys, otherwise
     // it will get more aggressive to avoid that too much memory is used by
     // keys that can be removed from the keyspace.
+    let expired = server_write().active_expire_cycle();
+    if !expired.is_empty() && server_read().append_only {
+        feed_append_only_expired_keys(&expired);
+    }
 
     // Check if we should connect to a MASTER
+    if run_with_period(1000) {
+        replication_cron();
+    }
+
+    period
+}
 
-    1000
+/// Close the connection of the clients idle for more than maxidletime
+/// seconds. Slaves, masters and clients blocked in BLPOP are never closed.
+fn close_timedout_clients() {
+    let now = timestamp().as_secs();
+    let max_idle_time = server_read().max_idle_time as u64;
+    for c in clients_read().iter() {
+        if let Ok(mut c) = c.try_write() {
+            if c.flags.is_slave() || c.flags.is_master() || c.flags.is_blocked() {
+                continue;
+            }
+            if now.saturating_sub(c.last_interaction) > max_idle_time {
+                log(LogLevel::Verbose, "Closing idle client");
+                c.free_client_async();
+            }
+        }
+    }
+}
+
+/// Reply nil to the clients blocked in BLPOP past their timeout and
+/// unblock them.
+pub(crate) fn handle_blocked_clients_timeout() {
+    let now = timestamp().as_millis() as u64;
+    let clients: Vec<Arc<RwLock<RedisClient>>> = clients_read().iter().cloned().collect();
+    for c in clients {
+        let Ok(mut c) = c.try_write() else { continue; };
+        if c.flags.is_blocked() && c.blocking_timeout() != 0 && c.blocking_timeout() <= now {
+            c.add_reply(NULL_MULTI_BULK.clone());
+            c.unblock_client_waiting_data();
+        }
+    }
 }
 
 /// A background saving child (BGSAVE) terminated its work. Handle this.
@@ -126,10 +210,11 @@ fn background_save_done_handler(status: i32) {
         log(LogLevel::Warning, "Background saving terminated by signal");
         rdb_remove_temp_file(server_read().bg_save_child_pid());
     }
+    rdb_read_background_save_info(!by_signal && exit_code == 0);
     server_write().bg_save_child_pid = -1;
     // Possibly there are slaves waiting for a BGSAVE in order to be served
     // (the first stage of SYNC is a bulk transfer of dump.rdb)
-    // TODO:
+    update_slaves_waiting_bgsave(!by_signal && exit_code == 0);
 }
 
 /// A background append only file rewriting (BGREWRITEAOF) terminated its work.
@@ -188,8 +273,11 @@ fn background_rewrite_done_handler(status: i32) {
                             log(LogLevel::Warning, &format!("failed to sync new append only file to disk: {}", e));
                         },
                     }
-                    server_write().append_file = Some(f);
+                    server_write().append_file = Some(Box::new(f));
                     server_write().append_sel_db = -1;  // Make sure it will issue SELECT
+                    // Whatever was still pending is already part of the
+                    // rewritten file (it went into bg_rewrite_buf too).
+                    server_write().aof_buf.clear();
                     log(LogLevel::Notice, "The new append only file was selected for future appends.");
                 },
                 Err(e) => {
@@ -206,31 +294,94 @@ fn background_rewrite_done_handler(status: i32) {
 }
 
 
-/// File Event handler: accept connection request
-/// 
-pub fn accept_handler(fd: i32, mask: Mask) {
-    let (c_fd, c_ip, c_port) = match accept(fd) {
-        Ok((c_fd, c_ip, c_port)) => { (c_fd, c_ip, c_port) },
-        Err(e) => {
-            log(LogLevel::Warning, &format!("Accepting client connection: {}", e));
-            return;
-        },
-    };
-    log(LogLevel::Verbose, &format!("Accepted {}:{c_port}", Ipv4Addr::from_bits(c_ip)));
+/// File Event handler: accept connection request. The pending connections
+/// are accepted until there are no more, up to MAX_ACCEPTS_PER_CALL so
+/// that a connection storm doesn't starve the other clients.
+pub fn accept_handler(fd: i32, _mask: Mask) {
+    for _ in 0..MAX_ACCEPTS_PER_CALL {
+        let (c_fd, c_ip, c_port) = match accept(fd) {
+            Ok((c_fd, c_ip, c_port)) => { (c_fd, c_ip, c_port) },
+            Err((errno, _)) if errno == EAGAIN || errno == EWOULDBLOCK => { return; },
+            Err((errno, e)) if errno == EMFILE || errno == ENFILE => {
+                // Out of file descriptors: the connection stays pending and
+                // the listening socket stays readable. Refuse it with the
+                // reserved fd, or if there's none stop polling the socket for
+                // a while instead of spinning: clients freed meanwhile make
+                // room.
+                if refuse_with_reserved_fd(fd, &e) {
+                    continue;
+                }
+                warn_at_most_every_minute(&LAST_ACCEPT_WARNING, &format!("Accepting client connection: {}, pausing accepts for {} ms", e, ACCEPT_BACKOFF_MS));
+                delete_file_event(fd, Mask::Readable);
+                create_time_event(ACCEPT_BACKOFF_MS, Arc::new(resume_accepting), Some(Arc::new(fd)), None);
+                return;
+            },
+            Err((_, e)) => {
+                log(LogLevel::Warning, &format!("Accepting client connection: {}", e));
+                return;
+            },
+        };
+        log(LogLevel::Verbose, &format!("Accepted {}:{c_port}", Ipv4Addr::from_bits(c_ip)));
+        accept_common_handler(c_fd);
+    }
+}
+
+/// Free the reserved fd to accept the pending connection and close it at
+/// once, clearing it from the backlog, then reserve an fd again. Returns
+/// false if no fd is reserved.
+fn refuse_with_reserved_fd(fd: i32, e: &str) -> bool {
+    let reserved = take_reserved_fd();
+    if reserved == -1 {
+        return false;
+    }
+    unsafe { close(reserved); }
+    if let Ok((c_fd, c_ip, c_port)) = accept(fd) {
+        unsafe { close(c_fd); }
+        count_rejected_connection();
+        warn_at_most_every_minute(&LAST_ACCEPT_WARNING, &format!("Accepting client connection: {}, refusing {}:{}", e, Ipv4Addr::from_bits(c_ip), c_port));
+    }
+    match reserve_fd() {
+        Ok(reserved) => { server_write().reserved_fd = reserved; },
+        Err(e) => { log(LogLevel::Warning, &format!("Can't reserve a file descriptor again: {}", e)); },
+    }
+    true
+}
+
+fn take_reserved_fd() -> i32 {
+    std::mem::replace(&mut server_write().reserved_fd, -1)
+}
+
+/// Time Event handler: poll the listening socket again after accept()
+/// ran out of file descriptors.
+fn resume_accepting(_id: u128, client_data: Option<Arc<dyn Any + Sync + Send>>) -> i32 {
+    if let Some(fd) = client_data.and_then(|d| d.downcast_ref::<i32>().copied()) {
+        if let Err(e) = create_file_event(fd, Mask::Readable, Arc::new(accept_handler)) {
+            log(LogLevel::Warning, &format!("Can't resume accepting connections: {}", e));
+        }
+    }
+    NO_MORE
+}
+
+/// Create the client of an accepted connection, or refuse it when the
+/// max number of clients is reached.
+fn accept_common_handler(c_fd: i32) {
     match RedisClient::create(c_fd) {
         Ok(client) => {
             // If maxclient directive is set and this is one client more... close the
             // connection. Note that we create the client instead to check before
             // for this condition, since now the socket is already set in nonblocking
             // mode and we can send an error for free using the Kernel I/O
-            if server_read().max_clients() > 0 && clients_read().len() as u32 > server_read().max_clients() {
-                let err = "-ERR max number of clients reached\r\n";
+            let max_clients = server_read().max_clients();
+            if max_clients > 0 && clients_read().len() as u32 > max_clients {
+                let err = errors::error_line(&errors::err("max number of clients reached"));
                 unsafe {
                     // That's a best effort error message, don't check write errors
-                    if write(client.read().unwrap().fd(), err as *const _ as *const c_void, err.len()) == -1 {
+                    if write(client.read().unwrap().fd(), err.as_ptr() as *const c_void, err.len()) == -1 {
                     }
                 }
-                // TODO: free client?
+                client.write().unwrap().free_client_async();
+                count_rejected_connection();
+                warn_at_most_every_minute(&LAST_REJECT_WARNING, &format!("Rejecting client connections: max number of clients reached ({})", max_clients));
                 return;
             }
             let n = server_read().stat_numconnections();
@@ -239,11 +390,19 @@ pub fn accept_handler(fd: i32, mask: Mask) {
         Err(e) => {
             log(LogLevel::Warning, &format!("Error allocating resoures for the client: {}", e));
             unsafe { close(c_fd); } // May be already closed, just ingore errors
-            return;
         },
     }
 }
 
+/// Log a warning when a condition starts, then at most once per minute.
+fn warn_at_most_every_minute(last: &AtomicU64, msg: &str) {
+    let now = timestamp().as_secs();
+    if now >= last.load(Ordering::Relaxed) + 60 {
+        last.store(now, Ordering::Relaxed);
+        log(LogLevel::Warning, msg);
+    }
+}
+
 
 /// File Event handler: send reply to client
 /// 
@@ -259,47 +418,60 @@ pub fn send_reply_to_client(fd: i32, mask: Mask) {
     let mut obj_len: usize = 0;
     let mut n_written: isize = 0;
     let mut tot_written: usize = 0;
-    while client.has_reply() {
+
+    // The output buffer goes first: the replies in the list were all
+    // queued after it.
+    if !client.buf().is_empty() {
+        if client.flags.is_master() {
+            // Don't reply to a master
+            n_written = client.buf().len() as isize;
+        } else {
+            unsafe {
+                n_written = write(client.fd(), client.buf().as_ptr() as *const c_void, client.buf().len());
+            }
+        }
+        if n_written > 0 {
+            client.buf_consume(n_written as usize);
+            tot_written += n_written as usize;
+        }
+    }
+
+    while n_written >= 0 && client.buf().is_empty() && client.has_reply() {
         // TODO: glue output buf
 
         match client.reply_front().unwrap().borrow() {
             RedisObject::String { ptr } => {
-                match ptr {
-                    StringStorageType::String(s) => {
-                        let bytes = s.as_bytes();
-                        obj_len =  bytes.len();
-                        if obj_len == 0 {
-                            client.reply_pop_front();
-                            continue;
-                        }
-
-                        if client.flags.is_master() {
-                            // Don't reply to a master
-                            n_written = obj_len as isize - client.sent_len as isize;
-                        } else {
-                            unsafe {
-                                n_written = write(client.fd(), &bytes[client.sent_len] as *const _ as *const c_void, obj_len - client.sent_len);
-                            }
-                            if n_written < 0 { break; }
-                        }
-
-                        client.sent_len += n_written as usize;
-                        tot_written += n_written as usize;
-                        // If we fully sent the object on head go to the next one
-                        if client.sent_len == obj_len {
-                            client.reply_pop_front();
-                            client.sent_len = 0;
-                        }
-
-                        // Note that we avoid to send more thank REDIS_MAX_WRITE_PER_EVENT
-                        // bytes, in a single threaded server it's a good idea to serve
-                        // other clients as well, even if a very large request comes from
-                        // super fast link that is always able to accept data (in real world
-                        // scenario think about 'KEYS *' against the loopback interface)
-                        if tot_written > MAX_WRITE_PER_EVENT { break; }
-                    },
-                    StringStorageType::Integer(n) => {},
+                let bytes = ptr.as_bytes();
+                obj_len =  bytes.len();
+                if obj_len == 0 {
+                    client.reply_pop_front();
+                    continue;
                 }
+
+                if client.flags.is_master() {
+                    // Don't reply to a master
+                    n_written = obj_len as isize - client.sent_len as isize;
+                } else {
+                    unsafe {
+                        n_written = write(client.fd(), &bytes[client.sent_len] as *const _ as *const c_void, obj_len - client.sent_len);
+                    }
+                    if n_written < 0 { break; }
+                }
+
+                client.sent_len += n_written as usize;
+                tot_written += n_written as usize;
+                // If we fully sent the object on head go to the next one
+                if client.sent_len == obj_len {
+                    client.reply_pop_front();
+                    client.sent_len = 0;
+                }
+
+                // Note that we avoid to send more thank REDIS_MAX_WRITE_PER_EVENT
+                // bytes, in a single threaded server it's a good idea to serve
+                // other clients as well, even if a very large request comes from
+                // super fast link that is always able to accept data (in real world
+                // scenario think about 'KEYS *' against the loopback interface)
+                if tot_written > MAX_WRITE_PER_EVENT { break; }
             },
             _ => {},
         }
@@ -341,12 +513,12 @@ pub fn read_query_from_client(fd: i32, _mask: Mask) {
                 nread = 0;
             } else {
                 log(LogLevel::Verbose, &format!("Reading from client: {}", *strerror(error())));
-                // TODO: free client?
+                client.free_client_async();
                 return;
             }
         } else if nread == 0 {
             log(LogLevel::Verbose, "Client closed connection");
-            // TODO: free client?
+            client.free_client_async();
             return;
         }
     }
@@ -364,9 +536,8 @@ pub fn read_query_from_client(fd: i32, _mask: Mask) {
     } else {
         return;
     }
-    if !client.flags.is_blocked() {
-        client.process_input_buf();
-    }
+    // A blocked client only looks for a RESET, the rest waits in the buffer
+    client.process_input_buf();
 }
 
 
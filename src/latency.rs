@@ -0,0 +1,201 @@
+//! Latency Monitor.
+//!
+//! Events taking longer than 'latency-monitor-threshold' milliseconds are
+//! recorded in a per-event time series that can be inspected with the
+//! LATENCY command. A threshold of zero disables the monitor.
+
+use crate::{client::RedisClient, cmd::reply_subcommand_help, errors, server::{server_read, server_write}, util::timestamp};
+
+
+pub static LATENCY_TS_LEN: usize = 160;        // history length for every monitored event
+
+/// A single latency sample: when it happened (in seconds) and how long
+/// the event took (in milliseconds). Samples taken in the same second are
+/// merged keeping the highest latency.
+#[derive(Clone, Copy, Default)]
+pub struct LatencySample {
+    pub time: u64,
+    pub latency: u64,
+}
+
+/// The latency time series of a given event, as a ring buffer.
+pub struct LatencyTimeSeries {
+    idx: usize,                     // index of the next sample to store
+    max: u64,                       // max latency observed for this event
+    samples: Vec<LatencySample>,
+}
+impl LatencyTimeSeries {
+    fn new() -> LatencyTimeSeries {
+        LatencyTimeSeries { idx: 0, max: 0, samples: vec![LatencySample::default(); LATENCY_TS_LEN] }
+    }
+
+    fn add_sample(&mut self, time: u64, latency: u64) {
+        if latency > self.max {
+            self.max = latency;
+        }
+
+        // If the previous sample is in the same second, we update our old
+        // sample if this latency is > of the old one, or just return.
+        let prev = (self.idx + LATENCY_TS_LEN - 1) % LATENCY_TS_LEN;
+        if self.samples[prev].time == time {
+            if latency > self.samples[prev].latency {
+                self.samples[prev].latency = latency;
+            }
+            return;
+        }
+
+        self.samples[self.idx] = LatencySample { time, latency };
+        self.idx = (self.idx + 1) % LATENCY_TS_LEN;
+    }
+
+    /// The samples from the oldest to the most recent one.
+    pub fn history(&self) -> Vec<LatencySample> {
+        (0..LATENCY_TS_LEN)
+            .map(|j| self.samples[(self.idx + j) % LATENCY_TS_LEN])
+            .filter(|s| s.time != 0)
+            .collect()
+    }
+
+    /// The most recent sample, if any.
+    pub fn latest(&self) -> Option<LatencySample> {
+        let last = self.samples[(self.idx + LATENCY_TS_LEN - 1) % LATENCY_TS_LEN];
+        if last.time == 0 { None } else { Some(last) }
+    }
+
+    pub fn max(&self) -> u64 {
+        self.max
+    }
+}
+
+/// Add the sample only if the monitor is enabled and the latency is over
+/// the configured threshold.
+pub fn latency_add_sample_if_needed(event: &str, latency: u64) {
+    let threshold = server_read().latency_monitor_threshold;
+    if threshold > 0 && latency >= threshold {
+        latency_add_sample(event, latency);
+    }
+}
+
+/// Add the specified sample to the specified time series "event".
+pub fn latency_add_sample(event: &str, latency: u64) {
+    let now = timestamp().as_secs();
+    server_write().latency_events
+        .entry(event.to_string())
+        .or_insert_with(LatencyTimeSeries::new)
+        .add_sample(now, latency);
+}
+
+/// Reset the given events, or all the events if none is specified.
+/// Returns the number of time series that were removed.
+pub fn latency_reset_event(events: &[String]) -> usize {
+    let mut server = server_write();
+    if events.is_empty() {
+        let removed = server.latency_events.len();
+        server.latency_events.clear();
+        return removed;
+    }
+    events.iter().filter(|e| server.latency_events.remove(&e[..]).is_some()).count()
+}
+
+/// LATENCY LATEST
+/// LATENCY HISTORY <event>
+/// LATENCY RESET [<event> ...]
+static LATENCY_HELP: &[(&str, &str)] = &[
+    ("LATEST", "Return the latest latency samples for all events."),
+    ("HISTORY <event>", "Return time-latency samples for the <event>."),
+    ("RESET [<event> ...]", "Reset latency data of one or more <event> classes (default: reset all data for all event classes)."),
+];
+
+pub fn latency_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, LATENCY_HELP);
+        },
+        "latest" if c.argv.len() == 2 => {
+            let latest: Vec<(String, LatencySample, u64)> = {
+                let server = server_read();
+                let mut events: Vec<_> = server.latency_events.iter()
+                    .filter_map(|(name, ts)| ts.latest().map(|s| (name.clone(), s, ts.max())))
+                    .collect();
+                events.sort_by(|a, b| a.0.cmp(&b.0));
+                events
+            };
+            c.add_reply_str(&format!("*{}\r\n", latest.len()));
+            for (name, sample, max) in latest {
+                c.add_reply_str("*4\r\n");
+                c.add_reply_bulk_str(&name);
+                c.add_reply_u64(sample.time);
+                c.add_reply_u64(sample.latency);
+                c.add_reply_u64(max);
+            }
+        },
+        "history" if c.argv.len() == 3 => {
+            let event = c.argv[2].read().unwrap().as_key().to_string();
+            let history = server_read().latency_events.get(&event).map(|ts| ts.history()).unwrap_or_default();
+            c.add_reply_str(&format!("*{}\r\n", history.len()));
+            for sample in history {
+                c.add_reply_str("*2\r\n");
+                c.add_reply_u64(sample.time);
+                c.add_reply_u64(sample.latency);
+            }
+        },
+        "reset" => {
+            let events: Vec<String> = c.argv[2..].iter().map(|e| e.read().unwrap().as_key().to_string()).collect();
+            let removed = latency_reset_event(&events);
+            c.add_reply_u64(removed as u64);
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("latency", &c.arg(1)));
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::tests::{run, test_client}, server::{server_write, tests::setup}};
+    use super::{LatencyTimeSeries, LATENCY_TS_LEN};
+
+    #[test]
+    fn time_series_wraps_and_merges_same_second() {
+        let mut ts = LatencyTimeSeries::new();
+        ts.add_sample(1, 10);
+        ts.add_sample(1, 30);
+        ts.add_sample(1, 20);
+        assert_eq!(ts.history().len(), 1);
+        assert_eq!(ts.latest().unwrap().latency, 30);
+
+        for t in 2..(LATENCY_TS_LEN as u64 + 10) {
+            ts.add_sample(t, t);
+        }
+        let history = ts.history();
+        assert_eq!(history.len(), LATENCY_TS_LEN);
+        assert_eq!(history.last().unwrap().time, LATENCY_TS_LEN as u64 + 9);
+        assert!(history.windows(2).all(|w| w[0].time < w[1].time));
+        assert_eq!(ts.max(), LATENCY_TS_LEN as u64 + 9);
+    }
+
+    #[test]
+    fn debug_sleep_shows_up_in_latency_latest() {
+        let _guard = setup();
+        let mut c = test_client();
+        server_write().latency_monitor_threshold = 50;
+        assert_eq!(run(&mut c, &["LATENCY", "RESET"]).chars().next(), Some(':'));
+
+        assert_eq!(run(&mut c, &["DEBUG", "SLEEP", "0.1"]), "+OK\r\n");
+        let latest = run(&mut c, &["LATENCY", "LATEST"]);
+        assert!(latest.starts_with("*1\r\n*4\r\n$7\r\ncommand\r\n"), "{}", latest);
+        let history = run(&mut c, &["LATENCY", "HISTORY", "command"]);
+        assert!(history.starts_with("*1\r\n*2\r\n"), "{}", history);
+
+        assert_eq!(run(&mut c, &["LATENCY", "RESET", "command"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["LATENCY", "LATEST"]), "*0\r\n");
+        server_write().latency_monitor_threshold = 0;
+
+        let help = run(&mut c, &["LATENCY", "HELP"]);
+        assert!(help.starts_with("*9\r\n+LATENCY <subcommand> [<arg> [value] [opt] ...]. Subcommands are:\r\n+LATEST\r\n"), "{}", help);
+        assert_eq!(run(&mut c, &["LATENCY", "DOCTOR"]),
+            "-ERR Unknown subcommand or wrong number of arguments for 'DOCTOR'. Try LATENCY HELP.\r\n");
+    }
+}
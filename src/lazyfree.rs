@@ -0,0 +1,164 @@
+//! Lazy freeing.
+//!
+//! Dropping a big collection takes time proportional to its size. Values
+//! removed from the keyspace can instead be handed to a background thread
+//! that drops them, so that the event loop doesn't stall. Expired and
+//! evicted values take this path when 'lazyfree-lazy-expire' and
+//! 'lazyfree-lazy-eviction' are enabled.
+
+use std::{sync::{atomic::{AtomicUsize, Ordering}, mpsc::{channel, Sender}, Arc, Mutex, RwLock}, thread};
+use once_cell::sync::Lazy;
+use crate::obj::RedisObject;
+
+
+/// Values with fewer elements than this are cheaper to drop right away than
+/// to send to the background thread.
+pub static LAZYFREE_THRESHOLD: usize = 64;
+
+/// Values sent to the background thread and not dropped yet.
+static LAZYFREE_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+static LAZYFREE_QUEUE: Lazy<Mutex<Sender<Arc<RwLock<RedisObject>>>>> = Lazy::new(|| {
+    let (tx, rx) = channel::<Arc<RwLock<RedisObject>>>();
+    thread::Builder::new().name("lazyfree".to_string()).spawn(move || {
+        for obj in rx {
+            #[cfg(feature = "testing-hooks")]
+            pause::wait();
+            drop(obj);
+            LAZYFREE_PENDING.fetch_sub(1, Ordering::Relaxed);
+        }
+    }).expect("can't start the lazyfree thread");
+    Mutex::new(tx)
+});
+
+/// The number of elements to drop to free `obj`, roughly the work needed.
+fn free_effort(obj: &RedisObject) -> usize {
+    if let Some(l) = obj.list() {
+        l.len()
+    } else if let Some(s) = obj.set() {
+        s.len()
+    } else if let Some(zs) = obj.zset() {
+        zs.len()
+    } else if let Some(h) = obj.hash() {
+        h.len()
+    } else {
+        1
+    }
+}
+
+/// Drop a value removed from the keyspace in the background if it's big
+/// enough to be worth it, right away otherwise. Values still referenced
+/// elsewhere are only released.
+pub fn free_object_async(obj: Arc<RwLock<RedisObject>>) {
+    if Arc::strong_count(&obj) > 1 || free_effort(&obj.read().unwrap()) < LAZYFREE_THRESHOLD {
+        return;
+    }
+    LAZYFREE_PENDING.fetch_add(1, Ordering::Relaxed);
+    if let Err(e) = LAZYFREE_QUEUE.lock().unwrap().send(obj) {
+        // The thread is gone: free it here.
+        LAZYFREE_PENDING.fetch_sub(1, Ordering::Relaxed);
+        drop(e.0);
+    }
+}
+
+/// Drop `obj` in the background when `lazy` is set, see free_object_async().
+pub fn free_object(obj: Arc<RwLock<RedisObject>>, lazy: bool) {
+    if lazy {
+        free_object_async(obj);
+    }
+}
+
+pub fn lazyfree_pending_objects() -> usize {
+    LAZYFREE_PENDING.load(Ordering::Relaxed)
+}
+
+/// Test hook holding the background thread before it drops the next value,
+/// to observe objects that are still pending.
+#[cfg(feature = "testing-hooks")]
+pub mod pause {
+    use std::sync::{Condvar, Mutex};
+
+    static PAUSED: Mutex<bool> = Mutex::new(false);
+    static RESUMED: Condvar = Condvar::new();
+
+    pub fn set(paused: bool) {
+        *PAUSED.lock().unwrap() = paused;
+        RESUMED.notify_all();
+    }
+    pub(super) fn wait() {
+        let mut paused = PAUSED.lock().unwrap();
+        while *paused {
+            paused = RESUMED.wait(paused).unwrap();
+        }
+    }
+}
+
+
+#[cfg(all(test, feature = "testing-hooks"))]
+mod tests {
+    use std::{thread::sleep, time::{Duration, Instant}};
+    use crate::{client::tests::{run, test_client}, server::{server_write, tests::setup}, util::set_mock_time};
+    use super::{lazyfree_pending_objects, pause, LAZYFREE_THRESHOLD};
+
+    fn wait_for_no_pending() {
+        let start = Instant::now();
+        while lazyfree_pending_objects() > 0 {
+            assert!(start.elapsed() < Duration::from_secs(5), "lazyfree thread stuck");
+            sleep(Duration::from_millis(1));
+        }
+    }
+
+    fn big_set(c: &mut crate::client::RedisClient, key: &str) {
+        for i in 0..LAZYFREE_THRESHOLD * 10 {
+            run(c, &["SADD", key, &i.to_string()]);
+        }
+    }
+
+    #[test]
+    fn lazy_eviction_frees_in_the_background() {
+        let _guard = setup();
+        let mut c = test_client();
+        big_set(&mut c, "big");
+        run(&mut c, &["EXPIRE", "big", "1000"]);
+        wait_for_no_pending();
+
+        // The background thread is held: evicting must not wait for it.
+        pause::set(true);
+        {
+            let mut server = server_write();
+            server.lazyfree_lazy_eviction = true;
+            server.max_memory = 1;
+            server.free_memory_if_needed();
+            server.max_memory = 0;
+            server.lazyfree_lazy_eviction = false;
+        }
+        assert_eq!(run(&mut c, &["EXISTS", "big"]), ":0\r\n");
+        assert_eq!(lazyfree_pending_objects(), 1);
+        pause::set(false);
+        wait_for_no_pending();
+    }
+
+    #[test]
+    fn lazy_expire_frees_in_the_background() {
+        let _guard = setup();
+        let mut c = test_client();
+        set_mock_time(Some(Duration::from_secs(1_000_000)));
+        big_set(&mut c, "big");
+        run(&mut c, &["SET", "small", "v"]);
+        run(&mut c, &["EXPIRE", "big", "10"]);
+        run(&mut c, &["EXPIRE", "small", "10"]);
+        server_write().lazyfree_lazy_expire = true;
+        set_mock_time(Some(Duration::from_secs(1_000_020)));
+
+        pause::set(true);
+        assert_eq!(run(&mut c, &["EXISTS", "big"]), ":0\r\n");
+        assert_eq!(run(&mut c, &["EXISTS", "small"]), ":0\r\n");
+        // Only the big value was worth sending to the background.
+        assert_eq!(lazyfree_pending_objects(), 1);
+        pause::set(false);
+        wait_for_no_pending();
+
+        server_write().lazyfree_lazy_expire = false;
+        set_mock_time(None);
+    }
+}
@@ -9,8 +9,18 @@ pub mod client;
 pub mod cmd;
 pub mod aof;
 pub mod rdb;
+pub mod replication;
+pub mod latency;
+pub mod lazyfree;
+pub mod cluster;
+pub mod config;
+pub mod pubsub;
+pub mod resp;
+pub mod errors;
 pub mod obj;
+pub mod dict;
 pub mod list;
 pub mod skiplist;
 pub mod zmalloc;
+pub mod memtest;
 pub mod util;
@@ -1,23 +1,37 @@
 use rudis::{
-    aof::load_append_only_file, eventloop::{ae_main, set_before_sleep_proc}, handler::before_sleep, rdb::rdb_load, server::{print_logo, server_read, server_write}, util::{log, LogLevel}
+    aof::load_append_only_file, memtest::{memtest, memtest_words, MEMTEST_PASSES}, eventloop::{ae_main, set_before_sleep_proc}, handler::before_sleep, rdb::rdb_load, server::{print_logo, server_read, server_write}, util::{init_log, log, LogLevel}
 };
 use std::{env, process::exit, sync::Arc, time::Instant};
 
 fn main() {
     let args: Vec<String> = env::args().collect();
 
-    if args.len() == 2 {
-        server_write().reset_server_save_params();
-        server_write().load_server_config(&args[1]);
-    } else if args.len() > 2 {
-        eprintln!("Usage: ./redis-server [/path/to/redis.conf]");
-        exit(1);
+    if args.len() >= 2 {
+        if args[1] == "--help" || args[1] == "-h" {
+            usage();
+        }
+        if args[1] == "--test-memory" {
+            if args.len() != 3 {
+                eprintln!("Please specify the amount of memory to test in megabytes.");
+                eprintln!("Example: ./redis-server --test-memory 4096");
+                exit(1);
+            }
+            match args[2].parse::<usize>() {
+                Ok(megabytes) if memtest_words(megabytes).is_some() => exit(if memtest(megabytes, MEMTEST_PASSES) { 0 } else { 1 }),
+                _ => {
+                    eprintln!("Invalid amount of memory to test: '{}'", args[2]);
+                    exit(1);
+                },
+            }
+        }
+        server_write().load_server_config_from_args(&args[1..]);
     } else {
         log(LogLevel::Warning, "Warning: no config file specified, using the default config. In order to specify a config file use 'redis-server /path/to/redis.conf'");
     }
     if server_read().is_daemonize() {
         server_read().daemonize();
     }
+    init_log();
 
     server_write().init_server();
     print_logo();
@@ -26,6 +40,7 @@ fn main() {
     server_read().linux_overcommit_memory_warning();
 
     let start = Instant::now();
+    server_write().loading = true;
     if server_read().append_only() {
         let filename = { server_read().append_filename().to_string() };
         if let Ok(_) = load_append_only_file(&filename) {
@@ -37,6 +52,7 @@ fn main() {
             log(LogLevel::Notice, &format!("DB loaded from disk: {} seconds", start.elapsed().as_secs()));
         }
     }
+    server_write().loading = false;
 
     log(
         LogLevel::Notice,
@@ -48,3 +64,16 @@ fn main() {
     set_before_sleep_proc(Some(Arc::new(before_sleep)));
     ae_main();
 }
+
+fn usage() -> ! {
+    eprintln!("Usage: ./redis-server [/path/to/redis.conf] [options]");
+    eprintln!("       ./redis-server - (read config from stdin)");
+    eprintln!("       ./redis-server --test-memory <megabytes>");
+    eprintln!();
+    eprintln!("Examples:");
+    eprintln!("       ./redis-server (run the server with default conf)");
+    eprintln!("       ./redis-server /etc/redis/6379.conf");
+    eprintln!("       ./redis-server --port 7777");
+    eprintln!("       ./redis-server /etc/myredis.conf --loglevel verbose");
+    exit(1);
+}
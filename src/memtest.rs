@@ -0,0 +1,196 @@
+//! memtest - a simple RAM tester, run with `redis-server --test-memory <megabytes>`.
+//!
+//! The memory is allocated in chunks and every pass fills it with a few
+//! patterns, reading each one back: the address of every word, a one (and
+//! then a zero) walking through the bits, and pseudo random values. It's
+//! not a replacement for a real memory tester, but it's enough to catch a
+//! broken box before it corrupts a dataset.
+
+use std::{io::{stdout, Write}, ptr::{read_volatile, write_volatile}};
+
+pub const MEMTEST_PASSES: usize = 50;
+const MEMTEST_CHUNK_WORDS: usize = 8 * 1024 * 1024;    // 64 MB chunks
+
+/// The outcome of a test: how many words didn't read back what was written,
+/// and the first of them.
+#[derive(Default)]
+pub struct MemtestReport {
+    pub words: usize,
+    pub errors: usize,
+    pub first_error: Option<MemtestError>,
+}
+
+pub struct MemtestError {
+    pub addr: usize,
+    pub expected: u64,
+    pub found: u64,
+}
+
+/// The value of a word from its index, its address and the pass number.
+type Pattern = fn(usize, usize, usize) -> u64;
+
+/// The patterns written in turn during a pass, by name.
+static PATTERNS: &[(&str, Pattern)] = &[
+    ("addressing", |_, addr, _| addr as u64),
+    ("walking ones", |i, _, pass| 1u64.rotate_left((i + pass) as u32)),
+    ("walking zeros", |i, _, pass| !1u64.rotate_left((i + pass) as u32)),
+    ("random", |i, _, pass| xorshift((i as u64) << 8 | pass as u64)),
+];
+
+fn xorshift(seed: u64) -> u64 {
+    let mut x = seed.wrapping_mul(0x9E3779B97F4A7C15) | 1;
+    x ^= x << 13;
+    x ^= x >> 7;
+    x ^= x << 17;
+    x
+}
+
+/// Fill `words` with `pattern`. Volatile accesses, here and in check(),
+/// keep the compiler from skipping the round trip to memory.
+fn fill(words: &mut [u64], base: usize, pass: usize, pattern: Pattern) {
+    for (i, word) in words.iter_mut().enumerate() {
+        let addr = word as *mut u64 as usize;
+        unsafe { write_volatile(word, pattern(base + i, addr, pass)); }
+    }
+}
+
+/// Read `pattern` back from `words`, counting the mismatches.
+fn check(words: &[u64], base: usize, pass: usize, pattern: Pattern, report: &mut MemtestReport) {
+    for (i, word) in words.iter().enumerate() {
+        let addr = word as *const u64 as usize;
+        let expected = pattern(base + i, addr, pass);
+        let found = unsafe { read_volatile(word) };
+        if found != expected {
+            report.errors += 1;
+            if report.first_error.is_none() {
+                report.first_error = Some(MemtestError { addr, expected, found });
+            }
+        }
+    }
+}
+
+/// Run `passes` passes over `chunks`. `progress` is called after every
+/// pattern with the pass, the pattern name and the completed fraction.
+pub fn memtest_chunks(chunks: &mut [Vec<u64>], passes: usize, progress: &mut dyn FnMut(usize, &str, f64)) -> MemtestReport {
+    let mut report = MemtestReport { words: chunks.iter().map(Vec::len).sum(), ..Default::default() };
+    let steps = (passes * PATTERNS.len() * chunks.len()).max(1);
+    let mut done = 0;
+    for pass in 0..passes {
+        for (name, pattern) in PATTERNS {
+            let mut base = 0;
+            for chunk in chunks.iter_mut() {
+                fill(chunk, base, pass, *pattern);
+                check(chunk, base, pass, *pattern, &mut report);
+                base += chunk.len();
+                done += 1;
+                progress(pass, name, done as f64 / steps as f64);
+            }
+        }
+    }
+    report
+}
+
+/// The words of memory in `megabytes`, None if it's not a positive amount
+/// the address space can hold.
+pub fn memtest_words(megabytes: usize) -> Option<usize> {
+    megabytes.checked_mul(1024 * 1024).map(|bytes| bytes / 8).filter(|&words| words > 0)
+}
+
+/// Allocate `megabytes` of memory in chunks, test them and print a
+/// summary. Returns true if the memory passed the test.
+pub fn memtest(megabytes: usize, passes: usize) -> bool {
+    let Some(mut words) = memtest_words(megabytes) else {
+        eprintln!("Invalid amount of memory to test: {} megabytes", megabytes);
+        return false;
+    };
+    let mut chunks: Vec<Vec<u64>> = Vec::new();
+    while words > 0 {
+        let len = words.min(MEMTEST_CHUNK_WORDS);
+        let mut chunk: Vec<u64> = Vec::new();
+        if chunk.try_reserve_exact(len).is_err() {
+            eprintln!("Unable to allocate {} megabytes: out of memory", megabytes);
+            return false;
+        }
+        chunk.resize(len, 0);
+        chunks.push(chunk);
+        words -= len;
+    }
+
+    let mut progress = |pass: usize, name: &str, fraction: f64| {
+        print!("\rPass {}/{} {:<14} {:>3}%", pass + 1, passes, name, (fraction * 100.0) as u32);
+        let _ = stdout().flush();
+    };
+    let report = memtest_chunks(&mut chunks, passes, &mut progress);
+    println!();
+
+    match report.first_error {
+        None => {
+            println!("Your memory passed this test ({} megabytes, {} passes).", megabytes, passes);
+            println!("Please if you are still in doubt use the following two tools:");
+            println!("1) memtest86: http://www.memtest86.com/");
+            println!("2) memtester: http://pyropus.ca/software/memtester/");
+            true
+        },
+        Some(e) => {
+            println!("{} errors found in {} words. First one at {:#x}: expected {:#018x}, found {:#018x}.",
+                report.errors, report.words, e.addr, e.expected, e.found);
+            println!("Your memory is likely broken, please run a real memory tester.");
+            false
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, fill, memtest_chunks, memtest_words, MemtestReport, PATTERNS};
+
+    #[test]
+    fn sound_memory_passes_every_pattern() {
+        let mut chunks = vec![vec![0u64; 1000], vec![0u64; 24]];
+        let mut steps = Vec::new();
+        let report = memtest_chunks(&mut chunks, 2, &mut |pass, name, fraction| steps.push((pass, name.to_string(), fraction)));
+        assert_eq!(report.words, 1024);
+        assert_eq!(report.errors, 0);
+        assert!(report.first_error.is_none());
+        assert_eq!(steps.len(), 2 * PATTERNS.len() * 2);
+        assert_eq!(steps.last().unwrap(), &(1, "random".to_string(), 1.0));
+    }
+
+    #[test]
+    fn amounts_past_the_address_space_are_refused() {
+        assert_eq!(memtest_words(1), Some(128 * 1024));
+        assert_eq!(memtest_words(0), None);
+        assert_eq!(memtest_words(usize::MAX / (1024 * 1024) + 1), None);
+        assert_eq!(memtest_words(usize::MAX), None);
+    }
+
+    #[test]
+    fn patterns_flip_every_bit() {
+        // Across 64 consecutive words each bit is set and cleared.
+        for (name, pattern) in &PATTERNS[1..3] {
+            let (mut ones, mut zeros) = (0u64, 0u64);
+            for i in 0..64 {
+                ones |= pattern(i, 0, 0);
+                zeros |= !pattern(i, 0, 0);
+            }
+            assert_eq!((ones, zeros), (u64::MAX, u64::MAX), "{}", name);
+        }
+    }
+
+    #[test]
+    fn mismatches_are_counted_and_located() {
+        for (name, pattern) in PATTERNS {
+            let mut words = vec![0u64; 64];
+            let mut report = MemtestReport::default();
+            fill(&mut words, 0, 3, *pattern);
+            // A stuck bit, and a word overwritten with its neighbor's value
+            words[10] ^= 1 << 7;
+            words[20] = words[21];
+            check(&words, 0, 3, *pattern, &mut report);
+            assert_eq!(report.errors, 2, "{}", name);
+            let first = report.first_error.unwrap();
+            assert_eq!(first.addr, &words[10] as *const u64 as usize);
+            assert_eq!(first.found, first.expected ^ 1 << 7);
+        }
+    }
+}
@@ -1,11 +1,79 @@
 //! Basic TCP socket stuff made a bit less boring.
 
-use std::{mem::{size_of, size_of_val, zeroed}, net::Ipv4Addr};
-use libc::{bind, c_void, close, fcntl, listen, setsockopt, sockaddr, sockaddr_in, socket, strerror, AF_INET, EINTR, F_GETFL, F_SETFL, INADDR_ANY, IPPROTO_TCP, O_NONBLOCK, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR, TCP_NODELAY};
-use crate::util::error;
+use std::{mem::{size_of, size_of_val, zeroed}, net::Ipv4Addr, thread::sleep, time::Duration};
+use libc::{bind, c_void, close, fcntl, getpeername, getsockname, listen, open, setsockopt, sockaddr, sockaddr_in, socket, AF_INET, EACCES, EADDRINUSE, EINTR, F_GETFL, F_SETFL, INADDR_ANY, IPPROTO_TCP, O_NONBLOCK, O_RDONLY, SOCK_STREAM, SOL_SOCKET, SO_REUSEADDR, TCP_NODELAY};
+use crate::util::{error, strerror};
 
 
-pub fn tcp_server(port: u16, bindaddr: &str) -> Result<i32, String> {
+/// First wait before binding again a port in use, doubled every retry.
+const BIND_RETRY_DELAY_MS: u64 = 100;
+
+/// The default length of the queue of pending connections.
+pub const DEFAULT_TCP_BACKLOG: i32 = 511;   // the magic 511 constant is from nginx
+
+pub fn tcp_server(port: u16, bindaddr: &str, backlog: i32) -> Result<i32, String> {
+    tcp_server_errno(port, bindaddr, backlog).map_err(|(_, e)| e)
+}
+
+/// Like tcp_server(), but a port in use is tried again up to `retries`
+/// times with an exponential backoff: after a fast restart the sockets of
+/// the old process may still hold it.
+pub fn tcp_server_retry(port: u16, bindaddr: &str, backlog: i32, retries: u32) -> Result<i32, String> {
+    let mut delay = BIND_RETRY_DELAY_MS;
+    for _ in 0..retries {
+        match tcp_server_errno(port, bindaddr, backlog) {
+            Err((EADDRINUSE, _)) => {
+                sleep(Duration::from_millis(delay));
+                delay *= 2;
+            },
+            res => { return res.map_err(|(_, e)| e); },
+        }
+    }
+    tcp_server_errno(port, bindaddr, backlog).map_err(|(errno, e)| {
+        if errno == EADDRINUSE {
+            format!("{} (is another instance running on port {}?)", e, port)
+        } else {
+            e
+        }
+    })
+}
+
+/// The port a socket is bound to, e.g. the one picked for port 0.
+pub fn local_port(fd: i32) -> Result<u16, String> {
+    unsafe {
+        let mut sa: sockaddr_in = zeroed();
+        let mut len = size_of::<sockaddr_in>() as u32;
+        if getsockname(fd, &mut sa as *mut _ as *mut sockaddr, &mut len) == -1 {
+            return Err(format!("getsockname: {}", strerror(error())));
+        }
+        Ok(u16::from_be(sa.sin_port))
+    }
+}
+
+/// The address of the other end of a connected socket, as "ip:port".
+pub fn peer_to_string(fd: i32) -> Result<String, String> {
+    unsafe {
+        let mut sa: sockaddr_in = zeroed();
+        let mut len = size_of::<sockaddr_in>() as u32;
+        if getpeername(fd, &mut sa as *mut _ as *mut sockaddr, &mut len) == -1 {
+            return Err(format!("getpeername: {}", strerror(error())));
+        }
+        Ok(format!("{}:{}", Ipv4Addr::from(u32::from_be(sa.sin_addr.s_addr)), u16::from_be(sa.sin_port)))
+    }
+}
+
+/// Open a file descriptor only to keep it: once the process runs out of
+/// them, closing it makes room to accept and close a pending connection.
+pub fn reserve_fd() -> Result<i32, String> {
+    let fd = unsafe { open(c"/dev/null".as_ptr(), O_RDONLY) };
+    if fd == -1 {
+        return Err(format!("open /dev/null: {}", strerror(error())));
+    }
+    Ok(fd)
+}
+
+/// tcp_server() keeping the errno of the failed call, if any.
+fn tcp_server_errno(port: u16, bindaddr: &str, backlog: i32) -> Result<i32, (i32, String)> {
     let mut _sock = -1;
     let on = 1;
     let mut sa: sockaddr_in;
@@ -13,11 +81,13 @@ pub fn tcp_server(port: u16, bindaddr: &str) -> Result<i32, String> {
     unsafe {
         _sock = socket(AF_INET, SOCK_STREAM, 0);
         if _sock == -1 {
-            return Err(format!("socket: {}\n", *strerror(error())));
+            let errno = error();
+            return Err((errno, format!("socket: {}", strerror(errno))));
         }
         if setsockopt(_sock, SOL_SOCKET, SO_REUSEADDR, &on as *const _ as *const c_void, size_of::<i32>() as u32) == -1 {
+            let errno = error();
             close(_sock);
-            return Err(format!("setsockopt SO_REUSEADDR: {}\n", *strerror(error())));
+            return Err((errno, format!("setsockopt SO_REUSEADDR: {}", strerror(errno))));
         }
         sa = zeroed();
         #[cfg(target_os = "linux")]
@@ -39,25 +109,32 @@ pub fn tcp_server(port: u16, bindaddr: &str) -> Result<i32, String> {
                 },
                 Err(e) => {
                     close(_sock);
-                    return Err(format!("Invalid bind address '{}': {}\n", bindaddr, e));
+                    return Err((0, format!("Invalid bind address '{}': {}", bindaddr, e)));
                 },
             }
         }
 
         if bind(_sock, &sa as *const _ as *const sockaddr, size_of::<sockaddr>() as u32) == -1 {
+            let errno = error();
             close(_sock);
-            return Err(format!("bind: {}\n", *strerror(error())));
+            if errno == EACCES && port < 1024 {
+                return Err((errno, format!("bind: {} (ports below 1024 require root privileges)", strerror(errno))));
+            }
+            return Err((errno, format!("bind: {}", strerror(errno))));
         }
 
-        if listen(_sock, 511) == -1 {   // the magic 511 constant is from nginx
+        if listen(_sock, backlog) == -1 {
+            let errno = error();
             close(_sock);
-            return Err(format!("listen: {}\n", *strerror(error())));
+            return Err((errno, format!("listen: {}", strerror(errno))));
         }
     }
     Ok(_sock)
 }
 
-pub fn accept(serversock: i32) -> Result<(i32, u32, u16), String> {
+/// Accept a connection, the errno is kept on failure: on a nonblocking
+/// socket EAGAIN means there are no more pending connections.
+pub fn accept(serversock: i32) -> Result<(i32, u32, u16), (i32, String)> {
     let mut _fd = -1;
     let mut sa: sockaddr_in;
     loop {
@@ -66,10 +143,11 @@ pub fn accept(serversock: i32) -> Result<(i32, u32, u16), String> {
             let mut len = size_of::<sockaddr>() as u32;
             _fd = libc::accept(serversock, &mut sa as *mut _ as *mut sockaddr, &mut len);
             if _fd == -1 {
-                if error() == EINTR {
+                let errno = error();
+                if errno == EINTR {
                     continue;
                 } else {
-                    return Err(format!("accept: {}\n", *strerror(error())));
+                    return Err((errno, format!("accept: {}", strerror(errno))));
                 }
             }
             break;
@@ -89,10 +167,10 @@ pub fn nonblock(fd: i32) -> Result<(), String> {
     unsafe {
         let flag = fcntl(fd, F_GETFL);
         if flag == -1 {
-            return Err(format!("fcntl(F_GETFL): {}\n", *strerror(error())));
+            return Err(format!("fcntl(F_GETFL): {}", strerror(error())));
         }
         if fcntl(fd, F_SETFL, flag | O_NONBLOCK) == -1 {
-            return Err(format!("fcntl(F_SETFL,O_NONBLOCK): {}\n", *strerror(error())));
+            return Err(format!("fcntl(F_SETFL,O_NONBLOCK): {}", strerror(error())));
         }
     }
     Ok(())
@@ -102,8 +180,52 @@ pub fn tcp_no_delay(fd: i32) -> Result<(), String> {
     let yes = 1;
     unsafe {
         if setsockopt(fd, IPPROTO_TCP, TCP_NODELAY, &yes as *const _ as *const c_void, size_of_val(&yes) as u32) == -1 {
-            return Err(format!("setsockopt TCP_NODELAY: {}\n", *strerror(error())));
+            return Err(format!("setsockopt TCP_NODELAY: {}", strerror(error())));
         }
     }
     Ok(())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::{net::TcpListener, thread, time::Duration};
+    use libc::close;
+    use super::{local_port, tcp_server, tcp_server_retry, DEFAULT_TCP_BACKLOG};
+
+    #[test]
+    fn binding_a_port_in_use_fails_with_a_clear_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(tcp_server(port, "127.0.0.1", DEFAULT_TCP_BACKLOG), Err("bind: Address already in use".to_string()));
+
+        drop(listener);
+        let fd = tcp_server(port, "127.0.0.1", DEFAULT_TCP_BACKLOG).unwrap();
+        unsafe { close(fd); }
+    }
+
+    #[test]
+    fn port_zero_picks_a_free_port() {
+        let fd = tcp_server(0, "127.0.0.1", DEFAULT_TCP_BACKLOG).unwrap();
+        let port = local_port(fd).unwrap();
+        assert_ne!(port, 0);
+        assert!(TcpListener::bind(("127.0.0.1", port)).is_err());
+        unsafe { close(fd); }
+    }
+
+    #[test]
+    fn a_port_in_use_is_retried_until_freed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        assert_eq!(tcp_server_retry(port, "127.0.0.1", DEFAULT_TCP_BACKLOG, 1),
+            Err(format!("bind: Address already in use (is another instance running on port {}?)", port)));
+
+        let holder = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(150));
+            drop(listener);
+        });
+        let fd = tcp_server_retry(port, "127.0.0.1", DEFAULT_TCP_BACKLOG, 5).unwrap();
+        holder.join().unwrap();
+        unsafe { close(fd); }
+    }
+}
@@ -1,5 +1,6 @@
-use std::{cmp::Ordering, collections::{hash_set::{Intersection, Iter}, HashMap, HashSet, LinkedList}, hash::{Hash, RandomState}, ops::Deref, sync::{Arc, RwLock}};
+use std::{borrow::Cow, cmp::Ordering, collections::{HashMap, LinkedList}, hash::Hash, ops::Deref, sync::{Arc, RwLock}};
 use once_cell::sync::Lazy;
+use crate::{errors, util::RandomSampler};
 use super::skiplist::SkipList;
 
 
@@ -17,7 +18,7 @@ pub static OK: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
     Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("+OK\r\n".to_string()) }))
 });
 pub static ERR: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("-ERR\r\n".to_string()) }))
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(errors::error_line("ERR")) }))
 });
 pub static EMPTY_BULK: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
     Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("$0\r\n\r\n".to_string()) }))
@@ -40,23 +41,25 @@ pub static EMPTY_MULTI_BULK: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
 pub static PONG: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
     Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("+PONG\r\n".to_string()) }))
 });
+/// The PING reply as written on the wire, see RedisClient::add_reply_bytes().
+pub const PONG_BYTES: &[u8] = b"+PONG\r\n";
 pub static QUEUED: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
     Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("+QUEUED\r\n".to_string()) }))
 });
 pub static WRONG_TYPE_ERR: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("-ERR Operation against a key holding the wrong kind of value\r\n".to_string()) }))
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(errors::error_line(&errors::wrong_type())) }))
 });
 pub static NO_KEY_ERR: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("-ERR no such key\r\n".to_string()) }))
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(errors::error_line(&errors::err("no such key"))) }))
 });
 pub static SYNTAX_ERR: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("-ERR syntax error\r\n".to_string()) }))
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(errors::error_line(&errors::err("syntax error"))) }))
 });
 pub static SAME_OBJECT_ERR: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("-ERR source and destination objects are the same\r\n".to_string()) }))
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(errors::error_line(&errors::err("source and destination objects are the same"))) }))
 });
 pub static OUT_OF_RANGE_ERR: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
-    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("-ERR index out of range\r\n".to_string()) }))
+    Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(errors::error_line(&errors::err("index out of range"))) }))
 });
 pub static SPACE: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
     Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String(" ".to_string()) }))
@@ -99,8 +102,18 @@ pub static SELECT9: Lazy<Arc<RwLock<RedisObject>>> = Lazy::new(|| {
 });
 
 
+/// Strings up to this length are reported as "embstr" by OBJECT ENCODING,
+/// longer ones as "raw", like Redis does.
+pub const EMBSTR_SIZE_LIMIT: usize = 44;
+
+/// Number of live objects built at runtime (the shared ones above are never
+/// freed, so they are left out), see DEBUG OBJECT-COUNT.
+#[cfg(feature = "testing-hooks")]
+static LIVE_OBJECTS: std::sync::atomic::AtomicIsize = std::sync::atomic::AtomicIsize::new(0);
+
 /// Object types
-#[derive(Clone, Eq)]
+#[cfg_attr(not(feature = "testing-hooks"), derive(Clone))]
+#[derive(Eq)]
 pub enum RedisObject {
     String {
         ptr: StringStorageType,
@@ -114,8 +127,26 @@ pub enum RedisObject {
     ZSet {
         zs: ZSetStorageType,
     },
+    Hash {
+        h: HashStorageType,
+    },
 }
 impl RedisObject {
+    /// Account a newly built object in the live objects count. Every object
+    /// built at runtime goes through here. A no-op without the
+    /// testing-hooks feature.
+    #[inline]
+    pub fn tracked(self) -> RedisObject {
+        #[cfg(feature = "testing-hooks")]
+        LIVE_OBJECTS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
+        self
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    pub fn live_objects() -> isize {
+        LIVE_OBJECTS.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
     /// type code for dumping
     pub fn type_code(&self) -> u8 {
         match self {
@@ -123,6 +154,69 @@ impl RedisObject {
             RedisObject::List { l: _ } => 1,
             RedisObject::Set { s: _ } => 2,
             RedisObject::ZSet { zs: _ } => 3,
+            RedisObject::Hash { h: _ } => 4,
+        }
+    }
+
+    /// Name of the encoding, see OBJECT ENCODING.
+    pub fn encoding(&self) -> &'static str {
+        match self {
+            RedisObject::String { ptr: StringStorageType::Integer(_) } => "int",
+            RedisObject::String { ptr: StringStorageType::String(s) } => {
+                if s.len() <= EMBSTR_SIZE_LIMIT { "embstr" } else { "raw" }
+            },
+            RedisObject::List { l: ListStorageType::LinkedList(_) } => "linkedlist",
+            RedisObject::Set { s: SetStorageType::HashSet(_) } => "hashtable",
+            RedisObject::ZSet { zs: ZSetStorageType::SkipList(_, _) } => "skiplist",
+            RedisObject::Hash { h: HashStorageType::HashMap(_) } => "hashtable",
+        }
+    }
+
+    /// The elements of an aggregate value, the length of a string.
+    pub fn element_count(&self) -> usize {
+        match self {
+            RedisObject::String { ptr } => ptr.as_bytes().len(),
+            RedisObject::List { l } => l.len(),
+            RedisObject::Set { s } => s.len(),
+            RedisObject::ZSet { zs } => zs.len(),
+            RedisObject::Hash { h } => h.len(),
+        }
+    }
+
+    /// Estimate the memory taken by the value in bytes. The size of an
+    /// aggregate is extrapolated from its first `samples` elements, all of
+    /// them are looked at with 0. It's an estimate: allocator overhead and
+    /// the hash tables' spare capacity are not accounted.
+    pub fn estimated_size(&self, samples: usize) -> usize {
+        let obj = std::mem::size_of::<RedisObject>();
+        let ptr = std::mem::size_of::<usize>();
+        let extrapolate = |len: usize, sizes: &mut dyn Iterator<Item = usize>| -> usize {
+            let n = if samples == 0 { len } else { samples.min(len) };
+            if n == 0 {
+                return 0;
+            }
+            sizes.take(n).sum::<usize>() / n * len
+        };
+        obj + match self {
+            RedisObject::String { ptr: StringStorageType::Integer(_) } => 0,
+            RedisObject::String { ptr: StringStorageType::String(s) } => s.len(),
+            // Elements are strings: a list node holds two links, a set
+            // entry its hash.
+            RedisObject::List { l: ListStorageType::LinkedList(l) } => {
+                extrapolate(l.len(), &mut l.iter().map(|ele| ele.estimated_size(0) + 2 * ptr))
+            },
+            RedisObject::Set { s: SetStorageType::HashSet(s) } => {
+                extrapolate(s.len(), &mut s.iter().map(|ele| ele.estimated_size(0) + ptr))
+            },
+            // An element is in the dict with its score, and in a skiplist
+            // node with about two levels.
+            RedisObject::ZSet { zs: ZSetStorageType::SkipList(d, _) } => {
+                extrapolate(d.len(), &mut d.keys().map(|ele| 2 * ele.estimated_size(0) + ptr + 8 + 8 + 6 * ptr))
+            },
+            // A field string and its value object, plus the entry hash.
+            RedisObject::Hash { h: HashStorageType::HashMap(h) } => {
+                extrapolate(h.len(), &mut h.iter().map(|(f, v)| std::mem::size_of::<String>() + f.len() + v.estimated_size(0) + ptr))
+            },
         }
     }
 
@@ -214,6 +308,24 @@ impl RedisObject {
         }
     }
 
+    pub fn is_hash(&self) -> bool {
+        matches!(self, Self::Hash { .. })
+    }
+
+    pub fn hash_mut(&mut self) -> Option<&mut HashStorageType> {
+        match self {
+            Self::Hash { h } => { Some(h) },
+            _ => { None },
+        }
+    }
+
+    pub fn hash(&self) -> Option<&HashStorageType> {
+        match self {
+            Self::Hash { h } => { Some(h) },
+            _ => { None },
+        }
+    }
+
     /// Get a decoded version of an encoded object (returned as a new object).
     /// If the object is already raw-encoded just increment the ref count.
     pub fn get_decoded(&self) -> RedisObject {
@@ -221,7 +333,7 @@ impl RedisObject {
             Self::String { ptr } => {
                 match ptr {
                     StringStorageType::Integer(n) => {
-                        RedisObject::String { ptr: StringStorageType::String(n.to_string()) }
+                        RedisObject::String { ptr: StringStorageType::String(n.to_string()) }.tracked()
                     },
                     _ => { self.clone() },
                 }
@@ -230,6 +342,24 @@ impl RedisObject {
         }
     }
 }
+#[cfg(feature = "testing-hooks")]
+impl Clone for RedisObject {
+    fn clone(&self) -> Self {
+        match self {
+            Self::String { ptr } => Self::String { ptr: ptr.clone() },
+            Self::List { l } => Self::List { l: l.clone() },
+            Self::Set { s } => Self::Set { s: s.clone() },
+            Self::ZSet { zs } => Self::ZSet { zs: zs.clone() },
+            Self::Hash { h } => Self::Hash { h: h.clone() },
+        }.tracked()
+    }
+}
+#[cfg(feature = "testing-hooks")]
+impl Drop for RedisObject {
+    fn drop(&mut self) {
+        LIVE_OBJECTS.fetch_sub(1, std::sync::atomic::Ordering::Relaxed);
+    }
+}
 impl PartialEq for RedisObject {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
@@ -275,6 +405,28 @@ impl StringStorageType {
             _ => { false }
         }
     }
+
+    /// The value as it is seen by clients: integer encoded values are
+    /// rendered in base 10 without touching the stored encoding.
+    pub fn as_bytes(&self) -> Cow<'_, [u8]> {
+        match self {
+            Self::String(s) => { Cow::Borrowed(s.as_bytes()) },
+            Self::Integer(n) => { Cow::Owned(n.to_string().into_bytes()) },
+        }
+    }
+
+    /// Convert an integer encoded value to the raw representation, so that
+    /// it can be modified in place. Commands changing a string value must
+    /// go through here.
+    pub fn make_raw(&mut self) -> &mut String {
+        if let Self::Integer(n) = self {
+            *self = Self::String(n.to_string());
+        }
+        match self {
+            Self::String(s) => { s },
+            Self::Integer(_) => { unreachable!() },
+        }
+    }
 }
 impl PartialEq for StringStorageType {
     fn eq(&self, other: &Self) -> bool {
@@ -330,8 +482,7 @@ impl ListStorageType {
     pub fn range(&self, start: i32, end: i32) -> Vec<RedisObject> {
         match self {
             Self::LinkedList(l) => {
-                let mut skip = 0usize;
-                if start > 0 { skip = (start - 1) as usize; }
+                let skip = start as usize;
                 let size = (end - start + 1) as usize;
                 let v: Vec<RedisObject> = l.iter().cloned()
                                                 .skip(skip)
@@ -436,9 +587,63 @@ impl PartialEq for ListStorageType {
         false
     }
 }
+/// The container behind set objects. Builds with the testing-hooks feature
+/// swap it for an insertion-ordered one, so tests can assert exact replies
+/// (and RDB bytes) for SMEMBERS and friends. Everywhere else the iteration
+/// order is unspecified. The members are strings, hashed and compared by
+/// value only, so clippy::mutable_key_type is allowed where it's used.
+#[cfg(not(feature = "testing-hooks"))]
+pub type ObjSet = std::collections::HashSet<RedisObject>;
+#[cfg(not(feature = "testing-hooks"))]
+pub type ObjSetIter<'a> = std::collections::hash_set::Iter<'a, RedisObject>;
+#[cfg(feature = "testing-hooks")]
+pub type ObjSet = OrderedSet<RedisObject>;
+#[cfg(feature = "testing-hooks")]
+pub type ObjSetIter<'a> = std::slice::Iter<'a, RedisObject>;
+
+/// Vec-backed set keeping elements in insertion order. Lookups are linear,
+/// it's only meant for tests.
+#[cfg(feature = "testing-hooks")]
+#[derive(Clone, PartialEq, Eq, Default)]
+pub struct OrderedSet<T>(Vec<T>);
+#[cfg(feature = "testing-hooks")]
+impl<T: PartialEq> OrderedSet<T> {
+    pub fn new() -> OrderedSet<T> {
+        OrderedSet(Vec::new())
+    }
+    pub fn with_capacity(capacity: usize) -> OrderedSet<T> {
+        OrderedSet(Vec::with_capacity(capacity))
+    }
+    pub fn insert(&mut self, value: T) -> bool {
+        if self.contains(&value) {
+            return false;
+        }
+        self.0.push(value);
+        true
+    }
+    pub fn remove(&mut self, value: &T) -> bool {
+        match self.0.iter().position(|v| v == value) {
+            Some(i) => { self.0.remove(i); true },
+            None => false,
+        }
+    }
+    pub fn contains(&self, value: &T) -> bool {
+        self.0.contains(value)
+    }
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    pub fn iter(&self) -> std::slice::Iter<'_, T> {
+        self.0.iter()
+    }
+}
+
 #[derive(Clone, Eq)]
 pub enum SetStorageType {
-    HashSet(HashSet<RedisObject>)
+    HashSet(ObjSet)
 }
 impl SetStorageType {
     pub fn insert(&mut self, obj: Arc<RwLock<RedisObject>>) -> bool {
@@ -460,8 +665,7 @@ impl SetStorageType {
     pub fn get_random_key(&self) -> Option<Arc<RwLock<RedisObject>>> {
         match self {
             Self::HashSet(s) => {
-                // TODO: random
-                s.iter().nth(0).map(|e| Arc::new(RwLock::new(e.clone())))
+                RandomSampler::default().pick(s.iter()).map(|e| Arc::new(RwLock::new(e.clone())))
             },
         }
     }
@@ -489,15 +693,7 @@ impl SetStorageType {
         }
     }
 
-    pub fn inter<'a>(&'a self, other: &'a SetStorageType) -> Intersection<RedisObject, RandomState> {
-        match (self, other) {
-            (Self::HashSet(l), Self::HashSet(r)) => {
-                l.intersection(r)
-            },
-        }
-    }
-
-    pub fn iter(&self) -> Iter<RedisObject> {
+    pub fn iter(&self) -> ObjSetIter<'_> {
         match self {
             Self::HashSet(s) => {
                 s.iter()
@@ -558,6 +754,70 @@ impl PartialEq for ZSetStorageType {
 }
 impl Eq for ZSetStorageType {}
 
+/// Field names are plain strings, values are string objects so that they
+/// can be integer encoded. There's one encoding for now, a compact one for
+/// small hashes (Redis' zipmap) can be added as another variant.
+#[derive(Clone, Eq)]
+pub enum HashStorageType {
+    HashMap(HashMap<String, RedisObject>)
+}
+impl HashStorageType {
+    pub fn new() -> HashStorageType {
+        Self::HashMap(HashMap::new())
+    }
+
+    pub fn get(&self, field: &str) -> Option<&RedisObject> {
+        match self {
+            Self::HashMap(h) => h.get(field)
+        }
+    }
+
+    /// Set the field, returns true if it's a new one.
+    pub fn insert(&mut self, field: &str, value: RedisObject) -> bool {
+        match self {
+            Self::HashMap(h) => h.insert(field.to_string(), value).is_none()
+        }
+    }
+
+    pub fn remove(&mut self, field: &str) -> bool {
+        match self {
+            Self::HashMap(h) => h.remove(field).is_some()
+        }
+    }
+
+    pub fn contains(&self, field: &str) -> bool {
+        match self {
+            Self::HashMap(h) => h.contains_key(field)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        match self {
+            Self::HashMap(h) => h.len()
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&String, &RedisObject)> + Clone {
+        match self {
+            Self::HashMap(h) => h.iter()
+        }
+    }
+}
+impl Default for HashStorageType {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl PartialEq for HashStorageType {
+    fn eq(&self, _other: &Self) -> bool {
+        false
+    }
+}
+
 pub fn try_object_sharing(obj: Arc<RwLock<RedisObject>>) {
     todo!()
 }
@@ -580,7 +840,7 @@ pub fn try_object_encoding(obj: Arc<RwLock<RedisObject>>) -> Arc<RwLock<RedisObj
                 StringStorageType::String(s) => {
                     match is_string_representable_as_int(s) {
                         Ok(encoded) => { 
-                            return Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::Integer(encoded) }));
+                            return Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::Integer(encoded) }.tracked()));
                         },
                         Err(_) => {},
                     }
@@ -649,3 +909,72 @@ pub fn compare_string_objects(obj1: &RedisObject, obj2: &RedisObject) -> Orderin
     obj1.get_decoded().string().unwrap().string().unwrap()
         .cmp(obj2.get_decoded().string().unwrap().string().unwrap())
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, RwLock};
+    use super::{try_object_encoding, RedisObject, StringStorageType};
+
+    /// The same value both integer encoded and raw.
+    fn both(n: isize) -> (StringStorageType, StringStorageType) {
+        (StringStorageType::Integer(n), StringStorageType::String(n.to_string()))
+    }
+
+    #[test]
+    fn estimated_size_extrapolates_from_samples() {
+        let list = |sizes: &[usize]| RedisObject::List { l: super::ListStorageType::LinkedList(
+            sizes.iter().map(|n| RedisObject::String { ptr: StringStorageType::String("x".repeat(*n)) }).collect()) };
+        let empty = list(&[]).estimated_size(5);
+        assert_eq!(empty, std::mem::size_of::<RedisObject>());
+        // Same sized elements: sampling gives the exact figure
+        assert_eq!(list(&[10; 100]).estimated_size(5), list(&[10; 100]).estimated_size(0));
+        assert!(list(&[10; 100]).estimated_size(0) > list(&[10; 10]).estimated_size(0));
+        // Only the first elements are looked at
+        let mut sizes = vec![10; 5];
+        sizes.push(10_000);
+        assert!(list(&sizes).estimated_size(5) < list(&sizes).estimated_size(0));
+
+        let (int, raw) = both(12345);
+        assert!(RedisObject::String { ptr: int }.estimated_size(0) < RedisObject::String { ptr: raw }.estimated_size(0));
+    }
+
+    #[test]
+    fn as_bytes_renders_integers_like_raw_strings() {
+        for n in [0, 7, -12, 123, isize::MAX, isize::MIN] {
+            let (int, raw) = both(n);
+            assert_eq!(int.as_bytes(), raw.as_bytes());
+        }
+    }
+
+    #[test]
+    fn range_reads_do_not_change_the_encoding() {
+        let (int, raw) = both(-12345);
+        assert_eq!(int.as_bytes()[1..3], raw.as_bytes()[1..3]);
+        assert_eq!(int.as_bytes().len(), raw.as_bytes().len());
+        assert!(matches!(int, StringStorageType::Integer(-12345)));
+    }
+
+    #[test]
+    fn make_raw_allows_in_place_mutation() {
+        let (mut int, mut raw) = both(123);
+        int.make_raw().push_str("abc");
+        raw.make_raw().push_str("abc");
+        assert_eq!(int.string(), Some("123abc"));
+        assert_eq!(int.as_bytes(), raw.as_bytes());
+
+        let (mut int, mut raw) = both(99);
+        int.make_raw().replace_range(0..1, "1");
+        raw.make_raw().replace_range(0..1, "1");
+        assert_eq!(int.as_bytes(), raw.as_bytes());
+        assert_eq!(&int.as_bytes()[..], b"19");
+    }
+
+    #[test]
+    fn encoded_objects_read_back_as_the_original_string() {
+        let obj = RedisObject::String { ptr: StringStorageType::String("4096".to_string()) };
+        let encoded = try_object_encoding(Arc::new(RwLock::new(obj)));
+        assert!(matches!(encoded.read().unwrap().string(), Some(StringStorageType::Integer(4096))));
+        assert_eq!(&encoded.read().unwrap().string().unwrap().as_bytes()[..], b"4096");
+    }
+}
@@ -0,0 +1,446 @@
+//! Pub/Sub.
+//!
+//! Subscriptions are kept on both sides: every client has the channels and
+//! patterns it listens to, and the server maps them back to the clients so
+//! that PUBLISH finds the receivers. The server refers to clients by id,
+//! the messages are delivered walking the client list.
+
+use std::collections::HashMap;
+use crate::{client::{clients_read, RedisClient}, cmd::reply_subcommand_help, errors, server::{server_read, server_write}, util::string_pattern_match};
+
+
+/// The subscription replies: [kind, channel or pattern, subscription count].
+/// A None target is sent as a null bulk, when unsubscribing from nothing.
+/// Sharded subscriptions are counted on their own, as in Redis 7.
+fn add_reply_pubsub(c: &RedisClient, kind: &str, target: Option<&str>) {
+    let target = match target {
+        Some(t) => format!("${}\r\n{}\r\n", t.len(), t),
+        None => "$-1\r\n".to_string(),
+    };
+    let count = if kind == "ssubscribe" || kind == "sunsubscribe" {
+        c.shard_subscription_count()
+    } else {
+        c.subscription_count()
+    };
+    c.add_reply_str(&format!("*3\r\n${}\r\n{}\r\n{}:{}\r\n", kind.len(), kind, target, count));
+}
+
+/// Subscribe a client to a channel, as a sharded channel if `shard` is set.
+/// Returns false if the client was already subscribed to that channel.
+fn pubsub_subscribe_channel(c: &mut RedisClient, channel: &str, shard: bool) -> bool {
+    let added = if shard {
+        c.pubsub_shard_channels.insert(channel.to_string())
+    } else {
+        c.pubsub_channels.insert(channel.to_string())
+    };
+    if added {
+        let mut server = server_write();
+        let channels = if shard { &mut server.pubsub_shard_channels } else { &mut server.pubsub_channels };
+        channels.entry(channel.to_string()).or_default().push(c.id());
+    }
+    add_reply_pubsub(c, if shard { "ssubscribe" } else { "subscribe" }, Some(channel));
+    added
+}
+
+/// Unsubscribe a client from a channel, a sharded one if `shard` is set.
+/// Returns false if the client was not subscribed to the specified channel.
+fn pubsub_unsubscribe_channel(c: &mut RedisClient, channel: &str, shard: bool, notify: bool) -> bool {
+    let removed = if shard {
+        c.pubsub_shard_channels.remove(channel)
+    } else {
+        c.pubsub_channels.remove(channel)
+    };
+    if removed {
+        let mut server = server_write();
+        let channels = if shard { &mut server.pubsub_shard_channels } else { &mut server.pubsub_channels };
+        if let Some(clients) = channels.get_mut(channel) {
+            clients.retain(|id| *id != c.id());
+            // Free the entry of a channel nobody listens to anymore
+            if clients.is_empty() {
+                channels.remove(channel);
+            }
+        }
+    }
+    if notify {
+        add_reply_pubsub(c, if shard { "sunsubscribe" } else { "unsubscribe" }, Some(channel));
+    }
+    removed
+}
+
+/// Subscribe a client to a pattern. Returns false if the client was already
+/// subscribed to that pattern.
+fn pubsub_subscribe_pattern(c: &mut RedisClient, pattern: &str) -> bool {
+    let added = !c.pubsub_patterns.iter().any(|p| p == pattern);
+    if added {
+        c.pubsub_patterns.push(pattern.to_string());
+        server_write().pubsub_patterns.push((c.id(), pattern.to_string()));
+    }
+    add_reply_pubsub(c, "psubscribe", Some(pattern));
+    added
+}
+
+/// Unsubscribe a client from a pattern. Returns false if the client was not
+/// subscribed to the specified pattern.
+fn pubsub_unsubscribe_pattern(c: &mut RedisClient, pattern: &str, notify: bool) -> bool {
+    let removed = match c.pubsub_patterns.iter().position(|p| p == pattern) {
+        Some(i) => {
+            c.pubsub_patterns.remove(i);
+            server_write().pubsub_patterns.retain(|(id, p)| *id != c.id() || p != pattern);
+            true
+        },
+        None => false,
+    };
+    if notify {
+        add_reply_pubsub(c, "punsubscribe", Some(pattern));
+    }
+    removed
+}
+
+/// Unsubscribe from all the channels, or all the sharded channels if
+/// `shard` is set. Return the number of channels the client was subscribed to.
+pub fn pubsub_unsubscribe_all_channels(c: &mut RedisClient, shard: bool, notify: bool) -> usize {
+    let channels: Vec<String> = if shard {
+        c.pubsub_shard_channels.iter().cloned().collect()
+    } else {
+        c.pubsub_channels.iter().cloned().collect()
+    };
+    for channel in &channels {
+        pubsub_unsubscribe_channel(c, channel, shard, notify);
+    }
+    // We were subscribed to nothing? Still reply to the client.
+    if notify && channels.is_empty() {
+        add_reply_pubsub(c, if shard { "sunsubscribe" } else { "unsubscribe" }, None);
+    }
+    channels.len()
+}
+
+/// Unsubscribe from all the patterns. Return the number of patterns the
+/// client was subscribed to.
+pub fn pubsub_unsubscribe_all_patterns(c: &mut RedisClient, notify: bool) -> usize {
+    let patterns = c.pubsub_patterns.clone();
+    for pattern in &patterns {
+        pubsub_unsubscribe_pattern(c, pattern, notify);
+    }
+    if notify && patterns.is_empty() {
+        add_reply_pubsub(c, "punsubscribe", None);
+    }
+    patterns.len()
+}
+
+/// Publish a message to the clients subscribed to the channel, directly or
+/// through a pattern. Returns the number of messages delivered.
+///
+/// Without a cluster there is a single shard, so sharded channels share the
+/// namespace of the regular ones: PUBLISH and SPUBLISH reach the same
+/// subscribers, and only the message kind tells how they subscribed.
+pub fn pubsub_publish_message(channel: &str, message: &str) -> usize {
+    let (subscribers, shard_subscribers, patterns) = {
+        let server = server_read();
+        let subscribers = server.pubsub_channels.get(channel).cloned().unwrap_or_default();
+        let shard_subscribers = server.pubsub_shard_channels.get(channel).cloned().unwrap_or_default();
+        let patterns: Vec<(u64, String)> = server.pubsub_patterns.iter()
+            .filter(|(_, p)| string_pattern_match(p, channel))
+            .cloned()
+            .collect();
+        (subscribers, shard_subscribers, patterns)
+    };
+    if subscribers.is_empty() && shard_subscribers.is_empty() && patterns.is_empty() {
+        return 0;
+    }
+
+    let mut received = 0;
+    for c in clients_read().iter() {
+        // A client locked by the caller is the publisher: it is not
+        // subscribed, or it couldn't run PUBLISH.
+        let Ok(c) = c.try_read() else { continue; };
+        if subscribers.contains(&c.id()) {
+            c.add_reply_str(&format!("*3\r\n$7\r\nmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                channel.len(), channel, message.len(), message));
+            received += 1;
+        }
+        if shard_subscribers.contains(&c.id()) {
+            c.add_reply_str(&format!("*3\r\n$8\r\nsmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                channel.len(), channel, message.len(), message));
+            received += 1;
+        }
+        for (_, pattern) in patterns.iter().filter(|(id, _)| *id == c.id()) {
+            c.add_reply_str(&format!("*4\r\n$8\r\npmessage\r\n${}\r\n{}\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                pattern.len(), pattern, channel.len(), channel, message.len(), message));
+            received += 1;
+        }
+    }
+    received
+}
+
+
+/// SUBSCRIBE channel [channel ...]
+pub fn subscribe_command(c: &mut RedisClient) {
+    let channels: Vec<String> = c.argv[1..].iter().map(|a| a.read().unwrap().as_key().to_string()).collect();
+    for channel in &channels {
+        pubsub_subscribe_channel(c, channel, false);
+    }
+}
+
+/// UNSUBSCRIBE [channel ...]
+pub fn unsubscribe_command(c: &mut RedisClient) {
+    if c.argv.len() == 1 {
+        pubsub_unsubscribe_all_channels(c, false, true);
+    } else {
+        let channels: Vec<String> = c.argv[1..].iter().map(|a| a.read().unwrap().as_key().to_string()).collect();
+        for channel in &channels {
+            pubsub_unsubscribe_channel(c, channel, false, true);
+        }
+    }
+}
+
+/// SSUBSCRIBE shardchannel [shardchannel ...]
+pub fn ssubscribe_command(c: &mut RedisClient) {
+    let channels: Vec<String> = c.argv[1..].iter().map(|a| a.read().unwrap().as_key().to_string()).collect();
+    for channel in &channels {
+        pubsub_subscribe_channel(c, channel, true);
+    }
+}
+
+/// SUNSUBSCRIBE [shardchannel ...]
+pub fn sunsubscribe_command(c: &mut RedisClient) {
+    if c.argv.len() == 1 {
+        pubsub_unsubscribe_all_channels(c, true, true);
+    } else {
+        let channels: Vec<String> = c.argv[1..].iter().map(|a| a.read().unwrap().as_key().to_string()).collect();
+        for channel in &channels {
+            pubsub_unsubscribe_channel(c, channel, true, true);
+        }
+    }
+}
+
+/// PSUBSCRIBE pattern [pattern ...]
+pub fn psubscribe_command(c: &mut RedisClient) {
+    let patterns: Vec<String> = c.argv[1..].iter().map(|a| a.read().unwrap().as_key().to_string()).collect();
+    for pattern in &patterns {
+        pubsub_subscribe_pattern(c, pattern);
+    }
+}
+
+/// PUNSUBSCRIBE [pattern ...]
+pub fn punsubscribe_command(c: &mut RedisClient) {
+    if c.argv.len() == 1 {
+        pubsub_unsubscribe_all_patterns(c, true);
+    } else {
+        let patterns: Vec<String> = c.argv[1..].iter().map(|a| a.read().unwrap().as_key().to_string()).collect();
+        for pattern in &patterns {
+            pubsub_unsubscribe_pattern(c, pattern, true);
+        }
+    }
+}
+
+/// PUBLISH channel message
+pub fn publish_command(c: &mut RedisClient) {
+    let channel = c.argv[1].read().unwrap().as_key().to_string();
+    let message = c.argv[2].read().unwrap().as_key().to_string();
+    let received = pubsub_publish_message(&channel, &message);
+    c.add_reply_u64(received as u64);
+}
+
+/// SPUBLISH shardchannel message, the same as PUBLISH without a cluster.
+pub fn spublish_command(c: &mut RedisClient) {
+    publish_command(c);
+}
+
+/// The channels with subscribers, matching the pattern if any.
+fn reply_active_channels(c: &RedisClient, channels: &HashMap<String, Vec<u64>>) {
+    let pattern = c.argv.get(2).map(|p| p.read().unwrap().as_key().to_string());
+    let active: Vec<&String> = channels.keys()
+        .filter(|ch| pattern.as_ref().is_none_or(|p| string_pattern_match(p, ch)))
+        .collect();
+    c.add_reply_str(&format!("*{}\r\n", active.len()));
+    for channel in active {
+        c.add_reply_bulk_str(channel);
+    }
+}
+
+/// The number of subscribers of every channel in the arguments.
+fn reply_subscribers_count(c: &RedisClient, channels: &HashMap<String, Vec<u64>>) {
+    c.add_reply_str(&format!("*{}\r\n", (c.argv.len() - 2) * 2));
+    for arg in &c.argv[2..] {
+        let channel = arg.read().unwrap().as_key().to_string();
+        c.add_reply_bulk_str(&channel);
+        c.add_reply_u64(channels.get(&channel).map_or(0, |ids| ids.len()) as u64);
+    }
+}
+
+/// PUBSUB CHANNELS [pattern] | NUMSUB [channel ...] | NUMPAT |
+///        SHARDCHANNELS [pattern] | SHARDNUMSUB [shardchannel ...]
+static PUBSUB_HELP: &[(&str, &str)] = &[
+    ("CHANNELS [<pattern>]", "Return the currently active channels matching a <pattern> (default: '*')."),
+    ("NUMPAT", "Return number of subscriptions to patterns."),
+    ("NUMSUB [<channel> ...]", "Return the number of subscribers for the specified channels, excluding pattern subscriptions."),
+    ("SHARDCHANNELS [<pattern>]", "Return the currently active shard level channels matching a <pattern> (default: '*')."),
+    ("SHARDNUMSUB [<shardchannel> ...]", "Return the number of subscribers for the specified shard level channel(s)."),
+];
+
+pub fn pubsub_command(c: &mut RedisClient) {
+    let sub = c.argv[1].read().unwrap().as_key().to_ascii_lowercase();
+    match &sub[..] {
+        "help" if c.argv.len() == 2 => {
+            reply_subcommand_help(c, PUBSUB_HELP);
+        },
+        "channels" if c.argv.len() <= 3 => {
+            let channels = server_read().pubsub_channels.clone();
+            reply_active_channels(c, &channels);
+        },
+        "shardchannels" if c.argv.len() <= 3 => {
+            let channels = server_read().pubsub_shard_channels.clone();
+            reply_active_channels(c, &channels);
+        },
+        "numsub" => {
+            let channels = server_read().pubsub_channels.clone();
+            reply_subscribers_count(c, &channels);
+        },
+        "shardnumsub" => {
+            let channels = server_read().pubsub_shard_channels.clone();
+            reply_subscribers_count(c, &channels);
+        },
+        "numpat" if c.argv.len() == 2 => {
+            let patterns = server_read().pubsub_patterns.len();
+            c.add_reply_u64(patterns as u64);
+        },
+        _ => {
+            c.add_reply_error(&errors::unknown_subcommand("pubsub", &c.arg(1)));
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use crate::{client::tests::{listed_client, run, take_replies, test_client, unlist}, server::{server_read, tests::{parse_info, setup}}};
+
+    #[test]
+    fn subscribe_replies_and_counts() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        assert_eq!(run(&mut c, &["SUBSCRIBE", "a", "b", "a"]),
+            "*3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:1\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\nb\r\n:2\r\n\
+             *3\r\n$9\r\nsubscribe\r\n$1\r\na\r\n:2\r\n");
+        assert_eq!(run(&mut c, &["PSUBSCRIBE", "news.*"]), "*3\r\n$10\r\npsubscribe\r\n$6\r\nnews.*\r\n:3\r\n");
+
+        // Only the pubsub commands, PING and QUIT while subscribed.
+        assert_eq!(run(&mut c, &["GET", "k"]),
+            "-ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n");
+        assert_eq!(run(&mut c, &["PING"]), "*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+
+        assert_eq!(run(&mut c, &["UNSUBSCRIBE", "b", "x"]),
+            "*3\r\n$11\r\nunsubscribe\r\n$1\r\nb\r\n:2\r\n\
+             *3\r\n$11\r\nunsubscribe\r\n$1\r\nx\r\n:2\r\n");
+        assert_eq!(run(&mut c, &["PUNSUBSCRIBE"]), "*3\r\n$12\r\npunsubscribe\r\n$6\r\nnews.*\r\n:1\r\n");
+        assert_eq!(run(&mut c, &["UNSUBSCRIBE"]), "*3\r\n$11\r\nunsubscribe\r\n$1\r\na\r\n:0\r\n");
+        assert_eq!(run(&mut c, &["UNSUBSCRIBE"]), "*3\r\n$11\r\nunsubscribe\r\n$-1\r\n:0\r\n");
+
+        // Out of the pubsub context again.
+        assert_eq!(run(&mut c, &["GET", "k"]), "$-1\r\n");
+        assert_eq!(run(&mut c, &["PING"]), "+PONG\r\n");
+        assert!(server_read().pubsub_channels.is_empty());
+        assert!(server_read().pubsub_patterns.is_empty());
+    }
+
+    #[test]
+    fn publish_reaches_channel_and_pattern_subscribers() {
+        let _guard = setup();
+        let mut publisher = test_client();
+        let sub = listed_client();
+        let psub = listed_client();
+        run(&mut sub.write().unwrap(), &["SUBSCRIBE", "news.tech"]);
+        run(&mut psub.write().unwrap(), &["PSUBSCRIBE", "news.*", "*"]);
+
+        assert_eq!(run(&mut publisher, &["PUBLISH", "news.tech", "hi"]), ":3\r\n");
+        assert_eq!(take_replies(&mut sub.write().unwrap()), "*3\r\n$7\r\nmessage\r\n$9\r\nnews.tech\r\n$2\r\nhi\r\n");
+        assert_eq!(take_replies(&mut psub.write().unwrap()),
+            "*4\r\n$8\r\npmessage\r\n$6\r\nnews.*\r\n$9\r\nnews.tech\r\n$2\r\nhi\r\n\
+             *4\r\n$8\r\npmessage\r\n$1\r\n*\r\n$9\r\nnews.tech\r\n$2\r\nhi\r\n");
+
+        assert_eq!(run(&mut publisher, &["PUBLISH", "other", "x"]), ":1\r\n");
+        assert_eq!(take_replies(&mut sub.write().unwrap()), "");
+        take_replies(&mut psub.write().unwrap());
+
+        // A freed client doesn't receive messages anymore.
+        unlist(&sub);
+        drop(sub);
+        assert!(server_read().pubsub_channels.is_empty());
+        assert_eq!(run(&mut publisher, &["PUBLISH", "news.tech", "hi"]), ":2\r\n");
+        unlist(&psub);
+        drop(psub);
+        assert!(server_read().pubsub_patterns.is_empty());
+        assert_eq!(run(&mut publisher, &["PUBLISH", "news.tech", "hi"]), ":0\r\n");
+    }
+
+    #[test]
+    fn sharded_subscribers_get_smessages() {
+        let _guard = setup();
+        let mut publisher = test_client();
+        let both = listed_client();
+        let shard = listed_client();
+
+        let mut c = both.write().unwrap();
+        assert_eq!(run(&mut c, &["SUBSCRIBE", "ch"]), "*3\r\n$9\r\nsubscribe\r\n$2\r\nch\r\n:1\r\n");
+        // Sharded subscriptions are counted on their own.
+        assert_eq!(run(&mut c, &["SSUBSCRIBE", "ch", "other"]),
+            "*3\r\n$10\r\nssubscribe\r\n$2\r\nch\r\n:1\r\n\
+             *3\r\n$10\r\nssubscribe\r\n$5\r\nother\r\n:2\r\n");
+        drop(c);
+        run(&mut shard.write().unwrap(), &["SSUBSCRIBE", "ch"]);
+        assert_eq!(run(&mut shard.write().unwrap(), &["GET", "k"]),
+            "-ERR Can't execute 'get': only (P)SUBSCRIBE / (P)UNSUBSCRIBE / PING / QUIT / RESET are allowed in this context\r\n");
+
+        // One namespace: both PUBLISH and SPUBLISH reach every subscriber,
+        // with the kind of message matching the subscription.
+        for cmd in ["PUBLISH", "SPUBLISH"] {
+            assert_eq!(run(&mut publisher, &[cmd, "ch", "hi"]), ":3\r\n");
+            assert_eq!(take_replies(&mut both.write().unwrap()),
+                "*3\r\n$7\r\nmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n\
+                 *3\r\n$8\r\nsmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n");
+            assert_eq!(take_replies(&mut shard.write().unwrap()), "*3\r\n$8\r\nsmessage\r\n$2\r\nch\r\n$2\r\nhi\r\n");
+        }
+
+        let mut c = both.write().unwrap();
+        assert_eq!(run(&mut c, &["SUNSUBSCRIBE", "ch"]), "*3\r\n$12\r\nsunsubscribe\r\n$2\r\nch\r\n:1\r\n");
+        assert_eq!(run(&mut c, &["UNSUBSCRIBE"]), "*3\r\n$11\r\nunsubscribe\r\n$2\r\nch\r\n:0\r\n");
+        // Still subscribed to a sharded channel.
+        assert_eq!(run(&mut c, &["PING"]), "*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+        assert_eq!(run(&mut c, &["SUNSUBSCRIBE"]), "*3\r\n$12\r\nsunsubscribe\r\n$5\r\nother\r\n:0\r\n");
+        assert_eq!(run(&mut c, &["SUNSUBSCRIBE"]), "*3\r\n$12\r\nsunsubscribe\r\n$-1\r\n:0\r\n");
+        assert_eq!(run(&mut c, &["PING"]), "+PONG\r\n");
+        drop(c);
+
+        unlist(&both);
+        unlist(&shard);
+        drop(shard);
+        assert!(server_read().pubsub_shard_channels.is_empty());
+    }
+
+    #[test]
+    fn pubsub_introspection() {
+        let _guard = setup();
+        let mut c = test_client();
+        let mut sub = test_client();
+        let mut ssub = test_client();
+        run(&mut sub, &["SUBSCRIBE", "news", "sport"]);
+        run(&mut sub, &["PSUBSCRIBE", "n*"]);
+        run(&mut ssub, &["SSUBSCRIBE", "news"]);
+
+        assert_eq!(run(&mut c, &["PUBSUB", "CHANNELS", "n*"]), "*1\r\n$4\r\nnews\r\n");
+        assert_eq!(run(&mut c, &["PUBSUB", "SHARDCHANNELS"]), "*1\r\n$4\r\nnews\r\n");
+        assert_eq!(run(&mut c, &["PUBSUB", "SHARDCHANNELS", "s*"]), "*0\r\n");
+        assert_eq!(run(&mut c, &["PUBSUB", "NUMSUB", "news", "none"]), "*4\r\n$4\r\nnews\r\n:1\r\n$4\r\nnone\r\n:0\r\n");
+        assert_eq!(run(&mut c, &["PUBSUB", "SHARDNUMSUB", "news", "sport"]), "*4\r\n$4\r\nnews\r\n:1\r\n$5\r\nsport\r\n:0\r\n");
+        assert_eq!(run(&mut c, &["PUBSUB", "NUMPAT"]), ":1\r\n");
+        assert_eq!(run(&mut c, &["PUBSUB", "NOPE"]), "-ERR Unknown subcommand or wrong number of arguments for 'NOPE'. Try PUBSUB HELP.\r\n");
+        let help = run(&mut c, &["PUBSUB", "HELP"]);
+        assert!(help.starts_with("*13\r\n+PUBSUB <subcommand> [<arg> [value] [opt] ...]. Subcommands are:\r\n+CHANNELS [<pattern>]\r\n"), "{}", help);
+
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["pubsub_channels"], "2");
+        assert_eq!(info["pubsubshard_channels"], "1");
+    }
+}
@@ -1,8 +1,18 @@
-use std::{collections::{HashMap, HashSet, LinkedList}, fs::{metadata, remove_file, rename, File, OpenOptions}, io::{BufReader, BufWriter, Error, ErrorKind, Read, Write}, process::{exit, id}, str::from_utf8, sync::{Arc, RwLock}};
-use libc::{close, fork, pid_t, strerror};
+use std::{collections::{HashMap, LinkedList}, fs::{metadata, remove_file, rename, File, OpenOptions}, io::{BufReader, BufWriter, Error, ErrorKind, Read, Write}, process::{exit, id}, str::from_utf8, sync::{Arc, RwLock}, time::Instant};
+#[cfg(feature = "testing-hooks")]
+use std::{mem::take, thread::sleep, time::Duration};
+use libc::{c_void, close, fork, pid_t, pipe, read, strerror, write};
 use lzf::{compress, decompress};
-use crate::{server::{server_read, server_write, RedisDB}, util::{error, log, timestamp, LogLevel}};
-use super::{obj::{try_object_encoding, ListStorageType, RedisObject, SetStorageType, StringStorageType, ZSetStorageType}, skiplist::SkipList};
+use crate::{latency::latency_add_sample_if_needed, server::{server_read, server_write, RedisDB}, util::{error, log, timestamp, LogLevel}};
+use super::{obj::{try_object_encoding, HashStorageType, ListStorageType, ObjSet, RedisObject, SetStorageType, StringStorageType, ZSetStorageType}, skiplist::SkipList};
+
+// Encoded hash written by Redis 2.4 (RDB version 2). It's decoded on load,
+// rudis always saves hashes as the plain type 4.
+const REDIS_HASH_ZIPMAP: u8 = 9;
+// The other encoded types of RDB version 2, that can't be decoded.
+const REDIS_LIST_ZIPLIST: u8 = 10;
+const REDIS_SET_INTSET: u8 = 11;
+const REDIS_ZSET_ZIPLIST: u8 = 12;
 
 // Object types only used for dumping to disk
 static REDIS_EXPIRETIME: u8 = 253;
@@ -71,13 +81,15 @@ pub fn rdb_load(filename: &str) -> bool {
                 log(LogLevel::Warning, "Wrong signature trying to load DB from file");
                 return false;
             }
-            if &buf[5..] != b"0001" {
+            // Version 2 only adds the encoded types, see rdb_load_object()
+            if &buf[5..] != b"0001" && &buf[5..] != b"0002" {
                 log(LogLevel::Warning, &format!("Can't handle RDB format version {:?}", &buf[5..]));
                 return false;
             }
         },
         Err(e) => { eof_err(&e.to_string()); },
     }
+    let version = String::from_utf8_lossy(&buf[5..]).into_owned();
 
     let mut db: Option<Arc<RwLock<RedisDB>>> = None;
     loop {
@@ -120,6 +132,19 @@ pub fn rdb_load(filename: &str) -> bool {
             }
         }
 
+        // Only the zipmap of the version 2 encoded types is decoded
+        let encoding = match type_ {
+            REDIS_LIST_ZIPLIST => Some("ziplist encoded list"),
+            REDIS_SET_INTSET => Some("intset encoded set"),
+            REDIS_ZSET_ZIPLIST => Some("ziplist encoded sorted set"),
+            _ => None,
+        };
+        if let Some(encoding) = encoding {
+            log(LogLevel::Warning, &format!("Can't load type {} ({}) from RDB format version {}: only the zipmap encoding (type {}) is supported",
+                type_, encoding, version, REDIS_HASH_ZIPMAP));
+            return false;
+        }
+
         // Read key
         let mut key = String::new();
         match rdb_load_raw_string(&mut buf_reader) {
@@ -143,9 +168,9 @@ pub fn rdb_load(filename: &str) -> bool {
 
         // Set the expire time if needed
         if expire_time != -1 {
-            db.clone().unwrap().write().unwrap().expires.insert(key.clone(), expire_time as u64);
+            db.clone().unwrap().write().unwrap().expires.insert(key.clone(), expire_time as u64 * 1000);
             // Delete this key if already expired
-            if expire_time < timestamp().as_secs() as i128 {
+            if expire_time <= timestamp().as_secs() as i128 {
                 db.clone().unwrap().write().unwrap().dict.remove(&key);
                 db.clone().unwrap().write().unwrap().expires.remove(&key);
             }
@@ -156,6 +181,7 @@ pub fn rdb_load(filename: &str) -> bool {
 
 /// Load a Redis object of the specified type from the specified file.
 /// On success a newly allocated object is returned, otherwise NULL.
+#[allow(clippy::mutable_key_type)] // see ObjSet
 fn rdb_load_object(buf_r: &mut BufReader<File>, type_code: u8) -> Result<Arc<RwLock<RedisObject>>, Error> {
     if type_code == 0 {
         // String
@@ -169,16 +195,16 @@ fn rdb_load_object(buf_r: &mut BufReader<File>, type_code: u8) -> Result<Arc<RwL
             let s_obj = rdb_load_string_object(buf_r)?;
             list.push_back(s_obj);
         }
-        Ok(Arc::new(RwLock::new(RedisObject::List { l: ListStorageType::LinkedList(list) })))
+        Ok(Arc::new(RwLock::new(RedisObject::List { l: ListStorageType::LinkedList(list) }.tracked())))
     } else if type_code == 2 {
         // Set
         let (len, _) = rdb_load_len(buf_r)?;
-        let mut set = HashSet::with_capacity(len as usize);
+        let mut set = ObjSet::with_capacity(len as usize);
         for _ in 0..len {
             let s_obj = rdb_load_string_object(buf_r)?;
             set.insert(s_obj);
         }
-        Ok(Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(set) })))
+        Ok(Arc::new(RwLock::new(RedisObject::Set { s: SetStorageType::HashSet(set) }.tracked())))
     } else if type_code == 3 {
         // ZSet
         let (len, _) = rdb_load_len(buf_r)?;
@@ -190,7 +216,29 @@ fn rdb_load_object(buf_r: &mut BufReader<File>, type_code: u8) -> Result<Arc<RwL
             dict.insert(s_obj.clone(), score);
             zsl.insert(score, Arc::new(s_obj));
         }
-        Ok(Arc::new(RwLock::new(RedisObject::ZSet { zs: ZSetStorageType::SkipList(dict, zsl) })))
+        Ok(Arc::new(RwLock::new(RedisObject::ZSet { zs: ZSetStorageType::SkipList(dict, zsl) }.tracked())))
+    } else if type_code == 4 {
+        // Hash
+        let (len, _) = rdb_load_len(buf_r)?;
+        let mut hash = HashStorageType::new();
+        for _ in 0..len {
+            let field = rdb_load_raw_string(buf_r)?;
+            let value = rdb_load_string_object(buf_r)?;
+            hash.insert(&field, value);
+        }
+        Ok(Arc::new(RwLock::new(RedisObject::Hash { h: hash }.tracked())))
+    } else if type_code == REDIS_HASH_ZIPMAP {
+        // Hash dumped by Redis 2.4 as a serialized zipmap
+        let zm = rdb_load_raw_bytes(buf_r)?;
+        let mut hash = HashStorageType::new();
+        for (field, value) in zipmap_entries(&zm)? {
+            let value = RedisObject::String { ptr: StringStorageType::String(value) }.tracked();
+            hash.insert(&field, value);
+        }
+        if hash.is_empty() {
+            return Err(Error::other("empty zipmap"));
+        }
+        Ok(Arc::new(RwLock::new(RedisObject::Hash { h: hash }.tracked())))
     } else {
         Err(Error::new(ErrorKind::Other, "unsupported type"))
     }
@@ -271,18 +319,28 @@ fn rdb_load_len(buf_r: &mut BufReader<File>) -> Result<(u64, bool), Error> {
 
 fn rdb_load_string_object(buf_r: &mut BufReader<File>) -> Result<RedisObject, Error> {
     let s = rdb_load_raw_string(buf_r)?;
-    Ok(RedisObject::String { ptr: StringStorageType::String(s) })
+    Ok(RedisObject::String { ptr: StringStorageType::String(s) }.tracked())
 }
 
 fn rdb_load_raw_string(buf_r: &mut BufReader<File>) -> Result<String, Error> {
+    let buf = rdb_load_raw_bytes(buf_r)?;
+    match String::from_utf8(buf) {
+        Ok(s) => { Ok(s) },
+        Err(e) => { Err(Error::other(e.to_string())) },
+    }
+}
+
+/// Like rdb_load_raw_string() but for blobs that aren't text, such as the
+/// serialized zipmaps.
+fn rdb_load_raw_bytes(buf_r: &mut BufReader<File>) -> Result<Vec<u8>, Error> {
     let (len, is_encoded) = rdb_load_len(buf_r)?;
     if is_encoded {
         match len as u8 {
             REDIS_RDB_ENC_INT8 | REDIS_RDB_ENC_INT16 | REDIS_RDB_ENC_INT32 => {
-                return rdb_load_integer(buf_r, len as u8);
+                return Ok(rdb_load_integer(buf_r, len as u8)?.into_bytes());
             },
             REDIS_RDB_ENC_LZF => {
-                return rdb_load_lzf_raw_string(buf_r);
+                return rdb_load_lzf_raw_bytes(buf_r);
             },
             _ => { assert!(false, "impossible code"); },
         }
@@ -291,10 +349,7 @@ fn rdb_load_raw_string(buf_r: &mut BufReader<File>) -> Result<String, Error> {
     let mut buf: Vec<u8> = Vec::with_capacity(len as usize);
     for _ in 0..len { buf.push(0); }
     buf_r.read_exact(&mut buf)?;
-    match from_utf8(&buf) {
-        Ok(s) => { Ok(s.to_string()) },
-        Err(e) => { Err(Error::new(ErrorKind::Other, e.to_string())) },
-    }
+    Ok(buf)
 }
 
 fn rdb_load_integer(buf_r: &mut BufReader<File>, enc_type: u8) -> Result<String, Error> {
@@ -321,23 +376,72 @@ fn rdb_load_integer(buf_r: &mut BufReader<File>, enc_type: u8) -> Result<String,
     Ok(val.to_string())
 }
 
-fn rdb_load_lzf_raw_string(buf_r: &mut BufReader<File>) -> Result<String, Error> {
+fn rdb_load_lzf_raw_bytes(buf_r: &mut BufReader<File>) -> Result<Vec<u8>, Error> {
     let (clen, _) = rdb_load_len(buf_r)?;
     let (len, _) = rdb_load_len(buf_r)?;
     let mut buf: Vec<u8> = Vec::with_capacity(clen as usize);
     for _ in 0..clen { buf.push(0); }
     buf_r.read_exact(&mut buf)?;
     match decompress(&buf, len as usize) {
-        Ok(d) => {
-            match from_utf8(&d) {
-                Ok(s) => { Ok(s.to_string()) },
-                Err(e) => { Err(Error::new(ErrorKind::Other, e.to_string())) },
-            }
+        Ok(d) => { Ok(d) },
+        Err(e) => { Err(Error::other(e.to_string())) },
+    }
+}
+
+/// Decode a zipmap, the compact encoding Redis 2.x uses for small hashes
+/// and dumps as is with the type 9 since RDB version 2:
+///
+/// ```text
+/// <zmlen><len>"field"<len><free>"value"...<end>
+/// ```
+///
+/// zmlen is a one byte count (254 means unknown, count by walking), len is
+/// one byte if < 254, otherwise 254 followed by a 4 bytes length. free is
+/// the number of unused bytes after the value. The map ends with 255.
+fn zipmap_entries(zm: &[u8]) -> Result<Vec<(String, String)>, Error> {
+    let mut entries = Vec::new();
+    let mut pos = 1;
+    while let Some(field) = zipmap_next(zm, &mut pos, false)? {
+        let value = zipmap_next(zm, &mut pos, true)?.ok_or_else(zipmap_corrupted)?;
+        entries.push((field, value));
+    }
+    Ok(entries)
+}
+
+/// Read the zipmap string at `pos` and move past it, None at the end.
+fn zipmap_next(zm: &[u8], pos: &mut usize, with_free: bool) -> Result<Option<String>, Error> {
+    let len = match *zm.get(*pos).ok_or_else(zipmap_corrupted)? {
+        255 => { return Ok(None); },
+        254 => {
+            let bytes = zm.get(*pos + 1..*pos + 5).ok_or_else(zipmap_corrupted)?;
+            *pos += 5;
+            u32::from_le_bytes(bytes.try_into().unwrap()) as usize
+        },
+        l => {
+            *pos += 1;
+            l as usize
         },
+    };
+    let mut free = 0;
+    if with_free {
+        free = *zm.get(*pos).ok_or_else(zipmap_corrupted)? as usize;
+        *pos += 1;
+    }
+    let bytes = zm.get(*pos..*pos + len).ok_or_else(zipmap_corrupted)?;
+    *pos += len + free;
+    match from_utf8(bytes) {
+        Ok(s) => { Ok(Some(s.to_string())) },
         Err(e) => { Err(Error::new(ErrorKind::Other, e.to_string())) },
     }
 }
 
+fn zipmap_corrupted() -> Error {
+    Error::other("corrupted zipmap")
+}
+
+/// A key to save with its value and expire, in unix time in ms.
+type SavedEntry<'a> = (&'a String, &'a Arc<RwLock<RedisObject>>, Option<u64>);
+
 /// Save the DB on disk. Return false on error, true on success
 pub fn rdb_save(filename: &str) -> bool {
     let tmp_file = format!("temp-{}.rdb", id());
@@ -366,10 +470,19 @@ pub fn rdb_save(filename: &str) -> bool {
             Ok(_) => {},
             Err(e) => { return w_err(&e.to_string()); },
         }
+        let now = timestamp().as_millis() as u64;
+        let mut skipped_expired = 0;
         for i in 0..server_read().dbs.len() {
             let db = server_read().dbs[i].clone();
-            let dict = &db.read().unwrap().dict;
-            if dict.is_empty() {
+            let db_r = db.read().unwrap();
+            // Expired keys are not saved. A DB holding only expired keys is
+            // not selected at all.
+            let live: Vec<SavedEntry> = db_r.dict.iter()
+                .map(|(key, value)| (key, value, db_r.expires.get(key).copied()))
+                .filter(|(_, _, when)| when.is_none_or(|when| when > now))
+                .collect();
+            skipped_expired += db_r.dict.len() - live.len();
+            if live.is_empty() {
                 continue;
             }
 
@@ -384,41 +497,37 @@ pub fn rdb_save(filename: &str) -> bool {
             }
 
             // Iterate this DB writing every entry
-            let mut iter = dict.iter();
-            while let Some(entry) = iter.next() {
-                match db.read().unwrap().expires.get(entry.0) {
-                    Some(when) => {
-                        // Save the expire time
-                        if *when < timestamp().as_secs() {
-                            continue;
-                        }
-                        match rdb_save_type(&mut buf_writer, REDIS_EXPIRETIME) {
-                            Ok(_) => {},
-                            Err(e) => { return w_err(&e.to_string()); },
-                        }
-                        match rdb_save_time(&mut buf_writer, *when) {
-                            Ok(_) => {},
-                            Err(e) => { return w_err(&e.to_string()); },
-                        }
-                    },
-                    None => {},
+            for (key, value, when) in live {
+                if let Some(when) = when {
+                    // Save the expire time
+                    match rdb_save_type(&mut buf_writer, REDIS_EXPIRETIME) {
+                        Ok(_) => {},
+                        Err(e) => { return w_err(&e.to_string()); },
+                    }
+                    // The file keeps seconds: round up so the key never expires
+                    // earlier once loaded.
+                    match rdb_save_time(&mut buf_writer, when.div_ceil(1000)) {
+                        Ok(_) => {},
+                        Err(e) => { return w_err(&e.to_string()); },
+                    }
                 }
 
                 // Save type, key, value
-                match rdb_save_type(&mut buf_writer, entry.1.read().unwrap().type_code()) {
+                match rdb_save_type(&mut buf_writer, value.read().unwrap().type_code()) {
                     Ok(_) => {},
                     Err(e) => { return w_err(&e.to_string()); },
                 }
-                match rdb_save_raw_string(&mut buf_writer, entry.0) {
+                match rdb_save_raw_string(&mut buf_writer, key) {
                     Ok(_) => {},
                     Err(e) => { return w_err(&e.to_string()); },
                 }
-                match rdb_save_object(&mut buf_writer, entry.1.clone()) {
+                match rdb_save_object(&mut buf_writer, value.clone()) {
                     Ok(_) => {},
                     Err(e) => { return w_err(&e.to_string()); },
                 }
             }
         }
+        server_write().rdb_last_save_skipped_expired = skipped_expired;
         // EOF opcode
         match rdb_save_type(&mut buf_writer, REDIS_EOF) {
             Ok(_) => {},
@@ -443,7 +552,11 @@ pub fn rdb_save(filename: &str) -> bool {
         Err(e) => { return w_err(&e.to_string()); },
     }
     log(LogLevel::Notice, "DB saved on disk");
-    server_write().dirty += 1;
+    let skipped_expired = server_read().rdb_last_save_skipped_expired;
+    if skipped_expired > 0 {
+        log(LogLevel::Notice, &format!("{} expired keys were not saved", skipped_expired));
+    }
+    server_write().dirty = 0;
     server_write().last_save = timestamp().as_secs();
     true
 }
@@ -453,29 +566,75 @@ pub fn rdb_save_background(filename: &str) -> bool {
         return false;
     }
 
+    #[cfg(feature = "testing-hooks")]
+    let (sleep_after_fork, fail_bgsave) = {
+        let mut server = server_write();
+        (take(&mut server.debug_faults.sleep_after_fork), take(&mut server.debug_faults.fail_next_bgsave))
+    };
+
     unsafe {
+        // The child reports on a pipe what the parent can't see, i.e. the
+        // number of expired keys skipped. Saving works without it.
+        let mut info = [-1; 2];
+        if pipe(info.as_mut_ptr()) == -1 {
+            log(LogLevel::Warning, &format!("Can't create the pipe for the background save info: {}", *strerror(error())));
+            info = [-1; 2];
+        }
+        let start = Instant::now();
         let child_pid: pid_t = fork();
         if child_pid == 0 {
             // child
             close(server_read().fd);
+            close(info[0]);
+            #[cfg(feature = "testing-hooks")]
+            {
+                sleep(Duration::from_secs(sleep_after_fork));
+                if fail_bgsave {
+                    exit(1);
+                }
+            }
             if rdb_save(filename) {
+                let skipped_expired = (server_read().rdb_last_save_skipped_expired as u64).to_le_bytes();
+                write(info[1], skipped_expired.as_ptr() as *const c_void, skipped_expired.len());
                 exit(0);
             } else {
                 exit(1);
             }
         } else {
             // parent
+            latency_add_sample_if_needed("fork", start.elapsed().as_millis() as u64);
+            log(LogLevel::Debug, &format!("fork for the background save took {} us", start.elapsed().as_micros()));
+            close(info[1]);
             if child_pid == -1 {
                 log(LogLevel::Warning, &format!("Can't save in background: fork: {}", *strerror(error())));
+                close(info[0]);
                 return false;
             }
             log(LogLevel::Notice, &format!("Background saving started by pid {}", child_pid));
             server_write().bg_save_child_pid = child_pid;
+            server_write().bg_save_info_fd = info[0];
             return true;
         }
     }
 }
 
+/// Collect what the background save child reported, once it's done. The
+/// pipe is closed either way.
+pub fn rdb_read_background_save_info(success: bool) {
+    let fd = std::mem::replace(&mut server_write().bg_save_info_fd, -1);
+    if fd == -1 {
+        return;
+    }
+    let mut buf = [0u8; 8];
+    unsafe {
+        // The child is gone: its report is in the pipe, or never will be
+        if success && read(fd, buf.as_mut_ptr() as *mut c_void, buf.len()) == buf.len() as isize {
+            server_write().rdb_last_save_skipped_expired = u64::from_le_bytes(buf) as usize;
+        }
+        close(fd);
+    }
+}
+
 fn rdb_save_type(buf_w: &mut BufWriter<File>, type_: u8) -> Result<(), Error> {
     buf_w.write(&[type_])?;
     Ok(())
@@ -623,6 +782,14 @@ fn rdb_save_object(buf_w: &mut BufWriter<File>, obj: Arc<RwLock<RedisObject>>) -
             rdb_save_string_object(buf_w, ele.0.string().unwrap())?;
             rdb_save_f64(buf_w, *ele.1)?;
         }
+    } else if obj.read().unwrap().is_hash() {
+        let obj_r = obj.read().unwrap();
+        let hash = obj_r.hash().unwrap();
+        rdb_save_len(buf_w, hash.len())?;
+        for (field, value) in hash.iter() {
+            rdb_save_raw_string(buf_w, field)?;
+            rdb_save_string_object(buf_w, value.string().unwrap())?;
+        }
     } else {
         assert!(false, "impossible code");
     }
@@ -672,7 +839,9 @@ pub fn rdb_remove_temp_file(child_pid: pid_t) {
 
 #[cfg(test)]
 mod tests {
-    use std::str::from_utf8;
+    use std::{fs::{read, remove_file, write}, process::id, str::from_utf8};
+    use crate::{client::tests::{run, test_client}, server::{server_read, tests::{parse_info, setup}}, util::{capture_log, timestamp}};
+    use super::{rdb_load, rdb_save, zipmap_entries, REDIS_SELECTDB};
 
 
     #[test]
@@ -694,4 +863,212 @@ mod tests {
             Err(_) => todo!(),
         }
     }
+
+    #[test]
+    fn hashes_survive_a_save_and_a_load() {
+        let _guard = setup();
+        let mut c = test_client();
+        let file = format!("test-hash-{}.rdb", id());
+        run(&mut c, &["HSET", "h", "name", "value"]);
+        run(&mut c, &["HINCRBY", "h", "counter", "42"]);
+        assert!(rdb_save(&file));
+
+        run(&mut c, &["FLUSHALL"]);
+        assert!(rdb_load(&file));
+        remove_file(&file).unwrap();
+        assert_eq!(run(&mut c, &["TYPE", "h"]), "+hash\r\n");
+        assert_eq!(run(&mut c, &["HLEN", "h"]), ":2\r\n");
+        assert_eq!(run(&mut c, &["HGET", "h", "name"]), "$5\r\nvalue\r\n");
+        assert_eq!(run(&mut c, &["HINCRBY", "h", "counter", "1"]), ":43\r\n");
+    }
+
+    #[test]
+    fn nul_bytes_survive_a_save_and_a_load() {
+        let _guard = setup();
+        let mut c = test_client();
+        let file = format!("test-nul-{}.rdb", id());
+        let long = "\0".repeat(100) + "x" + &"\0".repeat(100);
+        run(&mut c, &["SET", "k", "\0\0\0\0\0x"]);
+        run(&mut c, &["SET", "long", &long]);
+        run(&mut c, &["APPEND", "tail", "x"]);
+        run(&mut c, &["APPEND", "tail", "\0"]);
+        run(&mut c, &["SETRANGE", "padded", "5", "x"]);
+        assert!(rdb_save(&file));
+
+        run(&mut c, &["FLUSHALL"]);
+        assert!(rdb_load(&file));
+        remove_file(&file).unwrap();
+        assert_eq!(run(&mut c, &["STRLEN", "k"]), ":6\r\n");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$6\r\n\0\0\0\0\0x\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "k", "4", "-1"]), "$2\r\n\0x\r\n");
+        assert_eq!(run(&mut c, &["GET", "long"]), format!("$201\r\n{}\r\n", long));
+        assert_eq!(run(&mut c, &["GET", "tail"]), "$2\r\nx\0\r\n");
+        assert_eq!(run(&mut c, &["GET", "padded"]), "$6\r\n\0\0\0\0\0x\r\n");
+        assert_eq!(run(&mut c, &["GETRANGE", "padded", "0", "4"]), "$5\r\n\0\0\0\0\0\r\n");
+    }
+
+    #[test]
+    fn expired_keys_are_skipped_and_counted() {
+        let _guard = setup();
+        let mut c = test_client();
+        let file = format!("test-expired-{}.rdb", id());
+        let past = timestamp().as_millis() as u64 - 1000;
+        run(&mut c, &["SET", "live", "v"]);
+        run(&mut c, &["SET", "gone", "v"]);
+        c.set_expire("gone", past);
+        // Every key of DB 1 is expired
+        run(&mut c, &["SELECT", "1"]);
+        for key in ["a", "b"] {
+            run(&mut c, &["SET", key, "v"]);
+            c.set_expire(key, past);
+        }
+        assert!(rdb_save(&file));
+
+        let dump = read(&file).unwrap();
+        assert!(dump.windows(2).any(|w| w == [REDIS_SELECTDB, 0]));
+        assert!(!dump.windows(2).any(|w| w == [REDIS_SELECTDB, 1]));
+        assert_eq!(server_read().rdb_last_save_skipped_expired, 3);
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["rdb_last_save_skipped_expired"], "3");
+
+        run(&mut c, &["FLUSHALL"]);
+        assert!(rdb_load(&file));
+        remove_file(&file).unwrap();
+        assert_eq!(run(&mut c, &["DBSIZE"]), ":0\r\n");
+        run(&mut c, &["SELECT", "0"]);
+        assert_eq!(run(&mut c, &["KEYS", "*"]), "*1\r\n$4\r\nlive\r\n");
+    }
+
+    #[test]
+    fn hashes_load_from_a_redis_2_4_dump() {
+        let _guard = setup();
+        let mut c = test_client();
+        run(&mut c, &["FLUSHALL"]);
+        // "plain" is a type 4 hash, "small" a type 9 zipmap.
+        assert!(rdb_load(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/hashes-2.4.rdb")));
+        run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "1"]);
+        assert_eq!(run(&mut c, &["HGETALL", "plain"]), "*4\r\n$1\r\na\r\n$1\r\n1\r\n$1\r\nb\r\n$3\r\ntwo\r\n");
+        assert_eq!(run(&mut c, &["HGETALL", "small"]), "*4\r\n$1\r\nn\r\n$2\r\n42\r\n$4\r\nname\r\n$5\r\nrudis\r\n");
+        run(&mut c, &["DEBUG", "SET-SORTED-REPLIES", "0"]);
+        assert_eq!(run(&mut c, &["HINCRBY", "small", "n", "1"]), ":43\r\n");
+    }
+
+    #[test]
+    fn zipmaps_are_decoded_or_refused() {
+        let long = "x".repeat(300);
+        let mut zm = vec![254u8, 1, b'f', 254];
+        zm.extend_from_slice(&(long.len() as u32).to_le_bytes());
+        zm.push(0);
+        zm.extend_from_slice(long.as_bytes());
+        zm.push(255);
+        assert_eq!(zipmap_entries(&zm).unwrap(), vec![("f".to_string(), long)]);
+
+        assert!(zipmap_entries(&[0, 255]).unwrap().is_empty());
+        // Truncated value, missing end, field without a value.
+        assert!(zipmap_entries(&[1, 1, b'f', 3, 0, b'v']).is_err());
+        assert!(zipmap_entries(&[1, 1, b'f', 1, 0, b'v']).is_err());
+        assert!(zipmap_entries(&[1, 1, b'f', 255]).is_err());
+    }
+
+    #[test]
+    fn other_version_2_encodings_are_refused() {
+        let _guard = setup();
+        let file = format!("test-intset-{}.rdb", id());
+        // A set of 1 and 2 as an intset of 16 bits integers
+        let mut dump = b"REDIS0002".to_vec();
+        dump.extend_from_slice(&[REDIS_SELECTDB, 0, 11, 1, b's', 12]);
+        dump.extend_from_slice(&[2, 0, 0, 0, 2, 0, 0, 0, 1, 0, 2, 0]);
+        dump.push(255);
+        write(&file, &dump).unwrap();
+
+        let mut loaded = true;
+        let logged = capture_log(|| loaded = rdb_load(&file));
+        remove_file(&file).unwrap();
+        assert!(!loaded);
+        assert!(logged.contains("Can't load type 11 (intset encoded set) from RDB format version 0002"), "{}", logged);
+        assert!(server_read().dbs[0].read().unwrap().dict.is_empty());
+    }
+
+    /// Persistence scheduling tests: they rely on the one-shot faults armed
+    /// through DEBUG to control how long the child takes and how it ends.
+    #[cfg(feature = "testing-hooks")]
+    mod scheduling {
+        use std::{fs::remove_file, path::Path, process::id, thread::sleep, time::{Duration, Instant}};
+        use crate::{client::tests::{run, test_client}, handler::server_cron, server::{server_read, server_write, tests::setup}, util::timestamp};
+
+        /// Run the cron until the background save child is reaped.
+        fn wait_bgsave_done() {
+            let start = Instant::now();
+            while server_read().bg_save_child_pid != -1 {
+                assert!(start.elapsed() < Duration::from_secs(10), "BGSAVE child never terminated");
+                server_cron(0, None);
+                sleep(Duration::from_millis(20));
+            }
+        }
+
+        fn with_db_file(name: &str, test: impl FnOnce(&str)) {
+            let file = format!("test-{}-{}.rdb", name, id());
+            server_write().db_filename = file.clone();
+            test(&file);
+            let _ = remove_file(&file);
+            server_write().db_filename = "dump.rdb".to_string();
+        }
+
+        #[test]
+        fn saves_are_refused_while_bgsave_child_runs() {
+            let _guard = setup();
+            with_db_file("inprogress", |file| {
+                let mut c = test_client();
+                run(&mut c, &["SET", "k", "v"]);
+                assert_eq!(run(&mut c, &["DEBUG", "SLEEP-AFTER-FORK", "1"]), "+OK\r\n");
+                assert_eq!(run(&mut c, &["BGSAVE"]), "+Background saving started\r\n");
+
+                assert!(run(&mut c, &["BGSAVE"]).starts_with("-ERR background save already in progress"));
+                assert!(run(&mut c, &["SAVE"]).starts_with("-ERR background save in progress"));
+                assert!(!Path::new(file).exists());
+
+                wait_bgsave_done();
+                assert!(Path::new(file).exists());
+                assert_eq!(server_read().dirty, 0);
+            });
+        }
+
+        #[test]
+        fn failed_bgsave_keeps_changes_dirty() {
+            let _guard = setup();
+            with_db_file("fail", |file| {
+                let mut c = test_client();
+                run(&mut c, &["SET", "k", "v"]);
+                let last_save = server_read().last_save;
+                assert_eq!(run(&mut c, &["DEBUG", "FAIL-NEXT-BGSAVE"]), "+OK\r\n");
+                assert_eq!(run(&mut c, &["BGSAVE"]), "+Background saving started\r\n");
+                wait_bgsave_done();
+                assert!(server_read().dirty > 0);
+                assert_eq!(server_read().last_save, last_save);
+                assert!(!Path::new(file).exists());
+
+                // The fault is one-shot: the next BGSAVE succeeds.
+                assert_eq!(run(&mut c, &["BGSAVE"]), "+Background saving started\r\n");
+                wait_bgsave_done();
+                assert_eq!(server_read().dirty, 0);
+                assert!(Path::new(file).exists());
+            });
+        }
+
+        #[test]
+        fn bgsave_child_reports_skipped_expired_keys() {
+            let _guard = setup();
+            with_db_file("skipped", |_| {
+                let mut c = test_client();
+                run(&mut c, &["SET", "k", "v"]);
+                run(&mut c, &["SET", "gone", "v"]);
+                c.set_expire("gone", timestamp().as_millis() as u64 - 1000);
+                server_write().rdb_last_save_skipped_expired = 0;
+                assert_eq!(run(&mut c, &["BGSAVE"]), "+Background saving started\r\n");
+                wait_bgsave_done();
+                assert_eq!(server_read().rdb_last_save_skipped_expired, 1);
+                assert_eq!(server_read().bg_save_info_fd, -1);
+            });
+        }
+    }
 }
@@ -0,0 +1,539 @@
+//! Master - slave replication.
+//!
+//! A slave sends SYNC to its master, that saves the dataset in background
+//! and sends the dump as a bulk payload. From the start of the save on,
+//! every write command the master runs is queued to the slave as well, so
+//! once the dump is loaded the slave just has to run them, as a client it
+//! never replies to.
+
+use std::{fs::{remove_file, rename, File}, io::{BufRead, BufReader, Read, Write}, net::{TcpStream, ToSocketAddrs}, os::{fd::IntoRawFd, unix::fs::FileExt}, path::Path, process, sync::{Arc, RwLock}, time::Duration};
+use libc::{c_void, strerror, write, EAGAIN};
+use crate::{client::{clients_read, ClientFlags, RedisClient}, cmd::RedisCommand, errors, eventloop::{create_file_event, delete_file_event, Mask}, handler::send_reply_to_client, obj::RedisObject, rdb::{rdb_load, rdb_save_background}, resp::Value, server::{server_read, server_write, ReplState}, util::{error, log, timestamp, LogLevel}};
+
+/// Seconds the slave waits for its master at every step of the sync, but
+/// the first line of the dump: the master has to save it before.
+const REPL_SYNCIO_TIMEOUT: u64 = 5;
+const REPL_BULK_TIMEOUT: u64 = 3600;
+/// Bytes of the dump sent to a slave per writable event.
+const REPL_BULK_CHUNK: usize = 16 * 1024;
+
+
+//
+// Master side.
+//
+
+
+/// SYNC
+pub fn sync_command(c: &mut RedisClient) {
+    // Ignore SYNC if it's already a slave
+    if c.flags.is_slave() {
+        return;
+    }
+
+    // Refuse SYNC requests if we are a slave but the link with our master
+    // is not ok: we have no dataset to send.
+    let link_down = {
+        let server = server_read();
+        server.is_slave() && server.repl_state != ReplState::Connected
+    };
+    if link_down {
+        c.add_reply_error(&errors::err("Can't SYNC while not connected with my master"));
+        return;
+    }
+
+    // SYNC can't be issued when the server has pending data to send to
+    // the client about already issued commands: it would be mixed with
+    // the dump.
+    if c.has_reply() {
+        c.add_reply_error(&errors::err("SYNC is invalid with pending input"));
+        return;
+    }
+
+    log(LogLevel::Notice, "Slave ask for synchronization");
+    // Here we need to check if there is a background saving operation
+    // in progress, or if it is required to start one
+    if server_read().bg_save_child_pid() != -1 {
+        // A BGSAVE is in progress. Can we use it? Only if another slave
+        // waits for it: what it got since the save started is what this
+        // one needs as well. The state is set first, so that copying the
+        // output doesn't install the write handler.
+        c.repl_state = ReplState::WaitBgSaveEnd;
+        if copy_output_of_slave_waiting_bgsave(c) {
+            log(LogLevel::Notice, "Waiting for end of BGSAVE for SYNC");
+        } else {
+            // No way, we need to wait for the next BGSAVE in order to
+            // register differences
+            c.repl_state = ReplState::WaitBgSaveStart;
+            log(LogLevel::Notice, "Waiting for next BGSAVE for SYNC");
+        }
+    } else {
+        // Ok we don't have a BGSAVE in progress, let's start one
+        log(LogLevel::Notice, "Starting BGSAVE for SYNC");
+        let filename = server_read().db_filename().to_string();
+        if !rdb_save_background(&filename) {
+            log(LogLevel::Notice, "Replication failed, can't BGSAVE");
+            c.add_reply_error(&errors::err("Unable to perform background save"));
+            return;
+        }
+        c.repl_state = ReplState::WaitBgSaveEnd;
+    }
+
+    c.repl_db = None;
+    // No DB selected yet: the first command goes after a SELECT
+    c.slave_sel_db = -1;
+    c.slave_addr = c.addr();
+    c.flags.enable(ClientFlags::slave());
+    server_write().slaves.push_back(c.id());
+}
+
+/// Give `c` the output of a slave waiting for the end of the BGSAVE in
+/// progress. False if there is no such slave.
+fn copy_output_of_slave_waiting_bgsave(c: &mut RedisClient) -> bool {
+    let slaves = server_read().slaves().clone();
+    for other in clients_read().iter() {
+        let Ok(other) = other.try_read() else { continue; };
+        if slaves.contains(&other.id()) && other.repl_state == ReplState::WaitBgSaveEnd {
+            if !other.buf().is_empty() {
+                c.add_reply_bytes(other.buf());
+            }
+            for obj in other.reply.read().unwrap().iter() {
+                c.add_reply_shared(obj.clone());
+            }
+            c.slave_sel_db = other.slave_sel_db;
+            return true;
+        }
+    }
+    false
+}
+
+/// Queue a write command to the slaves, but the ones waiting for the next
+/// BGSAVE to start: its dump will have the change. A SELECT goes first if
+/// the slave got the last command for another DB.
+pub fn replication_feed_slaves(cmd: &RedisCommand, db_id: i32, argv: &[Arc<RwLock<RedisObject>>]) {
+    let slaves = server_read().slaves().clone();
+    // Sent by its own name, so that the slave runs it whatever the renames
+    let mut args = vec![Value::bulk(cmd.name())];
+    args.extend(argv[1..].iter()
+        .map(|arg| Value::Bulk(Some(arg.read().unwrap().get_decoded().string().unwrap().as_bytes().to_vec()))));
+    let command = Value::Array(Some(args)).to_bytes();
+    let select = Value::Array(Some(vec![Value::bulk("SELECT"), Value::bulk(&db_id.to_string())])).to_bytes();
+
+    for slave in clients_read().iter() {
+        let Ok(mut slave) = slave.try_write() else { continue; };
+        if !slaves.contains(&slave.id()) || slave.repl_state == ReplState::WaitBgSaveStart {
+            continue;
+        }
+        if slave.slave_sel_db != db_id {
+            slave.add_reply_bytes(&select);
+            slave.slave_sel_db = db_id;
+        }
+        slave.add_reply_bytes(&command);
+    }
+}
+
+/// A BGSAVE terminated: the slaves waiting for its end get the dump, the
+/// ones waiting for the next one to start get it started.
+pub fn update_slaves_waiting_bgsave(success: bool) {
+    let slaves = server_read().slaves().clone();
+    let filename = server_read().db_filename().to_string();
+    let mut waiting_next = Vec::new();
+    for slave in clients_read().iter() {
+        let Ok(mut slave) = slave.try_write() else { continue; };
+        if !slaves.contains(&slave.id()) {
+            continue;
+        }
+        match slave.repl_state {
+            ReplState::WaitBgSaveStart => {
+                // It has to wait the end of the BGSAVE started below
+                slave.repl_state = ReplState::WaitBgSaveEnd;
+                waiting_next.push(slave.id());
+            },
+            ReplState::WaitBgSaveEnd => {
+                if !success {
+                    log(LogLevel::Warning, "SYNC failed. BGSAVE child returned an error");
+                    slave.free_client_async();
+                    continue;
+                }
+                let db = File::open(&filename).and_then(|f| f.metadata().map(|m| (f, m.len())));
+                let (db, size) = match db {
+                    Ok(db) => db,
+                    Err(e) => {
+                        log(LogLevel::Warning, &format!("SYNC failed. Can't open/stat DB after BGSAVE: {}", e));
+                        slave.free_client_async();
+                        continue;
+                    },
+                };
+                slave.repl_db = Some(db);
+                slave.repl_db_off = 0;
+                slave.repl_db_size = size;
+                slave.repl_state = ReplState::SendBulk;
+                delete_file_event(slave.fd(), Mask::Writable);
+                if let Err(e) = create_file_event(slave.fd(), Mask::Writable, Arc::new(send_bulk_to_slave)) {
+                    log(LogLevel::Warning, &format!("SYNC failed. Can't install the bulk write handler: {}", e));
+                    slave.free_client_async();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if !waiting_next.is_empty() && !rdb_save_background(&filename) {
+        log(LogLevel::Warning, "SYNC failed. BGSAVE failed");
+        for slave in clients_read().iter() {
+            let Ok(mut slave) = slave.try_write() else { continue; };
+            if waiting_next.contains(&slave.id()) {
+                slave.free_client_async();
+            }
+        }
+    }
+}
+
+/// File Event handler: send the dump to a slave, as a bulk payload. Once
+/// it's sent, the commands queued meanwhile follow.
+pub fn send_bulk_to_slave(fd: i32, _mask: Mask) {
+    let clients = clients_read();
+    let Some(slave) = clients.iter().find(|e| e.read().unwrap().fd() == fd) else { return; };
+    let mut slave = slave.write().unwrap();
+
+    // The "$<size>\r\n" preamble comes first, the offset counts it as well
+    let preamble = format!("${}\r\n", slave.repl_db_size);
+    let off = slave.repl_db_off as usize;
+    let mut buf = vec![0u8; REPL_BULK_CHUNK];
+    let len = if off < preamble.len() {
+        let len = preamble.len() - off;
+        buf[..len].copy_from_slice(&preamble.as_bytes()[off..]);
+        len
+    } else {
+        let read = slave.repl_db.as_ref().map(|db| db.read_at(&mut buf, (off - preamble.len()) as u64));
+        match read {
+            Some(Ok(len)) if len > 0 => len,
+            Some(Ok(_)) => {
+                log(LogLevel::Warning, "Premature EOF reading the DB file to send to the slave");
+                slave.free_client_async();
+                return;
+            },
+            Some(Err(e)) => {
+                log(LogLevel::Warning, &format!("Read error sending DB to slave: {}", e));
+                slave.free_client_async();
+                return;
+            },
+            None => { return; },
+        }
+    };
+
+    let written = unsafe { write(fd, buf.as_ptr() as *const c_void, len) };
+    if written == -1 {
+        if error() != EAGAIN {
+            log(LogLevel::Verbose, &format!("Write error sending DB to slave: {}", unsafe { *strerror(error()) }));
+            slave.free_client_async();
+        }
+        return;
+    }
+    slave.repl_db_off += written as u64;
+    if slave.repl_db_off == preamble.len() as u64 + slave.repl_db_size {
+        slave.repl_db = None;
+        delete_file_event(fd, Mask::Writable);
+        slave.repl_state = ReplState::Online;
+        if slave.has_reply() {
+            if let Err(e) = create_file_event(fd, Mask::Writable, Arc::new(send_reply_to_client)) {
+                log(LogLevel::Warning, &format!("Can't install the write handler of the slave: {}", e));
+                slave.free_client_async();
+                return;
+            }
+        }
+        log(LogLevel::Notice, "Synchronization with slave succeeded");
+    }
+}
+
+
+//
+// Slave side.
+//
+
+
+/// Called once per second by the server cron: connect to our master if
+/// we have to.
+pub fn replication_cron() {
+    if server_read().repl_state == ReplState::Connect {
+        log(LogLevel::Notice, "Connecting to MASTER...");
+        match sync_with_master() {
+            Ok(()) => { log(LogLevel::Notice, "MASTER <-> SLAVE sync succeeded"); },
+            Err(e) => { log(LogLevel::Warning, &e); },
+        }
+    }
+}
+
+/// Connect to our master, send SYNC and load the dump it replies with. The
+/// connection then becomes the master client, the commands it sends are
+/// run like the ones of any other client. The server waits for every step,
+/// as the dataset can't be served meanwhile anyway.
+pub fn sync_with_master() -> Result<(), String> {
+    let (host, port, auth, filename) = {
+        let server = server_read();
+        (server.master_host.clone(), server.master_port, server.master_auth.clone(), server.db_filename().to_string())
+    };
+    let syncio_timeout = Duration::from_secs(REPL_SYNCIO_TIMEOUT);
+    let timeout = Some(syncio_timeout);
+    let addr = (host.as_str(), port).to_socket_addrs().ok().and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("Unable to connect to MASTER: can't resolve {}", host))?;
+    let stream = TcpStream::connect_timeout(&addr, syncio_timeout)
+        .map_err(|e| format!("Unable to connect to MASTER: {}", e))?;
+    let io_err = |e: std::io::Error| format!("I/O error trying to sync with MASTER: {}", e);
+    stream.set_read_timeout(timeout).map_err(io_err)?;
+    stream.set_write_timeout(timeout).map_err(io_err)?;
+    let mut reader = BufReader::new(stream.try_clone().map_err(io_err)?);
+    let mut writer = &stream;
+
+    // AUTH with the master if required
+    if !auth.is_empty() {
+        let cmd = Value::Array(Some(vec![Value::bulk("AUTH"), Value::bulk(&auth)]));
+        writer.write_all(&cmd.to_bytes()).map_err(io_err)?;
+        let mut line = String::new();
+        reader.read_line(&mut line).map_err(io_err)?;
+        if line.starts_with('-') {
+            return Err(format!("Unable to AUTH to MASTER: {}", line.trim_end()));
+        }
+    }
+
+    // Issue the SYNC command, the master replies once the dump is saved
+    writer.write_all(b"SYNC\r\n").map_err(io_err)?;
+    stream.set_read_timeout(Some(Duration::from_secs(REPL_BULK_TIMEOUT))).map_err(io_err)?;
+    let mut line = String::new();
+    reader.read_line(&mut line).map_err(io_err)?;
+    stream.set_read_timeout(timeout).map_err(io_err)?;
+    let line = line.trim_end();
+    if let Some(e) = line.strip_prefix('-') {
+        return Err(format!("MASTER aborted replication with an error: {}", e));
+    }
+    let size: u64 = line.strip_prefix('$').and_then(|len| len.parse().ok())
+        .ok_or("Bad protocol from MASTER, the first byte is not '$', are you sure the host and port are right?")?;
+    log(LogLevel::Notice, &format!("Receiving {} bytes data dump from MASTER", size));
+
+    // Read the bulk data in a temp file, then rename it over the DB file
+    let tmp = Path::new(&filename).with_file_name(format!("temp-{}.{}.rdb", timestamp().as_secs(), process::id()));
+    let received = File::create(&tmp)
+        .and_then(|mut f| std::io::copy(&mut (&mut reader).take(size), &mut f).and_then(|n| f.sync_all().map(|_| n)));
+    match received {
+        Ok(n) if n == size => {},
+        Ok(_) => {
+            let _ = remove_file(&tmp);
+            return Err("I/O error trying to sync with MASTER: connection lost".to_string());
+        },
+        Err(e) => {
+            let _ = remove_file(&tmp);
+            return Err(io_err(e));
+        },
+    }
+    if let Err(e) = rename(&tmp, &filename) {
+        let _ = remove_file(&tmp);
+        return Err(format!("Failed trying to rename the temp DB into {} in MASTER <-> SLAVE synchronization: {}", filename, e));
+    }
+
+    server_write().clear();
+    if !rdb_load(&filename) {
+        return Err("Failed trying to load the MASTER synchronization DB from disk".to_string());
+    }
+
+    // The commands the master sent right after the dump may be buffered.
+    // The query buffer only holds UTF-8: a character cut by the end of the
+    // buffer is completed from the socket, anything else fails the sync.
+    let mut pending = reader.buffer().to_vec();
+    drop(reader);
+    let pending = loop {
+        match String::from_utf8(pending) {
+            Ok(s) => break s,
+            Err(e) if e.utf8_error().error_len().is_none() => {
+                pending = e.into_bytes();
+                let mut byte = [0u8; 1];
+                (&stream).read_exact(&mut byte).map_err(io_err)?;
+                pending.push(byte[0]);
+            },
+            Err(_) => return Err("Bad protocol from MASTER, the commands after the dump are not valid UTF-8".to_string()),
+        }
+    };
+    stream.set_read_timeout(None).map_err(io_err)?;
+    stream.set_write_timeout(None).map_err(io_err)?;
+    let master = RedisClient::create(stream.into_raw_fd())?;
+    let mut master = master.write().unwrap();
+    master.flags.enable(ClientFlags::master());
+    master.authenticated = true;
+    {
+        let mut server = server_write();
+        server.master = Some(master.id());
+        server.repl_state = ReplState::Connected;
+    }
+    if !pending.is_empty() {
+        master.query_buf.push_str(&pending);
+        master.process_input_buf();
+    }
+    Ok(())
+}
+
+/// Drop the link with our master, if any, e.g. on SLAVEOF. The caller
+/// updates the replication state.
+pub fn close_master_link() {
+    let Some(id) = server_read().master else { return; };
+    for c in clients_read().iter() {
+        let Ok(mut c) = c.try_write() else { continue; };
+        if c.id() == id {
+            c.free_client_async();
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::{fs::{read, remove_file}, io::{Read, Write}, net::TcpListener, process::id, sync::mpsc, thread, time::{Duration, Instant}};
+    use crate::{client::{clients_read, tests::{run, test_client}, RedisClient}, eventloop::Mask, handler::{before_sleep, read_query_from_client, send_reply_to_client}, rdb::rdb_save, server::{server_read, server_write, tests::{parse_info, setup}, ReplState}};
+    use super::replication_cron;
+
+    /// Run the command until it replies `expected`, reading what the
+    /// master sent in between.
+    fn wait_for(c: &mut RedisClient, master_fd: i32, args: &[&str], expected: &str) {
+        let start = Instant::now();
+        while run(c, args) != expected {
+            assert!(start.elapsed() < Duration::from_secs(5), "{:?} never replied {:?}", args, expected);
+            read_query_from_client(master_fd, Mask::Readable);
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+
+    #[test]
+    fn slave_loads_the_dump_and_runs_what_the_master_sends() {
+        let _guard = setup();
+        let file = format!("test-sync-{}.rdb", id());
+        server_write().db_filename = file.clone();
+        let mut c = test_client();
+
+        // The dataset of the master
+        let dump_file = format!("test-sync-master-{}.rdb", id());
+        run(&mut c, &["SET", "k", "from-dump"]);
+        run(&mut c, &["SELECT", "1"]);
+        run(&mut c, &["RPUSH", "l", "a"]);
+        run(&mut c, &["SELECT", "0"]);
+        assert!(rdb_save(&dump_file));
+        let dump = read(&dump_file).unwrap();
+        remove_file(&dump_file).unwrap();
+        server_write().clear();
+        run(&mut c, &["SET", "stale", "v"]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let (next, next_rx) = mpsc::channel();
+        let master = thread::spawn(move || {
+            let (mut slave, _) = listener.accept().unwrap();
+            slave.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+            let mut sync = [0u8; 6];
+            slave.read_exact(&mut sync).unwrap();
+            assert_eq!(&sync, b"SYNC\r\n");
+            slave.write_all(format!("${}\r\n", dump.len()).as_bytes()).unwrap();
+            slave.write_all(&dump).unwrap();
+            slave.write_all("*3\r\n$3\r\nSET\r\n$1\r\na\r\n$1\r\n1\r\n*3\r\n$3\r\nSET\r\n$1\r\nu\r\n$5\r\n\u{e9}t\u{e9}\r\n".as_bytes()).unwrap();
+            next_rx.recv().unwrap();
+            slave.write_all(b"*2\r\n$4\r\nINCR\r\n$1\r\na\r\n").unwrap();
+            // Nothing is replied to the master, until SLAVEOF NO ONE
+            // closes the link
+            slave.read(&mut [0u8; 64]).unwrap()
+        });
+
+        assert_eq!(run(&mut c, &["SLAVEOF", "127.0.0.1", &port.to_string()]), "+OK\r\n");
+        replication_cron();
+        assert!(server_read().repl_state == ReplState::Connected);
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["master_link_status"], "up");
+        assert_eq!(info["master_last_io_seconds_ago"], "0");
+        assert_eq!(run(&mut c, &["GET", "k"]), "$9\r\nfrom-dump\r\n");
+        assert_eq!(run(&mut c, &["GET", "stale"]), "$-1\r\n");
+        run(&mut c, &["SELECT", "1"]);
+        assert_eq!(run(&mut c, &["LRANGE", "l", "0", "-1"]), "*1\r\n$1\r\na\r\n");
+        run(&mut c, &["SELECT", "0"]);
+
+        let master_id = server_read().master.unwrap();
+        let master_fd = clients_read().iter().map(|m| m.read().unwrap()).find(|m| m.id() == master_id).unwrap().fd();
+        wait_for(&mut c, master_fd, &["GET", "a"], "$1\r\n1\r\n");
+        wait_for(&mut c, master_fd, &["GET", "u"], "$5\r\n\u{e9}t\u{e9}\r\n");
+        next.send(()).unwrap();
+        wait_for(&mut c, master_fd, &["GET", "a"], "$1\r\n2\r\n");
+        send_reply_to_client(master_fd, Mask::Writable);
+
+        assert_eq!(run(&mut c, &["SLAVEOF", "NO", "ONE"]), "+OK\r\n");
+        before_sleep();
+        assert_eq!(master.join().unwrap(), 0);
+        assert!(server_read().master.is_none());
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["role"], "master");
+        // The dataset is kept
+        assert_eq!(run(&mut c, &["GET", "a"]), "$1\r\n2\r\n");
+
+        let _ = remove_file(&file);
+        server_write().db_filename = "dump.rdb".to_string();
+    }
+
+    #[cfg(feature = "testing-hooks")]
+    #[test]
+    fn sync_sends_the_dump_then_the_write_commands() {
+        use std::{io::{BufRead, BufReader}, net::TcpStream, os::fd::IntoRawFd};
+        use crate::handler::server_cron;
+        use super::send_bulk_to_slave;
+
+        let _guard = setup();
+        let file = format!("test-sync-slave-{}.rdb", id());
+        server_write().db_filename = file.clone();
+        let mut c = test_client();
+        run(&mut c, &["SET", "k", "v"]);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let slave_port = peer.local_addr().unwrap().port();
+        let slave = RedisClient::create(listener.accept().unwrap().0.into_raw_fd()).unwrap();
+        let fd = slave.read().unwrap().fd();
+        assert_eq!(run(&mut slave.write().unwrap(), &["SYNC"]), "");
+        assert!(*slave.read().unwrap().repl_state() == ReplState::WaitBgSaveEnd);
+
+        // Written while the dump is saved: sent once it is
+        run(&mut c, &["SET", "k2", "v2"]);
+        let start = Instant::now();
+        while server_read().bg_save_child_pid != -1 {
+            assert!(start.elapsed() < Duration::from_secs(10), "BGSAVE child never terminated");
+            server_cron(0, None);
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(*slave.read().unwrap().repl_state() == ReplState::SendBulk);
+        while *slave.read().unwrap().repl_state() != ReplState::Online {
+            assert!(start.elapsed() < Duration::from_secs(10), "the dump was never sent");
+            send_bulk_to_slave(fd, Mask::Writable);
+        }
+        send_reply_to_client(fd, Mask::Writable);
+
+        let mut peer = BufReader::new(peer);
+        let mut line = String::new();
+        peer.read_line(&mut line).unwrap();
+        let mut dump = vec![0u8; line.trim_end()[1..].parse().unwrap()];
+        peer.read_exact(&mut dump).unwrap();
+        assert_eq!(dump, read(&file).unwrap());
+        let fed = "*2\r\n$6\r\nSELECT\r\n$1\r\n0\r\n*3\r\n$3\r\nset\r\n$2\r\nk2\r\n$2\r\nv2\r\n";
+        let mut buf = vec![0u8; fed.len()];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), fed);
+
+        // Online, the commands are sent as they run
+        run(&mut c, &["SET", "k3", "v3"]);
+        send_reply_to_client(fd, Mask::Writable);
+        let fed = "*3\r\n$3\r\nset\r\n$2\r\nk3\r\n$2\r\nv3\r\n";
+        let mut buf = vec![0u8; fed.len()];
+        peer.read_exact(&mut buf).unwrap();
+        assert_eq!(String::from_utf8(buf).unwrap(), fed);
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["connected_slaves"], "1");
+        assert_eq!(info["slave0"], format!("ip=127.0.0.1,port={},state=online,offset=0", slave_port));
+
+        slave.write().unwrap().free_client_async();
+        drop(slave);
+        before_sleep();
+        assert!(server_read().slaves().is_empty());
+        let _ = remove_file(&file);
+        server_write().db_filename = "dump.rdb".to_string();
+    }
+}
@@ -0,0 +1,388 @@
+//! The RESP protocol as values: a model of the replies, a parser that can
+//! be fed partial input and a serializer. The server uses the serializer
+//! for replies built as a whole (COMMAND DOCS, CLUSTER SLOTS), tools and
+//! tests use the parser to read replies. The RESP3 types are compiled in
+//! with the "resp3" feature.
+
+/// The longest bulk string accepted, as proto-max-bulk-len in Redis.
+pub const MAX_BULK_LEN: usize = 512 * 1024 * 1024;
+/// Arrays nested deeper than this are refused rather than parsed with an
+/// ever growing stack.
+pub const MAX_DEPTH: usize = 512;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    SimpleString(String),
+    Error(String),
+    Integer(i64),
+    /// None is the null bulk, "$-1".
+    Bulk(Option<Vec<u8>>),
+    /// None is the null array, "*-1".
+    Array(Option<Vec<Value>>),
+    #[cfg(feature = "resp3")]
+    Null,
+    #[cfg(feature = "resp3")]
+    Boolean(bool),
+    #[cfg(feature = "resp3")]
+    Double(f64),
+    #[cfg(feature = "resp3")]
+    BigNumber(String),
+    #[cfg(feature = "resp3")]
+    Map(Vec<(Value, Value)>),
+    #[cfg(feature = "resp3")]
+    Set(Vec<Value>),
+    #[cfg(feature = "resp3")]
+    Push(Vec<Value>),
+}
+
+impl Value {
+    pub fn bulk(s: &str) -> Value {
+        Value::Bulk(Some(s.as_bytes().to_vec()))
+    }
+
+    /// Append the protocol form of the value to `out`.
+    pub fn encode(&self, out: &mut Vec<u8>) {
+        let line = |out: &mut Vec<u8>, prefix: u8, s: &str| {
+            out.push(prefix);
+            out.extend_from_slice(s.as_bytes());
+            out.extend_from_slice(b"\r\n");
+        };
+        match self {
+            Value::SimpleString(s) => line(out, b'+', s),
+            Value::Error(s) => line(out, b'-', s),
+            Value::Integer(n) => line(out, b':', &n.to_string()),
+            Value::Bulk(None) => line(out, b'$', "-1"),
+            Value::Bulk(Some(b)) => {
+                line(out, b'$', &b.len().to_string());
+                out.extend_from_slice(b);
+                out.extend_from_slice(b"\r\n");
+            },
+            Value::Array(None) => line(out, b'*', "-1"),
+            Value::Array(Some(items)) => {
+                line(out, b'*', &items.len().to_string());
+                items.iter().for_each(|v| v.encode(out));
+            },
+            #[cfg(feature = "resp3")]
+            Value::Null => line(out, b'_', ""),
+            #[cfg(feature = "resp3")]
+            Value::Boolean(b) => line(out, b'#', if *b { "t" } else { "f" }),
+            #[cfg(feature = "resp3")]
+            Value::Double(d) => line(out, b',', &match d {
+                d if d.is_nan() => "nan".to_string(),
+                d if d.is_infinite() => if *d > 0.0 { "inf" } else { "-inf" }.to_string(),
+                d => d.to_string(),
+            }),
+            #[cfg(feature = "resp3")]
+            Value::BigNumber(n) => line(out, b'(', n),
+            #[cfg(feature = "resp3")]
+            Value::Map(pairs) => {
+                line(out, b'%', &pairs.len().to_string());
+                for (k, v) in pairs {
+                    k.encode(out);
+                    v.encode(out);
+                }
+            },
+            #[cfg(feature = "resp3")]
+            Value::Set(items) => {
+                line(out, b'~', &items.len().to_string());
+                items.iter().for_each(|v| v.encode(out));
+            },
+            #[cfg(feature = "resp3")]
+            Value::Push(items) => {
+                line(out, b'>', &items.len().to_string());
+                items.iter().for_each(|v| v.encode(out));
+            },
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode(&mut out);
+        out
+    }
+}
+
+/// Parse the value at the start of `buf`. Returns the value and the bytes
+/// it takes, None if `buf` holds only part of it, or an error if `buf`
+/// can't be the start of a valid value.
+pub fn parse(buf: &[u8]) -> Result<Option<(Value, usize)>, String> {
+    let mut pos = 0;
+    match parse_value(buf, &mut pos, 0)? {
+        Some(v) => Ok(Some((v, pos))),
+        None => Ok(None),
+    }
+}
+
+/// A parser for a stream of values: feed it the bytes as they arrive and
+/// take the values once complete. An incomplete value stays buffered and
+/// is parsed again when more input comes.
+#[derive(Default)]
+pub struct Parser {
+    buf: Vec<u8>,
+}
+
+impl Parser {
+    pub fn new() -> Parser {
+        Parser::default()
+    }
+
+    pub fn feed(&mut self, data: &[u8]) {
+        self.buf.extend_from_slice(data);
+    }
+
+    /// The next complete value, None if more input is needed. After an
+    /// error the stream can't be resynchronized: the parser is unusable.
+    pub fn next_value(&mut self) -> Result<Option<Value>, String> {
+        match parse(&self.buf)? {
+            Some((v, len)) => {
+                self.buf.drain(..len);
+                Ok(Some(v))
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Bytes fed but not returned as values yet.
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+}
+
+/// The line at `pos` without its CRLF, None if the CRLF isn't there yet.
+fn read_line<'a>(buf: &'a [u8], pos: &mut usize) -> Result<Option<&'a str>, String> {
+    let Some(len) = buf[*pos..].windows(2).position(|w| w == b"\r\n") else {
+        // A lone '\n' can't become a CRLF with more input
+        return match buf[*pos..].contains(&b'\n') {
+            true => Err("Protocol error: expected CRLF".to_string()),
+            false => Ok(None),
+        };
+    };
+    let line = std::str::from_utf8(&buf[*pos..*pos + len]).map_err(|_| "Protocol error: invalid UTF-8".to_string())?;
+    if line.contains('\n') {
+        return Err("Protocol error: expected CRLF".to_string());
+    }
+    *pos += len + 2;
+    Ok(Some(line))
+}
+
+/// An aggregate or bulk length: -1 for null, or 0 up to `max`.
+fn parse_len(line: &str, max: usize) -> Result<Option<usize>, String> {
+    match line.parse::<i64>() {
+        Ok(-1) => Ok(None),
+        Ok(n) if n >= 0 && n as u64 <= max as u64 => Ok(Some(n as usize)),
+        _ => Err(format!("Protocol error: invalid length '{}'", line)),
+    }
+}
+
+fn parse_items(buf: &[u8], pos: &mut usize, n: usize, depth: usize) -> Result<Option<Vec<Value>>, String> {
+    // The count comes from the peer: don't reserve memory on its word.
+    let mut items = Vec::with_capacity(n.min(1024));
+    for _ in 0..n {
+        match parse_value(buf, pos, depth + 1)? {
+            Some(v) => items.push(v),
+            None => return Ok(None),
+        }
+    }
+    Ok(Some(items))
+}
+
+fn parse_value(buf: &[u8], pos: &mut usize, depth: usize) -> Result<Option<Value>, String> {
+    if depth > MAX_DEPTH {
+        return Err("Protocol error: too deeply nested".to_string());
+    }
+    let Some(&prefix) = buf.get(*pos) else { return Ok(None); };
+    let start = *pos;
+    *pos += 1;
+    let Some(line) = read_line(buf, pos)? else {
+        *pos = start;
+        return Ok(None);
+    };
+    let value = match prefix {
+        b'+' => Value::SimpleString(line.to_string()),
+        b'-' => Value::Error(line.to_string()),
+        b':' => Value::Integer(line.parse().map_err(|_| format!("Protocol error: invalid integer '{}'", line))?),
+        b'$' => match parse_len(line, MAX_BULK_LEN)? {
+            None => Value::Bulk(None),
+            Some(len) => {
+                if buf.len() - *pos < len + 2 {
+                    *pos = start;
+                    return Ok(None);
+                }
+                if &buf[*pos + len..*pos + len + 2] != b"\r\n" {
+                    return Err("Protocol error: bulk not terminated by CRLF".to_string());
+                }
+                let bulk = buf[*pos..*pos + len].to_vec();
+                *pos += len + 2;
+                Value::Bulk(Some(bulk))
+            },
+        },
+        b'*' => match parse_len(line, usize::MAX)? {
+            None => Value::Array(None),
+            Some(n) => match parse_items(buf, pos, n, depth)? {
+                Some(items) => Value::Array(Some(items)),
+                None => {
+                    *pos = start;
+                    return Ok(None);
+                },
+            },
+        },
+        #[cfg(feature = "resp3")]
+        b'_' if line.is_empty() => Value::Null,
+        #[cfg(feature = "resp3")]
+        b'#' if line == "t" || line == "f" => Value::Boolean(line == "t"),
+        #[cfg(feature = "resp3")]
+        b',' => Value::Double(match line {
+            "inf" => f64::INFINITY,
+            "-inf" => f64::NEG_INFINITY,
+            _ => line.parse().map_err(|_| format!("Protocol error: invalid double '{}'", line))?,
+        }),
+        #[cfg(feature = "resp3")]
+        b'(' if !line.is_empty() && line.trim_start_matches('-').bytes().all(|b| b.is_ascii_digit()) => {
+            Value::BigNumber(line.to_string())
+        },
+        #[cfg(feature = "resp3")]
+        b'%' | b'~' | b'>' => {
+            let Some(n) = parse_len(line, usize::MAX)? else {
+                return Err(format!("Protocol error: invalid length '{}'", line));
+            };
+            let n_items = if prefix == b'%' { n.checked_mul(2).ok_or("Protocol error: invalid length")? } else { n };
+            let Some(items) = parse_items(buf, pos, n_items, depth)? else {
+                *pos = start;
+                return Ok(None);
+            };
+            match prefix {
+                b'%' => {
+                    let mut iter = items.into_iter();
+                    let mut pairs = Vec::with_capacity(n);
+                    while let (Some(k), Some(v)) = (iter.next(), iter.next()) {
+                        pairs.push((k, v));
+                    }
+                    Value::Map(pairs)
+                },
+                b'~' => Value::Set(items),
+                _ => Value::Push(items),
+            }
+        },
+        _ => return Err(format!("Protocol error: unexpected '{}'", prefix.escape_ascii())),
+    };
+    Ok(Some(value))
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+    use super::{parse, Parser, Value, MAX_DEPTH};
+
+    fn random_string(rng: &mut StdRng) -> String {
+        // Simple strings and errors can't hold CR or LF
+        (0..rng.gen_range(0..12)).map(|_| rng.gen_range(b' '..=b'~') as char).collect()
+    }
+
+    fn random_value(rng: &mut StdRng, depth: usize) -> Value {
+        // Only scalars below some depth, so that the values stay small
+        let kinds = match (depth >= 3, cfg!(feature = "resp3")) {
+            (true, _) => 5,
+            (false, false) => 6,
+            (false, true) => 13,
+        };
+        match rng.gen_range(0..kinds) {
+            0 => Value::SimpleString(random_string(rng)),
+            1 => Value::Error(random_string(rng)),
+            2 => Value::Integer(rng.gen()),
+            3 => Value::Bulk(None),
+            // Any byte, CRLF included
+            4 => Value::Bulk(Some((0..rng.gen_range(0..20)).map(|_| rng.gen()).collect())),
+            5 if rng.gen_ratio(1, 8) => Value::Array(None),
+            5 => Value::Array(Some((0..rng.gen_range(0..5)).map(|_| random_value(rng, depth + 1)).collect())),
+            #[cfg(feature = "resp3")]
+            6 => Value::Null,
+            #[cfg(feature = "resp3")]
+            7 => Value::Boolean(rng.gen()),
+            #[cfg(feature = "resp3")]
+            8 => Value::Double(rng.gen_range(-1e9..1e9)),
+            #[cfg(feature = "resp3")]
+            9 => Value::BigNumber(rng.gen::<i64>().to_string() + "123456789012345678901234567890"),
+            #[cfg(feature = "resp3")]
+            10 => Value::Map((0..rng.gen_range(0..4)).map(|_| (random_value(rng, depth + 1), random_value(rng, depth + 1))).collect()),
+            #[cfg(feature = "resp3")]
+            11 => Value::Set((0..rng.gen_range(0..4)).map(|_| random_value(rng, depth + 1)).collect()),
+            #[cfg(feature = "resp3")]
+            12 => Value::Push((0..rng.gen_range(0..4)).map(|_| random_value(rng, depth + 1)).collect()),
+            _ => Value::Integer(0),
+        }
+    }
+
+    #[test]
+    fn serialize_then_parse_round_trips() {
+        let mut rng = StdRng::seed_from_u64(1);
+        for _ in 0..2000 {
+            let value = random_value(&mut rng, 0);
+            let bytes = value.to_bytes();
+            assert_eq!(parse(&bytes), Ok(Some((value.clone(), bytes.len()))), "{:?}", bytes.escape_ascii().to_string());
+
+            // Any strict prefix is incomplete, not an error
+            let cut = rng.gen_range(0..bytes.len());
+            assert_eq!(parse(&bytes[..cut]), Ok(None), "{:?}", bytes[..cut].escape_ascii().to_string());
+        }
+    }
+
+    #[test]
+    fn parser_resumes_on_partial_input() {
+        let values = [Value::SimpleString("OK".to_string()), Value::bulk("a\r\nb"),
+            Value::Array(Some(vec![Value::Integer(-3), Value::Bulk(None), Value::Array(None)])),
+            Value::Error("ERR x".to_string())];
+        let bytes: Vec<u8> = values.iter().flat_map(|v| v.to_bytes()).collect();
+        for chunk in 1..=bytes.len() {
+            let mut parser = Parser::new();
+            let mut got = Vec::new();
+            for part in bytes.chunks(chunk) {
+                parser.feed(part);
+                while let Some(v) = parser.next_value().unwrap() {
+                    got.push(v);
+                }
+            }
+            assert_eq!(got, values, "chunks of {}", chunk);
+            assert_eq!(parser.pending(), 0);
+        }
+    }
+
+    #[test]
+    fn malformed_input_is_an_error() {
+        let cases: &[&[u8]] = &[b"?x\r\n", b":12a\r\n", b"$-2\r\n", b"$3\r\nabcd\r\n", b"*-5\r\n",
+            b"+OK\n", b"+a\nb\r\n", b"$99999999999\r\n"];
+        for case in cases {
+            assert!(parse(case).is_err(), "{:?}", case.escape_ascii().to_string());
+        }
+        let deep = "*1\r\n".repeat(MAX_DEPTH + 2);
+        assert!(parse(deep.as_bytes()).is_err());
+    }
+
+    /// Random and mutated input must give a value, an error or None: never
+    /// a panic.
+    #[test]
+    fn parser_survives_random_input() {
+        let mut rng = StdRng::seed_from_u64(2);
+        let alphabet = b"+-:$*_#,(%~>\r\n0123456789-1tfx";
+        for _ in 0..20000 {
+            let mut bytes = if rng.gen() {
+                random_value(&mut rng, 0).to_bytes()
+            } else {
+                (0..rng.gen_range(0..40)).map(|_| alphabet[rng.gen_range(0..alphabet.len())]).collect()
+            };
+            for _ in 0..rng.gen_range(0..4) {
+                if bytes.is_empty() {
+                    break;
+                }
+                let i = rng.gen_range(0..bytes.len());
+                match rng.gen_range(0..3) {
+                    0 => { bytes[i] = alphabet[rng.gen_range(0..alphabet.len())]; },
+                    1 => { bytes.remove(i); },
+                    _ => { bytes.insert(i, rng.gen()); },
+                }
+            }
+            if let Ok(Some((_, len))) = parse(&bytes) {
+                assert!(len <= bytes.len());
+            }
+        }
+    }
+}
@@ -1,7 +1,7 @@
-use std::{collections::{HashMap, LinkedList}, env::set_current_dir, fs::{File, OpenOptions}, io::{self, BufRead, BufReader, Read, Write}, process::{exit, id}, ptr::null_mut, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
+use std::{collections::{HashMap, LinkedList, VecDeque}, fs::{File, OpenOptions}, io::{self, Read, Write}, process::{exit, id}, ptr::null_mut, sync::{Arc, RwLock, RwLockReadGuard, RwLockWriteGuard}};
 use libc::{close, dup2, fclose, fopen, fork, fprintf, getpid, open, pid_t, setsid, signal, FILE, O_RDWR, SIGHUP, SIGPIPE, SIG_IGN, STDERR_FILENO, STDIN_FILENO, STDOUT_FILENO};
 use once_cell::sync::Lazy;
-use crate::{client::RedisClient, eventloop::{create_file_event, create_time_event, Mask}, handler::{accept_handler, server_cron}, net::tcp_server, obj::RedisObject, util::{log, oom, timestamp, yes_no_to_bool, LogLevel}};
+use crate::{aof::AofWriter, dict::Dict, client::{clients_read, close_all_clients, connected_clients, connected_clients_peak, rejected_connections, used_memory}, config::{apply_config_line, lookup_config}, latency::LatencyTimeSeries, eventloop::{create_file_event, create_time_event, delete_file_event, Mask}, handler::{accept_handler, server_cron}, lazyfree::{free_object, lazyfree_pending_objects}, net::{local_port, nonblock, reserve_fd, tcp_server_retry, DEFAULT_TCP_BACKLOG}, obj::RedisObject, util::{get_random_hex_chars, log, oom, quote_arg, split_args, timestamp, LogLevel}, zmalloc::MemCounter};
 
 
 /// 
@@ -13,7 +13,21 @@ pub const IO_BUF_LEN: usize = 1024;
 pub static ONE_GB: i32 = 1024 * 1024 * 1024;
 static MAX_IDLE_TIME: i32 = 60 * 5;             // default client timeout
 static DEFAULT_DBNUM: i32 = 16;
+static DEFAULT_HZ: u32 = 10;                    // server_cron runs hz times per second
+pub const MIN_HZ: u32 = 1;
+pub const MAX_HZ: u32 = 500;
+static EXPIRE_LOOKUPS_PER_CRON: usize = 10;     // keys with an expire sampled per DB every cron loop
+static EVICTION_SAMPLES: usize = 3;                 // volatile keys sampled per DB for every eviction
+static EXPIRE_MAX_ROUNDS_PER_CRON: usize = 16;  // upper bound of the sampling rounds per DB every cron loop
 static SERVER_PORT: u16 = 6379;
+pub const RUN_ID_SIZE: usize = 40;
+/// Output buffer limits of the normal, replica and pubsub clients.
+pub const CLIENT_OBUF_LIMIT_CLASSES: [&str; 3] = ["normal", "replica", "pubsub"];
+pub const DEFAULT_CLIENT_OBUF_LIMITS: [ClientBufferLimit; 3] = [
+    ClientBufferLimit { hard: 0, soft: 0, soft_seconds: 0 },
+    ClientBufferLimit { hard: 256 << 20, soft: 64 << 20, soft_seconds: 60 },
+    ClientBufferLimit { hard: 32 << 20, soft: 8 << 20, soft_seconds: 60 },
+];
 
 // Hashes related defaults
 static HASH_MAX_ZIPMAP_ENTRIES: usize = 64;
@@ -21,7 +35,13 @@ static HASH_MAX_ZIPMAP_VALUE: usize = 512;
 
 
 /// Redis Server state.
-/// 
+///
+/// Lock ordering: SERVER -> db -> object. A thread holding one of these
+/// locks may only take the ones after it, never one before: e.g. the cron
+/// holds SERVER while it walks the DBs, so a command holding a db lock
+/// must drop it before touching SERVER. A read guard is never upgraded in
+/// place, std's RwLock would deadlock: code that reads and then writes
+/// takes the write lock once (see the keyspace helpers in client.rs).
 pub static SERVER: Lazy<Arc<RwLock<RedisServer>>> = Lazy::new(|| { Arc::new(RwLock::new(RedisServer::new())) });
 pub fn server_read() -> RwLockReadGuard<'static, RedisServer> {
     SERVER.read().unwrap()
@@ -31,60 +51,96 @@ pub fn server_write() -> RwLockWriteGuard<'static, RedisServer> {
 }
 
 pub struct RedisServer {
-    port: u16,
+    pub(crate) port: u16,
     pub fd: i32,
+    pub run_id: String,                             // random id of this execution, changes at every restart
     pub dbs: Vec<Arc<RwLock<RedisDB>>>,
     sharing_pool: HashMap<Arc<RedisObject>, usize>,      // Pool used for object sharing
-    sharing_pool_size: u32,
+    pub(crate) sharing_pool_size: u32,
     pub dirty: u128,                                // changes to DB from the last save
-    slaves: LinkedList<Arc<RwLock<RedisClient>>>,
-    monitors: LinkedList<RedisClient>,
+    pub(crate) slaves: LinkedList<u64>,             // ids of the slaves, see SYNC
+    pub(crate) monitors: LinkedList<u64>,          // ids of the clients in MONITOR mode
     cron_loops: i32,                                            // number of times the cron function run
+    pub hz: u32,                                    // server_cron frequency, in calls per second
     pub last_save: u64,                                             // Unix time of last save succeeded (in seconds)
+    pub loading: bool,                              // the dataset is being loaded from disk
     // Fields used only for stats
     stat_starttime: u64,                        // server start time (in seconds)
     pub stat_numcommands: u128,                     // number of processed commands
+    pub stat_wrongtype_errors: u64,                 // WRONGTYPE errors replied
+    pub stat_expiredkeys: u64,                      // keys deleted because their expire was reached
+    pub stat_pipeline: PipelineStats,               // commands run per read event
     stat_numconnections: u128,                  // number of connections received
     // Configuration
-    verbosity: LogLevel,
-    glue_output_buf: bool,
+    pub(crate) verbosity: LogLevel,
+    pub(crate) glue_output_buf: bool,
     pub max_idle_time: i32,
     pub dbnum: i32,
+    pub config_file: Option<String>,                // absolute path, for CONFIG REWRITE
     pub daemonize: bool,
     pub append_only: bool,
     pub append_fsync: AppendFsync,
-    pub append_file: Option<File>,
+    pub aof_load_truncated: bool,                   // start with the good part of a damaged AOF
+    pub append_file: Option<Box<dyn AofWriter>>,
+    pub aof_buf: Vec<u8>,                           // AOF data not yet written on disk
+    pub aof_last_write_status: Result<(), String>,  // error of the last AOF write/fsync, if any
     pub last_fsync: u64,
     pub append_sel_db: i32,
     pub pid_file: String,
     pub bg_save_child_pid: pid_t,
+    pub(crate) bg_save_info_fd: i32,               // read end of the pipe the BGSAVE child reports on
+    pub rdb_last_save_skipped_expired: usize,       // expired keys left out of the last save
     pub bg_rewrite_child_pid: pid_t,
     pub bg_rewrite_buf: String,                     // buffer taken by parent during oppend only rewrite
     save_params: Vec<SaveParam>,
-    log_file: String,
-    bind_addr: String,
+    pub(crate) log_file: String,
+    pub(crate) bind_addr: String,
+    pub(crate) bind_retries: u32,                   // times a port in use is tried again at startup
+    pub(crate) tcp_backlog: i32,                    // length of the queue of pending connections
     pub db_filename: String,
     pub append_filename: String,
     pub require_pass: String,
+    pub(crate) command_renames: Vec<(String, String)>,  // (command, new name), an empty name disables it
     pub share_objects: bool,
     pub rdb_compression: bool,
+    pub flushall_saves: bool,                       // FLUSHALL also performs a SAVE
     // Replication related
-    is_slave: bool,
-    master_auth: String,
-    master_host: String,
-    master_port: u16,
-    pub master: Option<Arc<RedisClient>>,       // client that is master for this slave
+    pub(crate) is_slave: bool,
+    pub(crate) master_auth: String,
+    pub(crate) master_host: String,
+    pub(crate) master_port: u16,
+    pub(crate) repl_slave_ro: bool,                 // refuse writes from clients while a replica
+    pub(crate) repl_diskless_sync: bool,            // accepted only, SYNC always goes through the disk
+    pub master: Option<u64>,                        // id of the client that is master for this slave
     pub repl_state: ReplState,
 
-    max_clients: u32,
+    pub(crate) max_clients: u32,
+    pub(crate) client_obuf_limits: [ClientBufferLimit; 3],   // by class, see CLIENT_OBUF_LIMIT_CLASSES
     pub max_memory: u128,
+    pub lazyfree_lazy_expire: bool,                 // drop expired values in the background
+    pub lazyfree_lazy_eviction: bool,               // drop evicted values in the background
+    pub debug_sorted_replies: bool,                 // test-only, see DEBUG SET-SORTED-REPLIES
+    pub latency_monitor_threshold: u64,             // in milliseconds, 0 = disabled
+    pub latency_events: HashMap<String, LatencyTimeSeries>,
     pub blpop_blocked_clients: u32,
+    // Pubsub, clients are referred to by id, see pubsub.rs
+    pub pubsub_channels: HashMap<String, Vec<u64>>, // channel -> clients subscribed to it
+    pub pubsub_patterns: Vec<(u64, String)>,        // (client, pattern) for every PSUBSCRIBE
+    pub pubsub_shard_channels: HashMap<String, Vec<u64>>,   // sharded channel -> clients subscribed to it
     // Hashes config
-    hash_max_zipmap_entries: usize,
-    hash_max_zipmap_value: usize,
+    pub(crate) hash_max_zipmap_entries: usize,
+    pub(crate) hash_max_zipmap_value: usize,
 
     // Virtual memory state
     devnull: Option<Arc<dyn Write + Sync + Send>>,
+    pub(crate) reserved_fd: i32,                   // closed to refuse connections when out of fds
+
+    // Faults armed through DEBUG, see DebugFaults
+    #[cfg(feature = "testing-hooks")]
+    pub debug_faults: DebugFaults,
+    // Keyspace events log, see DEBUG EVENTS
+    #[cfg(feature = "testing-hooks")]
+    pub key_events: std::collections::VecDeque<KeyEvent>,
 }
 impl RedisServer {
     pub fn new() -> RedisServer {
@@ -96,42 +152,67 @@ impl RedisServer {
         RedisServer { 
             port: SERVER_PORT, 
             fd: -1,
+            run_id: get_random_hex_chars(RUN_ID_SIZE),
             dbs: Vec::with_capacity(DEFAULT_DBNUM as usize),
             sharing_pool: HashMap::new(),
             dirty: 0,
             slaves: LinkedList::new(),
             monitors: LinkedList::new(),
             cron_loops: 0,
+            hz: DEFAULT_HZ,
             last_save: timestamp().as_secs(),
+            loading: false,
             stat_starttime: timestamp().as_secs(),
             stat_numcommands: 0,
+            stat_wrongtype_errors: 0,
+            stat_expiredkeys: 0,
+            stat_pipeline: PipelineStats::default(),
             stat_numconnections: 0,
             verbosity: LogLevel::Verbose,
             max_idle_time: MAX_IDLE_TIME,
             dbnum: DEFAULT_DBNUM,
+            config_file: None,
             save_params,
             log_file: String::new(),                       // "" = log on standard output
             bind_addr: String::new(),
+            bind_retries: 0,
+            tcp_backlog: DEFAULT_TCP_BACKLOG,
             glue_output_buf: true,
             daemonize: false,
             append_only: false,
             append_fsync: AppendFsync::Always,
+            aof_load_truncated: false,
             append_file: None,
+            aof_buf: Vec::new(),
+            aof_last_write_status: Ok(()),
             last_fsync: timestamp().as_secs(),
             append_sel_db: -1,                  // Make sure the first time will not match
             pid_file: "/var/run/redis.pid".to_string(),
             bg_save_child_pid: -1,
+            bg_save_info_fd: -1,
+            rdb_last_save_skipped_expired: 0,
             bg_rewrite_child_pid: -1,
             bg_rewrite_buf: String::new(),
             db_filename: "dump.rdb".to_string(),
             append_filename: "appendonly.aof".to_string(),
             require_pass: String::new(),
+            command_renames: Vec::new(),
             share_objects: false,
             rdb_compression: true,
+            flushall_saves: false,
             sharing_pool_size: 1024,
             max_clients: 0,
+            client_obuf_limits: DEFAULT_CLIENT_OBUF_LIMITS,
             blpop_blocked_clients: 0,
+            pubsub_channels: HashMap::new(),
+            pubsub_patterns: Vec::new(),
+            pubsub_shard_channels: HashMap::new(),
             max_memory: 0,
+            lazyfree_lazy_expire: false,
+            lazyfree_lazy_eviction: false,
+            debug_sorted_replies: false,
+            latency_monitor_threshold: 0,
+            latency_events: HashMap::new(),
             hash_max_zipmap_entries: HASH_MAX_ZIPMAP_ENTRIES,
             hash_max_zipmap_value: HASH_MAX_ZIPMAP_VALUE,
 
@@ -140,9 +221,16 @@ impl RedisServer {
             master_auth: String::new(),
             master_host: String::new(),
             master_port: 6379,
+            repl_slave_ro: true,
+            repl_diskless_sync: false,
             master: None,
             repl_state: ReplState::None,
             devnull: None,
+            reserved_fd: -1,
+            #[cfg(feature = "testing-hooks")]
+            debug_faults: DebugFaults::default(),
+            #[cfg(feature = "testing-hooks")]
+            key_events: std::collections::VecDeque::with_capacity(KEY_EVENTS_LEN),
         }
     }
 
@@ -160,11 +248,36 @@ impl RedisServer {
                 exit(1);
             },
         }
+        // Without it the server still works, but out of fds it can only
+        // pause accepting connections
+        match reserve_fd() {
+            Ok(fd) => { self.reserved_fd = fd; },
+            Err(e) => { log(LogLevel::Warning, &format!("Can't reserve a file descriptor: {}", e)); },
+        }
 
-        match tcp_server(self.port, &self.bind_addr) {
-            Ok(fd) => { self.fd = fd; },
+        match tcp_server_retry(self.port, &self.bind_addr, self.tcp_backlog, self.bind_retries) {
+            Ok(fd) => {
+                self.fd = fd;
+                // The accept handler drains the pending connections until
+                // accept() would block
+                if let Err(e) = nonblock(fd) {
+                    log(LogLevel::Warning, &format!("Could not set the listening socket nonblocking: {}", e));
+                    exit(1);
+                }
+                // Port 0 lets the kernel pick a free port
+                if self.port == 0 {
+                    match local_port(fd) {
+                        Ok(port) => { self.port = port; },
+                        Err(e) => {
+                            log(LogLevel::Warning, &format!("Could not get the listening port: {}", e));
+                            exit(1);
+                        },
+                    }
+                }
+            },
             Err(e) => {
-                log(LogLevel::Warning, &format!("Opening TCP port: {}", e));
+                let addr = if self.bind_addr.is_empty() { "*" } else { &self.bind_addr };
+                log(LogLevel::Warning, &format!("Could not create server TCP listening socket {}:{}: {}", addr, self.port, e));
                 exit(1);
             },
         }
@@ -181,7 +294,7 @@ impl RedisServer {
 
         if self.append_only {
             match OpenOptions::new().write(true).append(true).create(true).open(&self.append_filename) {
-                Ok(f) => { self.append_file = Some(f); },
+                Ok(f) => { self.append_file = Some(Box::new(f)); },
                 Err(e) => {
                     log(LogLevel::Warning, &format!("Can't open the append-only file: {}", e));
                     exit(1);
@@ -192,20 +305,55 @@ impl RedisServer {
 
     /// I agree, this is a very rudimental way to load a configuration...
     /// will improve later if the config gets more complex
-    pub fn load_server_config(&mut self, filename: &str) {
-        let mut _reader: Option<Box<dyn Read>> = None;
-        let mut line_num = 0;
+    /// Load the server configuration from the specified filename ("-" is
+    /// stdin, None means no file at all). The `options` string, in the same
+    /// format of the config file, is appended to the file content so that
+    /// its directives take precedence: it's used for the command line
+    /// overrides, see config_from_args().
+    pub fn load_server_config(&mut self, filename: Option<&str>, options: &str) {
+        let mut config = String::new();
 
-        if filename.eq("-") {
-            _reader = Some(Box::new(io::stdin()));
-        } else {
-            if let Ok(f) = File::open(filename) {
-                _reader = Some(Box::new(f));
-            } else {
-                log(LogLevel::Warning, "Fatal error, can't open config file");
-                exit(1);
-            }
+        match filename {
+            Some("-") => {
+                if let Err(e) = io::stdin().read_to_string(&mut config) {
+                    log(LogLevel::Warning, &format!("Fatal error, can't read config from stdin: {}", e));
+                    exit(1);
+                }
+            },
+            Some(filename) => {
+                if let Err(e) = File::open(filename).and_then(|mut f| f.read_to_string(&mut config)) {
+                    log(LogLevel::Warning, &format!("Fatal error, can't open config file: {}", e));
+                    exit(1);
+                }
+            },
+            None => {},
+        }
+
+        // Append the additional options
+        if !options.is_empty() {
+            config.push('\n');
+            config.push_str(options);
         }
+        self.load_server_config_from_string(&config);
+    }
+
+    /// Load the configuration from the command line arguments (program name
+    /// excluded): an optional config file followed by `--name value...`
+    /// overrides, see config_from_args().
+    pub fn load_server_config_from_args(&mut self, args: &[String]) {
+        let (config_file, options) = config_from_args(args);
+        // Keep an absolute path: the "dir" directive changes the working
+        // directory before CONFIG REWRITE may need the file.
+        self.config_file = config_file.as_deref()
+            .filter(|f| *f != "-")
+            .and_then(|f| std::fs::canonicalize(f).ok())
+            .map(|f| f.display().to_string());
+        self.reset_server_save_params();
+        self.load_server_config(config_file.as_deref(), &options);
+    }
+
+    pub fn load_server_config_from_string(&mut self, config: &str) {
+        let mut line_num = 0;
 
         let load_err = |err: &str, line: &str, line_num: i32| {
             eprintln!("*** FATAL CONFIG FILE ERROR ***");
@@ -214,206 +362,45 @@ impl RedisServer {
             eprintln!("{err}");
             exit(1);
         };
-        
-        let buf_reader = BufReader::new(_reader.unwrap());
-        for line in buf_reader.lines() {
-            if let Ok(line) = line {
-                line_num += 1;
-                let trimed_line = line.trim();
-
-                // Skip comments and blank lines
-                if trimed_line.starts_with("#") || trimed_line.is_empty() {
-                    continue;
-                }
 
-                // Split into arguments
-                let argv: Vec<&str> = trimed_line.split_whitespace().collect();
-                let argc = argv.len();
-
-                // Execute config directives
-                match &argv[0].to_ascii_lowercase()[..] {
-                    "timeout" if argc == 2 => {
-                        let mut err = String::new();
-                        match argv[1].parse() {
-                            Ok(t) => { self.max_idle_time = t; },
-                            Err(e) => { err = e.to_string(); },
-                        }
-                        if self.max_idle_time < 0 {
-                            err = "Invalid timeout value".to_string();
-                        }
-                        if !err.is_empty() { load_err(&err, trimed_line, line_num); }
-                    },
-                    "port" if argc == 2 => {
-                        let mut err = String::new();
-                        match argv[1].parse() {
-                            Ok(p) => { self.port = p; },
-                            Err(e) => { err = e.to_string(); },
-                        }
-                        if self.port < 1 {
-                            err = "Invalid port".to_string();
-                        }
-                        if !err.is_empty() { load_err(&err, trimed_line, line_num); }
-                    },
-                    "bind" if argc == 2 => { self.bind_addr = argv[1].to_string(); },
-                    "save" if argc == 3 => {
-                        let mut err = String::new();
-                        let s: Result<u64, _> = argv[1].parse();
-                        let c: Result<i32, _> = argv[2].parse();
-                        match (s, c) {
-                            (Ok(seconds), Ok(changes)) => {
-                                if seconds < 1 || changes < 0 {
-                                    err = "Invalid save parameters".to_string();
-                                } else {
-                                    self.append_server_save_params(seconds, changes); 
-                                }
-                            },
-                            _ => { err = "seconds or changes parsing failed".to_string(); }
-                        }
-                        if !err.is_empty() { load_err(&err, trimed_line, line_num); }
-                    },
-                    "dir" if argc == 2 => {
-                        match set_current_dir(argv[1]) {
-                            Ok(_) => {},
-                            Err(e) => {
-                                log(LogLevel::Warning, &format!("Can't chdir to '{}': {}", argv[1], e));
-                                exit(1);
-                            },
-                        };
-                    },
-                    "loglevel" if argc == 2 => {
-                        match &argv[1].to_ascii_lowercase()[..] {
-                            "debug" => { self.verbosity = LogLevel::Debug; },
-                            "verbose" => { self.verbosity = LogLevel::Verbose; },
-                            "notice" => { self.verbosity = LogLevel::Notice; },
-                            "warning" => { self.verbosity = LogLevel::Warning; },
-                            _ => {
-                                let err = "Invalid log level. Must be one of debug, verbose, notice or warning";
-                                load_err(err, &line, line_num);
-                            },
-                        }
-                    },
-                    "logfile" if argc == 2 => {
-                        match &argv[1].to_ascii_lowercase()[..] {
-                            "stdout" => { self.log_file = String::new(); },
-                            filename if !filename.is_empty() => {
-                                // Test if we are able to open the file. The server will not
-                                // be able to abort just for this problem later...
-                                match OpenOptions::new().append(true).open(filename) {
-                                    Ok(_) => {},
-                                    Err(e) => {
-                                        let err = format!("Can't open the log file: {}", e);
-                                        load_err(&err, &line, line_num);
-                                    },
-                                }
-                            },
-                            _ => { load_err("logfile can't be empty", &line, line_num); },
-                        }
-                    },
-                    "databases" if argc == 2 => {
-                        let mut err = String::new();
-                        match argv[1].parse() {
-                            Ok(n) => { self.dbnum = n; },
-                            Err(e) => { err = e.to_string(); },
-                        }
-                        if self.dbnum < 1 {
-                            err = "Invalid number of databases".to_string();
-                        }
-                        if !err.is_empty() { load_err(&err, trimed_line, line_num); }
-                    },
-                    "include" if argc == 2 => { self.load_server_config(argv[1]); },
-                    "maxclients" if argc == 2 => {
-                        match argv[1].parse() {
-                            Ok(m_c) => { self.max_clients = m_c; },
-                            Err(e) => { load_err(&e.to_string(), trimed_line, line_num); },
-                        }
-                    },
-                    "maxmemory" if argc == 2 => {
-                        match argv[1].parse() {
-                            Ok(m_m) => { self.max_memory = m_m; },
-                            Err(e) => { load_err(&e.to_string(), trimed_line, line_num); },
-                        }
-                    },
-                    "slaveof" if argc == 3 => {
-                        self.master_host = argv[1].to_string();
-                        match argv[2].parse() {
-                            Ok(m_p) => { self.master_port = m_p; },
-                            Err(e) => { load_err(&e.to_string(), trimed_line, line_num); },
-                        }
-                        self.repl_state = ReplState::Connect;
-                    },
-                    "masterauth" if argc == 2 => { self.master_auth = argv[1].to_string(); },
-                    "glueoutputbuf" if argc == 2 => {
-                        match yes_no_to_bool(argv[1]) {
-                            Ok(b) => { self.glue_output_buf = b; },
-                            Err(e) => { load_err(&e, trimed_line, line_num); },
-                        }
-                    },
-                    "shareobjects" if argc == 2 => {
-                        match yes_no_to_bool(argv[1]) {
-                            Ok(b) => { self.share_objects = b; },
-                            Err(e) => { load_err(&e, trimed_line, line_num); },
-                        }
-                    },
-                    "rdbcompression" if argc == 2 => {
-                        match yes_no_to_bool(argv[1]) {
-                            Ok(b) => { self.rdb_compression = b; },
-                            Err(e) => { load_err(&e, trimed_line, line_num); },
-                        }
-                    },
-                    "shareobjectspoolsize" if argc == 2 => {
-                        let mut err = String::new();
-                        match argv[1].parse() {
-                            Ok(sp_size) => { self.sharing_pool_size = sp_size; },
-                            Err(e) => { err = e.to_string(); },
-                        }
-                        if self.sharing_pool_size < 1 {
-                            err = "invalid object sharing pool size".to_string();
-                        }
-                        if !err.is_empty() { load_err(&err, trimed_line, line_num); }
-                    },
-                    "daemonize" if argc == 2 => {
-                        match yes_no_to_bool(argv[1]) {
-                            Ok(b) => { self.daemonize = b; },
-                            Err(e) => { load_err(&e, trimed_line, line_num); },
-                        }
-                    },
-                    "appendonly" if argc == 2 => {
-                        match yes_no_to_bool(argv[1]) {
-                            Ok(b) => { self.append_only = b; },
-                            Err(e) => { load_err(&e, trimed_line, line_num); },
-                        }
-                    },
-                    "appendfsync" if argc == 2 => {
-                        match &argv[1].to_ascii_lowercase()[..] {
-                            "no" => { self.append_fsync = AppendFsync::No; },
-                            "always" => { self.append_fsync = AppendFsync::Always; },
-                            "everysec" => { self.append_fsync = AppendFsync::EverySec; },
-                            _ => { load_err("argument must be 'no', 'always' or 'everysec'", &line, line_num); },
-                        }
-                    },
-                    "requirepass" if argc == 2 => { self.require_pass = argv[1].to_string(); },
-                    "pidfile" if argc == 2 => { self.pid_file = argv[1].to_string(); },
-                    "dbfilename" if argc == 2 => { self.db_filename = argv[1].to_string(); },
-                    "hash-max-zipmap-entries" if argc == 2 => {
-                        match argv[1].parse() {
-                            Ok(mz_e) => { self.hash_max_zipmap_entries = mz_e; },
-                            Err(e) => { load_err(&e.to_string(), trimed_line, line_num); },
-                        }
-                    },
-                    "hash-max-zipmap-value" if argc == 2 => {
-                        match argv[1].parse() {
-                            Ok(mz_v) => { self.hash_max_zipmap_value = mz_v; },
-                            Err(e) => { load_err(&e.to_string(), trimed_line, line_num); },
-                        }
-                    },
-                    _ => {
-                        let err = "Bad directive or wrong number of arguments";
-                        load_err(err, &line, line_num);
-                    },
-                }
-            } else {
-                let err = "Directive parsing failed";
-                load_err(err, "", line_num);
+        for line in config.lines() {
+            line_num += 1;
+            let trimed_line = line.trim();
+
+            // Skip comments and blank lines
+            if trimed_line.starts_with("#") || trimed_line.is_empty() {
+                continue;
+            }
+
+            // Split into arguments
+            let Some(argv) = split_args(trimed_line) else {
+                load_err("Unbalanced quotes in configuration line", line, line_num);
+                continue;
+            };
+            let argv: Vec<&str> = argv.iter().map(String::as_str).collect();
+            let argc = argv.len();
+
+            // Execute config directives
+            if argv[0].eq_ignore_ascii_case("include") && argc == 2 {
+                self.load_server_config(Some(argv[1]), "");
+                continue;
+            }
+            // save "" drops the save points, disabling automatic saving. A
+            // bare save is taken the same way.
+            if argv[0].eq_ignore_ascii_case("save") && (argc == 1 || (argc == 2 && argv[1].is_empty())) {
+                self.reset_server_save_params();
+                continue;
+            }
+            match lookup_config(argv[0]) {
+                Some(param) if argc == param.args + 1 => {
+                    if let Err(e) = apply_config_line(self, param, &argv[1..]) {
+                        load_err(&e, trimed_line, line_num);
+                    }
+                },
+                _ => {
+                    let err = "Bad directive or wrong number of arguments";
+                    load_err(err, line, line_num);
+                },
             }
         }
     }
@@ -456,8 +443,203 @@ impl RedisServer {
     /// the server will start refusing commands that will enlarge even more the
     /// memory usage.
     pub fn free_memory_if_needed(&mut self) {
-        // TODO
-        log(LogLevel::Warning, "free memory if needed!!!");
+        // Don't log here: we are called for every command with the server
+        // write lock held.
+        while self.max_memory > 0 && used_memory() as u128 > self.max_memory {
+            let mut freed = false;
+            for db in self.dbs.clone() {
+                let mut db_w = db.write().unwrap();
+                // Of a few volatile keys, evict the one expiring first
+                let Some(key) = db_w.expires.sample(EVICTION_SAMPLES).into_iter()
+                    .min_by_key(|(_, when)| **when)
+                    .map(|(k, _)| k.clone()) else { continue; };
+                db_w.expires.remove(&key);
+                if let Some(obj) = db_w.dict.remove(&key) {
+                    free_object(obj, self.lazyfree_lazy_eviction);
+                }
+                self.record_key_event("evicted", db_w.id, &key);
+                freed = true;
+            }
+            // Nothing left to evict, or the memory is being released in the
+            // background: the next command will check again.
+            if !freed || lazyfree_pending_objects() > 0 {
+                break;
+            }
+        }
+    }
+
+    /// Delete some of the keys whose expire is in the past, without waiting
+    /// for a client to touch them. Every DB is sampled a few keys at a time,
+    /// and the sampling goes on while more than a quarter of the sample was
+    /// expired. The rounds per call are bounded so a call takes about the
+    /// same time whatever the hz: more calls per second reap faster.
+    /// Returns the deleted keys with their DB, for the AOF.
+    pub fn active_expire_cycle(&mut self) -> Vec<(i32, String)> {
+        let now = timestamp().as_millis() as u64;
+        let mut deleted = Vec::new();
+        for db in self.dbs.clone() {
+            let mut db_w = db.write().unwrap();
+            for _ in 0..EXPIRE_MAX_ROUNDS_PER_CRON {
+                let sample: Vec<(String, u64)> = db_w.expires.sample(EXPIRE_LOOKUPS_PER_CRON)
+                    .into_iter().map(|(k, w)| (k.clone(), *w)).collect();
+                let mut expired = 0;
+                for (key, when) in sample {
+                    if when <= now {
+                        db_w.expires.remove(&key);
+                        if let Some(obj) = db_w.dict.remove(&key) {
+                            free_object(obj, self.lazyfree_lazy_expire);
+                        }
+                        self.record_key_event("expired", db_w.id, &key);
+                        deleted.push((db_w.id, key));
+                        expired += 1;
+                    }
+                }
+                if expired <= EXPIRE_LOOKUPS_PER_CRON / 4 {
+                    break;
+                }
+            }
+        }
+        self.dirty += deleted.len() as u128;
+        self.stat_expiredkeys += deleted.len() as u64;
+        deleted
+    }
+
+    /// Stop accepting connections.
+    pub fn close_listening_sockets(&mut self) {
+        if self.fd != -1 {
+            delete_file_event(self.fd, Mask::Readable);
+            unsafe { close(self.fd); }
+            self.fd = -1;
+        }
+    }
+
+    /// Become a slave of the given master. The link is established by the
+    /// replication cron, until then master_link_status is down.
+    pub fn set_master(&mut self, host: &str, port: u16) {
+        self.master_host = host.to_string();
+        self.master_port = port;
+        self.is_slave = true;
+        self.master = None;
+        self.repl_state = ReplState::Connect;
+    }
+
+    /// Go back to the master role, dropping the link with our master.
+    pub fn unset_master(&mut self) {
+        self.master_host.clear();
+        self.is_slave = false;
+        self.master = None;
+        self.repl_state = ReplState::None;
+    }
+
+    pub fn is_slave(&self) -> bool {
+        self.is_slave
+    }
+
+    /// The INFO output, one "# Section" after the other.
+    pub fn gen_redis_info_string(&self) -> String {
+        let mut info = String::new();
+        info.push_str(&self.info_server());
+        info.push_str(&self.info_clients());
+        info.push_str(&self.info_memory());
+        info.push_str(&self.info_persistence());
+        info.push_str(&self.info_stats());
+        info.push_str(&self.info_replication());
+        info
+    }
+
+    fn info_server(&self) -> String {
+        let uptime = timestamp().as_secs() - self.stat_starttime;
+        format!("# Server\r\n\
+            redis_version:{}\r\n\
+            process_id:{}\r\n\
+            tcp_port:{}\r\n\
+            uptime_in_seconds:{}\r\n\
+            uptime_in_days:{}\r\n\
+            hz:{}\r\n\r\n",
+            REDIS_VERSION, id(), self.port, uptime, uptime / (3600 * 24), self.hz)
+    }
+
+    fn info_memory(&self) -> String {
+        let used = used_memory();
+        let peak = MemCounter::update_peak();
+        let rss = MemCounter::used_memory_rss();
+        format!("# Memory\r\n\
+            used_memory:{}\r\n\
+            used_memory_peak:{}\r\n\
+            used_memory_rss:{}\r\n\
+            mem_fragmentation_ratio:{:.2}\r\n\r\n",
+            used, peak, rss, rss as f64 / used.max(1) as f64)
+    }
+
+    fn info_persistence(&self) -> String {
+        format!("# Persistence\r\n\
+            changes_since_last_save:{}\r\n\
+            bgsave_in_progress:{}\r\n\
+            last_save_time:{}\r\n\
+            rdb_last_save_skipped_expired:{}\r\n\
+            bgrewriteaof_in_progress:{}\r\n\r\n",
+            self.dirty, (self.bg_save_child_pid != -1) as u8, self.last_save, self.rdb_last_save_skipped_expired,
+            (self.bg_rewrite_child_pid != -1) as u8)
+    }
+
+    fn info_clients(&self) -> String {
+        format!("# Clients\r\n\
+            connected_clients:{}\r\n\
+            connected_clients_peak:{}\r\n\
+            blocked_clients:{}\r\n\r\n",
+            connected_clients(), connected_clients_peak(), self.blpop_blocked_clients)
+    }
+
+    fn info_stats(&self) -> String {
+        format!("# Stats\r\n\
+            total_connections_received:{}\r\n\
+            total_commands_processed:{}\r\n\
+            rejected_connections:{}\r\n\
+            pubsub_channels:{}\r\n\
+            pubsub_patterns:{}\r\n\
+            pubsubshard_channels:{}\r\n\
+            wrongtype_errors:{}\r\n\
+            expired_keys:{}\r\n\
+            cmds_per_event_mean:{:.2}\r\n\
+            cmds_per_event_p99:{}\r\n\
+            cmds_per_event_max:{}\r\n\
+            cmds_per_event_histogram:{}\r\n\r\n",
+            self.stat_numconnections, self.stat_numcommands, rejected_connections(), self.pubsub_channels.len(), self.pubsub_patterns.len(),
+            self.pubsub_shard_channels.len(), self.stat_wrongtype_errors, self.stat_expiredkeys,
+            self.stat_pipeline.mean(), self.stat_pipeline.p99(), self.stat_pipeline.max, self.stat_pipeline.histogram())
+    }
+
+    fn info_replication(&self) -> String {
+        let mut info = String::from("# Replication\r\n");
+        if self.is_slave {
+            let link_up = self.repl_state == ReplState::Connected;
+            let last_io = match (self.master, link_up) {
+                (Some(id), true) => clients_read().iter()
+                    .filter_map(|c| c.try_read().ok())
+                    .find(|c| c.id() == id)
+                    .map_or(-1, |master| timestamp().as_secs().saturating_sub(master.last_interaction) as i64),
+                _ => -1,
+            };
+            info.push_str(&format!("role:slave\r\n\
+                master_host:{}\r\n\
+                master_port:{}\r\n\
+                master_link_status:{}\r\n\
+                master_last_io_seconds_ago:{}\r\n",
+                self.master_host, self.master_port, if link_up { "up" } else { "down" }, last_io));
+        } else {
+            info.push_str("role:master\r\n");
+        }
+        info.push_str(&format!("connected_slaves:{}\r\n", self.slaves.len()));
+        // There are no replication offsets yet, the offset is always 0
+        let clients = clients_read();
+        let slaves = clients.iter()
+            .filter_map(|c| c.try_read().ok())
+            .filter(|c| self.slaves.contains(&c.id()));
+        for (i, slave) in slaves.enumerate() {
+            let (ip, port) = slave.slave_addr.rsplit_once(':').unwrap_or(("?", "0"));
+            info.push_str(&format!("slave{}:ip={},port={},state={},offset=0\r\n", i, ip, port, slave.repl_state().name()));
+        }
+        info
     }
 
     pub fn clear(&mut self) -> u128 {
@@ -468,14 +650,28 @@ impl RedisServer {
             db_w.dict.clear();
             db_w.expires.clear();
         }
+        self.record_key_event("flushall", -1, "");
         removed
     }
 
-    
+
+    /// Append an event to the keyspace events log, dropping the oldest one
+    /// when it's full. A no-op without the testing-hooks feature.
+    #[inline]
+    pub fn record_key_event(&mut self, _op: &str, _db: i32, _key: &str) {
+        #[cfg(feature = "testing-hooks")]
+        {
+            if self.key_events.len() == KEY_EVENTS_LEN {
+                self.key_events.pop_front();
+            }
+            self.key_events.push_back(KeyEvent { op: _op.to_string(), db: _db, key: _key.to_string(), time: timestamp().as_millis() });
+        }
+    }
+
     pub fn reset_server_save_params(&mut self) {
         self.save_params.clear();
     }
-    fn append_server_save_params(&mut self, seconds: u64, changes: i32) {
+    pub(crate) fn append_server_save_params(&mut self, seconds: u64, changes: i32) {
         self.save_params.push(SaveParam { seconds, changes });
     }
     pub fn save_params(&self) -> &Vec<SaveParam> {
@@ -521,7 +717,7 @@ impl RedisServer {
     pub fn set_stat_numconnections(&mut self, s: u128) {
         self.stat_numconnections = s;
     }
-    pub fn slaves(&self) -> &LinkedList<Arc<RwLock<RedisClient>>> {
+    pub fn slaves(&self) -> &LinkedList<u64> {
         &self.slaves
     }
     pub fn sharing_pool(&self) -> &HashMap<Arc<RedisObject>, usize> {
@@ -582,18 +778,68 @@ impl RedisServer {
 
 
 pub struct RedisDB {
-    pub dict: HashMap<String, Arc<RwLock<RedisObject>>>,                                        // The keyspace for this DB
-    pub expires: HashMap<String, u64>,                                                  // Timeout of keys with a timeout set
-    pub blocking_keys: HashMap<String, Arc<LinkedList<Arc<RwLock<RedisClient>>>>>,      // Keys with clients waiting for data (BLPOP)
+    pub dict: Dict<Arc<RwLock<RedisObject>>>,                                           // The keyspace for this DB
+    pub expires: Dict<u64>,                                                             // Unix time in ms at which keys with a timeout expire
+    pub blocking_keys: HashMap<String, VecDeque<u64>>,                                  // Keys with clients waiting for data (BLPOP), by client id in arrival order
     pub id: i32,
 }
 impl RedisDB {
     pub fn new(id: i32) -> RedisDB {
-        Self { dict: HashMap::new(), expires: HashMap::new(), blocking_keys: HashMap::new(), id }
+        Self { dict: Dict::with_scan_index(), expires: Dict::new(), blocking_keys: HashMap::new(), id }
+    }
+
+    /// Keys having an expire but no value. There should be none: a key
+    /// removed from the dict without delete_key() leaves its expire behind,
+    /// and a key created again later inherits it.
+    #[cfg(feature = "testing-hooks")]
+    pub fn dangling_expires(&self) -> Vec<String> {
+        self.expires.keys().filter(|key| !self.dict.contains_key(key)).cloned().collect()
+    }
+}
+
+/// Panic if any database has an expire without a key.
+#[cfg(feature = "testing-hooks")]
+pub fn assert_expires_consistent() {
+    for db in server_read().dbs.iter() {
+        let db_r = db.read().unwrap();
+        let dangling = db_r.dangling_expires();
+        assert!(dangling.is_empty(), "db {} has expires without a key: {:?}", db_r.id, dangling);
     }
 }
 
 
+/// One-shot faults used to test the persistence code paths. Every flag is
+/// consumed by the first operation that honors it. They can only be armed
+/// (with DEBUG) in builds with the testing-hooks feature.
+#[derive(Default)]
+pub struct DebugFaults {
+    pub sleep_after_fork: u64,          // seconds the next BGSAVE/BGREWRITEAOF child sleeps before serializing
+    pub fail_next_bgsave: bool,         // the next BGSAVE child exits with an error
+    pub fail_next_aof_write: bool,      // the next AOF flush fails as if the disk were full
+}
+
+pub const KEY_EVENTS_LEN: usize = 1024;     // events kept in the keyspace events log
+
+/// A keyspace event: a key was modified or deleted. The log lets tests
+/// check expiry and deletion paths without sleeping. It is only filled in
+/// builds with the testing-hooks feature.
+#[derive(Clone)]
+pub struct KeyEvent {
+    pub op: String,                     // command name, or "del", "expired", "flushdb", "flushall"
+    pub db: i32,                        // -1 for events spanning all the DBs
+    pub key: String,
+    pub time: u128,                     // unix time in milliseconds
+}
+
+
+/// Release the sockets before exiting: the listening one first, so the
+/// port can be bound again right away, then the client connections.
+pub fn server_teardown() {
+    server_write().close_listening_sockets();
+    close_all_clients();
+}
+
+
 #[derive(PartialEq)]
 pub enum ReplState {
     // Slave replication state - slave side
@@ -609,6 +855,19 @@ pub enum ReplState {
     SendBulk,               // master is sending the bulk DB
     Online,                 // bulk DB already transmitted, receive updates
 }
+impl ReplState {
+    /// Name used in the INFO output.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::None => "none",
+            Self::Connect => "connect",
+            Self::Connected => "connected",
+            Self::WaitBgSaveStart | Self::WaitBgSaveEnd => "wait_bgsave",
+            Self::SendBulk => "send_bulk",
+            Self::Online => "online",
+        }
+    }
+}
 
 
 pub struct SaveParam {
@@ -625,6 +884,66 @@ impl SaveParam {
 }
 
 
+/// A client is closed as soon as its output buffer reaches `hard` bytes,
+/// or once it stays over `soft` bytes for more than `soft_seconds`. Zero
+/// disables a limit.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ClientBufferLimit {
+    pub hard: u64,
+    pub soft: u64,
+    pub soft_seconds: u64,
+}
+
+
+/// Histogram of the commands run per read event of a client, i.e. how much
+/// the clients pipeline. The buckets hold 1, 2-10, 11-100, 101-1000 and
+/// more commands.
+#[derive(Default)]
+pub struct PipelineStats {
+    buckets: [u64; 5],
+    commands: u64,
+    pub max: u64,
+}
+impl PipelineStats {
+    /// The upper bound of every bucket but the last one.
+    const BOUNDS: [u64; 4] = [1, 10, 100, 1000];
+    const NAMES: [&'static str; 5] = ["1", "2-10", "11-100", "101-1000", "1001+"];
+
+    pub fn record(&mut self, commands: u64) {
+        let bucket = Self::BOUNDS.iter().position(|b| commands <= *b).unwrap_or(Self::BOUNDS.len());
+        self.buckets[bucket] += 1;
+        self.commands += commands;
+        self.max = self.max.max(commands);
+    }
+    pub fn events(&self) -> u64 {
+        self.buckets.iter().sum()
+    }
+    pub fn mean(&self) -> f64 {
+        match self.events() {
+            0 => 0.0,
+            events => self.commands as f64 / events as f64,
+        }
+    }
+    /// The 99th percentile, as the upper bound of its bucket: the buckets
+    /// are all there is. Never more than the max.
+    pub fn p99(&self) -> u64 {
+        let rank = (self.events() * 99).div_ceil(100);
+        let mut seen = 0;
+        for (i, count) in self.buckets.iter().enumerate() {
+            seen += count;
+            if seen >= rank && *count > 0 {
+                return Self::BOUNDS.get(i).map_or(self.max, |b| (*b).min(self.max));
+            }
+        }
+        0
+    }
+    /// The buckets as `1=<events>,2-10=<events>,...`.
+    pub fn histogram(&self) -> String {
+        Self::NAMES.iter().zip(self.buckets).map(|(name, count)| format!("{}={}", name, count)).collect::<Vec<_>>().join(",")
+    }
+}
+
+
 #[derive(PartialEq)]
 pub enum AppendFsync {
     No,
@@ -633,6 +952,41 @@ pub enum AppendFsync {
 }
 
 
+/// Split the command line arguments (program name excluded) into the
+/// config file, if any, and the options overriding it. Every `--name`
+/// starts a new directive, the following arguments are its values, so
+/// `redis.conf --port 7000 --save 60 1000` becomes the config file
+/// "redis.conf" and the options "port \"7000\"\nsave \"60\" \"1000\"". The
+/// values are quoted: they can be empty or hold spaces.
+pub fn config_from_args(args: &[String]) -> (Option<String>, String) {
+    let mut config_file = None;
+    let mut rest = args;
+    if let Some(first) = args.first() {
+        if !first.starts_with("--") {
+            config_file = Some(first.clone());
+            rest = &args[1..];
+        }
+    }
+
+    let mut options = String::new();
+    for arg in rest {
+        match arg.strip_prefix("--") {
+            Some(name) => {
+                if !options.is_empty() {
+                    options.push('\n');
+                }
+                options.push_str(name);
+            },
+            None => {
+                options.push(' ');
+                options.push_str(&quote_arg(arg));
+            },
+        }
+    }
+    (config_file, options)
+}
+
+
 static REDIS_VERSION: &str = "1.3.7";
 pub fn print_logo() {
     log(LogLevel::Notice, &format!("                _._                                                  "));
@@ -656,8 +1010,45 @@ pub fn print_logo() {
 
 
 #[cfg(test)]
-mod tests {
-    use std::io::{BufRead, Cursor};
+pub(crate) mod tests {
+    use std::{io::{BufRead, Cursor}, sync::{Arc, Mutex, MutexGuard, RwLock}};
+    use std::{collections::HashMap, io::Read, net::{TcpListener, TcpStream}, time::Duration};
+    use libc::close;
+    use crate::{client::{tests::{run, take_replies, test_client, unlist}, RedisClient}, config::lookup_config, handler::server_cron, net::{accept, tcp_server, DEFAULT_TCP_BACKLOG}, obj::{RedisObject, StringStorageType}};
+    use crate::util::{capture_log, log, LogLevel};
+    use super::{config_from_args, server_read, server_teardown, server_write, PipelineStats, RedisDB, RedisServer};
+
+    static LOCK: Mutex<()> = Mutex::new(());
+
+    /// Keeps the other tests out of the global server state. With the
+    /// testing-hooks feature, the keyspace is checked for expires left
+    /// without a key when the test ends.
+    pub(crate) struct TestGuard {
+        _lock: MutexGuard<'static, ()>,
+    }
+    impl Drop for TestGuard {
+        fn drop(&mut self) {
+            #[cfg(feature = "testing-hooks")]
+            if !std::thread::panicking() {
+                super::assert_expires_consistent();
+            }
+        }
+    }
+
+    /// Tests touching the global server state run one at a time. The
+    /// returned guard keeps the others out; the databases are created on
+    /// first use and emptied every time.
+    pub(crate) fn setup() -> TestGuard {
+        let guard = LOCK.lock().unwrap_or_else(|e| e.into_inner());
+        let mut server = server_write();
+        if server.dbs.is_empty() {
+            for i in 0..server.dbnum {
+                server.dbs.push(Arc::new(RwLock::new(RedisDB::new(i))));
+            }
+        }
+        server.clear();
+        TestGuard { _lock: guard }
+    }
 
     #[test]
     fn char_test() {
@@ -674,5 +1065,333 @@ mod tests {
         let lines: Vec<String> = cursor.lines().map(|l| l.unwrap()).collect();
         assert_eq!(lines.len(), 4);
     }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let args: Vec<String> = ["redis.conf", "--port", "7000", "--save", "60", "1000", "--appendonly", "yes"]
+            .iter().map(|a| a.to_string()).collect();
+        let (config_file, options) = config_from_args(&args);
+        assert_eq!(config_file.as_deref(), Some("redis.conf"));
+        assert_eq!(options, "port \"7000\"\nsave \"60\" \"1000\"\nappendonly \"yes\"");
+
+        let (config_file, options) = config_from_args(&args[1..3]);
+        assert_eq!(config_file, None);
+        assert_eq!(options, "port \"7000\"");
+
+        // The options come after the file content, so they win.
+        let mut server = RedisServer::new();
+        server.load_server_config_from_string(&format!("port 6380\n{}", options));
+        assert_eq!(server.port(), 7000);
+    }
+
+    #[test]
+    fn command_line_values_can_be_empty_or_hold_spaces() {
+        let args = |args: &[&str]| args.iter().map(|a| a.to_string()).collect::<Vec<String>>();
+        let mut server = RedisServer::new();
+        server.load_server_config_from_args(&args(&["--requirepass", "a b", "--port", "7000"]));
+        assert_eq!(server.require_pass, "a b");
+        assert_eq!(server.port(), 7000);
+
+        server.load_server_config_from_args(&args(&["--requirepass", ""]));
+        assert_eq!(server.require_pass, "");
+    }
+
+    #[test]
+    fn command_line_accepts_memory_units() {
+        let conf = std::env::temp_dir().join(format!("rudis-cli-{}.conf", std::process::id()));
+        std::fs::write(&conf, "port 6380\nmaxmemory 1gb\n").unwrap();
+        let args: Vec<String> = [conf.to_str().unwrap(), "--port", "7000", "--maxmemory", "100mb"]
+            .iter().map(|a| a.to_string()).collect();
+
+        let mut server = RedisServer::new();
+        server.load_server_config_from_args(&args);
+        std::fs::remove_file(&conf).unwrap();
+        assert_eq!(server.port(), 7000);
+        assert_eq!(server.max_memory, 100 * 1024 * 1024);
+
+        let mut server = RedisServer::new();
+        server.load_server_config_from_args(&["--maxmemory".to_string(), "2k".to_string()]);
+        assert_eq!(server.max_memory, 2000);
+    }
+
+    #[test]
+    fn empty_save_directive_disables_automatic_saves() {
+        let _guard = setup();
+        let mut server = RedisServer::new();
+        server.load_server_config(None, "save 900 1\nsave \"\"\nsave 60 10");
+        assert_eq!(server.save_params().len(), 1);
+        server.load_server_config(None, "save ''");
+        assert!(server.save_params().is_empty());
+        server.load_server_config(None, "save 900 1\nsave");
+        assert!(server.save_params().is_empty());
+
+        // From the command line the shell passes an empty argument
+        let mut server = RedisServer::new();
+        server.load_server_config_from_args(&["--port".to_string(), "0".to_string(), "--save".to_string(), String::new()]);
+        assert!(server.save_params().is_empty());
+
+        let (dirty, last_save) = (server_read().dirty, server_read().last_save);
+        let save = (lookup_config("save").unwrap().get)(&server_read()).join(" ");
+        server_write().load_server_config_from_string("save 1 0\nsave \"\"");
+        server_write().dirty = 1000;
+        server_write().last_save = 0;
+        for _ in 0..3 {
+            server_cron(0, None);
+        }
+        assert_eq!(server_read().bg_save_child_pid, -1);
+
+        let mut server = server_write();
+        let param = lookup_config("save").unwrap();
+        (param.set)(&mut server, &save.split_whitespace().collect::<Vec<_>>()).unwrap();
+        (server.dirty, server.last_save) = (dirty, last_save);
+    }
+
+    #[test]
+    fn hz_is_configurable() {
+        let mut server = RedisServer::new();
+        assert_eq!(server.hz, 10);
+        server.load_server_config(None, "hz 100\n");
+        assert_eq!(server.hz, 100);
+    }
+
+    #[test]
+    fn active_expire_reaps_faster_with_a_higher_hz() {
+        let _guard = setup();
+        let db = server_read().dbs[0].clone();
+        let fill = || {
+            let mut db_w = db.write().unwrap();
+            for i in 0..2000 {
+                let key = format!("expired:{}", i);
+                db_w.dict.insert(key.clone(), Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("v".to_string()) })));
+                db_w.expires.insert(key, 1);
+            }
+            db_w.dict.insert("live".to_string(), Arc::new(RwLock::new(RedisObject::String { ptr: StringStorageType::String("v".to_string()) })));
+            db_w.expires.insert("live".to_string(), u64::MAX);
+        };
+        // One second worth of cron calls.
+        let reaped_in_a_second = |hz: usize| {
+            server_write().clear();
+            fill();
+            let dirty = server_read().dirty;
+            let reaped: usize = (0..hz).map(|_| server_write().active_expire_cycle().len()).sum();
+            assert_eq!(server_read().dirty, dirty + reaped as u128);
+            assert_eq!(db.read().unwrap().dict.len(), 2001 - reaped);
+            assert!(db.read().unwrap().dict.contains_key("live"));
+            reaped
+        };
+        let slow = reaped_in_a_second(1);
+        let fast = reaped_in_a_second(10);
+        assert!(slow > 0);
+        assert!(fast > slow, "hz 10 reaped {}, hz 1 reaped {}", fast, slow);
+
+        // Nothing to reap.
+        server_write().clear();
+        assert!(server_write().active_expire_cycle().is_empty());
+    }
+
+    /// The fields of an INFO reply, checking the bulk framing.
+    pub(crate) fn parse_info(reply: &str) -> HashMap<String, String> {
+        let (len, body) = reply[1..].split_once("\r\n").unwrap();
+        assert_eq!(len.parse::<usize>().unwrap() + 2, body.len(), "{}", reply);
+        body.lines()
+            .filter(|l| !l.is_empty() && !l.starts_with('#'))
+            .map(|l| l.split_once(':').unwrap())
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn warning_level_logs_no_notices() {
+        let _guard = setup();
+        let mut c = test_client();
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "loglevel", "warning"]), "+OK\r\n");
+
+        let out = capture_log(|| {
+            run(&mut c, &["SET", "k", "v"]);
+            run(&mut c, &["GET", "k"]);
+            run(&mut c, &["INFO"]);
+            drop(test_client());
+            log(LogLevel::Notice, "a notice");
+            log(LogLevel::Verbose, "a verbose line");
+            log(LogLevel::Warning, "a warning");
+        });
+        assert!(out.ends_with(" #: a warning\n"), "unexpected log: {:?}", out);
+        assert_eq!(out.lines().count(), 1, "unexpected log: {:?}", out);
+
+        assert_eq!(run(&mut c, &["CONFIG", "SET", "loglevel", "verbose"]), "+OK\r\n");
+        assert!(capture_log(|| log(LogLevel::Verbose, "a verbose line")).ends_with(" -: a verbose line\n"));
+    }
+
+    #[test]
+    fn info_reports_the_replication_role() {
+        let _guard = setup();
+        let mut c = test_client();
+
+        let reply = run(&mut c, &["INFO"]);
+        assert!(reply.contains("# Replication\r\n"));
+        let info = parse_info(&reply);
+        assert_eq!(info["hz"], "10");
+        assert_eq!(info["role"], "master");
+        assert_eq!(info["connected_slaves"], "0");
+        assert!(!info.contains_key("master_link_status"));
+
+        assert_eq!(run(&mut c, &["SLAVEOF", "127.0.0.1", "6380"]), "+OK\r\n");
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["role"], "slave");
+        assert_eq!(info["master_host"], "127.0.0.1");
+        assert_eq!(info["master_port"], "6380");
+        assert_eq!(info["master_link_status"], "down");
+        assert_eq!(info["master_last_io_seconds_ago"], "-1");
+
+        assert!(run(&mut c, &["SLAVEOF", "127.0.0.1", "port"]).starts_with("-ERR "));
+        assert_eq!(run(&mut c, &["SLAVEOF", "no", "one"]), "+OK\r\n");
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["role"], "master");
+        assert!(!info.contains_key("master_host"));
+    }
+
+    #[test]
+    fn replicaof_and_slaveof_are_the_same_command() {
+        let _guard = setup();
+        let mut c = test_client();
+        for name in ["REPLICAOF", "SLAVEOF"] {
+            assert_eq!(run(&mut c, &[name, "127.0.0.1", "6380"]), "+OK\r\n");
+            let info = parse_info(&run(&mut c, &["INFO"]));
+            assert_eq!(info["role"], "slave", "{}", name);
+            assert_eq!(info["master_port"], "6380");
+
+            // Replicas are read only by default
+            assert_eq!(run(&mut c, &["SET", "k", "v"]), "-READONLY You can't write against a read only replica.\r\n");
+            assert_eq!(run(&mut c, &["GET", "k"]), "$-1\r\n");
+            assert_eq!(run(&mut c, &["CONFIG", "SET", "replica-read-only", "no"]), "+OK\r\n");
+            assert_eq!(run(&mut c, &["SET", "k", "v"]), "+OK\r\n");
+            assert_eq!(run(&mut c, &["CONFIG", "SET", "slave-read-only", "yes"]), "+OK\r\n");
+
+            assert_eq!(run(&mut c, &[name, "no", "one"]), "+OK\r\n");
+            let info = parse_info(&run(&mut c, &["INFO"]));
+            assert_eq!(info["role"], "master", "{}", name);
+            assert_eq!(run(&mut c, &["DEL", "k"]), ":1\r\n");
+            // NO ONE on a master is a no-op
+            assert_eq!(run(&mut c, &[name, "no", "one"]), "+OK\r\n");
+        }
+    }
+
+    #[test]
+    fn info_reports_the_server_statistics() {
+        let _guard = setup();
+        let mut c = test_client();
+        let reply = run(&mut c, &["INFO"]);
+        let (len, body) = reply[1..].split_once("\r\n").unwrap();
+        assert_eq!(len.parse::<usize>().unwrap(), body.len() - 2);
+        let info = parse_info(&reply);
+        assert_eq!(info["redis_version"], "1.3.7");
+        assert_eq!(info["role"], "master");
+        assert_eq!(info["bgsave_in_progress"], "0");
+        assert!(info["used_memory"].parse::<usize>().unwrap() > 0);
+
+        let dirty = server_read().dirty();
+        run(&mut c, &["SET", "k", "v"]);
+        let after = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(after["changes_since_last_save"], (dirty + 1).to_string());
+        assert_eq!(after["total_commands_processed"].parse::<u128>().unwrap(),
+            info["total_commands_processed"].parse::<u128>().unwrap() + 2);
+    }
+
+    #[test]
+    fn info_reports_the_subscriptions() {
+        let _guard = setup();
+        let mut c = test_client();
+        let mut other = test_client();
+
+        let info = parse_info(&run(&mut other, &["INFO"]));
+        assert_eq!(info["pubsub_channels"], "0");
+        assert_eq!(info["pubsub_patterns"], "0");
+        assert_eq!(info["blocked_clients"], "0");
+
+        run(&mut c, &["SUBSCRIBE", "a", "b"]);
+        run(&mut other, &["SUBSCRIBE", "b", "c"]);
+        run(&mut c, &["PSUBSCRIBE", "a*"]);
+        let info = parse_info(&run(&mut test_client(), &["INFO"]));
+        assert_eq!(info["pubsub_channels"], "3");
+        assert_eq!(info["pubsub_patterns"], "1");
+
+        drop(c);
+        drop(other);
+        let info = parse_info(&run(&mut test_client(), &["INFO"]));
+        assert_eq!(info["pubsub_channels"], "0");
+        assert_eq!(info["pubsub_patterns"], "0");
+    }
+
+    #[test]
+    fn info_reports_the_commands_run_per_read_event() {
+        let _guard = setup();
+        let mut c = test_client();
+        server_write().stat_pipeline = PipelineStats::default();
+        for batch in [1, 1, 5, 10, 11, 500, 2000] {
+            c.query_buf.push_str(&"PING\r\n".repeat(batch));
+            c.process_input_buf();
+            assert_eq!(take_replies(&mut c), "+PONG\r\n".repeat(batch));
+        }
+        // A partial command runs nothing, it's not an event
+        c.query_buf.push_str("*1\r\n$4\r\nPI");
+        c.process_input_buf();
+        c.query_buf.push_str("NG\r\n");
+        c.process_input_buf();
+        assert_eq!(take_replies(&mut c), "+PONG\r\n");
+
+        // The INFO event is recorded after its reply
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["cmds_per_event_histogram"], "1=3,2-10=2,11-100=1,101-1000=1,1001+=1");
+        assert_eq!(info["cmds_per_event_mean"], format!("{:.2}", 2529.0 / 8.0));
+        assert_eq!(info["cmds_per_event_max"], "2000");
+        assert_eq!(info["cmds_per_event_p99"], "2000");
+
+        // Mostly single commands: the 99th percentile is in the first bucket
+        for _ in 0..1000 {
+            run(&mut c, &["PING"]);
+        }
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        assert_eq!(info["cmds_per_event_p99"], "1");
+        assert_eq!(info["cmds_per_event_max"], "2000");
+    }
+
+    #[test]
+    fn memory_peak_outlives_the_data() {
+        let _guard = setup();
+        let mut c = test_client();
+        for i in 0..200 {
+            run(&mut c, &["SET", &format!("key:{}", i), &"x".repeat(1024)]);
+        }
+        let full = parse_info(&run(&mut c, &["INFO"]));
+        run(&mut c, &["FLUSHALL"]);
+        let info = parse_info(&run(&mut c, &["INFO"]));
+        let peak: usize = info["used_memory_peak"].parse().unwrap();
+        assert!(peak >= info["used_memory"].parse().unwrap());
+        assert!(peak >= full["used_memory"].parse().unwrap());
+        assert!(info["used_memory_rss"].parse::<usize>().unwrap() > 0);
+        assert!(info["mem_fragmentation_ratio"].parse::<f64>().unwrap() > 0.0);
+    }
+
+    #[test]
+    fn teardown_releases_the_port_and_the_clients() {
+        let _guard = setup();
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let fd = tcp_server(port, "127.0.0.1", DEFAULT_TCP_BACKLOG).unwrap();
+        server_write().fd = fd;
+        let mut peer = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let (cfd, _, _) = accept(fd).unwrap();
+        let client = RedisClient::create(cfd).unwrap();
+
+        server_teardown();
+        assert_eq!(server_read().fd, -1);
+        assert_eq!(client.read().unwrap().fd(), -1);
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+
+        let fd = tcp_server(port, "127.0.0.1", DEFAULT_TCP_BACKLOG).expect("the port should be free again");
+        unsafe { close(fd); }
+
+        unlist(&client);
+    }
 }
 
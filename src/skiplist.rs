@@ -45,7 +45,7 @@ impl SkipList {
                     compare_string_objects(x.read().unwrap().forward[i].as_ref().unwrap().read().unwrap().obj.as_ref().unwrap().as_ref(), obj.as_ref()) == Ordering::Less)) {
                 
                 if i > 0 {
-                    rank[i] += x.read().unwrap().forward[i].as_ref().unwrap().read().unwrap().span[i - 1];
+                    rank[i] += x.read().unwrap().span[i - 1];
                 } else {
                     rank[i] += 1;
                 }
@@ -141,7 +141,11 @@ impl SkipList {
                         compare_string_objects(next.read().unwrap().obj.as_ref().unwrap().as_ref(), x.read().unwrap().obj.as_ref().unwrap().as_ref()) == Ordering::Equal {
                         
                         if i > 0 {
-                            update[i].as_mut().unwrap().write().unwrap().span[i - 1] += x.read().unwrap().span[i - 1] - 1;
+                            // x may span 0 if it's the last node of the level:
+                            // add before subtracting.
+                            let x_span = x.read().unwrap().span[i - 1];
+                            let mut update_w = update[i].as_mut().unwrap().write().unwrap();
+                            update_w.span[i - 1] = update_w.span[i - 1] + x_span - 1;
                         }
                         update[i].as_mut().unwrap().write().unwrap().forward[i] = x.read().unwrap().forward[i].clone();
                         continue;
@@ -314,3 +318,28 @@ impl SkipListNode {
         self.forward[level].clone()
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use crate::obj::{RedisObject, StringStorageType};
+    use super::SkipList;
+
+    #[test]
+    fn every_rank_is_reachable() {
+        // Levels are random: build a few lists, inserting out of order, so
+        // that some nodes span several others.
+        for _ in 0..50 {
+            let mut zsl = SkipList::new();
+            for i in 0..64 {
+                let score = (i * 37) % 64;
+                zsl.insert(score as f64, Arc::new(RedisObject::String { ptr: StringStorageType::String(score.to_string()) }));
+            }
+            for rank in 1..=zsl.len() {
+                let node = zsl.get_ele_by_rank(rank).expect("every rank is reachable");
+                assert_eq!(node.read().unwrap().score(), (rank - 1) as f64);
+            }
+        }
+    }
+}
@@ -1,6 +1,7 @@
-use std::{fmt::Display, fs::OpenOptions, io::{self, BufWriter, Write}, process::{abort, exit, id}, sync::RwLock, thread::sleep, time::{Duration, SystemTime, UNIX_EPOCH}};
+use std::{ffi::CStr, fmt::Display, fs::OpenOptions, io::{self, BufWriter, Write}, process::{abort, exit, id}, sync::{atomic::{AtomicU8, Ordering}, RwLock}, thread::sleep, time::{Duration, SystemTime, UNIX_EPOCH}};
 use chrono::Utc;
 use once_cell::sync::Lazy;
+use rand::Rng;
 
 use crate::server::server_read;
 
@@ -9,9 +10,25 @@ use crate::server::server_read;
 /// 
 
 pub fn timestamp() -> Duration {
+    #[cfg(feature = "testing-hooks")]
+    if let Some(now) = MOCK_TIME.with(|t| t.get()) {
+        return now;
+    }
     SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
 }
 
+#[cfg(feature = "testing-hooks")]
+thread_local! {
+    static MOCK_TIME: std::cell::Cell<Option<Duration>> = const { std::cell::Cell::new(None) };
+}
+
+/// Pin what timestamp() returns in the calling thread, or go back to the
+/// real clock with None, so tests can check expires without sleeping.
+#[cfg(feature = "testing-hooks")]
+pub fn set_mock_time(now: Option<Duration>) {
+    MOCK_TIME.with(|t| t.set(now));
+}
+
 pub fn get_time_ms() -> u128 {
     timestamp().as_millis()
 }
@@ -20,6 +37,68 @@ pub fn add_ms_to_now(ms: u128) -> u128 {
     get_time_ms() + ms
 }
 
+/// A random string of `len` hex characters, used for the run id.
+pub fn get_random_hex_chars(len: usize) -> String {
+    let mut rng = rand::thread_rng();
+    (0..len).map(|_| char::from_digit(rng.gen_range(0..16), 16).unwrap()).collect()
+}
+
+/// Draws approximately uniform random entries from collections that can
+/// only be walked, like a std HashMap. There is no way to jump to a random
+/// bucket, so the sampler starts at a random position and reservoir-samples
+/// a window of at most `max_scan` entries, wrapping around at the end. Every
+/// entry belongs to the same number of windows so the draw stays uniform,
+/// and a call costs one walk to the start (no clones) plus the window,
+/// instead of one `.nth()` per drawn entry.
+pub struct RandomSampler {
+    max_scan: usize,
+}
+impl RandomSampler {
+    pub const DEFAULT_MAX_SCAN: usize = 64;
+
+    pub fn new(max_scan: usize) -> RandomSampler {
+        Self { max_scan: max_scan.max(1) }
+    }
+
+    /// Up to `k` distinct entries of `iter`, fewer only if it is shorter.
+    pub fn sample<I>(&self, iter: I, k: usize) -> Vec<I::Item>
+    where I: ExactSizeIterator + Clone {
+        let len = iter.len();
+        let window = self.max_scan.max(k).min(len);
+        if k == 0 || window == 0 {
+            return Vec::new();
+        }
+        let mut rng = rand::thread_rng();
+        let start = if window == len { 0 } else { rng.gen_range(0..len) };
+        let wrapped = window.saturating_sub(len - start);
+        let scanned = iter.clone().skip(start).chain(iter.take(wrapped)).take(window);
+
+        let mut reservoir = Vec::with_capacity(k);
+        for (i, item) in scanned.enumerate() {
+            if i < k {
+                reservoir.push(item);
+            } else {
+                let j = rng.gen_range(0..=i);
+                if j < k {
+                    reservoir[j] = item;
+                }
+            }
+        }
+        reservoir
+    }
+
+    /// A single random entry, None if `iter` is empty.
+    pub fn pick<I>(&self, iter: I) -> Option<I::Item>
+    where I: ExactSizeIterator + Clone {
+        self.sample(iter, 1).pop()
+    }
+}
+impl Default for RandomSampler {
+    fn default() -> Self {
+        Self::new(Self::DEFAULT_MAX_SCAN)
+    }
+}
+
 pub fn yes_no_to_bool(s: &str) -> Result<bool, String> {
     match &s.to_ascii_lowercase()[..] {
         "yes" => { Ok(true) },
@@ -28,8 +107,161 @@ pub fn yes_no_to_bool(s: &str) -> Result<bool, String> {
     }
 }
 
-pub fn string_pattern_match(_pattern: &str, _key: &str) -> bool {
-    todo!()
+/// Convert a memory amount like "100mb" into bytes. Like in Redis "k", "m"
+/// and "g" are powers of 1000, "kb", "mb" and "gb" powers of 1024, and the
+/// unit is case insensitive. A plain number is taken as bytes.
+pub fn mem_to_bytes(s: &str) -> Result<u128, String> {
+    let s = s.to_ascii_lowercase();
+    let digits = s.find(|c: char| !c.is_ascii_digit()).unwrap_or(s.len());
+    let mul: u128 = match &s[digits..] {
+        "" | "b" => 1,
+        "k" => 1000,
+        "kb" => 1024,
+        "m" => 1000 * 1000,
+        "mb" => 1024 * 1024,
+        "g" => 1000 * 1000 * 1000,
+        "gb" => 1024 * 1024 * 1024,
+        _ => { return Err(format!("invalid memory unit in '{}'", s)); },
+    };
+    match s[..digits].parse::<u128>() {
+        Ok(n) => Ok(n * mul),
+        Err(e) => Err(e.to_string()),
+    }
+}
+
+/// Split a config line into arguments, as Redis' sdssplitargs() does.
+/// Arguments are separated by spaces and can be quoted: "double quoted"
+/// ones take the \n, \r, \t, \b, \a and \xHH escapes, 'single quoted'
+/// ones only \'. None on unbalanced quotes, on a closing quote not
+/// followed by a space, or on escapes making invalid UTF-8.
+pub fn split_args(line: &str) -> Option<Vec<String>> {
+    let mut args = Vec::new();
+    let mut p = line.as_bytes();
+    loop {
+        p = p.trim_ascii_start();
+        if p.is_empty() {
+            return Some(args);
+        }
+        let mut arg = Vec::new();
+        let quote = match p[0] {
+            q @ (b'"' | b'\'') => { p = &p[1..]; Some(q) },
+            _ => None,
+        };
+        loop {
+            match (quote, p) {
+                (None, []) => break,
+                (None, [c, ..]) if c.is_ascii_whitespace() => break,
+                (Some(_), []) => return None,
+                (Some(b'"'), [b'\\', b'x', h, l, rest @ ..]) if h.is_ascii_hexdigit() && l.is_ascii_hexdigit() => {
+                    arg.push(((*h as char).to_digit(16)? << 4 | (*l as char).to_digit(16)?) as u8);
+                    p = rest;
+                },
+                (Some(b'"'), [b'\\', c, rest @ ..]) => {
+                    arg.push(match c { b'n' => b'\n', b'r' => b'\r', b't' => b'\t', b'b' => 8, b'a' => 7, c => *c });
+                    p = rest;
+                },
+                (Some(b'\''), [b'\\', b'\'', rest @ ..]) => {
+                    arg.push(b'\'');
+                    p = rest;
+                },
+                (Some(q), [c, rest @ ..]) if *c == q => {
+                    // The closing quote must end the argument
+                    if rest.first().is_some_and(|c| !c.is_ascii_whitespace()) {
+                        return None;
+                    }
+                    p = rest;
+                    break;
+                },
+                (_, [c, rest @ ..]) => {
+                    arg.push(*c);
+                    p = rest;
+                },
+            }
+        }
+        args.push(String::from_utf8(arg).ok()?);
+    }
+}
+
+/// Quote `s` so that split_args() reads it back as a single argument, as
+/// Redis' sdscatrepr() does.
+pub fn quote_arg(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '\\' => quoted.push_str("\\\\"),
+            '"' => quoted.push_str("\\\""),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_ascii_control() => quoted.push_str(&format!("\\x{:02x}", c as u8)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// Glob-style matching of `key` against `pattern`, as Redis does it:
+/// `*` matches any sequence of bytes, `?` a single byte, `[...]` one byte
+/// of a class (`[^...]` negates it, `a-z` is a range) and `\` makes the
+/// next byte match itself. Everything else matches itself.
+pub fn string_pattern_match(pattern: &str, key: &str) -> bool {
+    glob_match(pattern.as_bytes(), key.as_bytes())
+}
+fn glob_match(pattern: &[u8], key: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => key.is_empty(),
+        Some((b'*', rest)) => {
+            // A run of stars is one star, don't try every split for each
+            let rest = &rest[rest.iter().take_while(|c| **c == b'*').count()..];
+            rest.is_empty() || (0..=key.len()).any(|i| glob_match(rest, &key[i..]))
+        },
+        Some((b'?', rest)) => !key.is_empty() && glob_match(rest, &key[1..]),
+        Some((b'[', rest)) => match key.split_first() {
+            Some((c, key_rest)) => {
+                let (matched, rest) = class_match(rest, *c);
+                matched && glob_match(rest, key_rest)
+            },
+            None => false,
+        },
+        // A trailing backslash matches itself
+        Some((b'\\', [c, rest @ ..])) => key.first() == Some(c) && glob_match(rest, &key[1..]),
+        Some((c, rest)) => key.first() == Some(c) && glob_match(rest, &key[1..]),
+    }
+}
+/// Match `c` against the class starting after its `[`. Returns whether it
+/// matched and the pattern after the closing `]`. An unterminated class
+/// runs to the end of the pattern.
+fn class_match(mut pattern: &[u8], c: u8) -> (bool, &[u8]) {
+    let negate = pattern.first() == Some(&b'^');
+    if negate {
+        pattern = &pattern[1..];
+    }
+    let mut matched = false;
+    loop {
+        match pattern {
+            [] => break,
+            [b']', rest @ ..] => {
+                pattern = rest;
+                break;
+            },
+            [b'\\', e, rest @ ..] => {
+                matched |= *e == c;
+                pattern = rest;
+            },
+            [lo, b'-', hi, rest @ ..] if *hi != b']' => {
+                let (lo, hi) = if lo <= hi { (*lo, *hi) } else { (*hi, *lo) };
+                matched |= (lo..=hi).contains(&c);
+                pattern = rest;
+            },
+            [e, rest @ ..] => {
+                matched |= *e == c;
+                pattern = rest;
+            },
+        }
+    }
+    (matched != negate, pattern)
 }
 
 #[cfg(target_os = "linux")]
@@ -50,6 +282,11 @@ pub fn error() -> i32 {
     }
 }
 
+/// The description of the given errno, as strerror(3) gives it.
+pub fn strerror(errno: i32) -> String {
+    unsafe { CStr::from_ptr(libc::strerror(errno)).to_string_lossy().into_owned() }
+}
+
 #[derive(Clone, Copy)]
 pub enum LogLevel {
     Debug,
@@ -113,11 +350,37 @@ static LOG_WRITER: Lazy<RwLock<BufWriter<Box<dyn Write + Sync + Send>>>> = Lazy:
 
     RwLock::new(BufWriter::new(_writer.unwrap()))
 });
-static LOG_LEVEL: Lazy<LogLevel> = Lazy::new(|| { *server_read().verbosity() });
+/// The configured verbosity, kept out of the server so that log() never
+/// takes the server lock. Updated when 'loglevel' is set.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Verbose as u8);
+
+pub fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+fn log_level() -> LogLevel {
+    match LOG_LEVEL.load(Ordering::Relaxed) {
+        0 => LogLevel::Debug,
+        1 => LogLevel::Verbose,
+        2 => LogLevel::Notice,
+        _ => LogLevel::Warning,
+    }
+}
+
+/// Open the log with the configured file. It happens on the first log()
+/// anyway, but that reads the server config: call this once the config is
+/// loaded so that logging while holding the server lock is safe.
+pub fn init_log() {
+    set_log_level(*server_read().verbosity());
+    Lazy::force(&LOG_WRITER);
+}
+
+/// Would a line at this level be written? To skip building costly ones.
+pub fn log_enabled(level: LogLevel) -> bool {
+    !level.less(&log_level())
+}
 
-/// TODO: more convinent macro
 pub fn log(level: LogLevel, body: &str) {
-    if level.less(&LOG_LEVEL) {
+    if !log_enabled(level) {
         return;
     }
 
@@ -133,6 +396,40 @@ pub fn log(level: LogLevel, body: &str) {
     }
 }
 
+/// Debug logging for the hot paths (events fired, AOF writes...): the
+/// arguments aren't even evaluated unless the debug-logging feature is on.
+#[macro_export]
+macro_rules! log_debug {
+    ($($arg:tt)*) => {
+        if cfg!(feature = "debug-logging") {
+            $crate::util::log($crate::util::LogLevel::Debug, &format!($($arg)*));
+        }
+    };
+}
+
+/// Run `f` with the log written to a buffer instead, returning what it got.
+#[cfg(test)]
+pub(crate) fn capture_log(f: impl FnOnce()) -> String {
+    use std::sync::{Arc, Mutex};
+    struct Capture(Arc<Mutex<Vec<u8>>>);
+    impl Write for Capture {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let captured = Arc::new(Mutex::new(Vec::new()));
+    let writer = BufWriter::new(Box::new(Capture(captured.clone())) as Box<dyn Write + Sync + Send>);
+    let previous = std::mem::replace(&mut *LOG_WRITER.write().unwrap(), writer);
+    f();
+    *LOG_WRITER.write().unwrap() = previous;
+    let out = String::from_utf8_lossy(&captured.lock().unwrap()).to_string();
+    out
+}
+
 /// Redis generally does not try to recover from out of memory conditions
 /// when allocating objects or strings, it is not clear if it will be possible
 /// to report this condition to the client since the networking layer itself
@@ -154,6 +451,110 @@ mod tests {
         assert!(LogLevel::Debug.less(&LogLevel::Notice));
     }
 
+    #[test]
+    fn mem_to_bytes_units() {
+        assert_eq!(mem_to_bytes("1234"), Ok(1234));
+        assert_eq!(mem_to_bytes("10b"), Ok(10));
+        assert_eq!(mem_to_bytes("2k"), Ok(2000));
+        assert_eq!(mem_to_bytes("2kb"), Ok(2048));
+        assert_eq!(mem_to_bytes("100mb"), Ok(100 << 20));
+        assert_eq!(mem_to_bytes("100M"), Ok(100_000_000));
+        assert_eq!(mem_to_bytes("1GB"), Ok(1 << 30));
+        assert_eq!(mem_to_bytes("3g"), Ok(3_000_000_000));
+        assert!(mem_to_bytes("").is_err());
+        assert!(mem_to_bytes("mb").is_err());
+        assert!(mem_to_bytes("10tb").is_err());
+        assert!(mem_to_bytes("-1").is_err());
+    }
+
+    #[test]
+    fn split_args_handles_quotes() {
+        let args = |line: &str| split_args(line).map(|v| v.join("|"));
+        assert_eq!(args("save 60 1000"), Some("save|60|1000".to_string()));
+        assert_eq!(args("  port\t6379  "), Some("port|6379".to_string()));
+        assert_eq!(split_args("save \"\""), Some(vec!["save".to_string(), String::new()]));
+        assert_eq!(split_args("save ''"), Some(vec!["save".to_string(), String::new()]));
+        assert_eq!(args("requirepass \"a b\""), Some("requirepass|a b".to_string()));
+        assert_eq!(args("x \"\\\"\\n\\x41\\\\\" 'it\\'s'"), Some("x|\"\nA\\|it's".to_string()));
+        assert_eq!(args("x \"é\""), Some("x|é".to_string()));
+        assert_eq!(split_args("x \"unterminated"), None);
+        assert_eq!(split_args("x 'a'b"), None);
+        assert_eq!(split_args("x \"\\xff\""), None);
+
+        for s in ["", "a b", "\"quoted\"", "back\\slash", "new\nline\r\t", "\x01ctl", "ünïcode"] {
+            assert_eq!(split_args(&quote_arg(s)), Some(vec![s.to_string()]), "{:?}", s);
+        }
+    }
+
+    #[test]
+    fn pattern_wildcards() {
+        assert!(string_pattern_match("*", ""));
+        assert!(string_pattern_match("news.*", "news.tech"));
+        assert!(string_pattern_match("news.*", "news."));
+        assert!(!string_pattern_match("news.*", "news"));
+        assert!(string_pattern_match("h?llo", "hello"));
+        assert!(!string_pattern_match("h?llo", "hllo"));
+        assert!(string_pattern_match("*a*b", "xaybzb"));
+        assert!(!string_pattern_match("*a*b", "xaybz"));
+        assert!(string_pattern_match("a**b", "ab"));
+    }
+
+    #[test]
+    fn pattern_classes() {
+        assert!(string_pattern_match("h[ae]llo", "hello"));
+        assert!(string_pattern_match("h[ae]llo", "hallo"));
+        assert!(!string_pattern_match("h[ae]llo", "hillo"));
+        assert!(!string_pattern_match("h[ae]llo", "hllo"));
+        assert!(string_pattern_match("[a-c]*", "banana"));
+        assert!(!string_pattern_match("[a-c]*", "date"));
+        assert!(string_pattern_match("[c-a]x", "bx"));
+        assert!(string_pattern_match("x[a-]", "x-"));
+        // An empty class matches nothing
+        assert!(!string_pattern_match("[]x", "x"));
+        // An unterminated class runs to the end of the pattern
+        assert!(string_pattern_match("[ab", "a"));
+    }
+
+    #[test]
+    fn pattern_negated_classes() {
+        assert!(string_pattern_match("h[^e]llo", "hallo"));
+        assert!(!string_pattern_match("h[^e]llo", "hello"));
+        assert!(string_pattern_match("[^a-c]*", "date"));
+        assert!(!string_pattern_match("[^a-c]*", "banana"));
+        assert!(!string_pattern_match("[^a]", ""));
+    }
+
+    #[test]
+    fn pattern_escapes() {
+        assert!(string_pattern_match("h\\*llo", "h*llo"));
+        assert!(!string_pattern_match("h\\*llo", "hello"));
+        assert!(string_pattern_match("what\\?", "what?"));
+        assert!(!string_pattern_match("what\\?", "whatx"));
+        assert!(string_pattern_match("\\[a\\]", "[a]"));
+        assert!(string_pattern_match("[\\]]", "]"));
+        assert!(string_pattern_match("[\\^a]", "^"));
+        assert!(string_pattern_match("end\\", "end\\"));
+    }
+
+    #[test]
+    fn random_sampler_is_uniform() {
+        let map: std::collections::HashMap<u32, ()> = (0..100).map(|i| (i, ())).collect();
+        let sampler = RandomSampler::new(8);
+        let mut hits = [0u32; 100];
+        for _ in 0..100_000 {
+            hits[*sampler.pick(map.keys()).unwrap() as usize] += 1;
+        }
+        // 1000 expected per key.
+        assert!(hits.iter().all(|&h| (700..1300).contains(&h)), "{:?}", hits);
+
+        let mut drawn = sampler.sample(map.keys(), 20);
+        drawn.sort();
+        drawn.dedup();
+        assert_eq!(drawn.len(), 20);
+        assert_eq!(sampler.sample(map.keys().take(3), 5).len(), 3);
+        assert_eq!(sampler.pick(std::iter::empty::<u32>()), None);
+    }
+
     #[test]
     fn log_print_test() {
         log(LogLevel::Notice, &format!("hello {}", "redis"));
@@ -9,6 +9,7 @@ use std::sync::atomic::{AtomicUsize, Ordering::Relaxed};
 static A: MemCounter = MemCounter;
 
 static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
 
 pub struct MemCounter;
 
@@ -16,6 +17,37 @@ impl MemCounter {
     pub fn used_memory() -> usize {
         ALLOCATED.load(Relaxed)
     }
+
+    /// Record the current usage as the peak if it's above it. Returns the peak.
+    pub fn update_peak() -> usize {
+        let used = Self::used_memory();
+        PEAK.fetch_max(used, Relaxed).max(used)
+    }
+
+    /// Resident set size of the process as seen by the OS. Falls back to
+    /// used_memory() where there is no way to get it.
+    pub fn used_memory_rss() -> usize {
+        #[cfg(target_os = "linux")]
+        {
+            // The second field of statm is the number of resident pages
+            let statm = std::fs::read_to_string("/proc/self/statm").unwrap_or_default();
+            if let Some(Ok(pages)) = statm.split_whitespace().nth(1).map(str::parse::<usize>) {
+                return pages * unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+            }
+        }
+        #[cfg(target_os = "macos")]
+        {
+            let mut info: libc::proc_taskinfo = unsafe { std::mem::zeroed() };
+            let size = std::mem::size_of::<libc::proc_taskinfo>() as i32;
+            let ret = unsafe {
+                libc::proc_pidinfo(libc::getpid(), libc::PROC_PIDTASKINFO, 0, &mut info as *mut _ as *mut libc::c_void, size)
+            };
+            if ret == size {
+                return info.pti_resident_size as usize;
+            }
+        }
+        Self::used_memory()
+    }
 }
 
 unsafe impl GlobalAlloc for MemCounter {